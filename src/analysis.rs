@@ -0,0 +1,478 @@
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use anyhow::Result;
+use rodio::Source;
+use serde_json::{json, Value};
+
+use crate::fingerprint::{Chromagram, Downmix, SAMPLE_RATE};
+use crate::player;
+
+// ReplayGain 2.0 aims every track at -18 LUFS.
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+// Loudness is measured in 100ms steps; momentary blocks are 4 of them and
+// short-term windows 30.
+const STEPS_PER_SECOND: usize = 10;
+const MOMENTARY: usize = 4;
+const SHORT_TERM: usize = 30;
+// EBU R128 gates: blocks below -70 LUFS don't count at all, and neither
+// do blocks 10 LU (20 LU for the range) below the average of the ones
+// above -70.
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const RANGE_GATE: f64 = -20.0;
+// True peak: 4x oversampled with a windowed sinc over 12 input samples.
+const OVERSAMPLE: usize = 4;
+const TAPS: usize = 12;
+// The "DR" figure of the TT Dynamic Range meter, over 3 second blocks.
+const DR_BLOCK_SECONDS: usize = 3;
+// Onsets are tracked about every 5ms (every 55 samples, so really 200.45
+// times a second), and only tempos in this range are tried.
+const ONSETS_PER_SECOND: usize = 200;
+const ONSET_HOP: usize = SAMPLE_RATE / ONSETS_PER_SECOND;
+const MIN_BPM: f64 = 70.0;
+const MAX_BPM: f64 = 180.0;
+// Below this average rise in log level per step it's a held sound with
+// nothing to tap along to; and the onsets must repeat at least this
+// strongly against their overall variance.
+const MIN_FLUX: f64 = 0.05;
+const MIN_PULSE: f64 = 0.1;
+// Krumhansl-Kessler key profiles, from the tonic up.
+const MAJOR: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+// Pitch class 0 of the chromagram is A.
+const NOTES: [&str; 12] = ["A", "Bb", "B", "C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    // Integrated loudness in LUFS.
+    pub loudness: f64,
+    // In dBTP.
+    pub true_peak: f64,
+    // Loudness range in LU.
+    pub range: f64,
+    // As the DR meters print it: peak to loud RMS in dB.
+    pub dynamic_range: f64,
+    pub bpm: Option<f64>,
+    // "A minor"
+    pub key: Option<String>,
+    // The file as it was measured.
+    pub stamp: Option<Stamp>,
+}
+
+// A file's modification time (unix seconds) and size. A result whose stamp
+// no longer matches the file is measured again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stamp {
+    modified: i64,
+    size: u64,
+}
+
+impl Stamp {
+    pub fn of(path: &Path) -> Option<Stamp> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        Some(Stamp { modified, size: metadata.len() })
+    }
+}
+
+impl Analysis {
+    // Reads the whole file once, through the same decoder the player uses.
+    pub fn compute(path: &Path) -> Result<Analysis> {
+        let stamp = Stamp::of(path);
+        let decoder = player::open(path)?;
+        let rate = decoder.sample_rate() as usize;
+        let channels = decoder.channels().max(1) as usize;
+
+        let mut loudness = Loudness::new(rate as f64, channels);
+        let mut peaks: Vec<TruePeak> = (0..channels).map(|_| TruePeak::default()).collect();
+        let mut dr: Vec<DynamicRange> = (0..channels).map(|_| DynamicRange::new(rate)).collect();
+        let mut downmix = Downmix::new(rate as u32, channels as u16);
+        let mut chromagram = Chromagram::centred();
+        let mut chroma = [0.0; 12];
+        let mut onsets = Onsets::default();
+
+        for (i, sample) in decoder.enumerate() {
+            let channel = i % channels;
+            let x = sample as f64 / 32768.0;
+            loudness.push(channel, x);
+            peaks[channel].push(x);
+            dr[channel].push(x);
+            downmix.push(sample, |mono| {
+                onsets.push(mono);
+                // Each frame counts the same however loud it is.
+                if let Some(frame) = chromagram.push(mono) {
+                    let total: f64 = frame.iter().sum();
+                    if total > 0.0 {
+                        for (sum, x) in chroma.iter_mut().zip(frame) {
+                            *sum += x / total;
+                        }
+                    }
+                }
+            });
+        }
+
+        let (integrated, range) = loudness.finish();
+        let integrated = integrated.ok_or_else(|| anyhow::anyhow!("{} is silent", path.display()))?;
+        let peak = peaks.iter().map(|p| p.peak).fold(0.0, f64::max);
+        let dynamic_ranges: Vec<f64> = dr.into_iter().filter_map(DynamicRange::finish).collect();
+        Ok(Analysis {
+            loudness: integrated,
+            true_peak: 20.0 * peak.log10(),
+            range,
+            dynamic_range: (dynamic_ranges.iter().sum::<f64>() / dynamic_ranges.len().max(1) as f64).max(0.0),
+            bpm: onsets.tempo(),
+            key: key(&chroma),
+            stamp,
+        })
+    }
+
+    // Whether this is still the measurement of the file as it is now.
+    // Results saved before files were stamped never are.
+    pub fn is_current(&self, path: &Path) -> bool {
+        self.stamp.is_some() && self.stamp == Stamp::of(path)
+    }
+
+    // Track gain in dB and peak as a fraction of full scale.
+    pub fn replaygain(&self) -> (f64, f64) {
+        (REPLAYGAIN_REFERENCE - self.loudness, 10f64.powf(self.true_peak / 20.0))
+    }
+
+    // "-14.2 LUFS  -0.8 dBTP  LRA 6.1 LU  DR9  128 BPM  A minor"
+    pub fn summary(&self) -> String {
+        let mut parts = vec![
+            format!("{:.1} LUFS", self.loudness),
+            format!("{:.1} dBTP", self.true_peak),
+            format!("LRA {:.1} LU", self.range),
+            format!("DR{:.0}", self.dynamic_range),
+        ];
+        parts.extend(self.bpm.map(|bpm| format!("{:.0} BPM", bpm)));
+        parts.extend(self.key.clone());
+        parts.join("  ")
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "loudness": self.loudness,
+            "true_peak": self.true_peak,
+            "range": self.range,
+            "dynamic_range": self.dynamic_range,
+            "bpm": self.bpm,
+            "key": self.key,
+            "modified": self.stamp.map(|stamp| stamp.modified),
+            "size": self.stamp.map(|stamp| stamp.size),
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Option<Analysis> {
+        Some(Analysis {
+            loudness: value["loudness"].as_f64()?,
+            true_peak: value["true_peak"].as_f64()?,
+            range: value["range"].as_f64().unwrap_or(0.0),
+            dynamic_range: value["dynamic_range"].as_f64().unwrap_or(0.0),
+            bpm: value["bpm"].as_f64(),
+            key: value["key"].as_str().map(str::to_string),
+            stamp: value["modified"].as_i64()
+                .zip(value["size"].as_u64())
+                .map(|(modified, size)| Stamp { modified, size }),
+        })
+    }
+}
+
+// A biquad in direct form I.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// The K-weighting of BS.1770: a high shelf for the head, then a high pass,
+// worked out for any sample rate.
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+    [shelf, high_pass]
+}
+
+fn lufs(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+// Integrated loudness and loudness range per EBU R128 / Tech 3342.
+struct Loudness {
+    filters: Vec<[Biquad; 2]>,
+    // Surround channels count a bit more, the LFE not at all.
+    weights: Vec<f64>,
+    step_len: usize,
+    in_step: usize,
+    energy: f64,
+    // Weighted mean square of each 100ms step.
+    steps: Vec<f64>,
+}
+
+impl Loudness {
+    fn new(rate: f64, channels: usize) -> Loudness {
+        let weights = match channels {
+            6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+            _ => vec![1.0; channels],
+        };
+        Loudness {
+            filters: vec![k_weighting(rate); channels],
+            weights,
+            step_len: (rate as usize / STEPS_PER_SECOND).max(1),
+            in_step: 0,
+            energy: 0.0,
+            steps: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, channel: usize, x: f64) {
+        let [shelf, high_pass] = &mut self.filters[channel];
+        let y = high_pass.process(shelf.process(x));
+        self.energy += self.weights[channel] * y * y;
+        if channel + 1 == self.filters.len() {
+            self.in_step += 1;
+            if self.in_step == self.step_len {
+                self.steps.push(self.energy / self.step_len as f64);
+                self.energy = 0.0;
+                self.in_step = 0;
+            }
+        }
+    }
+
+    fn blocks(&self, len: usize, every: usize) -> Vec<f64> {
+        self.steps.windows(len).step_by(every).map(|w| w.iter().sum::<f64>() / len as f64).collect()
+    }
+
+    // Integrated loudness (`None` when nothing clears the gate) and range.
+    fn finish(&self) -> (Option<f64>, f64) {
+        let integrated = gated_mean(&self.blocks(MOMENTARY, 1), RELATIVE_GATE).map(lufs);
+
+        // Short-term loudness every second.
+        let short_term = self.blocks(SHORT_TERM, STEPS_PER_SECOND);
+        let range = match absolute_mean(&short_term) {
+            Some(mean) => {
+                let gate = lufs(mean) + RANGE_GATE;
+                let mut levels: Vec<f64> = short_term.iter()
+                    .map(|&z| lufs(z))
+                    .filter(|&level| level > ABSOLUTE_GATE && level > gate)
+                    .collect();
+                levels.sort_by(f64::total_cmp);
+                let at = |p: f64| levels[((levels.len() - 1) as f64 * p).round() as usize];
+                at(0.95) - at(0.10)
+            }
+            None => 0.0,
+        };
+        (integrated, range)
+    }
+}
+
+fn mean(blocks: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = blocks.fold((0.0, 0), |(sum, count), z| (sum + z, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+// Mean square of the blocks above the absolute gate.
+fn absolute_mean(blocks: &[f64]) -> Option<f64> {
+    mean(blocks.iter().copied().filter(|&z| lufs(z) > ABSOLUTE_GATE))
+}
+
+// Mean square of the blocks left after both gates.
+fn gated_mean(blocks: &[f64], relative: f64) -> Option<f64> {
+    let gate = lufs(absolute_mean(blocks)?) + relative;
+    mean(blocks.iter().copied().filter(|&z| lufs(z) > ABSOLUTE_GATE && lufs(z) > gate))
+}
+
+// Sample peaks can miss the real peak between samples, which is what
+// clips once the file is decoded and resampled; interpolating catches it.
+struct TruePeak {
+    history: [f64; TAPS],
+    filter: Vec<f64>,
+    peak: f64,
+}
+
+impl Default for TruePeak {
+    fn default() -> TruePeak {
+        let len = TAPS * OVERSAMPLE;
+        let center = (len - 1) as f64 / 2.0;
+        let filter = (0..len)
+            .map(|i| {
+                let t = (i as f64 - center) / OVERSAMPLE as f64;
+                let sinc = if t == 0.0 { 1.0 } else { (std::f64::consts::PI * t).sin() / (std::f64::consts::PI * t) };
+                let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * (i as f64 + 0.5) / len as f64).cos();
+                sinc * window
+            })
+            .collect();
+        TruePeak { history: [0.0; TAPS], filter, peak: 0.0 }
+    }
+}
+
+impl TruePeak {
+    fn push(&mut self, x: f64) {
+        self.history.rotate_right(1);
+        self.history[0] = x;
+        self.peak = self.peak.max(x.abs());
+        for phase in 0..OVERSAMPLE {
+            let y: f64 = self.history.iter()
+                .enumerate()
+                .map(|(k, x)| x * self.filter[k * OVERSAMPLE + phase])
+                .sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+}
+
+// Per channel: the second highest block peak against the RMS of the
+// loudest fifth of the blocks.
+struct DynamicRange {
+    block_len: usize,
+    in_block: usize,
+    square: f64,
+    peak: f64,
+    blocks: Vec<(f64, f64)>,
+}
+
+impl DynamicRange {
+    fn new(rate: usize) -> DynamicRange {
+        DynamicRange { block_len: rate * DR_BLOCK_SECONDS, in_block: 0, square: 0.0, peak: 0.0, blocks: Vec::new() }
+    }
+
+    fn push(&mut self, x: f64) {
+        self.square += x * x;
+        self.peak = self.peak.max(x.abs());
+        self.in_block += 1;
+        if self.in_block == self.block_len {
+            self.end_block();
+        }
+    }
+
+    fn end_block(&mut self) {
+        if self.in_block > 0 {
+            let rms = (2.0 * self.square / self.in_block as f64).sqrt();
+            self.blocks.push((rms, self.peak));
+        }
+        self.in_block = 0;
+        self.square = 0.0;
+        self.peak = 0.0;
+    }
+
+    fn finish(mut self) -> Option<f64> {
+        self.end_block();
+        let mut rms: Vec<f64> = self.blocks.iter().map(|(rms, _)| *rms).collect();
+        let mut peaks: Vec<f64> = self.blocks.iter().map(|(_, peak)| *peak).collect();
+        rms.sort_by(|a, b| b.total_cmp(a));
+        peaks.sort_by(|a, b| b.total_cmp(a));
+        let loud = &rms[..(rms.len() / 5).max(1).min(rms.len())];
+        let loud_rms = (loud.iter().map(|r| r * r).sum::<f64>() / loud.len() as f64).sqrt();
+        let peak = *peaks.get(1).or(peaks.first())?;
+        (loud_rms > 0.0 && peak > 0.0).then(|| 20.0 * (peak / loud_rms).log10())
+    }
+}
+
+// How sharply the level rises, a few hundred times a second.
+#[derive(Default)]
+struct Onsets {
+    energy: f64,
+    count: usize,
+    last: Option<f64>,
+    flux: Vec<f64>,
+}
+
+impl Onsets {
+    fn push(&mut self, x: f64) {
+        self.energy += x * x;
+        self.count += 1;
+        if self.count == ONSET_HOP {
+            let level = (self.energy + 1.0).ln();
+            if let Some(last) = self.last {
+                self.flux.push((level - last).max(0.0));
+            }
+            self.last = Some(level);
+            self.energy = 0.0;
+            self.count = 0;
+        }
+    }
+
+    // The beat period the onsets repeat at most strongly, with its double
+    // counted too so a half-time beat doesn't win by a fluke.
+    fn tempo(&self) -> Option<f64> {
+        if self.flux.len() < 10 * ONSETS_PER_SECOND {
+            return None;
+        }
+        let mean = self.flux.iter().sum::<f64>() / self.flux.len() as f64;
+        if mean < MIN_FLUX {
+            return None;
+        }
+        let flux: Vec<f64> = self.flux.iter().map(|x| x - mean).collect();
+        let correlation = |lag: usize| -> f64 {
+            flux.iter().zip(&flux[lag.min(flux.len())..]).map(|(a, b)| a * b).sum()
+        };
+        let rate = SAMPLE_RATE as f64 / ONSET_HOP as f64;
+        let lag_of = |bpm: f64| (60.0 * rate / bpm).round() as usize;
+        let score = |lag: usize| correlation(lag) + 0.5 * correlation(2 * lag);
+        let (lag, best) = (lag_of(MAX_BPM)..=lag_of(MIN_BPM))
+            .map(|lag| (lag, score(lag)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        if best <= MIN_PULSE * correlation(0) {
+            return None;
+        }
+        // Between the neighbouring lags, for more than whole-step precision.
+        let (before, after) = (score(lag - 1), score(lag + 1));
+        let shift = 0.5 * (before - after) / (before - 2.0 * best + after);
+        let lag = lag as f64 + if shift.is_finite() { shift.clamp(-0.5, 0.5) } else { 0.0 };
+        Some(60.0 * rate / lag)
+    }
+}
+
+// The key whose profile best matches the track's pitch classes.
+fn key(chroma: &[f64; 12]) -> Option<String> {
+    if chroma.iter().all(|&x| x == 0.0) {
+        return None;
+    }
+    let mut best: Option<(f64, String)> = None;
+    for tonic in 0..12 {
+        for (profile, mode) in [(&MAJOR, "major"), (&MINOR, "minor")] {
+            let rotated: Vec<f64> = (0..12).map(|i| chroma[(tonic + i) % 12]).collect();
+            let score = correlation(&rotated, profile);
+            if best.as_ref().is_none_or(|(top, _)| score > *top) {
+                best = Some((score, format!("{} {}", NOTES[tonic], mode)));
+            }
+        }
+    }
+    best.map(|(_, key)| key)
+}
+
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let mean = |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64;
+    let (ma, mb) = (mean(a), mean(b));
+    let cov: f64 = a.iter().zip(b).map(|(x, y)| (x - ma) * (y - mb)).sum();
+    let va: f64 = a.iter().map(|x| (x - ma).powi(2)).sum();
+    let vb: f64 = b.iter().map(|y| (y - mb).powi(2)).sum();
+    cov / (va * vb).sqrt()
+}
+
@@ -0,0 +1,477 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Context, Result};
+
+use crate::playlist::{self, SortKey};
+use crate::smart::{self, Query};
+use crate::rename::{Pattern, DEFAULT_PATTERN};
+use crate::tags::{Edit, Field};
+
+pub const COMMANDS: [&str; 24] = [
+    "play", "pause", "toggle", "stop", "next", "prev", "seek", "vol", "shuffle", "save",
+    "add", "sort", "filter", "query", "smart", "playlist", "dupes", "tag", "identify", "rename",
+    "analyze", "clear", "empty", "quit",
+];
+
+pub const FILTER_FIELDS: [&str; 10] = [
+    "path", "title", "artist", "album", "genre", "year", "rating", "favorite", "plays", "skips",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Seek {
+    To(Duration),
+    Forward(Duration),
+    Back(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Volume {
+    Set(u8),
+    Up(u8),
+    Down(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Play(Option<usize>),
+    Pause,
+    Toggle,
+    Stop,
+    Next,
+    Prev,
+    Seek(Seek),
+    Volume(Volume),
+    Shuffle(Option<bool>),
+    Save(PathBuf),
+    Add(PathBuf),
+    Sort(SortKey),
+    Filter(Option<(String, String)>),
+    // A smart playlist query to load, checked when parsed.
+    Query(String),
+    // Load a saved smart playlist, or list them.
+    Smart(Option<String>),
+    SmartSave(String, String),
+    SmartDelete(String),
+    // Load a named playlist, or list them.
+    Playlist(Option<String>),
+    PlaylistNew(String),
+    PlaylistRename(String, String),
+    PlaylistCopy(String, String),
+    PlaylistDelete(String),
+    // Add the selected tracks to a named playlist.
+    PlaylistAdd(String),
+    // Look for duplicates in the library, by fingerprint too when true.
+    Dupes(bool),
+    // Open the tag editor on the selection, queue an edit there (opening
+    // it first if needed), or write the pending edits.
+    TagEdit,
+    Tag(Edit),
+    TagWrite,
+    // Look the tag editor's files up in the fingerprint database.
+    Identify,
+    // Measure loudness, tempo and key of the selection, also writing
+    // ReplayGain tags when true.
+    Analyze(bool),
+    // Plan renaming the selection from its tags, or carry out the plan.
+    Rename(Pattern),
+    RenameApply,
+    Clear,
+    Empty,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command> {
+        let line = line.trim().trim_start_matches(':');
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (line, ""),
+        };
+
+        let command = match name {
+            "play" if arg.is_empty() => Command::Play(None),
+            "play" => {
+                let number: usize = arg.parse().with_context(|| format!("Invalid track number: {}", arg))?;
+                if number == 0 {
+                    anyhow::bail!("Track numbers start at 1");
+                }
+                Command::Play(Some(number - 1))
+            }
+            "pause" => Command::Pause,
+            "toggle" => Command::Toggle,
+            "stop" => Command::Stop,
+            "next" => Command::Next,
+            "prev" => Command::Prev,
+            "seek" => Command::Seek(parse_seek(arg)?),
+            "vol" | "volume" => Command::Volume(parse_volume(arg)?),
+            "shuffle" => Command::Shuffle(match arg {
+                "on" => Some(true),
+                "off" => Some(false),
+                "" | "toggle" => None,
+                _ => anyhow::bail!("Usage: shuffle on|off|toggle"),
+            }),
+            "save" => Command::Save(expand_path(required(arg, "save <file.m3u>")?)),
+            "add" => Command::Add(expand_path(required(arg, "add <path>")?)),
+            "sort" => {
+                let key = required(arg, "sort <key>")?;
+                Command::Sort(SortKey::parse(key).with_context(|| {
+                    format!("Unknown sort key '{}' (try {})", key, SortKey::NAMES.join(", "))
+                })?)
+            }
+            "filter" if arg.is_empty() || arg == "off" => Command::Filter(None),
+            "filter" => {
+                let (field, value) = arg.split_once('=').context("Usage: filter <field>=<value>")?;
+                let field = field.trim();
+                if !FILTER_FIELDS.contains(&field) {
+                    anyhow::bail!("Unknown filter field '{}' (try {})", field, FILTER_FIELDS.join(", "));
+                }
+                Command::Filter(Some((field.to_string(), value.trim().to_string())))
+            }
+            "query" => {
+                let query = required(arg, "query <query>")?;
+                Query::parse(query)?;
+                Command::Query(query.to_string())
+            }
+            "smart" => parse_smart(arg)?,
+            "playlist" => parse_playlist(arg)?,
+            "dupes" => Command::Dupes(match arg {
+                "" => false,
+                "audio" => true,
+                _ => anyhow::bail!("Usage: dupes [audio]"),
+            }),
+            "tag" => parse_tag(arg)?,
+            "identify" => Command::Identify,
+            "analyze" => Command::Analyze(match arg {
+                "" => false,
+                "replaygain" => true,
+                _ => anyhow::bail!("Usage: analyze [replaygain]"),
+            }),
+            "rename" if arg == "apply" => Command::RenameApply,
+            "rename" => Command::Rename(Pattern::parse(required(arg, "rename <pattern> (or rename apply)")?)?),
+            "clear" => Command::Clear,
+            "empty" => Command::Empty,
+            "quit" | "q" => Command::Quit,
+            "" => anyhow::bail!("Empty command"),
+            _ => anyhow::bail!("Unknown command: {}", name),
+        };
+        Ok(command)
+    }
+}
+
+// `tag`, `tag <field> <value>`, `tag clear <field>`, `tag number`,
+// `tag frompath <pattern>`, `tag write`.
+fn parse_tag(arg: &str) -> Result<Command> {
+    let (action, rest) = match arg.split_once(char::is_whitespace) {
+        Some((action, rest)) => (action, rest.trim()),
+        None => (arg, ""),
+    };
+    let field = |name: &str| {
+        Field::parse(name).with_context(|| format!("Unknown tag '{}' (try {})", name, Field::NAMES.join(", ")))
+    };
+    let command = match action {
+        "" => Command::TagEdit,
+        "write" => Command::TagWrite,
+        "number" => Command::Tag(Edit::Number),
+        "frompath" => Command::Tag(Edit::FromPath(Pattern::parse(required(rest, "tag frompath <pattern>")?)?)),
+        "clear" => Command::Tag(Edit::Clear(field(required(rest, "tag clear <field>")?)?)),
+        name => {
+            let field = field(name)?;
+            let value = required(rest, &format!("tag {} <value> (or tag clear {})", name, name))?;
+            Command::Tag(Edit::Set(field, value.to_string()))
+        }
+    };
+    Ok(command)
+}
+
+// `smart`, `smart <name>`, `smart save <name> <query>`, `smart delete <name>`.
+fn parse_smart(arg: &str) -> Result<Command> {
+    let (action, rest) = match arg.split_once(char::is_whitespace) {
+        Some((action, rest)) => (action, rest.trim()),
+        None => (arg, ""),
+    };
+    let command = match action {
+        "" => Command::Smart(None),
+        "save" => {
+            let usage = "smart save <name> <query>";
+            let (name, query) = required(rest, usage)?
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("Usage: {}", usage))?;
+            Query::parse(query)?;
+            Command::SmartSave(name.to_string(), query.trim().to_string())
+        }
+        "delete" => Command::SmartDelete(required(rest, "smart delete <name>")?.to_string()),
+        name if rest.is_empty() => Command::Smart(Some(name.to_string())),
+        _ => anyhow::bail!("Usage: smart [<name> | save <name> <query> | delete <name>]"),
+    };
+    Ok(command)
+}
+
+// `playlist`, `playlist <name>`, `playlist new|delete|add <name>`,
+// `playlist rename|copy <name> <new name>`.
+fn parse_playlist(arg: &str) -> Result<Command> {
+    let words: Vec<&str> = arg.split_whitespace().collect();
+    let command = match words.as_slice() {
+        [] => Command::Playlist(None),
+        ["new", name] => Command::PlaylistNew(name.to_string()),
+        ["delete", name] => Command::PlaylistDelete(name.to_string()),
+        ["add", name] => Command::PlaylistAdd(name.to_string()),
+        ["rename", old, new] => Command::PlaylistRename(old.to_string(), new.to_string()),
+        ["copy", old, new] => Command::PlaylistCopy(old.to_string(), new.to_string()),
+        [name] => Command::Playlist(Some(name.to_string())),
+        _ => anyhow::bail!("Usage: playlist [<name> | new|delete|add <name> | rename|copy <name> <new name>]"),
+    };
+    Ok(command)
+}
+
+fn required<'a>(arg: &'a str, usage: &str) -> Result<&'a str> {
+    if arg.is_empty() {
+        anyhow::bail!("Usage: {}", usage);
+    }
+    Ok(arg)
+}
+
+// Accepts "90", "1:30", "1:02:03", and relative "+10" / "-1:00".
+fn parse_seek(arg: &str) -> Result<Seek> {
+    let arg = required(arg, "seek [+|-]<[h:]m:ss|seconds>")?;
+    if let Some(rest) = arg.strip_prefix('+') {
+        Ok(Seek::Forward(parse_time(rest)?))
+    } else if let Some(rest) = arg.strip_prefix('-') {
+        Ok(Seek::Back(parse_time(rest)?))
+    } else {
+        Ok(Seek::To(parse_time(arg)?))
+    }
+}
+
+pub fn parse_time(text: &str) -> Result<Duration> {
+    let mut secs = 0u64;
+    for part in text.split(':') {
+        let value: u64 = part.parse().with_context(|| format!("Invalid time: {}", text))?;
+        secs = secs.checked_mul(60)
+            .and_then(|secs| secs.checked_add(value))
+            .with_context(|| format!("Time out of range: {}", text))?;
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_volume(arg: &str) -> Result<Volume> {
+    let arg = required(arg, "vol [+|-]<0-100>")?;
+    let parse = |s: &str| -> Result<u8> {
+        let value: u8 = s.parse().with_context(|| format!("Invalid volume: {}", arg))?;
+        Ok(value.min(100))
+    };
+    if let Some(rest) = arg.strip_prefix('+') {
+        Ok(Volume::Up(parse(rest)?))
+    } else if let Some(rest) = arg.strip_prefix('-') {
+        Ok(Volume::Down(parse(rest)?))
+    } else {
+        Ok(Volume::Set(parse(arg)?))
+    }
+}
+
+pub fn expand_path(path: &str) -> PathBuf {
+    if path == "~" {
+        return dirs::home_dir().unwrap_or_else(|| PathBuf::from(path));
+    }
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = dirs::home_dir() {
+            return home.join(rest);
+        }
+    }
+    PathBuf::from(path)
+}
+
+// Candidate completions for the whole input line.
+pub fn complete(input: &str) -> Vec<String> {
+    let (name, arg) = match input.split_once(' ') {
+        Some((name, arg)) => (name, arg.trim_start()),
+        None => {
+            return COMMANDS.iter()
+                .filter(|c| c.starts_with(input))
+                .map(|c| format!("{} ", c))
+                .collect();
+        }
+    };
+
+    let words: Vec<String> = match name {
+        "save" | "add" => return complete_path(arg)
+            .into_iter()
+            .map(|p| format!("{} {}", name, p))
+            .collect(),
+        "sort" => SortKey::NAMES.iter().map(|s| s.to_string()).collect(),
+        "shuffle" => ["on", "off", "toggle"].iter().map(|s| s.to_string()).collect(),
+        "dupes" => vec!["audio".to_string()],
+        "analyze" => vec!["replaygain".to_string()],
+        "tag" => ["write", "number", "clear", "frompath"].into_iter().chain(Field::NAMES).map(|s| s.to_string()).collect(),
+        "rename" => vec!["apply".to_string(), DEFAULT_PATTERN.to_string()],
+        "filter" => FILTER_FIELDS.iter().map(|s| format!("{}=", s)).chain(["off".to_string()]).collect(),
+        "smart" => ["save ".to_string(), "delete ".to_string()].into_iter().chain(smart::saved_names()).collect(),
+        "playlist" => {
+            // After an action word, complete the playlist name.
+            let actions = ["new", "delete", "add", "rename", "copy"];
+            let (prefix, arg) = match arg.split_once(' ') {
+                Some((action, rest)) if actions.contains(&action) => (format!("{} {} ", name, action), rest),
+                _ => {
+                    let words = actions.iter().map(|a| format!("{} ", a)).chain(playlist::named_playlists());
+                    return words.filter(|w| w.starts_with(arg)).map(|w| format!("{} {}", name, w)).collect();
+                }
+            };
+            return playlist::named_playlists()
+                .into_iter()
+                .filter(|w| w.starts_with(arg))
+                .map(|w| format!("{}{}", prefix, w))
+                .collect();
+        }
+        _ => Vec::new(),
+    };
+    words.into_iter()
+        .filter(|w| w.starts_with(arg))
+        .map(|w| format!("{} {}", name, w))
+        .collect()
+}
+
+fn complete_path(partial: &str) -> Vec<String> {
+    // Split what the user typed into the directory to list and the file
+    // prefix to match, keeping their spelling (e.g. `~/`) in the result.
+    let (dir_part, file_prefix) = match partial.rfind('/') {
+        Some(pos) => (&partial[..=pos], &partial[pos + 1..]),
+        None => ("", partial),
+    };
+    let dir = if dir_part.is_empty() {
+        PathBuf::from(".")
+    } else {
+        expand_path(dir_part)
+    };
+
+    let mut candidates: Vec<String> = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !name.starts_with(file_prefix) || (name.starts_with('.') && !file_prefix.starts_with('.')) {
+                    return None;
+                }
+                let suffix = if entry.path().is_dir() { "/" } else { "" };
+                Some(format!("{}{}{}", dir_part, name, suffix))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    candidates.sort();
+    candidates
+}
+
+#[derive(Debug, Default)]
+pub struct CommandLine {
+    pub active: bool,
+    pub input: String,
+    history: Vec<String>,
+    history_pos: Option<usize>,
+    completions: Vec<String>,
+    completion_idx: usize,
+}
+
+impl CommandLine {
+    pub fn open(&mut self) {
+        self.active = true;
+        self.input.clear();
+        self.history_pos = None;
+        self.completions.clear();
+    }
+
+    // Opens with `text` already typed, for keys that prompt for a name.
+    pub fn open_with(&mut self, text: &str) {
+        self.open();
+        self.input.push_str(text);
+    }
+
+    pub fn close(&mut self) {
+        self.active = false;
+        self.input.clear();
+        self.completions.clear();
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.input.push(c);
+        self.completions.clear();
+    }
+
+    pub fn backspace(&mut self) {
+        if self.input.pop().is_none() {
+            self.close();
+        }
+        self.completions.clear();
+    }
+
+    // Tab cycles through the candidates computed on the first press.
+    pub fn complete(&mut self) {
+        if self.completions.is_empty() {
+            self.completions = complete(&self.input);
+            self.completion_idx = 0;
+        } else {
+            self.completion_idx = (self.completion_idx + 1) % self.completions.len();
+        }
+        if let Some(candidate) = self.completions.get(self.completion_idx) {
+            self.input = candidate.clone();
+        }
+        // A single candidate is final; the next Tab should complete further
+        // from it (e.g. into a directory).
+        if self.completions.len() == 1 {
+            self.completions.clear();
+        }
+    }
+
+    pub fn history_prev(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+        let pos = match self.history_pos {
+            Some(0) => 0,
+            Some(pos) => pos - 1,
+            None => self.history.len() - 1,
+        };
+        self.history_pos = Some(pos);
+        self.input = self.history[pos].clone();
+        self.completions.clear();
+    }
+
+    pub fn history_next(&mut self) {
+        match self.history_pos {
+            Some(pos) if pos + 1 < self.history.len() => {
+                self.history_pos = Some(pos + 1);
+                self.input = self.history[pos + 1].clone();
+            }
+            _ => {
+                self.history_pos = None;
+                self.input.clear();
+            }
+        }
+        self.completions.clear();
+    }
+
+    pub fn submit(&mut self) -> Option<String> {
+        let line = self.input.trim().to_string();
+        self.close();
+        if line.is_empty() {
+            return None;
+        }
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        Some(line)
+    }
+}
+
+pub fn default_rc_path() -> Option<PathBuf> {
+    crate::config::config_dir().map(|dir| dir.join("rc"))
+}
+
+// Non-empty, non-comment lines of an rc file with their line numbers.
+pub fn read_rc(path: &Path) -> Result<Vec<(usize, String)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(content.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim().to_string()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect())
+}
@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use toml::{Table, Value};
+
+use crate::command::expand_path;
+use crate::hooks::EVENTS;
+
+pub const APP_NAME: &str = "rust-cli-music-player";
+pub const ENV_PREFIX: &str = "RCMP_";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    Off,
+    Track,
+    Position,
+}
+
+impl Resume {
+    fn parse(value: &str) -> Option<Resume> {
+        match value {
+            "off" => Some(Resume::Off),
+            "track" => Some(Resume::Track),
+            "position" => Some(Resume::Position),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScrobbleService {
+    ListenBrainz,
+    LastFm,
+}
+
+// The `[scrobble]` table. Scrobbling starts once the credentials for the
+// chosen service are filled in; `url` points it at a compatible server.
+#[derive(Debug, Clone)]
+pub struct ScrobbleConfig {
+    pub service: ScrobbleService,
+    pub url: Option<String>,
+    pub token: Option<String>,
+    pub api_key: Option<String>,
+    pub api_secret: Option<String>,
+    pub session_key: Option<String>,
+}
+
+impl Default for ScrobbleConfig {
+    fn default() -> Self {
+        ScrobbleConfig {
+            service: ScrobbleService::ListenBrainz,
+            url: None,
+            token: None,
+            api_key: None,
+            api_secret: None,
+            session_key: None,
+        }
+    }
+}
+
+impl ScrobbleConfig {
+    // Shared by the config table and RCMP_SCROBBLE_* variables.
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let value = Some(value.to_string()).filter(|v| !v.is_empty());
+        match key {
+            "service" => {
+                self.service = match value.as_deref() {
+                    Some("listenbrainz") => ScrobbleService::ListenBrainz,
+                    Some("lastfm") => ScrobbleService::LastFm,
+                    _ => anyhow::bail!("expected \"listenbrainz\" or \"lastfm\""),
+                }
+            }
+            "url" => self.url = value,
+            "token" => self.token = value,
+            "api_key" => self.api_key = value,
+            "api_secret" => self.api_secret = value,
+            "session_key" => self.session_key = value,
+            _ => anyhow::bail!("unknown key '{}'", key),
+        }
+        Ok(())
+    }
+}
+
+// Settings resolved from, in increasing priority: built-in defaults,
+// config.toml, RCMP_* environment variables, then command-line flags.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub music_dirs: Vec<PathBuf>,
+    pub volume: f32,
+    pub output: Option<String>,
+    pub tick_rate: Duration,
+    pub theme: String,
+    pub keymap: Option<PathBuf>,
+    pub keys: HashMap<String, HashMap<String, String>>,
+    pub extensions: Vec<String>,
+    pub exclude: Vec<String>,
+    pub max_depth: Option<usize>,
+    pub hidden: bool,
+    pub follow_symlinks: bool,
+    pub resume: Resume,
+    pub mpris: bool,
+    pub ipc: bool,
+    pub history: bool,
+    pub rating_tags: bool,
+    // Album headers in the playlist view.
+    pub group_albums: bool,
+    pub mpd: Option<String>,
+    pub http: Option<String>,
+    // Needed for the HTTP API unless it only listens on localhost.
+    pub http_token: Option<String>,
+    // Event name (see `hooks::EVENTS`) to the shell command it runs.
+    pub hooks: HashMap<String, String>,
+    pub scrobble: ScrobbleConfig,
+    // Known fingerprints to identify untagged files with; see `identify`.
+    pub fingerprint_db: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            music_dirs: vec![PathBuf::from(".")],
+            volume: 0.5,
+            output: None,
+            tick_rate: Duration::from_millis(50),
+            theme: "default".to_string(),
+            keymap: None,
+            keys: HashMap::new(),
+            extensions: vec!["mp3".to_string(), "wav".to_string()],
+            exclude: Vec::new(),
+            max_depth: None,
+            hidden: false,
+            follow_symlinks: true,
+            resume: Resume::Off,
+            mpris: true,
+            ipc: true,
+            history: true,
+            rating_tags: false,
+            group_albums: false,
+            mpd: None,
+            http: None,
+            http_token: None,
+            hooks: HashMap::new(),
+            scrobble: ScrobbleConfig::default(),
+            fingerprint_db: None,
+        }
+    }
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(APP_NAME))
+}
+
+pub fn data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join(APP_NAME))
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config.toml"))
+}
+
+impl Config {
+    // Reads the config file (an explicit path must exist, the default one
+    // may not) and then the environment. Problems are collected rather than
+    // stopping at the first one so `config check` can list them all.
+    pub fn load(explicit: Option<&Path>) -> Result<(Config, Vec<String>)> {
+        let mut config = Config::default();
+        let mut problems = Vec::new();
+
+        let path = explicit.map(Path::to_path_buf).or_else(default_path);
+        if let Some(path) = path {
+            if explicit.is_some() || path.exists() {
+                let content = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let table: Table = toml::from_str(&content)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?;
+                config.apply_table(&table, &mut problems);
+            }
+        }
+
+        config.apply_env(std::env::vars(), &mut problems);
+        Ok((config, problems))
+    }
+
+    fn apply_table(&mut self, table: &Table, problems: &mut Vec<String>) {
+        for (key, value) in table {
+            let result = match key.as_str() {
+                "music_dirs" => string_list(value).map(|dirs| {
+                    self.music_dirs = dirs.iter().map(|d| expand_path(d)).collect();
+                }),
+                "volume" => number(value).and_then(|v| self.set_volume(v)),
+                "output" => string(value).map(|v| self.output = output(&v)),
+                "tick_rate_ms" => integer(value).and_then(|v| self.set_tick_rate(v)),
+                "theme" => string(value).map(|v| self.theme = v),
+                "keymap" => string(value).map(|v| self.keymap = Some(expand_path(&v))),
+                "keys" => keys(value).map(|v| self.keys = v),
+                "extensions" => string_list(value).map(|v| self.extensions = normalize_extensions(v)),
+                "exclude" => string_list(value).and_then(|v| {
+                    build_globset(&v)?;
+                    self.exclude = v;
+                    Ok(())
+                }),
+                "max_depth" => integer(value).and_then(|v| self.set_max_depth(v)),
+                "hidden" => boolean(value).map(|v| self.hidden = v),
+                "follow_symlinks" => boolean(value).map(|v| self.follow_symlinks = v),
+                "resume" => string(value).and_then(|v| self.set_resume(&v)),
+                "mpris" => boolean(value).map(|v| self.mpris = v),
+                "ipc" => boolean(value).map(|v| self.ipc = v),
+                "history" => boolean(value).map(|v| self.history = v),
+                "rating_tags" => boolean(value).map(|v| self.rating_tags = v),
+                "group_albums" => boolean(value).map(|v| self.group_albums = v),
+                "mpd" => string(value).map(|v| self.mpd = address(&v)),
+                "http" => string(value).map(|v| self.http = address(&v)),
+                "http_token" => string(value).map(|v| self.http_token = Some(v).filter(|v| !v.is_empty())),
+                "hooks" => hooks(value).map(|v| self.hooks = v),
+                "scrobble" => self.set_scrobble(value),
+                "fingerprint_db" => string(value).map(|v| self.fingerprint_db = Some(expand_path(&v))),
+                _ => Err(anyhow::anyhow!("unknown key")),
+            };
+            if let Err(err) = result {
+                problems.push(format!("{}: {}", key, err));
+            }
+        }
+    }
+
+    fn apply_env(&mut self, vars: impl Iterator<Item = (String, String)>, problems: &mut Vec<String>) {
+        for (name, value) in vars {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) => key,
+                None => continue,
+            };
+            let list = || -> Vec<String> {
+                value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+            };
+            let result = match key {
+                "MUSIC_DIRS" => {
+                    self.music_dirs = list().iter().map(|dir| expand_path(dir)).collect();
+                    Ok(())
+                }
+                "VOLUME" => value.parse::<f64>()
+                    .context("expected a number")
+                    .and_then(|v| self.set_volume(v)),
+                "OUTPUT" => {
+                    self.output = output(&value);
+                    Ok(())
+                }
+                "TICK_RATE_MS" => value.parse::<i64>()
+                    .context("expected an integer")
+                    .and_then(|v| self.set_tick_rate(v)),
+                "THEME" => {
+                    self.theme = value.clone();
+                    Ok(())
+                }
+                "KEYMAP" => {
+                    self.keymap = Some(expand_path(&value));
+                    Ok(())
+                }
+                "EXTENSIONS" => {
+                    self.extensions = normalize_extensions(list());
+                    Ok(())
+                }
+                "EXCLUDE" => {
+                    let patterns = list();
+                    build_globset(&patterns).map(|_| self.exclude = patterns)
+                }
+                "MAX_DEPTH" => value.parse::<i64>()
+                    .context("expected an integer")
+                    .and_then(|v| self.set_max_depth(v)),
+                "HIDDEN" => parse_bool(&value).map(|v| self.hidden = v),
+                "FOLLOW_SYMLINKS" => parse_bool(&value).map(|v| self.follow_symlinks = v),
+                "RESUME" => self.set_resume(&value),
+                "MPRIS" => parse_bool(&value).map(|v| self.mpris = v),
+                "IPC" => parse_bool(&value).map(|v| self.ipc = v),
+                "HISTORY" => parse_bool(&value).map(|v| self.history = v),
+                "RATING_TAGS" => parse_bool(&value).map(|v| self.rating_tags = v),
+                "GROUP_ALBUMS" => parse_bool(&value).map(|v| self.group_albums = v),
+                "MPD" => {
+                    self.mpd = address(&value);
+                    Ok(())
+                }
+                "HTTP" => {
+                    self.http = address(&value);
+                    Ok(())
+                }
+                "HTTP_TOKEN" => {
+                    self.http_token = Some(value.clone()).filter(|v| !v.is_empty());
+                    Ok(())
+                }
+                "FINGERPRINT_DB" => {
+                    self.fingerprint_db = Some(expand_path(&value));
+                    Ok(())
+                }
+                hook if hook.starts_with("HOOK_") => {
+                    let event = hook["HOOK_".len()..].to_lowercase();
+                    check_hook_event(&event).map(|_| {
+                        self.hooks.insert(event, value.clone());
+                    })
+                }
+                setting if setting.starts_with("SCROBBLE_") => {
+                    self.scrobble.set(&setting["SCROBBLE_".len()..].to_lowercase(), &value)
+                }
+                // Not ours to complain about, e.g. unrelated tools sharing the prefix.
+                _ => continue,
+            };
+            if let Err(err) = result {
+                problems.push(format!("{}: {}", name, err));
+            }
+        }
+    }
+
+    fn set_volume(&mut self, volume: f64) -> Result<()> {
+        if !(0.0..=1.0).contains(&volume) {
+            anyhow::bail!("must be between 0.0 and 1.0");
+        }
+        self.volume = volume as f32;
+        Ok(())
+    }
+
+    fn set_tick_rate(&mut self, ms: i64) -> Result<()> {
+        if !(1..=1000).contains(&ms) {
+            anyhow::bail!("must be between 1 and 1000");
+        }
+        self.tick_rate = Duration::from_millis(ms as u64);
+        Ok(())
+    }
+
+    pub fn set_max_depth(&mut self, depth: i64) -> Result<()> {
+        if depth < 1 {
+            anyhow::bail!("must be at least 1");
+        }
+        self.max_depth = Some(depth as usize);
+        Ok(())
+    }
+
+    // Adds to the patterns from the config rather than replacing them.
+    pub fn add_exclude(&mut self, patterns: &[String]) -> Result<()> {
+        let mut exclude = self.exclude.clone();
+        exclude.extend(patterns.iter().cloned());
+        build_globset(&exclude)?;
+        self.exclude = exclude;
+        Ok(())
+    }
+
+    fn set_resume(&mut self, value: &str) -> Result<()> {
+        self.resume = Resume::parse(value).context("expected \"off\", \"track\" or \"position\"")?;
+        Ok(())
+    }
+
+    fn set_scrobble(&mut self, value: &Value) -> Result<()> {
+        let table = value.as_table().context("expected a table")?;
+        for (key, value) in table {
+            let value = value.as_str().with_context(|| format!("scrobble.{} should be a string", key))?;
+            self.scrobble.set(key, value).map_err(|err| anyhow::anyhow!("{}: {}", key, err))?;
+        }
+        Ok(())
+    }
+
+    pub fn exclude_set(&self) -> GlobSet {
+        // Patterns were validated when they were loaded.
+        build_globset(&self.exclude).unwrap_or_else(|_| GlobSet::empty())
+    }
+}
+
+fn output(value: &str) -> Option<String> {
+    if value.is_empty() || value == "default" {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+// Where a network server (MPD, HTTP) listens; empty or "off" disables it.
+fn address(value: &str) -> Option<String> {
+    match value.trim() {
+        "" | "off" => None,
+        address => Some(address.to_string()),
+    }
+}
+
+fn normalize_extensions(extensions: Vec<String>) -> Vec<String> {
+    extensions.into_iter()
+        .map(|e| e.trim_start_matches('.').to_lowercase())
+        .collect()
+}
+
+pub fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("invalid glob '{}'", pattern))?);
+    }
+    Ok(builder.build()?)
+}
+
+fn string(value: &Value) -> Result<String> {
+    value.as_str().map(str::to_string).context("expected a string")
+}
+
+fn number(value: &Value) -> Result<f64> {
+    value.as_float()
+        .or_else(|| value.as_integer().map(|i| i as f64))
+        .context("expected a number")
+}
+
+fn boolean(value: &Value) -> Result<bool> {
+    value.as_bool().context("expected true or false")
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => anyhow::bail!("expected true or false"),
+    }
+}
+
+fn integer(value: &Value) -> Result<i64> {
+    value.as_integer().context("expected an integer")
+}
+
+fn string_list(value: &Value) -> Result<Vec<String>> {
+    value.as_array()
+        .context("expected a list of strings")?
+        .iter()
+        .map(|v| v.as_str().map(str::to_string).context("expected a list of strings"))
+        .collect()
+}
+
+fn keys(value: &Value) -> Result<HashMap<String, HashMap<String, String>>> {
+    let table = value.as_table().context("expected tables of key bindings")?;
+    let mut modes = HashMap::new();
+    for (mode, bindings) in table {
+        let bindings = bindings.as_table()
+            .with_context(|| format!("[keys.{}] should be a table", mode))?;
+        let mut map = HashMap::new();
+        for (key, action) in bindings {
+            let action = action.as_str()
+                .with_context(|| format!("keys.{}.\"{}\" should be a string", mode, key))?;
+            map.insert(key.clone(), action.to_string());
+        }
+        modes.insert(mode.clone(), map);
+    }
+    Ok(modes)
+}
+
+fn hooks(value: &Value) -> Result<HashMap<String, String>> {
+    let table = value.as_table().context("expected a table of event = \"command\"")?;
+    let mut hooks = HashMap::new();
+    for (event, command) in table {
+        check_hook_event(event)?;
+        let command = command.as_str()
+            .with_context(|| format!("hooks.{} should be a string", event))?;
+        hooks.insert(event.clone(), command.to_string());
+    }
+    Ok(hooks)
+}
+
+fn check_hook_event(event: &str) -> Result<()> {
+    if !EVENTS.contains(&event) {
+        anyhow::bail!("unknown event '{}', expected one of {}", event, EVENTS.join(", "));
+    }
+    Ok(())
+}
+
+// Last played track and position, written on every quit and read back at
+// startup when `resume` is enabled.
+#[derive(Debug, Clone)]
+pub struct ResumeState {
+    pub track: PathBuf,
+    pub position: Duration,
+}
+
+fn state_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("state.toml"))
+}
+
+impl ResumeState {
+    pub fn load() -> Option<ResumeState> {
+        let content = std::fs::read_to_string(state_path()?).ok()?;
+        let table: Table = toml::from_str(&content).ok()?;
+        Some(ResumeState {
+            track: PathBuf::from(table.get("track")?.as_str()?),
+            position: Duration::from_secs(table.get("position")?.as_integer()?.max(0) as u64),
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = state_path().context("No data directory")?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut table = Table::new();
+        table.insert("track".to_string(), Value::String(self.track.to_string_lossy().to_string()));
+        table.insert("position".to_string(), Value::Integer(self.position.as_secs() as i64));
+        std::fs::write(&path, toml::to_string(&table)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+
+use crate::config::APP_NAME;
+
+pub fn default_pidfile() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("{}.pid", APP_NAME))
+}
+
+// Holds the pidfile for as long as the daemon runs and removes it on exit.
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    pub fn create(path: &Path) -> Result<Pidfile> {
+        if let Some(pid) = read_pid(path) {
+            if process_exists(pid) {
+                anyhow::bail!("Already running with pid {} (see {})", pid, path.display());
+            }
+            // Left behind by a daemon that didn't exit cleanly.
+            let _ = std::fs::remove_file(path);
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Only one of two daemons starting at once gets to create it.
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                anyhow::bail!("Already running (see {})", path.display());
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed to create {}", path.display())),
+        };
+        let pidfile = Pidfile { path: path.to_path_buf() };
+        writeln!(file, "{}", std::process::id())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(pidfile)
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn read_pid(path: &Path) -> Option<i32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok().filter(|pid| *pid > 0)
+}
+
+fn process_exists(pid: i32) -> bool {
+    // Signal 0 only checks that the process exists; EPERM means it does
+    // but belongs to someone else.
+    let alive = unsafe { libc::kill(pid, 0) == 0 };
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Flags set from signal handlers and polled by the daemon loop:
+// SIGTERM, SIGINT and SIGHUP stop the player, SIGUSR1 skips a track.
+pub struct Signals {
+    quit: Arc<AtomicBool>,
+    skip: Arc<AtomicBool>,
+}
+
+impl Signals {
+    pub fn register() -> Result<Signals> {
+        let quit = Arc::new(AtomicBool::new(false));
+        let skip = Arc::new(AtomicBool::new(false));
+        for signal in [SIGTERM, SIGINT, SIGHUP] {
+            signal_hook::flag::register(signal, quit.clone())?;
+        }
+        signal_hook::flag::register(SIGUSR1, skip.clone())?;
+        Ok(Signals { quit, skip })
+    }
+
+    pub fn quit_requested(&self) -> bool {
+        self.quit.load(Ordering::Relaxed)
+    }
+
+    pub fn take_skip(&self) -> bool {
+        self.skip.swap(false, Ordering::Relaxed)
+    }
+}
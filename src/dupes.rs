@@ -0,0 +1,282 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::fingerprint::{Fingerprint, Index, SAME_RECORDING};
+use crate::library::{TrackInfo, UNKNOWN_ARTIST};
+
+// Why files ended up in the same group, strongest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Match {
+    Content,
+    Tags,
+    Audio,
+}
+
+impl Match {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Match::Content => "same content",
+            Match::Tags => "same tags",
+            Match::Audio => "similar audio",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Duplicate {
+    pub path: PathBuf,
+    // The file extension, e.g. "MP3".
+    pub format: String,
+    // In kbps. MP3 and WAV headers give it straight; otherwise it's the
+    // size over the length, which counts tags and cover art too.
+    pub bitrate: Option<u32>,
+    pub size: u64,
+}
+
+impl Duplicate {
+    fn read(track: &TrackInfo) -> Duplicate {
+        let size = std::fs::metadata(&track.path).map(|m| m.len()).unwrap_or(0);
+        let format = track.path.extension()
+            .map(|e| e.to_string_lossy().to_uppercase())
+            .unwrap_or_default();
+        let from_header = match format.as_str() {
+            "MP3" | "WAV" => header_bitrate(&track.path),
+            _ => None,
+        };
+        let bitrate = from_header.or_else(|| {
+            track.duration
+                .filter(|duration| !duration.is_zero())
+                .map(|duration| (size as f64 * 8.0 / 1000.0 / duration.as_secs_f64()).round() as u32)
+        });
+        Duplicate {
+            path: track.path.clone(),
+            format,
+            bitrate,
+            size,
+        }
+    }
+
+    // "MP3  320 kbps   8.1 MB  /music/a.mp3"
+    pub fn label(&self) -> String {
+        let bitrate = self.bitrate.map(|kbps| format!("{} kbps", kbps)).unwrap_or_else(|| "? kbps".to_string());
+        format!("{:<4} {:>9}  {:>8}  {}", self.format, bitrate, format_size(self.size), self.path.display())
+    }
+}
+
+// Copies of one track, best first: highest bitrate, then lossless
+// formats, then path order.
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub matches: Vec<Match>,
+    pub files: Vec<Duplicate>,
+}
+
+impl Group {
+    pub fn title(&self) -> String {
+        let matches: Vec<&str> = self.matches.iter().map(Match::label).collect();
+        format!("{} copies: {}", self.files.len(), matches.join(", "))
+    }
+}
+
+// Groups tracks that are byte-for-byte the same, share artist, album and
+// title, or (with `audio`) sound the same. Files linked any of those ways
+// end up in one group. Fingerprinting decodes every track, so it's slow.
+pub fn find(tracks: &[TrackInfo], audio: bool) -> Vec<Group> {
+    let mut links: Vec<(usize, usize, Match)> = Vec::new();
+
+    // Same size first, so only those files get read.
+    let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, track) in tracks.iter().enumerate() {
+        if let Ok(metadata) = std::fs::metadata(&track.path) {
+            by_size.entry(metadata.len()).or_default().push(i);
+        }
+    }
+    for same_size in by_size.values().filter(|indices| indices.len() > 1) {
+        let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+        for &i in same_size {
+            if let Some(hash) = content_hash(&tracks[i].path) {
+                by_hash.entry(hash).or_default().push(i);
+            }
+        }
+        link_all(&mut links, by_hash.into_values(), Match::Content);
+    }
+
+    // Titles fall back to the file name, so untagged files are left out.
+    let mut by_tags: HashMap<(String, String, String), Vec<usize>> = HashMap::new();
+    for (i, track) in tracks.iter().enumerate() {
+        if track.artist != UNKNOWN_ARTIST {
+            let key = (normalize(&track.artist), normalize(&track.album), normalize(&track.title));
+            by_tags.entry(key).or_default().push(i);
+        }
+    }
+    link_all(&mut links, by_tags.into_values(), Match::Tags);
+
+    if audio {
+        // Each print is only compared with earlier ones the index puts
+        // forward, rather than with every other track.
+        let mut prints: HashMap<usize, Fingerprint> = HashMap::new();
+        let mut index = Index::default();
+        for (i, track) in tracks.iter().enumerate() {
+            let print = match Fingerprint::compute(&track.path) {
+                Ok(print) => print,
+                Err(_) => continue,
+            };
+            for candidate in index.candidates(&print) {
+                if print.similarity(&prints[&candidate]) >= SAME_RECORDING {
+                    links.push((candidate, i, Match::Audio));
+                }
+            }
+            index.insert(i, &print);
+            prints.insert(i, print);
+        }
+    }
+
+    let mut sets = DisjointSets::new(tracks.len());
+    for &(a, b, _) in &links {
+        sets.union(a, b);
+    }
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..tracks.len() {
+        members.entry(sets.find(i)).or_default().push(i);
+    }
+    let mut matches: HashMap<usize, BTreeSet<Match>> = HashMap::new();
+    for &(a, _, kind) in &links {
+        matches.entry(sets.find(a)).or_default().insert(kind);
+    }
+
+    let mut groups: Vec<Group> = members.into_iter()
+        .filter(|(_, indices)| indices.len() > 1)
+        .map(|(root, indices)| {
+            let mut files: Vec<Duplicate> = indices.iter().map(|&i| Duplicate::read(&tracks[i])).collect();
+            files.sort_by(|a, b| {
+                b.bitrate.cmp(&a.bitrate)
+                    .then_with(|| is_lossless(b).cmp(&is_lossless(a)))
+                    .then_with(|| a.path.cmp(&b.path))
+            });
+            Group { matches: matches.remove(&root).unwrap_or_default().into_iter().collect(), files }
+        })
+        .collect();
+    groups.sort_by(|a, b| a.files[0].path.cmp(&b.files[0].path));
+    groups
+}
+
+fn link_all(links: &mut Vec<(usize, usize, Match)>, buckets: impl Iterator<Item = Vec<usize>>, kind: Match) {
+    for bucket in buckets {
+        for pair in bucket.windows(2) {
+            links.push((pair[0], pair[1], kind));
+        }
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn is_lossless(file: &Duplicate) -> bool {
+    matches!(file.format.as_str(), "WAV" | "FLAC")
+}
+
+// Not cryptographic, but collisions between real files of the same size
+// are vanishingly rare, which is all we need.
+fn content_hash(path: &Path) -> Option<u64> {
+    let mut file = File::open(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).ok()?;
+        if read == 0 {
+            return Some(hasher.finish());
+        }
+        hasher.write(&buffer[..read]);
+    }
+}
+
+// The byte rate of a WAV, or the bitrate of the first MP3 frame (a good
+// guess for constant bitrate files, which most are).
+fn header_bitrate(path: &Path) -> Option<u32> {
+    let mut file = File::open(path).ok()?;
+    let mut header = [0; 10];
+    file.read_exact(&mut header).ok()?;
+    if &header[..4] == b"RIFF" {
+        return wav_bitrate(&mut file);
+    }
+    // Skip an ID3v2 tag; its size is stored 7 bits to the byte.
+    let start = if &header[..3] == b"ID3" {
+        10 + header[6..10].iter().fold(0u64, |size, byte| size << 7 | (byte & 0x7f) as u64)
+    } else {
+        0
+    };
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut bytes = Vec::new();
+    file.take(64 * 1024).read_to_end(&mut bytes).ok()?;
+    bytes.windows(4).find_map(mp3_frame_bitrate)
+}
+
+fn wav_bitrate(file: &mut File) -> Option<u32> {
+    file.seek(SeekFrom::Start(12)).ok()?;
+    loop {
+        let mut chunk = [0; 8];
+        file.read_exact(&mut chunk).ok()?;
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        if &chunk[..4] == b"fmt " {
+            let mut format = [0; 12];
+            file.read_exact(&mut format).ok()?;
+            let byte_rate = u32::from_le_bytes([format[8], format[9], format[10], format[11]]);
+            return Some(byte_rate * 8 / 1000);
+        }
+        // Chunks are padded to an even length.
+        file.seek(SeekFrom::Current((size + size % 2) as i64)).ok()?;
+    }
+}
+
+// Layer III only; that's what .mp3 files hold.
+fn mp3_frame_bitrate(header: &[u8]) -> Option<u32> {
+    const MPEG1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const MPEG2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    let version = (header[1] >> 3) & 0b11;
+    let layer = (header[1] >> 1) & 0b11;
+    let index = (header[2] >> 4) as usize;
+    if version == 0b01 || layer != 0b01 || index == 0 || index == 15 {
+        return None;
+    }
+    Some(if version == 0b11 { MPEG1[index] } else { MPEG2[index] })
+}
+
+// "8.1 MB", "640 KB"
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{} KB", bytes / 1024)
+    }
+}
+
+struct DisjointSets {
+    parent: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        DisjointSets { parent: (0..len).collect() }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parent[a] = b;
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    TrackStarted { path: PathBuf },
+    // `finished` when the track played to the end rather than being
+    // skipped or stopped.
+    TrackEnded { path: PathBuf, finished: bool },
+    Paused,
+    Resumed,
+    Stopped,
+    VolumeChanged(f32),
+    Seeked(Duration),
+    PlaylistChanged,
+    Error { path: Option<PathBuf>, message: String },
+}
+
+impl PlayerEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlayerEvent::TrackStarted { .. } => "track_start",
+            PlayerEvent::TrackEnded { .. } => "track_end",
+            PlayerEvent::Paused => "pause",
+            PlayerEvent::Resumed => "resume",
+            PlayerEvent::Stopped => "stop",
+            PlayerEvent::VolumeChanged(_) => "volume",
+            PlayerEvent::Seeked(_) => "seek",
+            PlayerEvent::PlaylistChanged => "playlist",
+            PlayerEvent::Error { .. } => "error",
+        }
+    }
+
+    // The track an event is about, when it names one.
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self {
+            PlayerEvent::TrackStarted { path } | PlayerEvent::TrackEnded { path, .. } => Some(path),
+            PlayerEvent::Error { path, .. } => path.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({ "event": self.name() });
+        match self {
+            PlayerEvent::TrackStarted { path } => value["path"] = json!(path.to_string_lossy()),
+            PlayerEvent::TrackEnded { path, finished } => {
+                value["path"] = json!(path.to_string_lossy());
+                value["finished"] = json!(finished);
+            }
+            PlayerEvent::VolumeChanged(volume) => value["volume"] = json!((volume * 100.0).round() as u8),
+            PlayerEvent::Seeked(position) => value["position"] = json!(position.as_secs_f64()),
+            PlayerEvent::Error { path, message } => {
+                value["path"] = json!(path.as_ref().map(|p| p.to_string_lossy()));
+                value["error"] = json!(message);
+            }
+            PlayerEvent::Paused | PlayerEvent::Resumed | PlayerEvent::Stopped | PlayerEvent::PlaylistChanged => {}
+        }
+        value
+    }
+}
+
+// Fan-out of player events to any number of listeners (MPRIS, IPC, ...).
+// Each subscriber gets its own channel; ones that hang up are dropped on
+// the next emit.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<PlayerEvent>>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn emit(&self, event: PlayerEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use anyhow::{Context, Result};
+use rodio::Source;
+
+use crate::player;
+
+// The layout Chromaprint uses: mono at 11025 Hz, 4096-sample frames two
+// thirds overlapped, the first two minutes.
+pub const SAMPLE_RATE: usize = 11025;
+const FRAME_SIZE: usize = 4096;
+const HOP: usize = FRAME_SIZE / 3;
+const MAX_SECONDS: usize = 120;
+// Notes between these frequencies are folded into the 12 pitch classes.
+const MIN_FREQ: f64 = 28.0;
+const MAX_FREQ: f64 = 3520.0;
+// Smoothing of each pitch class across neighbouring frames.
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+// Codes come about 8 to the second, so this is about two seconds each way.
+const MAX_OFFSET: usize = 16;
+// Overlap needed before a comparison means anything, about ten seconds.
+const MIN_OVERLAP: usize = 80;
+
+// Prints at least this alike are taken to be the same recording.
+pub const SAME_RECORDING: f64 = 0.8;
+// The index goes by the top 20 bits of each code (the first ten
+// classifiers). Copies of a recording share dozens of those exactly,
+// unrelated tracks next to none, so this many in common is worth a proper
+// comparison.
+const KEY_SHIFT: u32 = 12;
+const MIN_SHARED_KEYS: usize = 4;
+
+#[derive(Clone, Copy)]
+enum Shape {
+    Whole,
+    // Upper against lower pitch classes.
+    Pitch2,
+    // Later against earlier frames.
+    Time2,
+    Diagonal,
+    // Middle third against the outer ones.
+    Pitch3,
+    Time3,
+}
+
+// One of Chromaprint's 16 classifiers: a box filter over the chroma
+// image (pitch classes `y..y + height`, `width` frames), turned into two
+// bits by comparing against three thresholds.
+struct Classifier {
+    shape: Shape,
+    y: usize,
+    height: usize,
+    width: usize,
+    thresholds: [f64; 3],
+}
+
+const fn classifier(shape: Shape, y: usize, height: usize, width: usize, thresholds: [f64; 3]) -> Classifier {
+    Classifier { shape, y, height, width, thresholds }
+}
+
+const CLASSIFIERS: [Classifier; 16] = [
+    classifier(Shape::Whole, 4, 3, 15, [1.98215, 2.35817, 2.63523]),
+    classifier(Shape::Pitch3, 4, 6, 15, [-1.03809, -0.651211, -0.282167]),
+    classifier(Shape::Pitch2, 0, 4, 16, [-0.298702, 0.119262, 0.558497]),
+    classifier(Shape::Diagonal, 8, 2, 12, [-0.105439, 0.0153946, 0.135898]),
+    classifier(Shape::Diagonal, 4, 4, 8, [-0.142891, 0.0258736, 0.200632]),
+    classifier(Shape::Pitch3, 0, 3, 5, [-0.826319, -0.590612, -0.368214]),
+    classifier(Shape::Pitch2, 2, 2, 9, [-0.557409, -0.233035, 0.0534525]),
+    classifier(Shape::Time2, 7, 3, 4, [-0.0646826, 0.00620476, 0.0784847]),
+    classifier(Shape::Time2, 6, 2, 16, [-0.192387, -0.029699, 0.215855]),
+    classifier(Shape::Time2, 1, 3, 2, [-0.0397818, -0.00568076, 0.0292026]),
+    classifier(Shape::Time3, 10, 1, 15, [-0.53823, -0.369934, -0.190235]),
+    classifier(Shape::Diagonal, 6, 2, 10, [-0.124877, 0.0296483, 0.139239]),
+    classifier(Shape::Time2, 1, 1, 14, [-0.101475, 0.0225617, 0.231971]),
+    classifier(Shape::Diagonal, 5, 6, 4, [-0.0799915, -0.00729616, 0.063262]),
+    classifier(Shape::Pitch2, 9, 2, 12, [-0.272556, 0.019424, 0.302559]),
+    classifier(Shape::Diagonal, 4, 2, 14, [-0.164292, -0.0321188, 0.08463]),
+];
+const MAX_WIDTH: usize = 16;
+
+// An acoustic fingerprint in the style of Chromaprint (what AcoustID
+// uses): one 32-bit code per frame describing how the energy of each
+// pitch class moves. Codes survive re-encoding, resampling and volume
+// changes, so two copies of a recording agree on most bits while
+// unrelated tracks agree on about half. They're only comparable with
+// prints made here: the resampling isn't Chromaprint's, so fpcalc's
+// codes for the same file come out different.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    codes: Vec<u32>,
+}
+
+impl Fingerprint {
+    pub fn compute(path: &Path) -> Result<Fingerprint> {
+        let decoder = player::open(path)?;
+        let (sample_rate, channels) = (decoder.sample_rate(), decoder.channels());
+        Fingerprint::from_samples(sample_rate, channels, decoder)
+            .with_context(|| format!("{} is too short to fingerprint", path.display()))
+    }
+
+    // `None` when there's too little audio to make a print from.
+    fn from_samples(sample_rate: u32, channels: u16, samples: impl Iterator<Item = i16>) -> Option<Fingerprint> {
+        let mut downmix = Downmix::new(sample_rate, channels);
+        let mut chromagram = Chromagram::default();
+        let mut chroma = Vec::new();
+        let mut mono = 0;
+        for sample in samples {
+            downmix.push(sample, |sample| {
+                mono += 1;
+                chroma.extend(chromagram.push(sample));
+            });
+            if mono >= MAX_SECONDS * SAMPLE_RATE {
+                break;
+            }
+        }
+        if chroma.len() < CHROMA_FILTER.len() {
+            return None;
+        }
+        let image = normalize(smooth(&chroma));
+
+        let integral = Integral::new(&image);
+        let codes = (0..image.len().saturating_sub(MAX_WIDTH - 1))
+            .map(|x| CLASSIFIERS.iter().fold(0, |code, c| (code << 2) | gray_code(c.classify(&integral, x))))
+            .collect();
+        Some(Fingerprint { codes })
+    }
+
+    // Comma-separated codes as `to_raw` writes them. `fpcalc -raw` output
+    // looks much the same but never matches ours, so it's turned away
+    // rather than loaded into a database that can't find anything.
+    pub fn parse(text: &str) -> Result<Fingerprint> {
+        let text = text.trim();
+        if text.starts_with("FINGERPRINT=") || text.starts_with("DURATION=") {
+            anyhow::bail!("That's fpcalc output; fingerprints have to come from the fingerprint command");
+        }
+        let codes = text.split(',')
+            .map(|code| {
+                let code = code.trim();
+                if code.starts_with('-') {
+                    anyhow::bail!("'{}' looks like an fpcalc code; fingerprints have to come from the fingerprint command", code);
+                }
+                code.parse::<u32>().with_context(|| format!("'{}' isn't a fingerprint code", code))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Fingerprint { codes })
+    }
+
+    // Each distinct index key once, so a long silence counts as one.
+    fn keys(&self) -> HashSet<u32> {
+        self.codes.iter().map(|code| code >> KEY_SHIFT).collect()
+    }
+
+    pub fn to_raw(&self) -> String {
+        self.codes.iter().map(u32::to_string).collect::<Vec<_>>().join(",")
+    }
+
+    // Share of bits that agree at the best alignment, from about 0.5 for
+    // unrelated tracks to 1.0 for the same audio. Zero when either is too
+    // short to tell.
+    pub fn similarity(&self, other: &Fingerprint) -> f64 {
+        let mut best = 0.0;
+        for offset in 0..=MAX_OFFSET {
+            for (a, b) in [(&self.codes, &other.codes), (&other.codes, &self.codes)] {
+                let a = match a.get(offset..) {
+                    Some(a) => a,
+                    None => continue,
+                };
+                let overlap = a.len().min(b.len());
+                if overlap < MIN_OVERLAP {
+                    continue;
+                }
+                let differing: u32 = a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum();
+                best = f64::max(best, 1.0 - differing as f64 / (overlap * 32) as f64);
+            }
+        }
+        best
+    }
+}
+
+// Narrows down which prints are worth comparing, so matching against many
+// doesn't mean comparing every pair at every alignment.
+#[derive(Debug, Default)]
+pub struct Index {
+    // Key -> ids of the prints that have it.
+    postings: HashMap<u32, Vec<usize>>,
+}
+
+impl Index {
+    pub fn insert(&mut self, id: usize, print: &Fingerprint) {
+        for key in print.keys() {
+            self.postings.entry(key).or_default().push(id);
+        }
+    }
+
+    // Ids of the prints sharing enough keys with `print` to be worth a
+    // call to `similarity`, most shared first.
+    pub fn candidates(&self, print: &Fingerprint) -> Vec<usize> {
+        let mut shared: HashMap<usize, usize> = HashMap::new();
+        for key in print.keys() {
+            for &id in self.postings.get(&key).into_iter().flatten() {
+                *shared.entry(id).or_default() += 1;
+            }
+        }
+        let mut candidates: Vec<(usize, usize)> = shared.into_iter()
+            .filter(|&(_, count)| count >= MIN_SHARED_KEYS)
+            .collect();
+        candidates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        candidates.into_iter().map(|(id, _)| id).collect()
+    }
+}
+
+// Mono at SAMPLE_RATE from interleaved samples, averaging the input
+// samples that fall into each output sample, which also keeps most of
+// what's above the new Nyquist from folding back down.
+pub struct Downmix {
+    channels: usize,
+    // Input frames per output sample.
+    step: f64,
+    frame: f64,
+    in_frame: usize,
+    sum: f64,
+    count: usize,
+    position: f64,
+    last: f64,
+}
+
+impl Downmix {
+    pub fn new(sample_rate: u32, channels: u16) -> Downmix {
+        Downmix {
+            channels: channels.max(1) as usize,
+            step: sample_rate as f64 / SAMPLE_RATE as f64,
+            frame: 0.0,
+            in_frame: 0,
+            sum: 0.0,
+            count: 0,
+            position: 0.0,
+            last: 0.0,
+        }
+    }
+
+    // Takes one sample and hands `out` any mono samples it completes.
+    pub fn push(&mut self, sample: i16, mut out: impl FnMut(f64)) {
+        self.frame += sample as f64;
+        self.in_frame += 1;
+        if self.in_frame < self.channels {
+            return;
+        }
+        self.sum += self.frame / self.channels as f64;
+        self.count += 1;
+        self.frame = 0.0;
+        self.in_frame = 0;
+        self.position += 1.0;
+        while self.position >= self.step {
+            self.position -= self.step;
+            // Upsampling repeats the last value.
+            if self.count > 0 {
+                self.last = self.sum / self.count as f64;
+            }
+            out(self.last);
+            self.sum = 0.0;
+            self.count = 0;
+        }
+    }
+}
+
+// Energy per pitch class of each frame of mono SAMPLE_RATE audio, one
+// frame every HOP samples.
+pub struct Chromagram {
+    window: Vec<f64>,
+    buffer: Vec<f64>,
+    // In semitones, added before frequencies are put in classes.
+    shift: f64,
+}
+
+impl Default for Chromagram {
+    fn default() -> Chromagram {
+        let window = (0..FRAME_SIZE)
+            .map(|i| 0.54 - 0.46 * (2.0 * std::f64::consts::PI * i as f64 / (FRAME_SIZE - 1) as f64).cos())
+            .collect();
+        Chromagram { window, buffer: Vec::with_capacity(FRAME_SIZE), shift: 0.0 }
+    }
+}
+
+impl Chromagram {
+    // Chromaprint's classes start at each note, so a note in tune sits
+    // right on the edge between two. Telling keys apart needs them
+    // centred on the notes instead.
+    pub fn centred() -> Chromagram {
+        Chromagram { shift: 0.5, ..Chromagram::default() }
+    }
+
+    pub fn push(&mut self, sample: f64) -> Option<[f64; 12]> {
+        self.buffer.push(sample);
+        if self.buffer.len() < FRAME_SIZE {
+            return None;
+        }
+        let mut re: Vec<f64> = self.buffer.iter().zip(&self.window).map(|(s, w)| s * w).collect();
+        let mut im = vec![0.0; FRAME_SIZE];
+        fft(&mut re, &mut im);
+        self.buffer.drain(..HOP);
+        Some(pitch_classes(&re, &im, self.shift))
+    }
+}
+
+// In-place radix-2 FFT; the length must be a power of two.
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f64::consts::PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// Energy per pitch class, whatever the octave. Class 0 is A.
+fn pitch_classes(re: &[f64], im: &[f64], shift: f64) -> [f64; 12] {
+    let bin = |freq: f64| (freq * FRAME_SIZE as f64 / SAMPLE_RATE as f64) as usize;
+    let mut classes = [0.0; 12];
+    for i in bin(MIN_FREQ)..bin(MAX_FREQ) {
+        let freq = i as f64 * SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+        let octave = (freq / (440.0 / 16.0)).log2();
+        let note = ((12.0 * octave.fract() + shift) as usize) % 12;
+        classes[note] += re[i] * re[i] + im[i] * im[i];
+    }
+    classes
+}
+
+fn smooth(chroma: &[[f64; 12]]) -> Vec<[f64; 12]> {
+    chroma.windows(CHROMA_FILTER.len())
+        .map(|frames| {
+            let mut smoothed = [0.0; 12];
+            for (frame, weight) in frames.iter().zip(CHROMA_FILTER) {
+                for (value, x) in smoothed.iter_mut().zip(frame) {
+                    *value += x * weight;
+                }
+            }
+            smoothed
+        })
+        .collect()
+}
+
+// Scaled to unit length, so only the balance between pitch classes
+// counts. Near-silent frames become all zero.
+fn normalize(mut image: Vec<[f64; 12]>) -> Vec<[f64; 12]> {
+    for frame in &mut image {
+        let norm = frame.iter().map(|x| x * x).sum::<f64>().sqrt();
+        for x in frame.iter_mut() {
+            *x = if norm < 0.01 { 0.0 } else { *x / norm };
+        }
+    }
+    image
+}
+
+fn gray_code(n: u32) -> u32 {
+    [0, 1, 3, 2][n as usize]
+}
+
+// Sums over any rectangle of the chroma image in constant time.
+struct Integral {
+    sums: Vec<[f64; 13]>,
+}
+
+impl Integral {
+    fn new(image: &[[f64; 12]]) -> Integral {
+        let mut sums = vec![[0.0; 13]; image.len() + 1];
+        for (x, frame) in image.iter().enumerate() {
+            for y in 0..12 {
+                sums[x + 1][y + 1] = frame[y] + sums[x][y + 1] + sums[x + 1][y] - sums[x][y];
+            }
+        }
+        Integral { sums }
+    }
+
+    // Frames `x1..x2`, pitch classes `y1..y2`.
+    fn area(&self, x1: usize, y1: usize, x2: usize, y2: usize) -> f64 {
+        self.sums[x2][y2] - self.sums[x1][y2] - self.sums[x2][y1] + self.sums[x1][y1]
+    }
+}
+
+impl Classifier {
+    fn classify(&self, image: &Integral, x: usize) -> u32 {
+        let (y, w, h) = (self.y, self.width, self.height);
+        let area = |x1, y1, x2, y2| image.area(x + x1, y1, x + x2, y2);
+        let (a, b) = match self.shape {
+            Shape::Whole => (area(0, y, w, y + h), 0.0),
+            Shape::Pitch2 => (area(0, y + h / 2, w, y + h), area(0, y, w, y + h / 2)),
+            Shape::Time2 => (area(w / 2, y, w, y + h), area(0, y, w / 2, y + h)),
+            Shape::Diagonal => (
+                area(0, y + h / 2, w / 2, y + h) + area(w / 2, y, w, y + h / 2),
+                area(0, y, w / 2, y + h / 2) + area(w / 2, y + h / 2, w, y + h),
+            ),
+            Shape::Pitch3 => (
+                area(0, y + h / 3, w, y + 2 * h / 3),
+                area(0, y, w, y + h / 3) + area(0, y + 2 * h / 3, w, y + h),
+            ),
+            Shape::Time3 => (
+                area(w / 3, y, 2 * w / 3, y + h),
+                area(0, y, w / 3, y + h) + area(2 * w / 3, y, w, y + h),
+            ),
+        };
+        let value = (1.0 + a).ln() - (1.0 + b).ln();
+        self.thresholds.iter().filter(|&&t| value >= t).count() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Twenty seconds of half a second of each chord in turn, as
+    // interleaved stereo.
+    fn chords(sample_rate: u32, chords: &[[f64; 3]], volume: f64) -> Vec<i16> {
+        let per_chord = sample_rate as usize / 2;
+        let mut samples = Vec::new();
+        for i in 0..sample_rate as usize * 20 {
+            let t = i as f64 / sample_rate as f64;
+            let chord = chords[i / per_chord % chords.len()];
+            let value: f64 = chord.iter().map(|freq| (2.0 * std::f64::consts::PI * freq * t).sin()).sum();
+            let sample = (value / 3.0 * volume * i16::MAX as f64) as i16;
+            samples.extend([sample, sample]);
+        }
+        samples
+    }
+
+    const SONG: [[f64; 3]; 5] = [
+        [261.63, 329.63, 392.00],
+        [220.00, 261.63, 329.63],
+        [174.61, 220.00, 261.63],
+        [196.00, 246.94, 293.66],
+        [293.66, 369.99, 440.00],
+    ];
+    const OTHER: [[f64; 3]; 3] = [
+        [233.08, 277.18, 349.23],
+        [311.13, 392.00, 466.16],
+        [207.65, 261.63, 311.13],
+    ];
+
+    #[test]
+    fn copies_match_and_other_tracks_dont() {
+        let print = |rate, song: &[[f64; 3]], volume| {
+            Fingerprint::from_samples(rate, 2, chords(rate, song, volume).into_iter()).unwrap()
+        };
+        let original = print(44100, &SONG, 0.8);
+        // Another sample rate and half the volume, as a re-encode might be.
+        let copy = print(48000, &SONG, 0.4);
+        let other = print(44100, &OTHER, 0.8);
+
+        assert!(original.similarity(&copy) >= SAME_RECORDING, "{}", original.similarity(&copy));
+        assert!(original.similarity(&other) < SAME_RECORDING, "{}", original.similarity(&other));
+
+        let mut index = Index::default();
+        index.insert(0, &other);
+        index.insert(1, &original);
+        assert_eq!(index.candidates(&copy), vec![1]);
+
+        assert_eq!(Fingerprint::parse(&original.to_raw()).unwrap(), original);
+        assert!(Fingerprint::parse(&format!("FINGERPRINT={}", original.to_raw())).is_err());
+        assert!(Fingerprint::parse("12,-34,56").is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, TimeZone};
+use serde_json::{json, Value};
+
+use crate::config::data_dir;
+use crate::events::PlayerEvent;
+use crate::library::format_duration;
+use crate::player::ListenTimer;
+use crate::remote::Remote;
+
+const POLL: Duration = Duration::from_secs(1);
+// Tracks flicked past in under a second aren't worth a history entry.
+const MIN_LISTEN: Duration = Duration::from_secs(1);
+
+// One playback of a track, one JSON line per play in `history.jsonl`. The
+// tags are copied in so stats still make sense for files that have since
+// moved or been retagged.
+#[derive(Debug, Clone)]
+pub struct Play {
+    pub path: PathBuf,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    // Unix time the track started.
+    pub started: i64,
+    pub listened: Duration,
+    // Played to the end, as opposed to skipped or stopped.
+    pub finished: bool,
+}
+
+impl Play {
+    fn to_json(&self) -> Value {
+        json!({
+            "path": self.path.to_string_lossy(),
+            "title": self.title,
+            "artist": self.artist,
+            "album": self.album,
+            "started": self.started,
+            "listened": self.listened.as_secs(),
+            "finished": self.finished,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Play> {
+        Some(Play {
+            path: PathBuf::from(value["path"].as_str()?),
+            title: value["title"].as_str()?.to_string(),
+            artist: value["artist"].as_str()?.to_string(),
+            album: value["album"].as_str()?.to_string(),
+            started: value["started"].as_i64()?,
+            listened: Duration::from_secs(value["listened"].as_u64()?),
+            finished: value["finished"].as_bool()?,
+        })
+    }
+
+    fn started_local(&self) -> Option<DateTime<Local>> {
+        Local.timestamp_opt(self.started, 0).single()
+    }
+
+    // "2026-10-18 14:03  Artist - Title  3:12", marked when skipped.
+    pub fn label(&self) -> String {
+        let started = self.started_local()
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let skipped = if self.finished { "" } else { "  (skipped)" };
+        format!("{}  {} - {}  {}{}", started, self.artist, self.title, format_duration(self.listened), skipped)
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("history.jsonl"))
+}
+
+// Every recorded play, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    plays: Vec<Play>,
+}
+
+impl History {
+    // A missing file is an empty history; lines that don't parse (say, one
+    // cut short by a crash) are skipped.
+    pub fn load(path: &Path) -> Result<History> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(History::default()),
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let plays = content.lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .filter_map(|value| Play::from_json(&value))
+            .collect();
+        Ok(History { plays })
+    }
+
+    fn append(path: &Path, play: &Play) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", play.to_json())?;
+        Ok(())
+    }
+
+    pub fn plays(&self) -> &[Play] {
+        &self.plays
+    }
+
+    // Points the plays of files that have moved at their new paths.
+    // Returns whether any did.
+    pub fn rename(&mut self, moves: &HashMap<PathBuf, PathBuf>) -> bool {
+        let mut renamed = false;
+        for play in &mut self.plays {
+            if let Some(to) = moves.get(&play.path) {
+                play.path = to.clone();
+                renamed = true;
+            }
+        }
+        renamed
+    }
+
+    // Written next to the old file and renamed over it, so a crash halfway
+    // doesn't lose the lot.
+    fn save(&self, path: &Path) -> Result<()> {
+        let content: String = self.plays.iter().map(|play| format!("{}\n", play.to_json())).collect();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        std::fs::write(&temp, content)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn stats(&self, period: Period) -> Stats {
+        let mut tracks: HashMap<String, usize> = HashMap::new();
+        let mut artists: HashMap<String, usize> = HashMap::new();
+        let mut albums: HashMap<String, usize> = HashMap::new();
+        let mut periods: HashMap<String, Duration> = HashMap::new();
+        for play in &self.plays {
+            *tracks.entry(format!("{} - {}", play.artist, play.title)).or_default() += 1;
+            *artists.entry(play.artist.clone()).or_default() += 1;
+            *albums.entry(format!("{} - {}", play.artist, play.album)).or_default() += 1;
+            if let Some(started) = play.started_local() {
+                *periods.entry(period.label(&started)).or_default() += play.listened;
+            }
+        }
+        // Labels sort chronologically, so newest first is a reverse sort.
+        let mut periods: Vec<(String, Duration)> = periods.into_iter().collect();
+        periods.sort_by(|a, b| b.0.cmp(&a.0));
+
+        Stats {
+            period,
+            plays: self.plays.len(),
+            skipped: self.plays.iter().filter(|play| !play.finished).count(),
+            listened: self.plays.iter().map(|play| play.listened).sum(),
+            tracks: ranked(tracks),
+            artists: ranked(artists),
+            albums: ranked(albums),
+            periods,
+        }
+    }
+}
+
+// Most played first, ties in name order.
+fn ranked(counts: HashMap<String, usize>) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    Week,
+}
+
+impl Period {
+    pub fn parse(name: &str) -> Option<Period> {
+        match name {
+            "day" => Some(Period::Day),
+            "week" => Some(Period::Week),
+            _ => None,
+        }
+    }
+
+    fn label(&self, time: &DateTime<Local>) -> String {
+        match self {
+            Period::Day => time.format("%Y-%m-%d %a").to_string(),
+            Period::Week => time.format("%G-W%V").to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    period: Period,
+    pub plays: usize,
+    pub skipped: usize,
+    pub listened: Duration,
+    pub tracks: Vec<(String, usize)>,
+    pub artists: Vec<(String, usize)>,
+    pub albums: Vec<(String, usize)>,
+    // Listening time per day or week, newest first.
+    pub periods: Vec<(String, Duration)>,
+}
+
+impl Stats {
+    pub fn skip_rate(&self) -> f64 {
+        if self.plays == 0 {
+            0.0
+        } else {
+            self.skipped as f64 / self.plays as f64
+        }
+    }
+
+    // The plain-text report shared by `stats` and the history view: `top`
+    // entries per ranking and the `periods` most recent days or weeks.
+    pub fn report(&self, top: usize, periods: usize) -> Vec<String> {
+        let mut lines = vec![
+            format!("Plays: {} (skip rate {:.0}%)", self.plays, self.skip_rate() * 100.0),
+            format!("Listening time: {}", format_hours(self.listened)),
+        ];
+        for (title, ranking) in [
+            ("Top tracks:", &self.tracks),
+            ("Top artists:", &self.artists),
+            ("Top albums:", &self.albums),
+        ] {
+            lines.push(String::new());
+            lines.push(title.to_string());
+            lines.extend(ranking.iter().take(top).map(|(name, count)| format!("  {:>5}  {}", count, name)));
+        }
+        lines.push(String::new());
+        lines.push(match self.period {
+            Period::Day => "Listening per day:".to_string(),
+            Period::Week => "Listening per week:".to_string(),
+        });
+        lines.extend(self.periods.iter().take(periods).map(|(label, time)| {
+            format!("  {}  {:>8}", label, format_hours(*time))
+        }));
+        lines
+    }
+}
+
+// "3h 05m" or "12m"; track-style m:ss reads badly for whole days.
+fn format_hours(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    if minutes >= 60 {
+        format!("{}h {:02}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+// The track being listened to, until its `TrackEnded` arrives.
+struct Playing {
+    play: Play,
+    generation: u64,
+    timer: ListenTimer,
+}
+
+impl Playing {
+    fn start(remote: &Remote, path: PathBuf) -> Playing {
+        let track = remote.track_info(&path);
+        Playing {
+            play: Play {
+                path,
+                title: track.title,
+                artist: track.artist,
+                album: track.album,
+                started: Local::now().timestamp(),
+                listened: Duration::ZERO,
+                finished: false,
+            },
+            generation: remote.player.generation(),
+            timer: ListenTimer::new(remote.player.position()),
+        }
+    }
+
+    fn record(self, path: &Path, finished: bool) {
+        if self.timer.heard < MIN_LISTEN {
+            return;
+        }
+        let play = Play { listened: self.timer.heard, finished, ..self.play };
+        // Nowhere to report a failure from here; the player carries on.
+        let _ = History::append(path, &play);
+    }
+}
+
+// Rewrites the history after files have been renamed, so their plays keep
+// pointing at them.
+pub fn rename_in_file(path: &Path, moves: &HashMap<PathBuf, PathBuf>) -> Result<()> {
+    let mut history = History::load(path)?;
+    if history.rename(moves) {
+        history.save(path).with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+enum Control {
+    Moved(HashMap<PathBuf, PathBuf>),
+    Finish,
+}
+
+// Appends a line to `history.jsonl` in the data directory whenever a track
+// ends. The track still playing when the player quits is recorded by
+// `Recorder::finish`.
+pub fn spawn(remote: Remote) -> Option<Recorder> {
+    let path = default_path()?;
+    let events = remote.events.subscribe();
+    let (sender, control) = mpsc::channel();
+    let thread = std::thread::spawn(move || {
+        let mut playing: Option<Playing> = None;
+        let mut quitting = false;
+        loop {
+            match control.try_recv() {
+                // Renames go through here, the only writer, so no play is
+                // appended between reading the file and replacing it.
+                Ok(Control::Moved(moves)) => {
+                    if let Some(current) = playing.as_mut() {
+                        if let Some(to) = moves.get(&current.play.path) {
+                            current.play.path = to.clone();
+                        }
+                    }
+                    if let Err(err) = rename_in_file(&path, &moves) {
+                        remote.events.emit(PlayerEvent::Error { path: None, message: format!("{:#}", err) });
+                    }
+                }
+                Ok(Control::Finish) | Err(mpsc::TryRecvError::Disconnected) => quitting = true,
+                Err(mpsc::TryRecvError::Empty) => {}
+            }
+            let event = if quitting {
+                // Catch up on whatever the player announced before quitting.
+                events.try_recv().map_err(|_| RecvTimeoutError::Timeout)
+            } else {
+                events.recv_timeout(POLL)
+            };
+            match event {
+                Ok(PlayerEvent::TrackStarted { path: track }) => {
+                    if let Some(previous) = playing.take() {
+                        previous.record(&path, false);
+                    }
+                    playing = Some(Playing::start(&remote, track));
+                }
+                Ok(PlayerEvent::TrackEnded { finished, .. }) => {
+                    if let Some(previous) = playing.take() {
+                        previous.record(&path, finished);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) if !quitting => {}
+                Err(_) => {
+                    if let Some(mut current) = playing.take() {
+                        if current.generation == remote.player.generation() {
+                            current.timer.update(remote.player.position(), POLL / 2);
+                        }
+                        current.record(&path, false);
+                    }
+                    return;
+                }
+            }
+
+            if let Some(current) = playing.as_mut() {
+                // A different track is already on; its event is on the way.
+                if current.generation == remote.player.generation() {
+                    current.timer.update(remote.player.position(), POLL / 2);
+                }
+            }
+        }
+    });
+    Some(Recorder { control: sender, thread })
+}
+
+// The running history thread.
+pub struct Recorder {
+    control: Sender<Control>,
+    thread: JoinHandle<()>,
+}
+
+impl Recorder {
+    // Has the thread point the history at files' new paths.
+    pub fn moved(&self, moves: &HashMap<PathBuf, PathBuf>) {
+        let _ = self.control.send(Control::Moved(moves.clone()));
+    }
+
+    // Records the track that's still playing and waits for it to be written.
+    pub fn finish(self) {
+        let _ = self.control.send(Control::Finish);
+        let _ = self.thread.join();
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::events::PlayerEvent;
+use crate::remote::{track_json, Remote};
+
+// Events a hook can be attached to, as named in the `[hooks]` config table.
+pub const EVENTS: [&str; 6] = ["track_start", "track_end", "pause", "resume", "stop", "error"];
+
+// Runs the configured shell command for each event. The track is described
+// both in TRACK_* environment variables and as JSON on stdin. Every hook
+// runs on a thread of its own, so a slow one never holds up the player or
+// the hooks after it.
+pub fn spawn(remote: Remote, hooks: HashMap<String, String>) {
+    if hooks.is_empty() {
+        return;
+    }
+    let events = remote.events.subscribe();
+    std::thread::spawn(move || {
+        for event in events {
+            let command = match hooks.get(event.name()) {
+                Some(command) => command.clone(),
+                None => continue,
+            };
+            // Described now rather than in the hook thread, while the
+            // player is still on the track the event is about.
+            let (env, input) = describe(&remote, &event);
+            std::thread::spawn(move || {
+                let _ = run(&command, &env, &input);
+            });
+        }
+    });
+}
+
+fn describe(remote: &Remote, event: &PlayerEvent) -> (Vec<(String, String)>, Value) {
+    let path = event.path().cloned().or_else(|| remote.player.current_path());
+    let track = path.map(|path| remote.track_info(&path));
+
+    let mut env = vec![("PLAYER_EVENT".to_string(), event.name().to_string())];
+    if let Some(track) = &track {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        env.extend([
+            ("TRACK_PATH", track.path.to_string_lossy().to_string()),
+            ("TRACK_TITLE", track.title.clone()),
+            ("TRACK_ARTIST", track.artist.clone()),
+            ("TRACK_ALBUM", track.album.clone()),
+            ("TRACK_GENRE", track.genre.clone()),
+            ("TRACK_NUMBER", optional(track.track.map(|n| n.to_string()))),
+            ("TRACK_DISC", optional(track.disc.map(|n| n.to_string()))),
+            ("TRACK_YEAR", optional(track.year.map(|y| y.to_string()))),
+            ("TRACK_DURATION", optional(track.duration.map(|d| d.as_secs().to_string()))),
+        ].map(|(name, value)| (name.to_string(), value)));
+    }
+    if let PlayerEvent::Error { message, .. } = event {
+        env.push(("PLAYER_ERROR".to_string(), message.clone()));
+    }
+
+    let mut input = event.to_json();
+    input["track"] = json!(track.as_ref().map(track_json));
+    (env, input)
+}
+
+fn run(command: &str, env: &[(String, String)], input: &Value) -> Result<()> {
+    // Output is discarded so hooks can't scribble over the TUI.
+    let mut child = shell(command)
+        .envs(env.iter().cloned())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Hooks that don't read stdin may exit before we're done writing.
+        let _ = writeln!(stdin, "{}", input);
+    }
+    child.wait()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
@@ -0,0 +1,297 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::command::{Command, Seek, Volume};
+use crate::remote::{percent_decode, track_json, Remote};
+
+const REMOTE_PAGE: &str = include_str!("../assets/remote.html");
+const SEARCH_LIMIT: usize = 100;
+// Comment lines keep idle event streams open through proxies and let us
+// notice clients that went away.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+// Every request body is a small JSON object.
+const MAX_BODY: usize = 64 * 1024;
+
+// REST endpoints (all JSON):
+//
+//     GET  /api/status                  GET  /api/queue
+//     POST /api/queue {"path"}          POST /api/queue/<n>/play
+//     GET  /api/library?q=<query>       GET  /api/events (Server-Sent Events)
+//     POST /api/play|pause|toggle|stop|next|prev
+//     PUT  /api/volume {"volume": 0-100}
+//     PUT  /api/position {"position": seconds}
+//     PUT  /api/shuffle {"shuffle": bool}
+//     POST /api/command {"command": ":text"}
+//
+// Everything that changes the player becomes a `Command` for `App::execute`.
+// Anything beyond localhost needs `token`, sent as `Authorization: Bearer
+// <token>` or `?token=<token>`.
+pub fn spawn(remote: Remote, address: &str, token: Option<String>) -> Result<()> {
+    let loopback = address.to_socket_addrs()
+        .with_context(|| format!("Bad address {}", address))?
+        .all(|addr| addr.ip().is_loopback());
+    if !loopback && token.is_none() {
+        anyhow::bail!("Set http_token to listen on {}, or use 127.0.0.1", address);
+    }
+    let server = Server::http(address)
+        .map_err(|err| anyhow::anyhow!("Failed to listen on {}: {}", address, err))?;
+    let access = Arc::new(Access { token, loopback });
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let remote = remote.clone();
+            let access = access.clone();
+            std::thread::spawn(move || handle(request, &remote, &access));
+        }
+    });
+    Ok(())
+}
+
+// Who gets to use the API. Other web pages the browser has open must not,
+// so cross-origin requests are refused and writes have to be JSON, which a
+// page can't send elsewhere without a CORS preflight we never answer. A
+// page reached through a rebound DNS name is caught by the Host check.
+struct Access {
+    token: Option<String>,
+    // Listening on loopback only, so the Host has to be a loopback name.
+    loopback: bool,
+}
+
+impl Access {
+    fn check(&self, request: &Request, path: &str, query: &str) -> Result<(), HttpError> {
+        let forbidden = |message: &str| Err(HttpError(403, message.to_string()));
+        let host = header_value(request, "Host").unwrap_or_default();
+        if self.loopback && !is_loopback_host(host) {
+            return forbidden("Unexpected Host header");
+        }
+        if let Some(origin) = header_value(request, "Origin") {
+            if origin != format!("http://{}", host) {
+                return forbidden("Cross-origin requests are not allowed");
+            }
+        }
+        if !path.starts_with("/api") {
+            return Ok(());
+        }
+        if let Some(token) = &self.token {
+            let given = header_value(request, "Authorization")
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(str::to_string)
+                .or_else(|| query_param(query, "token"));
+            if !given.is_some_and(|given| same_secret(given.as_bytes(), token.as_bytes())) {
+                return Err(HttpError(401, "Missing or wrong token".to_string()));
+            }
+        }
+        if matches!(request.method(), Method::Post | Method::Put) {
+            let content_type = header_value(request, "Content-Type").unwrap_or_default();
+            let media_type = content_type.split(';').next().unwrap_or_default().trim();
+            if !media_type.eq_ignore_ascii_case("application/json") {
+                return Err(HttpError(415, "Content-Type should be application/json".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header_value<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request.headers().iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+// "localhost:8080", "127.0.0.1:8080" or "[::1]:8080".
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+// Compares without stopping at the first difference, so the time taken
+// doesn't give away how much of a guess was right.
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+struct HttpError(u16, String);
+
+impl HttpError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        HttpError(400, message.into())
+    }
+}
+
+enum Reply {
+    Json(Value),
+    Page,
+}
+
+fn handle(mut request: Request, remote: &Remote, access: &Access) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let checked = access.check(&request, path, query).and_then(|_| read_body(&mut request));
+    let body = match checked {
+        Ok(body) => body,
+        Err(HttpError(status, message)) => {
+            let _ = request.respond(json_response(status, &json!({ "ok": false, "error": message })));
+            return;
+        }
+    };
+    if *request.method() == Method::Get && path == "/api/events" {
+        stream_events(request, remote);
+        return;
+    }
+
+    let method = request.method().clone();
+    let response = match route(&method, path, query, &body, remote) {
+        Ok(Reply::Json(value)) => json_response(200, &value),
+        Ok(Reply::Page) => Response::from_string(REMOTE_PAGE)
+            .with_header(header("Content-Type", "text/html; charset=utf-8")),
+        Err(HttpError(status, message)) => json_response(status, &json!({ "ok": false, "error": message })),
+    };
+    let _ = request.respond(response);
+}
+
+fn read_body(request: &mut Request) -> Result<String, HttpError> {
+    if request.body_length().is_some_and(|length| length > MAX_BODY) {
+        return Err(HttpError(413, "Body too large".to_string()));
+    }
+    let mut body = String::new();
+    request.as_reader()
+        .take(MAX_BODY as u64 + 1)
+        .read_to_string(&mut body)
+        .map_err(|_| HttpError::bad_request("Body should be UTF-8 text"))?;
+    if body.len() > MAX_BODY {
+        return Err(HttpError(413, "Body too large".to_string()));
+    }
+    Ok(body)
+}
+
+fn route(method: &Method, path: &str, query: &str, body: &str, remote: &Remote) -> Result<Reply, HttpError> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let value = match (method, segments.as_slice()) {
+        (Method::Get, [""] | ["index.html"]) => return Ok(Reply::Page),
+        (Method::Get, ["api", "status"]) => remote.status().to_json(),
+        (Method::Get, ["api", "queue"]) => remote.playlist_json(),
+        (Method::Post, ["api", "queue"]) => {
+            let path = field(body, "path")?.as_str()
+                .ok_or_else(|| HttpError::bad_request("\"path\" should be a string"))?
+                .to_string();
+            // Only what the library search offers, not any file on the machine.
+            if remote.library.read().unwrap().get(Path::new(&path)).is_none() {
+                return Err(HttpError(404, format!("Not in the library: {}", path)));
+            }
+            run(remote, Command::Add(path.into()))?
+        }
+        (Method::Post, ["api", "queue", index, "play"]) => {
+            let index: usize = index.parse().map_err(|_| HttpError::bad_request("Bad track index"))?;
+            run(remote, Command::Play(Some(index)))?
+        }
+        (Method::Get, ["api", "library"]) => {
+            let query = query_param(query, "q").unwrap_or_default();
+            let library = remote.library.read().unwrap();
+            library.search(&query, SEARCH_LIMIT).into_iter().map(track_json).collect()
+        }
+        (Method::Post, ["api", action @ ("play" | "pause" | "toggle" | "stop" | "next" | "prev")]) => {
+            let command = Command::parse(action).map_err(|err| HttpError::bad_request(err.to_string()))?;
+            run(remote, command)?
+        }
+        (Method::Put, ["api", "volume"]) => {
+            let volume = field(body, "volume")?.as_u64()
+                .filter(|v| *v <= 100)
+                .ok_or_else(|| HttpError::bad_request("\"volume\" should be 0-100"))?;
+            run(remote, Command::Volume(Volume::Set(volume as u8)))?
+        }
+        (Method::Put, ["api", "position"]) => {
+            let position = field(body, "position")?.as_f64()
+                .and_then(|p| Duration::try_from_secs_f64(p).ok())
+                .ok_or_else(|| HttpError::bad_request("\"position\" should be seconds"))?;
+            run(remote, Command::Seek(Seek::To(position)))?
+        }
+        (Method::Put, ["api", "shuffle"]) => {
+            let shuffle = field(body, "shuffle")?.as_bool()
+                .ok_or_else(|| HttpError::bad_request("\"shuffle\" should be true or false"))?;
+            run(remote, Command::Shuffle(Some(shuffle)))?
+        }
+        (Method::Post, ["api", "command"]) => {
+            let text = field(body, "command")?.as_str()
+                .ok_or_else(|| HttpError::bad_request("\"command\" should be a string"))?
+                .to_string();
+            let command = Command::parse(&text).map_err(|err| HttpError::bad_request(err.to_string()))?;
+            // Playback only: nothing that writes files, tags or playlists.
+            let allowed = matches!(command,
+                Command::Play(_) | Command::Pause | Command::Toggle | Command::Stop | Command::Next
+                | Command::Prev | Command::Seek(_) | Command::Volume(_) | Command::Shuffle(_));
+            if !allowed {
+                return Err(HttpError(403, format!("Not allowed over HTTP: {}", text)));
+            }
+            run(remote, command)?
+        }
+        (_, ["api", ..]) => return Err(HttpError(404, format!("No endpoint {} {}", method, path))),
+        _ => return Err(HttpError(404, "Not found".to_string())),
+    };
+    Ok(Reply::Json(value))
+}
+
+fn run(remote: &Remote, command: Command) -> Result<Value, HttpError> {
+    match remote.call(command) {
+        Ok(message) => Ok(json!({ "ok": true, "message": message })),
+        Err(err) => Err(HttpError(409, err.to_string())),
+    }
+}
+
+fn field(body: &str, name: &str) -> Result<Value, HttpError> {
+    let value: Value = serde_json::from_str(body)
+        .map_err(|_| HttpError::bad_request("Body should be a JSON object"))?;
+    value.get(name)
+        .cloned()
+        .ok_or_else(|| HttpError::bad_request(format!("Missing \"{}\"", name)))
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        // Forms send spaces as `+`; a real plus comes as %2B.
+        .map(|(_, value)| percent_decode(&value.replace('+', " ")))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn json_response(status: u16, value: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+// tiny_http buffers chunked bodies, so the event stream is written to the
+// raw connection instead.
+fn stream_events(request: Request, remote: &Remote) {
+    let events = remote.events.subscribe();
+    let mut writer = request.into_writer();
+    let head = "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/event-stream\r\n\
+                Cache-Control: no-cache\r\n\
+                Connection: close\r\n\r\n";
+    if writer.write_all(head.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+    loop {
+        let chunk = match events.recv_timeout(KEEPALIVE) {
+            Ok(event) => format!("event: {}\ndata: {}\n\n", event.name(), event.to_json()),
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if writer.write_all(chunk.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+    }
+}
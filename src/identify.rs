@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+
+use crate::config;
+use crate::fingerprint::{Fingerprint, Index, SAME_RECORDING};
+use crate::library::{TrackInfo, UNKNOWN_ALBUM, UNKNOWN_ARTIST};
+use crate::tags::{self, Field};
+
+// The columns after the fingerprint, in order. Trailing ones may be left
+// out and empty ones are skipped.
+const COLUMNS: [Field; 4] = [Field::Artist, Field::Title, Field::Album, Field::Year];
+
+pub fn default_path() -> Option<PathBuf> {
+    config::data_dir().map(|dir| dir.join("fingerprints.tsv"))
+}
+
+struct Entry {
+    print: Fingerprint,
+    values: Vec<(Field, String)>,
+}
+
+// Known recordings to match untagged files against, read from a local
+// file with one per line:
+//
+//     <fingerprint>\t<artist>\t<title>\t<album>\t<year>
+//
+// The fingerprint is comma-separated codes, as the `fingerprint`
+// subcommand writes them. Blank lines and lines starting with # are
+// skipped.
+pub struct Database {
+    entries: Vec<Entry>,
+    index: Index,
+}
+
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub values: Vec<(Field, String)>,
+    pub score: f64,
+}
+
+impl Database {
+    pub fn load(path: &Path) -> Result<Database> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut entries = Vec::new();
+        let mut index = Index::default();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut columns = line.split('\t');
+            let print = Fingerprint::parse(columns.next().unwrap_or_default())
+                .with_context(|| format!("{} line {}", path.display(), number + 1))?;
+            let values: Vec<(Field, String)> = COLUMNS.iter()
+                .zip(columns)
+                .filter(|(_, value)| !value.trim().is_empty())
+                .map(|(field, value)| (*field, value.trim().to_string()))
+                .collect();
+            for (field, value) in &values {
+                tags::check_value(*field, value).with_context(|| format!("{} line {}", path.display(), number + 1))?;
+            }
+            index.insert(entries.len(), &print);
+            entries.push(Entry { print, values });
+        }
+        Ok(Database { entries, index })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // The closest recording, if it's close enough to be the same one. Only
+    // the entries the index picks out get compared.
+    pub fn identify(&self, print: &Fingerprint) -> Option<Suggestion> {
+        self.index.candidates(print).into_iter()
+            .map(|id| &self.entries[id])
+            .map(|entry| (entry, entry.print.similarity(print)))
+            .filter(|(_, score)| *score >= SAME_RECORDING)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(entry, score)| Suggestion { values: entry.values.clone(), score })
+    }
+}
+
+// A database line for a track, or `None` when it has no artist tag to
+// make it worth matching against.
+pub fn line(print: &Fingerprint, track: &TrackInfo) -> Option<String> {
+    if track.artist == UNKNOWN_ARTIST {
+        return None;
+    }
+    let year = track.year.map(|year| year.to_string()).unwrap_or_default();
+    let album = if track.album == UNKNOWN_ALBUM { "" } else { track.album.as_str() };
+    // Tabs would shift the columns.
+    let columns: Vec<String> = [track.artist.as_str(), track.title.as_str(), album, year.as_str()].iter()
+        .map(|value| value.replace('\t', " "))
+        .collect();
+    Some(format!("{}\t{}", print.to_raw(), columns.join("\t")))
+}
+
+// Files with what they matched.
+pub type Suggestions = Vec<(PathBuf, Suggestion)>;
+
+// Fingerprints each file and looks it up. Files that can't be decoded or
+// match nothing are left out.
+pub fn suggest(database: &Database, paths: &[PathBuf]) -> Suggestions {
+    paths.iter()
+        .filter_map(|path| {
+            let print = Fingerprint::compute(path).ok()?;
+            Some((path.clone(), database.identify(&print)?))
+        })
+        .collect()
+}
@@ -0,0 +1,191 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::command::Command;
+use crate::config::APP_NAME;
+use crate::library::format_duration;
+use crate::remote::Remote;
+
+// The protocol is one JSON object per line in each direction. A request
+// names either a query (`status`, `playlist`, `subscribe`) or any `:`
+// command, e.g. {"command": "seek +30"}. Replies carry "ok" plus either
+// "error" or the result; after `subscribe` every line is an event.
+pub fn socket_path() -> Result<PathBuf> {
+    let dir = match dirs::runtime_dir() {
+        Some(dir) => dir,
+        None => private_temp_dir()?,
+    };
+    Ok(dir.join(format!("{}.sock", APP_NAME)))
+}
+
+// Without $XDG_RUNTIME_DIR the socket goes in a directory of our own under
+// the shared temp dir that only we can get into, so nobody else can connect
+// to it in the moment before its permissions are set, or put one in its
+// place.
+fn private_temp_dir() -> Result<PathBuf> {
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("{}-{}", APP_NAME, uid));
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err).with_context(|| format!("Failed to create {}", dir.display())),
+    }
+    let metadata = std::fs::symlink_metadata(&dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        anyhow::bail!("{} is not a private directory of ours; remove it and try again", dir.display());
+    }
+    Ok(dir)
+}
+
+// Removes the socket file again when the player exits.
+pub struct Server {
+    path: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub fn spawn(remote: Remote) -> Result<Server> {
+    let path = socket_path()?;
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            anyhow::bail!("Another instance is already listening on {}", path.display());
+        }
+        // Left behind by a player that didn't exit cleanly.
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind {}", path.display()))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let remote = remote.clone();
+            std::thread::spawn(move || {
+                let _ = serve_client(stream, &remote);
+            });
+        }
+    });
+    Ok(Server { path })
+}
+
+fn serve_client(stream: UnixStream, remote: &Remote) -> Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match parse_request(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                writeln!(writer, "{}", json!({ "ok": false, "error": err.to_string() }))?;
+                continue;
+            }
+        };
+        let reply = match request.as_str() {
+            "status" => json!({ "ok": true, "status": remote.status().to_json() }),
+            "playlist" => json!({ "ok": true, "playlist": remote.playlist_json() }),
+            "subscribe" | "events" => {
+                let events = remote.events.subscribe();
+                writeln!(writer, "{}", json!({ "ok": true }))?;
+                // Runs until the client hangs up and a write fails.
+                for event in events {
+                    writeln!(writer, "{}", event.to_json())?;
+                }
+                return Ok(());
+            }
+            text => match Command::parse(text).and_then(|command| remote.call(command)) {
+                Ok(message) => json!({ "ok": true, "message": message }),
+                Err(err) => json!({ "ok": false, "error": err.to_string() }),
+            },
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+fn parse_request(line: &str) -> Result<String> {
+    let value: Value = serde_json::from_str(line).context("Request is not valid JSON")?;
+    let command = value.get("command")
+        .and_then(Value::as_str)
+        .context("Request needs a \"command\" string")?;
+    Ok(command.trim().to_string())
+}
+
+// `ctl` client: sends one request to the running player and prints the
+// reply, either raw (`--json`) or for humans.
+pub fn ctl(words: &[String], raw: bool) -> Result<()> {
+    let command = words.join(" ");
+    if command.trim().is_empty() {
+        anyhow::bail!("Nothing to send; try `ctl status` or `ctl next`");
+    }
+    let path = socket_path()?;
+    let mut stream = UnixStream::connect(&path)
+        .with_context(|| format!("Could not connect to {} (is the player running?)", path.display()))?;
+    writeln!(stream, "{}", json!({ "command": command }))?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let line = lines.next().context("The player closed the connection")??;
+    let reply: Value = serde_json::from_str(&line).context("Invalid reply from the player")?;
+    if reply["ok"] != json!(true) {
+        anyhow::bail!("{}", reply["error"].as_str().unwrap_or("Request failed"));
+    }
+
+    if let Some(status) = reply.get("status") {
+        println!("{}", if raw { line.clone() } else { describe_status(status) });
+    } else if let Some(playlist) = reply.get("playlist") {
+        if raw {
+            println!("{}", line);
+        } else {
+            for (index, track) in playlist.as_array().into_iter().flatten().enumerate() {
+                println!("{:4}  {} - {}", index + 1, text(&track["artist"]), text(&track["title"]));
+            }
+        }
+    } else if matches!(command.trim(), "subscribe" | "events") {
+        // Events are JSON either way, they're meant for scripts.
+        for line in lines {
+            println!("{}", line?);
+        }
+    } else if raw {
+        println!("{}", line);
+    } else if let Some(message) = reply["message"].as_str() {
+        println!("{}", message);
+    }
+    Ok(())
+}
+
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap_or("")
+}
+
+fn describe_status(status: &Value) -> String {
+    let seconds = |value: &Value| format_duration(std::time::Duration::from_secs_f64(value.as_f64().unwrap_or(0.0)));
+    let track = &status["track"];
+    let now_playing = if track.is_null() {
+        "Nothing".to_string()
+    } else {
+        format!("{} - {}", text(&track["artist"]), text(&track["title"]))
+    };
+    let duration = if track["duration"].is_null() { "?".to_string() } else { seconds(&track["duration"]) };
+    format!(
+        "[{}] {}  {}/{}  track {}/{}  volume {}%  shuffle {}",
+        text(&status["state"]),
+        now_playing,
+        seconds(&status["position"]),
+        duration,
+        status["index"].as_u64().unwrap_or(0) + 1,
+        status["length"],
+        status["volume"],
+        if status["shuffle"] == json!(true) { "on" } else { "off" },
+    )
+}
@@ -0,0 +1,694 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::AppMode;
+
+// Keys pressed further apart than this start a new chord.
+const CHORD_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Quit,
+    Back,
+    TogglePause,
+    Next,
+    Prev,
+    VolumeUp,
+    VolumeDown,
+    SeekForward,
+    SeekBack,
+    Playlist,
+    Library,
+    History,
+    Help,
+    Search,
+    SearchNext,
+    SearchPrev,
+    CommandLine,
+    Up,
+    Down,
+    Left,
+    Right,
+    PlaySelected,
+    Enqueue,
+    PlayTrack(usize),
+    // 1-5 stars, 0 clears the rating.
+    Rate(u8),
+    Favorite,
+    Playlists,
+    NewPlaylist,
+    RenamePlaylist,
+    CopyPlaylist,
+    DeletePlaylist,
+    AddToPlaylist,
+    // Playlist editing; most work on the marked range, or the selected
+    // track when nothing is marked.
+    Mark,
+    Remove,
+    MoveUp,
+    MoveDown,
+    Cut,
+    Paste,
+    Undo,
+    Redo,
+    CycleSort,
+    GroupAlbums,
+    Dupes,
+    // In the duplicates view.
+    ToggleKeep,
+    DropDuplicates,
+    EditTags,
+    // In the tag editor, on the selected field.
+    EditField,
+    ClearField,
+    NumberTracks,
+    WriteTags,
+    TagsFromPath,
+    Identify,
+    TrackInfo,
+    Analyze,
+    RenameFiles,
+    // In the rename preview.
+    ApplyRename,
+    Run(String),
+}
+
+impl Action {
+    pub fn parse(name: &str) -> Result<Action> {
+        if let Some(command) = name.strip_prefix(':') {
+            return Ok(Action::Run(command.to_string()));
+        }
+        if let Some(number) = name.strip_prefix("track_") {
+            let number: usize = number.parse().with_context(|| format!("Invalid action: {}", name))?;
+            if number == 0 {
+                anyhow::bail!("Track numbers start at 1: {}", name);
+            }
+            return Ok(Action::PlayTrack(number - 1));
+        }
+        if let Some(stars) = name.strip_prefix("rate_") {
+            let stars: u8 = stars.parse().with_context(|| format!("Invalid action: {}", name))?;
+            if stars > 5 {
+                anyhow::bail!("Ratings go from 1 to 5 (0 clears): {}", name);
+            }
+            return Ok(Action::Rate(stars));
+        }
+        let action = match name {
+            "quit" => Action::Quit,
+            "back" => Action::Back,
+            "toggle_pause" => Action::TogglePause,
+            "next" => Action::Next,
+            "prev" => Action::Prev,
+            "volume_up" => Action::VolumeUp,
+            "volume_down" => Action::VolumeDown,
+            "seek_forward" => Action::SeekForward,
+            "seek_back" => Action::SeekBack,
+            "playlist" => Action::Playlist,
+            "library" => Action::Library,
+            "history" => Action::History,
+            "help" => Action::Help,
+            "search" => Action::Search,
+            "search_next" => Action::SearchNext,
+            "search_prev" => Action::SearchPrev,
+            "command_line" => Action::CommandLine,
+            "up" => Action::Up,
+            "down" => Action::Down,
+            "left" => Action::Left,
+            "right" => Action::Right,
+            "play_selected" => Action::PlaySelected,
+            "enqueue" => Action::Enqueue,
+            "favorite" => Action::Favorite,
+            "playlists" => Action::Playlists,
+            "new_playlist" => Action::NewPlaylist,
+            "rename_playlist" => Action::RenamePlaylist,
+            "copy_playlist" => Action::CopyPlaylist,
+            "delete_playlist" => Action::DeletePlaylist,
+            "add_to_playlist" => Action::AddToPlaylist,
+            "mark" => Action::Mark,
+            "remove" => Action::Remove,
+            "move_up" => Action::MoveUp,
+            "move_down" => Action::MoveDown,
+            "cut" => Action::Cut,
+            "paste" => Action::Paste,
+            "undo" => Action::Undo,
+            "redo" => Action::Redo,
+            "cycle_sort" => Action::CycleSort,
+            "group_albums" => Action::GroupAlbums,
+            "dupes" => Action::Dupes,
+            "toggle_keep" => Action::ToggleKeep,
+            "drop_duplicates" => Action::DropDuplicates,
+            "edit_tags" => Action::EditTags,
+            "edit_field" => Action::EditField,
+            "clear_field" => Action::ClearField,
+            "number_tracks" => Action::NumberTracks,
+            "write_tags" => Action::WriteTags,
+            "tags_from_path" => Action::TagsFromPath,
+            "identify" => Action::Identify,
+            "track_info" => Action::TrackInfo,
+            "analyze" => Action::Analyze,
+            "rename_files" => Action::RenameFiles,
+            "apply_rename" => Action::ApplyRename,
+            _ => anyhow::bail!("Unknown action: {}", name),
+        };
+        Ok(action)
+    }
+
+    pub fn description(&self) -> String {
+        match self {
+            Action::Quit => "Quit".to_string(),
+            Action::Back => "Back".to_string(),
+            Action::TogglePause => "Play/Pause".to_string(),
+            Action::Next => "Next track".to_string(),
+            Action::Prev => "Previous track".to_string(),
+            Action::VolumeUp => "Volume up".to_string(),
+            Action::VolumeDown => "Volume down".to_string(),
+            Action::SeekForward => "Seek forward 10s".to_string(),
+            Action::SeekBack => "Seek back 10s".to_string(),
+            Action::Playlist => "Toggle playlist view".to_string(),
+            Action::Library => "Library browser".to_string(),
+            Action::History => "Play history and stats".to_string(),
+            Action::Help => "Show this help".to_string(),
+            Action::Search => "Fuzzy search title/artist/album/path".to_string(),
+            Action::SearchNext => "Next search match".to_string(),
+            Action::SearchPrev => "Previous search match".to_string(),
+            Action::CommandLine => "Command line".to_string(),
+            Action::Up => "Move up".to_string(),
+            Action::Down => "Move down".to_string(),
+            Action::Left => "Previous column".to_string(),
+            Action::Right => "Next column".to_string(),
+            Action::PlaySelected => "Play selection".to_string(),
+            Action::Enqueue => "Enqueue selection".to_string(),
+            Action::PlayTrack(_) => "Play track number".to_string(),
+            Action::Rate(_) => "Rate 1-5 stars (0 clears)".to_string(),
+            Action::Favorite => "Toggle favorite".to_string(),
+            Action::Playlists => "Named playlists".to_string(),
+            Action::NewPlaylist => "New playlist".to_string(),
+            Action::RenamePlaylist => "Rename playlist".to_string(),
+            Action::CopyPlaylist => "Duplicate playlist".to_string(),
+            Action::DeletePlaylist => "Delete playlist".to_string(),
+            Action::AddToPlaylist => "Add selection to a named playlist".to_string(),
+            Action::Mark => "Mark the start of a range".to_string(),
+            Action::Remove => "Remove track(s)".to_string(),
+            Action::MoveUp => "Move track(s) up".to_string(),
+            Action::MoveDown => "Move track(s) down".to_string(),
+            Action::Cut => "Cut track(s)".to_string(),
+            Action::Paste => "Paste after selection".to_string(),
+            Action::Undo => "Undo edit".to_string(),
+            Action::Redo => "Redo edit".to_string(),
+            Action::CycleSort => "Sort by the next key".to_string(),
+            Action::GroupAlbums => "Toggle album headers".to_string(),
+            Action::Dupes => "Find duplicate tracks".to_string(),
+            Action::ToggleKeep => "Keep or drop this copy".to_string(),
+            Action::DropDuplicates => "Remove dropped copies from the playlist".to_string(),
+            Action::EditTags => "Edit tags of the selection".to_string(),
+            Action::EditField => "Change this field for all tracks".to_string(),
+            Action::ClearField => "Clear this field".to_string(),
+            Action::NumberTracks => "Number tracks in list order".to_string(),
+            Action::WriteTags => "Write changes to the files".to_string(),
+            Action::TagsFromPath => "Fill tags from file paths".to_string(),
+            Action::Identify => "Suggest tags by fingerprint".to_string(),
+            Action::TrackInfo => "Track info".to_string(),
+            Action::Analyze => "Measure loudness, tempo and key".to_string(),
+            Action::RenameFiles => "Rename files from tags".to_string(),
+            Action::ApplyRename => "Rename the files".to_string(),
+            Action::Run(command) => format!(":{}", command),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyChord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyChord {
+    pub fn from_event(event: &KeyEvent) -> Self {
+        Self::new(event.code, event.modifiers)
+    }
+
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        // Shift is already folded into the character for printable keys.
+        let modifiers = match code {
+            KeyCode::Char(_) => modifiers - KeyModifiers::SHIFT,
+            KeyCode::BackTab => modifiers - KeyModifiers::SHIFT,
+            _ => modifiers,
+        };
+        KeyChord { code, modifiers }
+    }
+
+    // Parses "q", "ctrl-s", "alt-enter", "space", "f1", "shift-tab".
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = text;
+        loop {
+            let lower = rest.to_lowercase();
+            if lower.len() > 1 && lower.starts_with("ctrl-") {
+                modifiers |= KeyModifiers::CONTROL;
+                rest = &rest[5..];
+            } else if lower.len() > 1 && lower.starts_with("alt-") {
+                modifiers |= KeyModifiers::ALT;
+                rest = &rest[4..];
+            } else if lower.len() > 1 && lower.starts_with("shift-") {
+                modifiers |= KeyModifiers::SHIFT;
+                rest = &rest[6..];
+            } else {
+                break;
+            }
+        }
+
+        let code = match rest.to_lowercase().as_str() {
+            "space" => KeyCode::Char(' '),
+            "tab" if modifiers.contains(KeyModifiers::SHIFT) => KeyCode::BackTab,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "enter" | "return" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "insert" => KeyCode::Insert,
+            lower if lower.len() > 1 && lower.starts_with('f') => {
+                let n: u8 = lower[1..].parse().with_context(|| format!("Unknown key: {}", text))?;
+                KeyCode::F(n)
+            }
+            _ => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => {
+                        if modifiers.contains(KeyModifiers::SHIFT) {
+                            KeyCode::Char(c.to_ascii_uppercase())
+                        } else {
+                            KeyCode::Char(c)
+                        }
+                    }
+                    _ => anyhow::bail!("Unknown key: {}", text),
+                }
+            }
+        };
+        Ok(KeyChord::new(code, modifiers))
+    }
+
+    pub fn label(&self) -> String {
+        let key = match self.code {
+            KeyCode::Char(' ') => "Space".to_string(),
+            KeyCode::Char(c) => c.to_string(),
+            KeyCode::Tab => "Tab".to_string(),
+            KeyCode::BackTab => "Shift-Tab".to_string(),
+            KeyCode::Enter => "Enter".to_string(),
+            KeyCode::Esc => "Esc".to_string(),
+            KeyCode::Backspace => "Backspace".to_string(),
+            KeyCode::Delete => "Del".to_string(),
+            KeyCode::Up => "↑".to_string(),
+            KeyCode::Down => "↓".to_string(),
+            KeyCode::Left => "←".to_string(),
+            KeyCode::Right => "→".to_string(),
+            KeyCode::Home => "Home".to_string(),
+            KeyCode::End => "End".to_string(),
+            KeyCode::PageUp => "PgUp".to_string(),
+            KeyCode::PageDown => "PgDn".to_string(),
+            KeyCode::Insert => "Ins".to_string(),
+            KeyCode::F(n) => format!("F{}", n),
+            other => format!("{:?}", other),
+        };
+        let mut label = String::new();
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            label.push_str("Ctrl-");
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            label.push_str("Alt-");
+        }
+        label + &key
+    }
+}
+
+// A whitespace-separated sequence such as "g g" or "ctrl-x ctrl-s".
+fn parse_sequence(text: &str) -> Result<Vec<KeyChord>> {
+    let keys: Vec<KeyChord> = text.split_whitespace()
+        .map(KeyChord::parse)
+        .collect::<Result<_>>()?;
+    if keys.is_empty() {
+        anyhow::bail!("Empty key binding");
+    }
+    Ok(keys)
+}
+
+fn sequence_label(keys: &[KeyChord]) -> String {
+    keys.iter().map(KeyChord::label).collect::<Vec<_>>().join(" ")
+}
+
+#[derive(Debug, Clone)]
+struct Binding {
+    keys: Vec<KeyChord>,
+    action: Action,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    modes: HashMap<AppMode, Vec<Binding>>,
+    pending: Vec<KeyChord>,
+    last_key: Option<Instant>,
+    // What `pending` is bound to when a longer chord starts with it too,
+    // e.g. "g" with "g g" also bound. It fires once the chord can't go on.
+    held: Option<Action>,
+}
+
+pub const MODE_SECTIONS: [(&str, AppMode); 10] = [
+    ("player", AppMode::Player),
+    ("playlist", AppMode::Playlist),
+    ("playlists", AppMode::Playlists),
+    ("library", AppMode::Library),
+    ("history", AppMode::History),
+    ("dupes", AppMode::Dupes),
+    ("tags", AppMode::Tags),
+    ("rename", AppMode::Rename),
+    ("info", AppMode::Info),
+    ("help", AppMode::Help),
+];
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let digits: Vec<(String, String)> = (1..=9)
+            .map(|n| (n.to_string(), format!("track_{}", n)))
+            .collect();
+        let digits: Vec<(&str, &str)> = digits.iter().map(|(k, a)| (k.as_str(), a.as_str())).collect();
+
+        let player: Vec<(&str, &str)> = [
+            ("space", "toggle_pause"), ("p", "toggle_pause"),
+            ("n", "next"), ("right", "next"),
+            ("b", "prev"), ("left", "prev"),
+            ("+", "volume_up"), ("=", "volume_up"),
+            ("-", "volume_down"),
+            ("]", "seek_forward"), ("[", "seek_back"),
+        ].into_iter()
+            .chain(digits)
+            .chain([
+                ("r 0", "rate_0"), ("r 1", "rate_1"), ("r 2", "rate_2"),
+                ("r 3", "rate_3"), ("r 4", "rate_4"), ("r 5", "rate_5"),
+                ("f", "favorite"),
+                ("A", "add_to_playlist"),
+                ("tab", "playlist"), ("l", "playlist"),
+                ("o", "playlists"),
+                ("m", "library"),
+                ("y", "history"),
+                ("D", "dupes"),
+                ("e", "edit_tags"),
+                ("i", "track_info"),
+                ("/", "search"),
+                (":", "command_line"),
+                ("h", "help"), ("f1", "help"),
+                ("q", "quit"), ("esc", "quit"),
+            ])
+            .collect();
+        let playlist = [
+            ("up", "up"), ("k", "up"),
+            ("down", "down"), ("j", "down"),
+            ("enter", "play_selected"),
+            ("/", "search"),
+            ("n", "search_next"),
+            ("N", "search_prev"),
+            ("r 0", "rate_0"), ("r 1", "rate_1"), ("r 2", "rate_2"),
+            ("r 3", "rate_3"), ("r 4", "rate_4"), ("r 5", "rate_5"),
+            ("f", "favorite"),
+            ("v", "mark"),
+            ("d", "remove"), ("delete", "remove"),
+            ("K", "move_up"), ("J", "move_down"),
+            ("x", "cut"), ("p", "paste"),
+            ("u", "undo"), ("ctrl-r", "redo"),
+            ("s", "cycle_sort"),
+            ("g", "group_albums"),
+            ("A", "add_to_playlist"),
+            ("o", "playlists"),
+            ("D", "dupes"),
+            ("e", "edit_tags"),
+            ("i", "track_info"),
+            (":", "command_line"),
+            ("tab", "playlist"),
+            ("q", "back"), ("esc", "back"),
+        ];
+        let playlists = [
+            ("up", "up"), ("k", "up"),
+            ("down", "down"), ("j", "down"),
+            ("enter", "play_selected"),
+            ("n", "new_playlist"),
+            ("r", "rename_playlist"),
+            ("c", "copy_playlist"),
+            ("d", "delete_playlist"),
+            (":", "command_line"),
+            ("tab", "playlist"),
+            ("q", "back"), ("esc", "back"),
+        ];
+        let library = [
+            ("left", "left"), ("h", "left"),
+            ("right", "right"), ("l", "right"),
+            ("up", "up"), ("k", "up"),
+            ("down", "down"), ("j", "down"),
+            ("enter", "play_selected"),
+            ("a", "enqueue"),
+            ("A", "add_to_playlist"),
+            ("e", "edit_tags"),
+            ("i", "track_info"),
+            ("/", "search"),
+            ("n", "search_next"),
+            ("N", "search_prev"),
+            (":", "command_line"),
+            ("tab", "playlist"),
+            ("q", "back"), ("esc", "back"),
+        ];
+        let history = [
+            ("up", "up"), ("k", "up"),
+            ("down", "down"), ("j", "down"),
+            ("enter", "play_selected"),
+            ("a", "enqueue"),
+            ("A", "add_to_playlist"),
+            (":", "command_line"),
+            ("tab", "playlist"),
+            ("q", "back"), ("esc", "back"),
+        ];
+        let dupes = [
+            ("up", "up"), ("k", "up"),
+            ("down", "down"), ("j", "down"),
+            ("enter", "play_selected"),
+            ("space", "toggle_keep"),
+            ("x", "drop_duplicates"),
+            (":", "command_line"),
+            ("tab", "playlist"),
+            ("q", "back"), ("esc", "back"),
+        ];
+        let tags = [
+            ("up", "up"), ("k", "up"),
+            ("down", "down"), ("j", "down"),
+            ("enter", "edit_field"),
+            ("c", "clear_field"), ("delete", "clear_field"),
+            ("n", "number_tracks"),
+            ("p", "tags_from_path"),
+            ("i", "identify"),
+            ("r", "rename_files"),
+            ("u", "undo"),
+            ("w", "write_tags"),
+            (":", "command_line"),
+            ("q", "back"), ("esc", "back"),
+        ];
+        let rename = [
+            ("up", "up"), ("k", "up"),
+            ("down", "down"), ("j", "down"),
+            ("enter", "apply_rename"),
+            (":", "command_line"),
+            ("q", "back"), ("esc", "back"),
+        ];
+        let info = [
+            ("a", "analyze"),
+            (":", "command_line"),
+            ("q", "back"), ("esc", "back"), ("i", "back"),
+        ];
+        let help = [("q", "back"), ("esc", "back"), ("h", "back")];
+
+        let build = |bindings: &[(&str, &str)]| -> Vec<Binding> {
+            bindings.iter()
+                .map(|(keys, action)| Binding {
+                    keys: parse_sequence(keys).expect("valid default key"),
+                    action: Action::parse(action).expect("valid default action"),
+                })
+                .collect()
+        };
+
+        let mut modes = HashMap::new();
+        modes.insert(AppMode::Player, build(&player));
+        modes.insert(AppMode::Playlist, build(&playlist));
+        modes.insert(AppMode::Playlists, build(&playlists));
+        modes.insert(AppMode::Library, build(&library));
+        modes.insert(AppMode::History, build(&history));
+        modes.insert(AppMode::Dupes, build(&dupes));
+        modes.insert(AppMode::Tags, build(&tags));
+        modes.insert(AppMode::Rename, build(&rename));
+        modes.insert(AppMode::Info, build(&info));
+        modes.insert(AppMode::Help, build(&help));
+
+        Keymap {
+            modes,
+            pending: Vec::new(),
+            last_key: None,
+            held: None,
+        }
+    }
+}
+
+impl Keymap {
+    pub fn default_path() -> Option<PathBuf> {
+        crate::config::config_dir().map(|dir| dir.join("keys.toml"))
+    }
+
+    // Default bindings with a user file layered on top. The file has one
+    // table per mode, mapping key sequences to action names, `:commands`,
+    // or "none" to drop a default binding:
+    //
+    //     [player]
+    //     "ctrl-s" = ":save ~/last.m3u"
+    //     "g g" = "track_1"
+    //     "q" = "none"
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let table: HashMap<String, HashMap<String, String>> = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let mut keymap = Keymap::default();
+        keymap.apply(&table)?;
+        Ok(keymap)
+    }
+
+    pub fn apply(&mut self, table: &HashMap<String, HashMap<String, String>>) -> Result<()> {
+        for (section, bindings) in table {
+            let mode = MODE_SECTIONS.iter()
+                .find(|(name, _)| name == section)
+                .map(|(_, mode)| mode.clone())
+                .with_context(|| format!("Unknown keymap section [{}]", section))?;
+            let mode_bindings = self.modes.entry(mode).or_default();
+
+            for (keys, action) in bindings {
+                let keys = parse_sequence(keys).with_context(|| format!("In [{}]", section))?;
+                mode_bindings.retain(|b| b.keys != keys);
+                if action != "none" {
+                    let action = Action::parse(action).with_context(|| format!("In [{}]", section))?;
+                    mode_bindings.push(Binding { keys, action });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Feeds one key press. Returns the bound actions once a full sequence
+    // matches; keys that could still grow into a longer chord are held
+    // until the next key or the timeout settles them.
+    pub fn feed(&mut self, mode: &AppMode, event: &KeyEvent) -> Vec<Action> {
+        let mut actions: Vec<Action> = self.expire().into_iter().collect();
+        if self.last_key.is_some_and(|t| t.elapsed() > CHORD_TIMEOUT) {
+            self.pending.clear();
+        }
+        self.last_key = Some(Instant::now());
+        self.pending.push(KeyChord::from_event(event));
+
+        let bindings = self.modes.get(mode).map(Vec::as_slice).unwrap_or_default();
+        let mut exact = None;
+        let mut longer = false;
+        for binding in bindings {
+            if binding.keys.starts_with(&self.pending) {
+                if binding.keys.len() == self.pending.len() {
+                    exact = Some(binding.action.clone());
+                } else {
+                    longer = true;
+                }
+            }
+        }
+
+        if longer {
+            self.held = exact;
+            return actions;
+        }
+        if exact.is_none() && self.pending.len() > 1 {
+            // The chord went nowhere: settle on what was held, if anything,
+            // and treat this key as the start of a new one.
+            self.pending.clear();
+            actions.extend(self.held.take());
+            actions.extend(self.feed(mode, event));
+            return actions;
+        }
+        self.pending.clear();
+        self.held = None;
+        actions.extend(exact);
+        actions
+    }
+
+    // Called between key presses: fires a held action once no longer
+    // chord followed in time.
+    pub fn expire(&mut self) -> Option<Action> {
+        if self.pending.is_empty() || self.last_key.is_none_or(|t| t.elapsed() <= CHORD_TIMEOUT) {
+            return None;
+        }
+        self.pending.clear();
+        self.held.take()
+    }
+
+    pub fn pending(&self) -> String {
+        sequence_label(&self.pending)
+    }
+
+    // Keys for an action in a mode, e.g. "Space/p".
+    pub fn keys_for(&self, mode: &AppMode, action: &Action) -> Option<String> {
+        let labels: Vec<String> = self.modes.get(mode)?
+            .iter()
+            .filter(|b| b.action == *action)
+            .map(|b| sequence_label(&b.keys))
+            .collect();
+        if labels.is_empty() {
+            None
+        } else {
+            Some(labels.join("/"))
+        }
+    }
+
+    // (keys, description) rows for the help screen, one per description in
+    // binding order, so "1".."9" collapse into a single row.
+    pub fn help_rows(&self, mode: &AppMode) -> Vec<(String, String)> {
+        let mut rows: Vec<(Vec<String>, String)> = Vec::new();
+        for binding in self.modes.get(mode).map(Vec::as_slice).unwrap_or_default() {
+            let description = binding.action.description();
+            let label = sequence_label(&binding.keys);
+            match rows.iter_mut().find(|(_, d)| *d == description) {
+                Some((labels, _)) => labels.push(label),
+                None => rows.push((vec![label], description)),
+            }
+        }
+        rows.into_iter()
+            .map(|(labels, description)| (compress_digits(&labels), description))
+            .collect()
+    }
+}
+
+// "1/2/3/4" -> "1-4" for runs of single digits, also after a shared
+// chord prefix ("r 1/r 2/r 3" -> "r 1-3").
+fn compress_digits(labels: &[String]) -> String {
+    let split: Option<Vec<(&str, u32)>> = labels.iter()
+        .map(|l| {
+            let last = l.chars().last()?;
+            Some((&l[..l.len() - last.len_utf8()], last.to_digit(10)?))
+        })
+        .collect();
+    match split {
+        Some(d) if d.len() > 2
+            && d.iter().all(|(prefix, _)| *prefix == d[0].0)
+            && (d[0].0.is_empty() || d[0].0.ends_with(' '))
+            && d.windows(2).all(|w| w[1].1 == w[0].1 + 1) => {
+            format!("{}{}-{}", d[0].0, d[0].1, d[d.len() - 1].1)
+        }
+        _ => labels.join("/"),
+    }
+}
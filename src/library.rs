@@ -0,0 +1,676 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use id3::frame::{Content, ExtendedText, Popularimeter};
+use id3::{Frame, Tag, TagLike, Version};
+use serde_json::{json, Map, Value};
+
+use crate::analysis::Analysis;
+use crate::config::{data_dir, APP_NAME};
+use crate::search::fuzzy_score;
+use crate::vorbis::{self, Comments};
+
+const UNKNOWN_GENRE: &str = "Unknown Genre";
+pub const UNKNOWN_ARTIST: &str = "Unknown Artist";
+pub const UNKNOWN_ALBUM: &str = "Unknown Album";
+// User data is written once changes have been quiet this long, so a run of
+// play counts or ratings is one write rather than one each.
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct TrackInfo {
+    pub path: PathBuf,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub genre: String,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    pub year: Option<i32>,
+    pub duration: Option<Duration>,
+}
+
+impl TrackInfo {
+    pub fn read(path: &Path) -> Self {
+        // FLAC and Ogg carry Vorbis comments; everything else is read as
+        // ID3, plain in MP3s or the `id3 ` chunk in WAVs.
+        if vorbis::is_vorbis(path) {
+            let comments = Comments::read(path).unwrap_or_default();
+            let text = |name| comments.get(name).filter(|value| !value.is_empty());
+            let number = |name| text(name).and_then(vorbis::number);
+            return TrackInfo {
+                track: number("TRACKNUMBER"),
+                disc: number("DISCNUMBER"),
+                year: number("DATE").map(|year| year as i32),
+                duration: None,
+                ..TrackInfo::named(
+                    path,
+                    text("TITLE"),
+                    text("ARTIST").or_else(|| text("ALBUMARTIST")),
+                    text("ALBUM"),
+                    text("GENRE").map(str::to_string),
+                )
+            };
+        }
+
+        let tag = Tag::read_from_path(path).ok();
+        let Some(tag) = tag else {
+            return TrackInfo::named(path, None, None, None, None);
+        };
+        TrackInfo {
+            track: tag.track(),
+            disc: tag.disc(),
+            // ID3v2.4 keeps the year in the recording date.
+            year: tag.year().or_else(|| tag.date_recorded().map(|date| date.year)),
+            duration: tag.duration().map(|ms| Duration::from_millis(ms as u64)),
+            ..TrackInfo::named(
+                path,
+                tag.title(),
+                tag.artist().or_else(|| tag.album_artist()),
+                tag.album(),
+                tag.genre_parsed().map(|g| g.to_string()),
+            )
+        }
+    }
+
+    // The text fields, with stand-ins for whatever the tags leave out.
+    fn named(path: &Path, title: Option<&str>, artist: Option<&str>, album: Option<&str>, genre: Option<String>) -> Self {
+        TrackInfo {
+            path: path.to_path_buf(),
+            title: title.map(str::to_string).unwrap_or_else(|| {
+                path.file_stem().unwrap_or_default().to_string_lossy().to_string()
+            }),
+            artist: artist.unwrap_or(UNKNOWN_ARTIST).to_string(),
+            album: album.unwrap_or(UNKNOWN_ALBUM).to_string(),
+            genre: genre.unwrap_or_else(|| UNKNOWN_GENRE.to_string()),
+            track: None,
+            disc: None,
+            year: None,
+            duration: None,
+        }
+    }
+}
+
+// What the player knows about a track beyond its tags. Kept per path in
+// `library.json` in the data directory, including for tracks that aren't in
+// the current scan, so nothing is lost when a music dir is left out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserData {
+    // 1-5 stars.
+    pub rating: Option<u8>,
+    pub favorite: bool,
+    // Times played to the end, and times skipped for another track.
+    pub plays: u32,
+    pub skips: u32,
+    // Loudness, tempo and key, once measured.
+    pub analysis: Option<Analysis>,
+    // Unix time the track first turned up in the library.
+    pub added: Option<i64>,
+}
+
+impl UserData {
+    fn to_json(&self) -> Value {
+        json!({
+            "rating": self.rating,
+            "favorite": self.favorite,
+            "plays": self.plays,
+            "skips": self.skips,
+            "analysis": self.analysis.as_ref().map(Analysis::to_json),
+            "added": self.added,
+        })
+    }
+
+    fn from_json(value: &Value) -> UserData {
+        UserData {
+            rating: value["rating"].as_u64().map(|r| r.clamp(1, 5) as u8),
+            favorite: value["favorite"].as_bool().unwrap_or(false),
+            plays: value["plays"].as_u64().unwrap_or(0) as u32,
+            skips: value["skips"].as_u64().unwrap_or(0) as u32,
+            analysis: Analysis::from_json(&value["analysis"]),
+            added: value["added"].as_i64(),
+        }
+    }
+
+    // "★★★★☆ ♥", or empty when there's nothing to show.
+    pub fn badge(&self) -> String {
+        let mut badge = match self.rating {
+            Some(rating) => "★".repeat(rating as usize) + &"☆".repeat(5 - rating as usize),
+            None => String::new(),
+        };
+        if self.favorite {
+            if !badge.is_empty() {
+                badge.push(' ');
+            }
+            badge.push('♥');
+        }
+        badge
+    }
+}
+
+pub fn user_data_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("library.json"))
+}
+
+// The saved entries by path; a missing file has none.
+fn read_user_data(path: &Path) -> Result<Map<String, Value>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Map::new()),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Library {
+    tracks: Vec<TrackInfo>,
+    by_path: HashMap<PathBuf, usize>,
+    user_data: HashMap<PathBuf, UserData>,
+    // Where `user_data` is saved; nothing is written until it's opened.
+    user_data_path: Option<PathBuf>,
+    // When user data last changed, while there are changes not yet saved,
+    // and the tracks they were for.
+    unsaved: Option<Instant>,
+    changed: HashSet<PathBuf>,
+    // Bumped on every change to tracks or user data, so smart playlists
+    // know to re-evaluate.
+    revision: u64,
+    // Genre -> artist -> album -> track indices, kept sorted for the browser
+    // columns so drawing them doesn't walk the whole library every frame.
+    columns: BTreeMap<String, BTreeMap<String, BTreeMap<String, Vec<usize>>>>,
+}
+
+impl Library {
+    // Loads ratings and counts from `path` (a missing file is fine) and
+    // saves changes there from now on.
+    pub fn open_user_data(&mut self, path: PathBuf) -> Result<()> {
+        self.user_data = read_user_data(&path)?.iter()
+            .map(|(track, value)| (PathBuf::from(track), UserData::from_json(value)))
+            .collect();
+        self.user_data_path = Some(path);
+        Ok(())
+    }
+
+    // Called after every change to a track's user data; `save_if_due`
+    // writes it.
+    fn user_data_changed(&mut self, path: &Path) {
+        self.revision += 1;
+        self.unsaved = Some(Instant::now());
+        self.changed.insert(path.to_path_buf());
+    }
+
+    // Saves user data once it has stopped changing for a moment. Called on
+    // every tick.
+    pub fn save_if_due(&mut self) -> Result<()> {
+        match self.unsaved {
+            Some(changed) if changed.elapsed() >= SAVE_DELAY => self.save_user_data(),
+            _ => Ok(()),
+        }
+    }
+
+    // Writes any unsaved user data now. Another process may have saved
+    // since we read the file (`analyze` while the player runs, say), so
+    // only the tracks changed here are written over what's there now, and
+    // everything else is picked up from it. Goes through a temporary file
+    // so a crash mid-write leaves the old file rather than half a new one.
+    pub fn save_user_data(&mut self) -> Result<()> {
+        let path = match (&self.user_data_path, self.unsaved) {
+            (Some(path), Some(_)) => path.clone(),
+            _ => return Ok(()),
+        };
+        let written = self.merge_user_data(&path);
+        // On failure, try again after another delay rather than every tick.
+        self.unsaved = written.is_err().then(Instant::now);
+        written
+    }
+
+    fn merge_user_data(&mut self, path: &Path) -> Result<()> {
+        let mut entries = read_user_data(path)?;
+        for track in &self.changed {
+            let name = track.to_string_lossy().to_string();
+            match self.user_data.get(track).filter(|data| **data != UserData::default()) {
+                Some(data) => entries.insert(name, data.to_json()),
+                None => entries.remove(&name),
+            };
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        std::fs::write(&temp, serde_json::to_string_pretty(&entries)?)
+            .and_then(|_| std::fs::rename(&temp, path))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        self.changed.clear();
+        self.user_data = entries.iter()
+            .map(|(track, value)| (PathBuf::from(track), UserData::from_json(value)))
+            .collect();
+        self.revision += 1;
+        Ok(())
+    }
+
+    pub fn user_data(&self, path: &Path) -> UserData {
+        self.user_data.get(path).cloned().unwrap_or_default()
+    }
+
+    // `None` clears the rating.
+    pub fn set_rating(&mut self, path: &Path, rating: Option<u8>) {
+        self.user_data.entry(path.to_path_buf()).or_default().rating = rating.map(|r| r.clamp(1, 5));
+        self.user_data_changed(path);
+    }
+
+    // Returns whether the track is now a favorite.
+    pub fn toggle_favorite(&mut self, path: &Path) -> bool {
+        let data = self.user_data.entry(path.to_path_buf()).or_default();
+        data.favorite = !data.favorite;
+        let favorite = data.favorite;
+        self.user_data_changed(path);
+        favorite
+    }
+
+    pub fn set_analysis(&mut self, path: &Path, analysis: Analysis) {
+        self.user_data.entry(path.to_path_buf()).or_default().analysis = Some(analysis);
+        self.user_data_changed(path);
+    }
+
+    // Counts a track that ended: a play when it ran to the end, a skip
+    // when something else was started first.
+    pub fn record_end(&mut self, path: &Path, finished: bool) {
+        let data = self.user_data.entry(path.to_path_buf()).or_default();
+        if finished {
+            data.plays += 1;
+        } else {
+            data.skips += 1;
+        }
+        self.user_data_changed(path);
+    }
+
+    pub fn add_paths(&mut self, paths: &[PathBuf]) {
+        let tracks = paths.iter()
+            .filter(|path| !self.by_path.contains_key(*path))
+            .map(|path| TrackInfo::read(path))
+            .collect();
+        self.add_tracks(tracks);
+    }
+
+    // Tracks whose tags were read elsewhere, such as by the startup scan.
+    pub fn add_tracks(&mut self, tracks: Vec<TrackInfo>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64);
+        let mut first_seen = Vec::new();
+        for track in tracks {
+            if !self.by_path.contains_key(&track.path) {
+                let data = self.user_data.entry(track.path.clone()).or_default();
+                if data.added.is_none() {
+                    data.added = Some(now);
+                    first_seen.push(track.path.clone());
+                }
+                self.by_path.insert(track.path.clone(), self.tracks.len());
+                self.tracks.push(track);
+                self.index_track(self.tracks.len() - 1);
+                self.revision += 1;
+            }
+        }
+        for path in first_seen {
+            self.user_data_changed(&path);
+        }
+    }
+
+    // Re-reads tags for tracks whose files have changed.
+    pub fn reload(&mut self, paths: &[PathBuf]) {
+        let mut changed = false;
+        for path in paths {
+            if let Some(&i) = self.by_path.get(path) {
+                self.tracks[i] = TrackInfo::read(path);
+                self.revision += 1;
+                changed = true;
+            }
+        }
+        // Any tag may have moved a track to another genre, artist or album.
+        if changed {
+            self.columns.clear();
+            for i in 0..self.tracks.len() {
+                self.index_track(i);
+            }
+        }
+    }
+
+    fn index_track(&mut self, i: usize) {
+        let track = &self.tracks[i];
+        let tracks = self.columns.entry(track.genre.clone()).or_default()
+            .entry(track.artist.clone()).or_default()
+            .entry(track.album.clone()).or_default();
+        tracks.push(i);
+        let all = &self.tracks;
+        tracks.sort_by(|&a, &b| {
+            let (a, b) = (&all[a], &all[b]);
+            (a.disc, a.track, &a.title).cmp(&(b.disc, b.track, &b.title))
+        });
+    }
+
+    // Follows files moved on disk, ratings and counts included.
+    pub fn rename(&mut self, moves: &[(PathBuf, PathBuf)]) {
+        for (from, to) in moves {
+            if let Some(i) = self.by_path.remove(from) {
+                self.tracks[i].path = to.clone();
+                self.by_path.insert(to.clone(), i);
+            }
+            if let Some(data) = self.user_data.remove(from) {
+                self.user_data.insert(to.clone(), data);
+            }
+            self.user_data_changed(from);
+            self.user_data_changed(to);
+        }
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&TrackInfo> {
+        self.by_path.get(path).map(|&i| &self.tracks[i])
+    }
+
+    pub fn all(&self) -> &[TrackInfo] {
+        &self.tracks
+    }
+
+    // Where the track is in `all`.
+    pub fn index_of(&self, path: &Path) -> Option<usize> {
+        self.by_path.get(path).copied()
+    }
+
+    // Best fuzzy matches for `query` over title, artist and album.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&TrackInfo> {
+        let needle: Vec<char> = query.to_lowercase().chars().collect();
+        let mut scored: Vec<(i64, &TrackInfo)> = self.tracks.iter()
+            .filter_map(|track| {
+                let haystack = format!("{} {} {}", track.title, track.artist, track.album).to_lowercase();
+                fuzzy_score(&needle, &haystack).map(|score| (score, track))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.path.cmp(&b.1.path)));
+        scored.into_iter().take(limit).map(|(_, track)| track).collect()
+    }
+
+    pub fn genres(&self) -> Vec<&str> {
+        self.columns.keys().map(String::as_str).collect()
+    }
+
+    pub fn artists(&self, genre: &str) -> Vec<&str> {
+        self.columns.get(genre)
+            .map(|artists| artists.keys().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn albums(&self, genre: &str, artist: &str) -> Vec<&str> {
+        self.columns.get(genre)
+            .and_then(|artists| artists.get(artist))
+            .map(|albums| albums.keys().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn tracks(&self, genre: &str, artist: &str, album: &str) -> Vec<&TrackInfo> {
+        self.columns.get(genre)
+            .and_then(|artists| artists.get(artist))
+            .and_then(|albums| albums.get(album))
+            .map(|tracks| tracks.iter().map(|&i| &self.tracks[i]).collect())
+            .unwrap_or_default()
+    }
+}
+
+// Writes a rating into the file's ID3 tag, both as a POPM frame (in the
+// 1-255 scale most players use) and as an FMPS_Rating TXXX frame (0.0-1.0).
+// FLAC and Ogg files get just the FMPS_RATING comment. `None` removes them.
+pub fn write_rating_tag(path: &Path, rating: Option<u8>) -> Result<()> {
+    if vorbis::is_vorbis(path) {
+        let mut comments = Comments::read(path)?;
+        let value = rating.map(|rating| format!("{:.1}", rating as f32 / 5.0));
+        comments.set("FMPS_RATING", value.as_deref());
+        return comments.write(path);
+    }
+    let mut tag = match Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(err) if matches!(err.kind, id3::ErrorKind::NoTag) => Tag::new(),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read tags from {}", path.display())),
+    };
+    tag.remove("POPM");
+    tag.remove_extended_text(Some("FMPS_Rating"), None);
+    if let Some(rating) = rating {
+        let popm = match rating {
+            1 => 1,
+            2 => 64,
+            3 => 128,
+            4 => 196,
+            _ => 255,
+        };
+        tag.add_frame(Frame::with_content("POPM", Content::Popularimeter(Popularimeter {
+            user: APP_NAME.to_string(),
+            rating: popm,
+            counter: 0,
+        })));
+        tag.add_frame(ExtendedText {
+            description: "FMPS_Rating".to_string(),
+            value: format!("{:.1}", rating as f32 / 5.0),
+        });
+    }
+    // Like reading, this picks the MP3 or WAV layout from the file itself.
+    tag.write_to_path(path, Version::Id3v24)
+        .with_context(|| format!("Failed to write tags to {}", path.display()))
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn owned(values: Vec<&str>) -> Vec<String> {
+    values.into_iter().map(str::to_string).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrowserColumn {
+    Genre,
+    Artist,
+    Album,
+    Track,
+}
+
+impl BrowserColumn {
+    pub const ALL: [BrowserColumn; 4] = [
+        BrowserColumn::Genre,
+        BrowserColumn::Artist,
+        BrowserColumn::Album,
+        BrowserColumn::Track,
+    ];
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            BrowserColumn::Genre => "Genre",
+            BrowserColumn::Artist => "Artist",
+            BrowserColumn::Album => "Album",
+            BrowserColumn::Track => "Track",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+// Drill-down state for the Genre -> Artist -> Album -> Track columns.
+// Each column's selection indexes into the items produced by the
+// selections of the columns to its left.
+#[derive(Debug, Clone)]
+pub struct LibraryBrowser {
+    pub column: BrowserColumn,
+    selected: [usize; 4],
+}
+
+impl LibraryBrowser {
+    pub fn new() -> Self {
+        LibraryBrowser {
+            column: BrowserColumn::Genre,
+            selected: [0; 4],
+        }
+    }
+
+    pub fn selected(&self, column: BrowserColumn) -> usize {
+        self.selected[column.index()]
+    }
+
+    pub fn items(&self, library: &Library, column: BrowserColumn) -> Vec<String> {
+        let genre = self.current_value(library, BrowserColumn::Genre);
+        let artist = self.current_value(library, BrowserColumn::Artist);
+
+        match column {
+            BrowserColumn::Genre => owned(library.genres()),
+            BrowserColumn::Artist => genre
+                .map(|g| owned(library.artists(&g)))
+                .unwrap_or_default(),
+            BrowserColumn::Album => genre.zip(artist)
+                .map(|(g, a)| owned(library.albums(&g, &a)))
+                .unwrap_or_default(),
+            BrowserColumn::Track => self.shown_tracks(library)
+                .iter()
+                .map(|t| {
+                    let name = match t.track {
+                        Some(n) => format!("{:02}. {}", n, t.title),
+                        None => t.title.clone(),
+                    };
+                    match t.duration {
+                        Some(d) => format!("{} ({})", name, format_duration(d)),
+                        None => name,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    // The tracks listed in the Track column, in the order `items` gives.
+    pub fn shown_tracks<'a>(&self, library: &'a Library) -> Vec<&'a TrackInfo> {
+        let genre = self.current_value(library, BrowserColumn::Genre);
+        let artist = self.current_value(library, BrowserColumn::Artist);
+        let album = self.current_value(library, BrowserColumn::Album);
+        match (genre, artist, album) {
+            (Some(g), Some(a), Some(al)) => library.tracks(&g, &a, &al),
+            _ => Vec::new(),
+        }
+    }
+
+    fn current_value(&self, library: &Library, column: BrowserColumn) -> Option<String> {
+        // Resolve the columns left of this one first; only their values are
+        // needed to know which list `column` is selecting from.
+        let genre = library.genres().get(self.selected(BrowserColumn::Genre)).map(|g| g.to_string());
+        if column == BrowserColumn::Genre {
+            return genre;
+        }
+        let genre = genre?;
+        let artist = library.artists(&genre).get(self.selected(BrowserColumn::Artist)).map(|a| a.to_string());
+        if column == BrowserColumn::Artist {
+            return artist;
+        }
+        let artist = artist?;
+        library.albums(&genre, &artist).get(self.selected(BrowserColumn::Album)).map(|a| a.to_string())
+    }
+
+    // Paths of every track under the current selection at the focused column,
+    // e.g. a whole artist when the Artist column has focus.
+    pub fn selected_paths(&self, library: &Library) -> Vec<PathBuf> {
+        let genre = match self.current_value(library, BrowserColumn::Genre) {
+            Some(g) => g,
+            None => return Vec::new(),
+        };
+        let artists = match self.column {
+            BrowserColumn::Genre => owned(library.artists(&genre)),
+            _ => self.current_value(library, BrowserColumn::Artist).into_iter().collect(),
+        };
+
+        let mut paths = Vec::new();
+        for artist in artists {
+            let albums = match self.column {
+                BrowserColumn::Genre | BrowserColumn::Artist => owned(library.albums(&genre, &artist)),
+                _ => self.current_value(library, BrowserColumn::Album).into_iter().collect(),
+            };
+            for album in albums {
+                let tracks = library.tracks(&genre, &artist, &album);
+                if self.column == BrowserColumn::Track {
+                    if let Some(track) = tracks.get(self.selected(BrowserColumn::Track)) {
+                        paths.push(track.path.clone());
+                    }
+                } else {
+                    paths.extend(tracks.iter().map(|t| t.path.clone()));
+                }
+            }
+        }
+        paths
+    }
+
+    // Selects `path` in every column and focuses the Track one. Returns
+    // false when the library doesn't have it.
+    pub fn reveal(&mut self, library: &Library, path: &Path) -> bool {
+        let track = match library.get(path) {
+            Some(track) => track,
+            None => return false,
+        };
+        let position = |values: Vec<&str>, value: &str| values.iter().position(|v| *v == value).unwrap_or(0);
+        self.selected = [
+            position(library.genres(), &track.genre),
+            position(library.artists(&track.genre), &track.artist),
+            position(library.albums(&track.genre, &track.artist), &track.album),
+            library.tracks(&track.genre, &track.artist, &track.album).iter()
+                .position(|t| t.path == track.path)
+                .unwrap_or(0),
+        ];
+        self.column = BrowserColumn::Track;
+        true
+    }
+
+    // The track selected in the Track column, when that has focus.
+    pub fn selected_track<'a>(&self, library: &'a Library) -> Option<&'a TrackInfo> {
+        if self.column != BrowserColumn::Track {
+            return None;
+        }
+        self.shown_tracks(library).get(self.selected(BrowserColumn::Track)).copied()
+    }
+
+    pub fn move_up(&mut self, library: &Library) {
+        let len = self.items(library, self.column).len();
+        if len > 0 {
+            let idx = self.column.index();
+            self.selected[idx] = if self.selected[idx] == 0 { len - 1 } else { self.selected[idx] - 1 };
+            self.reset_right_of(self.column);
+        }
+    }
+
+    pub fn move_down(&mut self, library: &Library) {
+        let len = self.items(library, self.column).len();
+        if len > 0 {
+            let idx = self.column.index();
+            self.selected[idx] = if self.selected[idx] >= len - 1 { 0 } else { self.selected[idx] + 1 };
+            self.reset_right_of(self.column);
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        let idx = self.column.index();
+        if idx > 0 {
+            self.column = BrowserColumn::ALL[idx - 1];
+        }
+    }
+
+    pub fn move_right(&mut self, library: &Library) {
+        let idx = self.column.index();
+        if idx + 1 < BrowserColumn::ALL.len() {
+            let next = BrowserColumn::ALL[idx + 1];
+            if !self.items(library, next).is_empty() {
+                self.column = next;
+            }
+        }
+    }
+
+    fn reset_right_of(&mut self, column: BrowserColumn) {
+        for idx in column.index() + 1..self.selected.len() {
+            self.selected[idx] = 0;
+        }
+    }
+}
@@ -1,11 +1,42 @@
 mod playlist;
 mod player;
+mod library;
+mod search;
+mod command;
+mod keymap;
+mod config;
+mod theme;
+mod events;
+mod remote;
+#[cfg(target_os = "linux")]
+mod mpris;
+#[cfg(unix)]
+mod ipc;
+mod mpd;
+mod http;
+mod hooks;
+mod scrobble;
+mod history;
+mod smart;
+mod scanner;
+mod dupes;
+mod fingerprint;
+mod tags;
+mod vorbis;
+mod rename;
+mod identify;
+mod analysis;
+#[cfg(unix)]
+mod daemon;
 
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 use std::io;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
     execute,
@@ -14,240 +45,2043 @@ use crossterm::{
 use ratatui::{
     backend::{Backend, CrosstermBackend},
     layout::{Alignment, Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
+    style::Style,
     text::{Line, Span},
     widgets::{
         Block, Borders, Gauge, List, ListItem, ListState, Paragraph, Wrap,
     },
     Frame, Terminal,
 };
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use playlist::Playlist;
+use playlist::{Playlist, ScanOptions, SortKey};
 use player::{AudioPlayer, PlaybackState};
+use library::{BrowserColumn, Library, LibraryBrowser};
+use search::{Search, SearchScope};
+use command::{Command, CommandLine, Seek};
+use keymap::{Action, Keymap};
+use config::{Config, Resume, ResumeState};
+use theme::Theme;
+use events::{EventBus, PlayerEvent};
+use remote::{Remote, Request};
+use history::{History, Period};
+use smart::Query;
+use scanner::Scanner;
+use tags::{Edit, Field, TagEditor};
+use rename::{RenamePlan, DEFAULT_PATTERN};
+use identify::{Database, Suggestions};
+use fingerprint::Fingerprint;
+use analysis::Analysis;
+
+// The history view only lists the most recent plays.
+const HISTORY_VIEW_LIMIT: usize = 500;
 
 #[derive(Parser)]
 #[command(name = "rust-cli-music-player")]
 struct Args {
-    #[arg(short, long, default_value = ".")]
-    dir: PathBuf,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+    
+    /// Music directory; repeat for several (overrides `music_dirs` from the config)
+    #[arg(short, long)]
+    dir: Vec<PathBuf>,
+    
+    /// Music files or directories to play (overrides `music_dirs` from the config)
+    paths: Vec<PathBuf>,
+    
+    /// Skip paths matching a glob, e.g. "**/Podcasts/**" (adds to `exclude`)
+    #[arg(long)]
+    exclude: Vec<String>,
+    
+    /// How many directory levels to scan (1 = only the files directly inside)
+    #[arg(long)]
+    max_depth: Option<i64>,
+    
+    /// Include hidden files and directories
+    #[arg(long)]
+    hidden: bool,
+    
+    /// Don't descend into symlinked directories
+    #[arg(long)]
+    no_follow_symlinks: bool,
     
-    #[arg(short, long, default_value = "0.5")]
-    volume: f32,
+    /// Initial volume from 0.0 to 1.0
+    #[arg(short, long)]
+    volume: Option<f32>,
+    
+    /// Config file (defaults to ~/.config/rust-cli-music-player/config.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
+    
+    /// Command file to run at startup (defaults to ~/.config/rust-cli-music-player/rc)
+    #[arg(long)]
+    rc: Option<PathBuf>,
+    
+    /// Key bindings file (defaults to ~/.config/rust-cli-music-player/keys.toml)
+    #[arg(long)]
+    keys: Option<PathBuf>,
+    
+    /// Start with a saved smart playlist instead of the whole music directory
+    #[arg(long)]
+    smart: Option<String>,
+    
+    /// Run headless, controlled through ctl, MPRIS or MPD
+    #[arg(long, visible_alias = "no-tui")]
+    daemon: bool,
+    
+    /// Pidfile for --daemon (defaults to $XDG_RUNTIME_DIR/rust-cli-music-player.pid)
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Control a running player, e.g. `ctl next`, `ctl status --json` or `ctl events`
+    Ctl {
+        /// Print the raw JSON reply
+        #[arg(long)]
+        json: bool,
+        
+        /// A `:` command, or one of status, playlist, events
+        #[arg(required = true, allow_hyphen_values = true)]
+        words: Vec<String>,
+    },
+    /// Most played tracks, artists and albums, listening time and skip rate
+    Stats {
+        /// Group listening time by day or week
+        #[arg(long, default_value = "day", value_parser = ["day", "week"])]
+        by: String,
+        
+        /// How many entries to show per ranking
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        
+        /// How many days or weeks of listening time to show
+        #[arg(long, default_value_t = 7)]
+        periods: usize,
+    },
+    /// List duplicate tracks in the music directories, best copy first
+    Dupes {
+        /// Also compare acoustic fingerprints (decodes every file, so slow)
+        #[arg(long)]
+        audio: bool,
+    },
+    /// Measure loudness (EBU R128), true peak, loudness range, dynamic range, tempo and key
+    Analyze {
+        /// Files to analyze (default: everything in the music directories)
+        files: Vec<PathBuf>,
+        
+        /// Also write ReplayGain tags (MP3 and WAV)
+        #[arg(long)]
+        replaygain: bool,
+        
+        /// Measure again even when the library already has results
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print fingerprint database lines for the tagged tracks in the music directories
+    Fingerprint,
+    /// Suggest tags for files by matching their fingerprints against the database
+    Identify {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Report unknown keys and invalid values in the config and environment
+    Check,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum AppMode {
     Player,
     Playlist,
+    Playlists,
+    Library,
+    History,
+    Dupes,
+    Tags,
+    Rename,
+    Info,
     Help,
 }
 
 struct App {
     playlist: Arc<Mutex<Playlist>>,
     player: Arc<AudioPlayer>,
+    library: Arc<RwLock<Library>>,
+    events: EventBus,
+    requests: Receiver<Request>,
+    remote: Remote,
+    browser: LibraryBrowser,
+    // Loaded from disk whenever the history view opens.
+    history: History,
+    history_report: Vec<String>,
+    history_state: ListState,
+    // Named playlists with their track counts, re-read when the manager
+    // opens and after each change.
+    playlists: Vec<(String, usize)>,
+    playlists_state: ListState,
+    // Duplicate groups, the copies marked to drop and the selected copy
+    // (counting copies only, not group headers).
+    dupes: Vec<dupes::Group>,
+    dupes_dropped: HashSet<PathBuf>,
+    dupes_state: ListState,
+    // The search running in the background, if any.
+    dupes_job: Option<Receiver<Vec<dupes::Group>>>,
+    // The tag editor's files and pending edits, and the selected field.
+    tag_editor: Option<TagEditor>,
+    tags_state: ListState,
+    // Fingerprint lookups for the editor's files, while they run, and
+    // where the known fingerprints are.
+    identify_job: Option<Receiver<Result<Suggestions>>>,
+    fingerprint_db: Option<PathBuf>,
+    // The track the info panel shows, and its tags when it isn't in the
+    // library, read once rather than on every frame.
+    info_path: Option<PathBuf>,
+    info_track: Option<library::TrackInfo>,
+    // Tracks being analyzed, one result at a time, how far along that is,
+    // and whether ReplayGain tags get written as results come in.
+    analysis_job: Option<Receiver<(PathBuf, Result<Analysis>)>>,
+    analysis_progress: (usize, usize),
+    analysis_failed: Vec<String>,
+    analysis_replaygain: bool,
+    // The renames waiting to be confirmed, and the line scrolled to.
+    rename_plan: Option<RenamePlan>,
+    rename_state: ListState,
+    // Start of the range being marked in the playlist view.
+    mark: Option<usize>,
+    clipboard: Vec<PathBuf>,
+    search: Search,
+    command: CommandLine,
+    keymap: Keymap,
+    theme: Theme,
+    status: Option<String>,
+    should_quit: bool,
     mode: AppMode,
     list_state: ListState,
+    playlist_offset: usize,
     last_tick: Instant,
+    // What was last announced on the event bus, to detect changes.
+    last_track: Option<(u64, PathBuf)>,
+    last_state: PlaybackState,
+    last_volume: f32,
+    // Set when the current track ran out, so its end is announced as finished.
+    track_finished: bool,
+    // Also write ratings into POPM/FMPS tags.
+    rating_tags: bool,
+    // Album headers in the playlist view.
+    group_albums: bool,
+    // The startup scan, until every file has been read.
+    scanner: Option<Scanner>,
+    // A `--smart` query, run once the scan is done.
+    pending_query: Option<Query>,
+    // Start playing as soon as the scan turns up something, at `resume`
+    // when that's set (and found).
+    autoplay: bool,
+    resume: Option<ResumeState>,
+    // Paths the scan skipped, printed once the terminal is restored.
+    unreadable: Vec<(PathBuf, String)>,
+    // Writes listening history; told to finish up on the way out.
+    recorder: Option<history::Recorder>,
 }
 
 impl App {
-    fn new(playlist: Playlist, player: AudioPlayer) -> Self {
+    fn new(playlist: Playlist, library: Library, player: AudioPlayer, keymap: Keymap, theme: Theme) -> Self {
         let mut list_state = ListState::default();
         list_state.select(Some(0));
+        let library = Arc::new(RwLock::new(library));
+        let playlist = Arc::new(Mutex::new(playlist));
+        let player = Arc::new(player);
+        let events = EventBus::default();
+        let (requests_tx, requests) = mpsc::channel();
+        let remote = Remote::new(
+            playlist.clone(),
+            player.clone(),
+            library.clone(),
+            events.clone(),
+            requests_tx,
+        );
+        let last_volume = player.get_volume();
         
         Self {
-            playlist: Arc::new(Mutex::new(playlist)),
-            player: Arc::new(player),
+            playlist,
+            player,
+            library,
+            events,
+            requests,
+            remote,
+            browser: LibraryBrowser::new(),
+            history: History::default(),
+            history_report: Vec::new(),
+            history_state: ListState::default(),
+            playlists: Vec::new(),
+            playlists_state: ListState::default(),
+            dupes: Vec::new(),
+            dupes_dropped: HashSet::new(),
+            dupes_state: ListState::default(),
+            dupes_job: None,
+            tag_editor: None,
+            tags_state: ListState::default(),
+            identify_job: None,
+            fingerprint_db: None,
+            info_path: None,
+            info_track: None,
+            analysis_job: None,
+            analysis_progress: (0, 0),
+            analysis_failed: Vec::new(),
+            analysis_replaygain: false,
+            rename_plan: None,
+            rename_state: ListState::default(),
+            mark: None,
+            clipboard: Vec::new(),
+            search: Search::default(),
+            command: CommandLine::default(),
+            keymap,
+            theme,
+            status: None,
+            should_quit: false,
             mode: AppMode::Player,
             list_state,
+            playlist_offset: 0,
             last_tick: Instant::now(),
+            last_track: None,
+            last_state: PlaybackState::Stopped,
+            last_volume,
+            track_finished: false,
+            rating_tags: false,
+            group_albums: false,
+            scanner: None,
+            pending_query: None,
+            autoplay: false,
+            resume: None,
+            unreadable: Vec::new(),
+            recorder: None,
         }
     }
     
     fn on_tick(&mut self) {
         self.last_tick = Instant::now();
         
+        // Commands from MPRIS and other remote front-ends
+        while let Ok(request) = self.requests.try_recv() {
+            let result = self.execute(request.command).map_err(|err| err.to_string());
+            if let Some(reply) = request.reply {
+                let _ = reply.send(result);
+            }
+        }
+        
         // Auto-play next track if current finished
         if matches!(self.player.get_state(), PlaybackState::Playing) && self.player.is_finished() {
+            self.track_finished = true;
             let mut playlist = self.playlist.lock().unwrap();
             if let Some(next_song) = playlist.next() {
-                let _ = self.player.play_song(next_song);
+                let _ = self.play_song(next_song);
+                self.list_state.select(Some(playlist.current_index()));
+            }
+        }
+        
+        self.poll_scan();
+        self.poll_dupes();
+        self.poll_identify();
+        self.poll_analysis();
+        
+        // Smart playlists follow library changes
+        {
+            let mut playlist = self.playlist.lock().unwrap();
+            if playlist.refresh(&self.library.read().unwrap()) {
+                self.list_state.select(Some(playlist.current_index()));
+                self.events.emit(PlayerEvent::PlaylistChanged);
+            }
+            // And so does a search, over whichever list it's in.
+            self.search.refresh(playlist.songs(), &self.library.read().unwrap(), playlist.revision());
+        }
+        
+        self.publish_events();
+        if let Err(err) = self.library.write().unwrap().save_if_due() {
+            self.status = Some(format!("Error: {:#}", err));
+        }
+    }
+    
+    // Feeds the startup scan into the playlist and library as it goes.
+    fn poll_scan(&mut self) {
+        let scanner = match &mut self.scanner {
+            Some(scanner) => scanner,
+            None => return,
+        };
+        let batch = scanner.poll();
+        let done = scanner.is_done();
+        
+        if !batch.tracks.is_empty() {
+            self.library.write().unwrap().add_tracks(batch.tracks);
+        }
+        if self.playlist.lock().unwrap().extend_scan(batch.songs) {
+            self.events.emit(PlayerEvent::PlaylistChanged);
+        }
+        if done {
+            self.finish_scan();
+        }
+        if self.autoplay {
+            self.start_playing(done);
+        }
+    }
+    
+    fn finish_scan(&mut self) {
+        let scanner = match self.scanner.take() {
+            Some(scanner) => scanner,
+            None => return,
+        };
+        if let Some(summary) = scanner.summary() {
+            self.status = Some(summary);
+        }
+        self.unreadable = scanner.unreadable;
+        
+        if let Some(query) = self.pending_query.take() {
+            let library = self.library.read().unwrap();
+            let mut playlist = self.playlist.lock().unwrap();
+            match Playlist::from_query(query, &library, playlist.scan_options().clone()) {
+                Ok(smart) => {
+                    *playlist = smart;
+                    self.list_state.select(Some(playlist.current_index()));
+                    self.events.emit(PlayerEvent::PlaylistChanged);
+                }
+                Err(err) => self.status = Some(format!("Error: {:#}", err)),
+            }
+        }
+    }
+    
+    // Plays the first track, or the one we left off at once it turns up,
+    // unless something else got played meanwhile.
+    fn start_playing(&mut self, done: bool) {
+        if self.player.current_path().is_some() {
+            self.autoplay = false;
+            return;
+        }
+        if self.pending_query.is_some() {
+            return;
+        }
+        let mut playlist = self.playlist.lock().unwrap();
+        let resumed = self.resume.as_ref().and_then(|state| {
+            Some((playlist.songs().iter().position(|s| *s == state.track)?, state.position))
+        });
+        let (index, position) = match resumed {
+            Some(resumed) => resumed,
+            // It may yet be found.
+            None if self.resume.is_some() && !done => return,
+            None => (0, Duration::ZERO),
+        };
+        let song = match playlist.play_index(index) {
+            Some(song) => song.clone(),
+            None => return,
+        };
+        self.autoplay = false;
+        self.list_state.select(Some(index));
+        if self.play_song(&song).is_ok() && !position.is_zero() {
+            let _ = self.player.seek(position);
+        }
+    }
+    
+    // Compares the player with what we last announced and emits the
+    // differences, so changes from keys, commands and remotes all show up.
+    fn publish_events(&mut self) {
+        let state = self.player.get_state();
+        let track = self.player.current_path().map(|path| (self.player.generation(), path));
+        
+        let finished = std::mem::take(&mut self.track_finished);
+        if track != self.last_track {
+            // A stopped track was already announced as ended.
+            if let Some((_, path)) = self.last_track.take() {
+                if self.last_state != PlaybackState::Stopped {
+                    self.library.write().unwrap().record_end(&path, finished);
+                    self.events.emit(PlayerEvent::TrackEnded { path, finished });
+                }
+            }
+            if let Some((_, path)) = &track {
+                self.events.emit(PlayerEvent::TrackStarted { path: path.clone() });
+            }
+            self.last_track = track;
+        } else if state != self.last_state {
+            if let (PlaybackState::Stopped, Some((_, path))) = (state, &self.last_track) {
+                self.events.emit(PlayerEvent::TrackEnded { path: path.clone(), finished });
+            }
+            self.events.emit(match state {
+                PlaybackState::Playing => PlayerEvent::Resumed,
+                PlaybackState::Paused => PlayerEvent::Paused,
+                PlaybackState::Stopped => PlayerEvent::Stopped,
+            });
+        }
+        self.last_state = state;
+        
+        let volume = self.player.get_volume();
+        if (volume - self.last_volume).abs() > f32::EPSILON {
+            self.events.emit(PlayerEvent::VolumeChanged(volume));
+            self.last_volume = volume;
+        }
+    }
+    
+    // Starts a track, announcing failures (unreadable or undecodable files)
+    // on the event bus as well as returning them.
+    fn play_song(&self, path: &Path) -> Result<()> {
+        let result = self.player.play_song(path);
+        if let Err(err) = &result {
+            self.events.emit(PlayerEvent::Error { path: Some(path.to_path_buf()), message: err.to_string() });
+        }
+        result
+    }
+    
+    fn next_track(&mut self) {
+        let mut playlist = self.playlist.lock().unwrap();
+        if let Some(next_song) = playlist.next() {
+            let _ = self.play_song(next_song);
+            self.list_state.select(Some(playlist.current_index()));
+        }
+    }
+    
+    fn prev_track(&mut self) {
+        let mut playlist = self.playlist.lock().unwrap();
+        if let Some(prev_song) = playlist.prev() {
+            let _ = self.play_song(prev_song);
+            self.list_state.select(Some(playlist.current_index()));
+        }
+    }
+    
+    fn play_selected(&mut self) {
+        if let Some(selected) = self.list_state.selected() {
+            let mut playlist = self.playlist.lock().unwrap();
+            if let Some(song) = playlist.play_index(selected) {
+                let _ = self.play_song(song);
+            }
+        }
+    }
+    
+    fn play_library_selection(&mut self) {
+        let paths = self.browser.selected_paths(&self.library.read().unwrap());
+        if paths.is_empty() {
+            return;
+        }
+        let mut playlist = self.playlist.lock().unwrap();
+        playlist.replace(paths);
+        if let Some(song) = playlist.current() {
+            let _ = self.play_song(song);
+        }
+        self.list_state.select(Some(0));
+        self.events.emit(PlayerEvent::PlaylistChanged);
+    }
+    
+    fn enqueue_library_selection(&mut self) {
+        let paths = self.browser.selected_paths(&self.library.read().unwrap());
+        self.playlist.lock().unwrap().enqueue(paths);
+        self.events.emit(PlayerEvent::PlaylistChanged);
+    }
+    
+    // Plays any track, queueing it at the end of the playlist first when it
+    // isn't already on it.
+    fn play_path(&mut self, path: PathBuf) -> Result<()> {
+        if !path.is_file() {
+            anyhow::bail!("{} no longer exists", path.display());
+        }
+        let mut playlist = self.playlist.lock().unwrap();
+        let index = match playlist.songs().iter().position(|song| *song == path) {
+            Some(index) => index,
+            None => {
+                self.library.write().unwrap().add_paths(std::slice::from_ref(&path));
+                playlist.enqueue(vec![path]);
+                self.events.emit(PlayerEvent::PlaylistChanged);
+                playlist.len() - 1
+            }
+        };
+        if let Some(song) = playlist.play_index(index) {
+            self.play_song(song)?;
+        }
+        self.list_state.select(Some(index));
+        Ok(())
+    }
+    
+    fn open_history(&mut self) {
+        let loaded = history::default_path()
+            .map(|path| History::load(&path))
+            .unwrap_or_else(|| Ok(History::default()));
+        match loaded {
+            Ok(history) => self.history = history,
+            Err(err) => self.status = Some(format!("Error: {:#}", err)),
+        }
+        self.history_report = self.history.stats(Period::Day).report(5, 7);
+        self.history_state.select(Some(0));
+        self.mode = AppMode::History;
+    }
+    
+    // The history view lists plays newest first.
+    fn selected_play(&self) -> Option<&history::Play> {
+        let selected = self.history_state.selected()?;
+        self.history.plays().iter().rev().nth(selected)
+    }
+    
+    fn replay_selected(&mut self) {
+        if let Some(play) = self.selected_play() {
+            let path = play.path.clone();
+            if let Err(err) = self.play_path(path) {
+                self.status = Some(format!("Error: {}", err));
+            }
+        }
+    }
+    
+    fn enqueue_selected_play(&mut self) {
+        if let Some(play) = self.selected_play() {
+            let path = play.path.clone();
+            self.library.write().unwrap().add_paths(std::slice::from_ref(&path));
+            self.playlist.lock().unwrap().enqueue(vec![path]);
+            self.events.emit(PlayerEvent::PlaylistChanged);
+        }
+    }
+    
+    fn scroll_history(&mut self, up: bool) {
+        let len = self.history.plays().len().min(HISTORY_VIEW_LIMIT);
+        wrap_select(&mut self.history_state, len, up);
+    }
+    
+    // Looks through the library on another thread, since hashing (and
+    // fingerprinting) every file takes a while.
+    fn find_dupes(&mut self, audio: bool) {
+        let tracks = self.library.read().unwrap().all().to_vec();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(dupes::find(&tracks, audio));
+        });
+        self.dupes_job = Some(rx);
+        self.dupes.clear();
+        self.mode = AppMode::Dupes;
+    }
+    
+    fn poll_dupes(&mut self) {
+        let groups = match self.dupes_job.as_ref().map(Receiver::try_recv) {
+            Some(Ok(groups)) => groups,
+            Some(Err(mpsc::TryRecvError::Empty)) | None => return,
+            Some(Err(mpsc::TryRecvError::Disconnected)) => {
+                self.dupes_job = None;
+                return;
+            }
+        };
+        self.dupes_job = None;
+        // The best copy of each is kept unless told otherwise.
+        self.dupes_dropped = groups.iter()
+            .flat_map(|group| group.files.iter().skip(1).map(|file| file.path.clone()))
+            .collect();
+        self.status = Some(format!("Found {} tracks with duplicates", groups.len()));
+        self.dupes = groups;
+        self.dupes_state.select(Some(0));
+    }
+    
+    fn selected_duplicate(&self) -> Option<&dupes::Duplicate> {
+        let selected = self.dupes_state.selected()?;
+        self.dupes.iter().flat_map(|group| &group.files).nth(selected)
+    }
+    
+    fn toggle_keep(&mut self) {
+        if let Some(path) = self.selected_duplicate().map(|file| file.path.clone()) {
+            if !self.dupes_dropped.remove(&path) {
+                self.dupes_dropped.insert(path);
+            }
+        }
+    }
+    
+    fn drop_duplicates(&mut self) -> Result<String> {
+        let dropped = self.dupes_dropped.clone();
+        let removed = self.edit_playlist(|playlist| playlist.remove_paths(&dropped))?;
+        Ok(format!("Removed {} tracks from the playlist", removed))
+    }
+    
+    fn open_tag_editor(&mut self) -> Result<usize> {
+        let editor = TagEditor::open(&self.selected_tracks())?;
+        let count = editor.len();
+        self.tag_editor = Some(editor);
+        self.tags_state.select(Some(0));
+        self.mode = AppMode::Tags;
+        Ok(count)
+    }
+    
+    fn selected_field(&self) -> Field {
+        Field::ALL[self.tags_state.selected().unwrap_or(0).min(Field::ALL.len() - 1)]
+    }
+    
+    fn close_tag_editor(&mut self) {
+        if let Some(editor) = self.tag_editor.take() {
+            if editor.has_changes() {
+                self.status = Some("Tag changes discarded".to_string());
+            }
+        }
+        self.mode = AppMode::Player;
+    }
+    
+    // Fingerprints the editor's files on another thread; the matches come
+    // back as one pending edit.
+    fn identify(&mut self) -> Result<String> {
+        if self.tag_editor.is_none() {
+            self.open_tag_editor()?;
+        }
+        let paths = self.tag_editor.as_ref().map(TagEditor::paths).unwrap_or_default();
+        let path = self.fingerprint_db.clone().or_else(identify::default_path).context("No data directory")?;
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(Database::load(&path).map(|database| identify::suggest(&database, &paths)));
+        });
+        self.identify_job = Some(rx);
+        self.mode = AppMode::Tags;
+        Ok("Fingerprinting…".to_string())
+    }
+    
+    fn poll_identify(&mut self) {
+        let result = match self.identify_job.as_ref().map(Receiver::try_recv) {
+            Some(Ok(result)) => result,
+            Some(Err(mpsc::TryRecvError::Empty)) | None => return,
+            Some(Err(mpsc::TryRecvError::Disconnected)) => {
+                self.identify_job = None;
+                return;
+            }
+        };
+        self.identify_job = None;
+        let result = result.and_then(|suggestions| {
+            // The editor may have been closed or reopened on other files
+            // in the meantime; matches for files it doesn't have are ignored.
+            let editor = self.tag_editor.as_mut().context("The tag editor was closed")?;
+            let total = editor.len();
+            let found = suggestions.len();
+            let worst = suggestions.iter().map(|(_, s)| s.score).min_by(f64::total_cmp);
+            if found > 0 {
+                let values = suggestions.into_iter().map(|(path, s)| (path, s.values)).collect();
+                editor.apply(Edit::Suggested(values))?;
+            }
+            Ok(match worst {
+                Some(worst) => format!("Matched {} of {} files (lowest match {:.0}%)", found, total, worst * 100.0),
+                None => format!("No matches for {} files", total),
+            })
+        });
+        self.show_result(result);
+    }
+    
+    fn open_info(&mut self) -> Result<()> {
+        let path = self.selected_tracks().into_iter().next().context("Nothing selected")?;
+        let known = self.library.read().unwrap().get(&path).is_some();
+        self.info_track = (!known).then(|| library::TrackInfo::read(&path));
+        self.info_path = Some(path);
+        self.mode = AppMode::Info;
+        Ok(())
+    }
+    
+    // Decoding every file takes a while, so it runs on another thread and
+    // results are stored as they come in.
+    fn analyze(&mut self, replaygain: bool) -> Result<String> {
+        if self.analysis_job.is_some() {
+            anyhow::bail!("Already analyzing");
+        }
+        let paths = self.selected_tracks();
+        if paths.is_empty() {
+            anyhow::bail!("Nothing selected to analyze");
+        }
+        let (tx, rx) = mpsc::channel();
+        let total = paths.len();
+        std::thread::spawn(move || {
+            for path in paths {
+                let analysis = Analysis::compute(&path);
+                if tx.send((path, analysis)).is_err() {
+                    return;
+                }
+            }
+        });
+        self.analysis_job = Some(rx);
+        self.analysis_progress = (0, total);
+        self.analysis_failed.clear();
+        self.analysis_replaygain = replaygain;
+        Ok(format!("Analyzing {} tracks…", total))
+    }
+    
+    fn poll_analysis(&mut self) {
+        let job = match &self.analysis_job {
+            Some(job) => job,
+            None => return,
+        };
+        let mut results = Vec::new();
+        let finished = loop {
+            match job.try_recv() {
+                Ok(result) => results.push(result),
+                Err(mpsc::TryRecvError::Empty) => break false,
+                Err(mpsc::TryRecvError::Disconnected) => break true,
+            }
+        };
+        for (path, analysis) in results {
+            self.analysis_progress.0 += 1;
+            let stored = analysis.and_then(|mut analysis| {
+                if self.analysis_replaygain {
+                    write_replaygain(&path, &mut analysis)?;
+                }
+                self.library.write().unwrap().set_analysis(&path, analysis);
+                Ok(())
+            });
+            if let Err(err) = stored {
+                self.analysis_failed.push(format!("{:#}", err));
+            }
+        }
+        let (done, total) = self.analysis_progress;
+        self.status = Some(if !finished {
+            format!("Analyzing {}/{}…", done, total)
+        } else if let Some(err) = self.analysis_failed.last() {
+            format!("Analyzed {} tracks, {} failed: {}", done - self.analysis_failed.len(), self.analysis_failed.len(), err)
+        } else {
+            format!("Analyzed {} tracks", done)
+        });
+        if finished {
+            self.analysis_job = None;
+        }
+    }
+    
+    fn plan_rename(&mut self, pattern: rename::Pattern) -> Result<String> {
+        let paths = self.selected_tracks();
+        if paths.is_empty() {
+            anyhow::bail!("Nothing selected to rename");
+        }
+        let plan = RenamePlan::new(&paths, pattern);
+        let message = match plan.problems.len() {
+            0 => format!("{} files to rename; enter to go ahead", plan.moves.len()),
+            problems => format!("{} files to rename, {} problems to sort out first", plan.moves.len(), problems),
+        };
+        self.rename_plan = Some(plan);
+        self.rename_state.select(Some(0));
+        self.mode = AppMode::Rename;
+        Ok(message)
+    }
+    
+    // Moves the files, then points everything that knows them by path at
+    // the new places: the playlist (which keeps its order and position),
+    // saved playlists, the library, the player, the listening history and
+    // the resume state. Queued scrobbles only carry tags, so they need
+    // nothing.
+    fn apply_rename(&mut self) -> Result<String> {
+        let plan = self.rename_plan.as_ref().context("Nothing to rename")?;
+        let (done, result) = plan.apply();
+        if !done.is_empty() {
+            let moves: HashMap<PathBuf, PathBuf> = done.iter().cloned().collect();
+            self.edit_playlist(|playlist| {
+                playlist.rename_paths(&moves);
+                Ok(())
+            })?;
+            self.library.write().unwrap().rename(&done);
+            playlist::rename_in_named(&moves)?;
+            for (from, to) in &done {
+                self.player.moved(from, to);
+                if let Some((_, path)) = &mut self.last_track {
+                    if path == from {
+                        *path = to.clone();
+                    }
+                }
+            }
+            match &self.recorder {
+                Some(recorder) => recorder.moved(&moves),
+                None => {
+                    if let Some(path) = history::default_path() {
+                        history::rename_in_file(&path, &moves)?;
+                    }
+                }
+            }
+            self.history.rename(&moves);
+            if let Some(state) = &mut self.resume {
+                if let Some(to) = moves.get(&state.track) {
+                    state.track = to.clone();
+                }
+            }
+            // Quitting saves it again, but a crash shouldn't lose the track.
+            if let Some(state) = ResumeState::load() {
+                if let Some(to) = moves.get(&state.track) {
+                    ResumeState { track: to.clone(), ..state }.save()?;
+                }
+            }
+            // The editor's files are gone from where it read them.
+            self.tag_editor = None;
+        }
+        result?;
+        self.rename_plan = None;
+        self.mode = AppMode::Playlist;
+        Ok(format!("Renamed {} files", done.len()))
+    }
+    
+    fn open_playlists(&mut self) {
+        self.refresh_playlists();
+        let active = self.playlist.lock().unwrap().name().map(str::to_string);
+        let index = self.playlists.iter().position(|(name, _)| Some(name) == active.as_ref());
+        self.playlists_state.select(Some(index.unwrap_or(0)));
+        self.mode = AppMode::Playlists;
+    }
+    
+    fn refresh_playlists(&mut self) {
+        self.playlists = playlist::named_playlists()
+            .into_iter()
+            .map(|name| {
+                let count = playlist::read_named(&name).map(|songs| songs.len()).unwrap_or(0);
+                (name, count)
+            })
+            .collect();
+        let len = self.playlists.len();
+        if self.playlists_state.selected().is_some_and(|selected| selected >= len) {
+            self.playlists_state.select(Some(len.saturating_sub(1)));
+        }
+    }
+    
+    fn selected_playlist(&self) -> Option<String> {
+        let selected = self.playlists_state.selected()?;
+        self.playlists.get(selected).map(|(name, _)| name.clone())
+    }
+    
+    // Keys in the manager prompt on the command line, with the selected
+    // playlist filled in where the command needs it.
+    fn prompt_playlist(&mut self, action: &str, with_selected: bool) {
+        let name = if with_selected { self.selected_playlist() } else { None };
+        match name {
+            Some(name) => self.command.open_with(&format!("playlist {} {} ", action, name)),
+            None if with_selected => {}
+            None => self.command.open_with(&format!("playlist {} ", action)),
+        }
+    }
+    
+    // The marked range in the playlist view, or just the selected track.
+    fn selected_range(&self, len: usize) -> Option<RangeInclusive<usize>> {
+        let selected = self.list_state.selected().filter(|&selected| selected < len)?;
+        let mark = self.mark.filter(|&mark| mark < len).unwrap_or(selected);
+        Some(mark.min(selected)..=mark.max(selected))
+    }
+    
+    // What "add to playlist" adds: the selection in the current view, or
+    // the track playing.
+    fn selected_tracks(&self) -> Vec<PathBuf> {
+        match self.mode {
+            AppMode::Library => self.browser.selected_paths(&self.library.read().unwrap()),
+            AppMode::History => self.selected_play().map(|play| vec![play.path.clone()]).unwrap_or_default(),
+            AppMode::Tags => self.tag_editor.as_ref().map(TagEditor::paths).unwrap_or_default(),
+            AppMode::Info => self.info_path.clone().into_iter().collect(),
+            AppMode::Playlist => {
+                let playlist = self.playlist.lock().unwrap();
+                self.selected_range(playlist.len())
+                    .map(|range| playlist.songs()[range].to_vec())
+                    .unwrap_or_default()
+            }
+            _ => self.playlist.lock().unwrap().current().cloned().into_iter().collect(),
+        }
+    }
+    
+    // Runs a hand edit on the playlist and saves it back when it's a named
+    // one.
+    fn edit_playlist<T>(&mut self, edit: impl FnOnce(&mut Playlist) -> Result<T>) -> Result<T> {
+        let mut playlist = self.playlist.lock().unwrap();
+        let result = edit(&mut playlist)?;
+        playlist.save_named()?;
+        let len = playlist.len();
+        if self.list_state.selected().is_some_and(|selected| selected >= len) {
+            self.list_state.select(len.checked_sub(1));
+        }
+        self.events.emit(PlayerEvent::PlaylistChanged);
+        Ok(result)
+    }
+    
+    fn remove_tracks(&mut self, cut: bool) -> Result<String> {
+        let len = self.playlist.lock().unwrap().len();
+        let range = self.selected_range(len).context("Nothing selected")?;
+        let start = *range.start();
+        let removed = self.edit_playlist(|playlist| playlist.remove(range))?;
+        self.mark = None;
+        self.list_state.select(Some(start.min(len.saturating_sub(removed.len() + 1))));
+        let count = removed.len();
+        if cut {
+            self.clipboard = removed;
+            Ok(format!("Cut {} tracks", count))
+        } else {
+            Ok(format!("Removed {} tracks", count))
+        }
+    }
+    
+    fn paste_tracks(&mut self) -> Result<String> {
+        if self.clipboard.is_empty() {
+            anyhow::bail!("Nothing to paste");
+        }
+        let songs = self.clipboard.clone();
+        let count = songs.len();
+        let at = self.list_state.selected().map(|selected| selected + 1).unwrap_or(0);
+        self.edit_playlist(|playlist| playlist.insert(at, songs))?;
+        self.list_state.select(Some(at));
+        Ok(format!("Pasted {} tracks", count))
+    }
+    
+    fn move_tracks(&mut self, up: bool) -> Result<()> {
+        let len = self.playlist.lock().unwrap().len();
+        let range = match self.selected_range(len) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        if self.edit_playlist(|playlist| playlist.move_range(range, up))? {
+            let shift = |index: usize| if up { index - 1 } else { index + 1 };
+            self.mark = self.mark.map(shift);
+            self.list_state.select(self.list_state.selected().map(shift));
+        }
+        Ok(())
+    }
+    
+    fn undo(&mut self, back: bool) -> Result<Option<String>> {
+        let changed = self.edit_playlist(|playlist| if back { playlist.undo() } else { playlist.redo() })?;
+        self.mark = None;
+        Ok(match (changed, back) {
+            (true, _) => None,
+            (false, true) => Some("Nothing to undo".to_string()),
+            (false, false) => Some("Nothing to redo".to_string()),
+        })
+    }
+    
+    // The track rating and favorite keys apply to: the selected one in the
+    // playlist view, otherwise the one playing.
+    fn target_track(&self) -> Option<PathBuf> {
+        let playlist = self.playlist.lock().unwrap();
+        match self.mode {
+            AppMode::Playlist => self.list_state.selected().and_then(|i| playlist.songs().get(i)).cloned(),
+            _ => playlist.current().cloned(),
+        }
+    }
+    
+    fn rate(&mut self, stars: u8) -> Result<String> {
+        let path = self.target_track().context("No track to rate")?;
+        let rating = Some(stars).filter(|&stars| stars > 0);
+        self.library.write().unwrap().set_rating(&path, rating);
+        if self.rating_tags {
+            library::write_rating_tag(&path, rating)?;
+        }
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        Ok(match rating {
+            Some(stars) => format!("Rated {} {}", name, "★".repeat(stars as usize)),
+            None => format!("Cleared the rating of {}", name),
+        })
+    }
+    
+    fn toggle_favorite(&mut self) -> Result<String> {
+        let path = self.target_track().context("No track to mark")?;
+        let favorite = self.library.write().unwrap().toggle_favorite(&path);
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        Ok(if favorite {
+            format!("♥ {}", name)
+        } else {
+            format!("Removed {} from favorites", name)
+        })
+    }
+    
+    // Searches the library from the library view, and the playlist from
+    // anywhere else.
+    fn start_search(&mut self) {
+        let scope = if self.mode == AppMode::Library {
+            SearchScope::Library
+        } else {
+            self.mode = AppMode::Playlist;
+            SearchScope::Playlist
+        };
+        let playlist = self.playlist.lock().unwrap();
+        self.search.start(scope, playlist.songs(), &self.library.read().unwrap(), playlist.revision());
+    }
+    
+    fn update_search(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Char(c) => self.search.push(c),
+            KeyCode::Backspace => self.search.pop(),
+            _ => return,
+        }
+        if let Some(best) = self.search.best() {
+            self.select_match(best);
+        }
+    }
+    
+    // Where n and N count from: the selected playlist entry, or the index
+    // in the library of the track selected in the browser.
+    fn search_position(&self) -> usize {
+        match self.search.scope {
+            SearchScope::Playlist => self.list_state.selected().unwrap_or(0),
+            SearchScope::Library => {
+                let library = self.library.read().unwrap();
+                self.browser.selected_track(&library)
+                    .and_then(|track| library.index_of(&track.path))
+                    .unwrap_or(0)
+            }
+        }
+    }
+    
+    fn select_match(&mut self, idx: usize) {
+        match self.search.scope {
+            SearchScope::Playlist => self.list_state.select(Some(idx)),
+            SearchScope::Library => {
+                let library = self.library.read().unwrap();
+                if let Some(track) = library.all().get(idx) {
+                    self.browser.reveal(&library, &track.path);
+                }
+            }
+        }
+    }
+    
+    fn search_next(&mut self) {
+        if let Some(idx) = self.search.next_after(self.search_position()) {
+            self.select_match(idx);
+        }
+    }
+    
+    fn search_prev(&mut self) {
+        if let Some(idx) = self.search.prev_before(self.search_position()) {
+            self.select_match(idx);
+        }
+    }
+    
+    // Everything that has to reach disk before the process exits, however
+    // it was asked to: quit, a closed terminal or a signal to the daemon.
+    fn shutdown(&mut self) {
+        // Saved whether or not `resume` is on now, so turning it on later
+        // picks up from the last session.
+        if let Err(err) = self.resume_state().save() {
+            eprintln!("Failed to save resume state: {}", err);
+        }
+        // Announce anything the last tick missed before history writes
+        // out the track that's still on.
+        self.publish_events();
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish();
+        }
+        if let Err(err) = self.library.write().unwrap().save_user_data() {
+            eprintln!("{:#}", err);
+        }
+    }
+    
+    fn resume_state(&self) -> ResumeState {
+        let playlist = self.playlist.lock().unwrap();
+        ResumeState {
+            track: playlist.current().cloned().unwrap_or_default(),
+            position: self.player.position(),
+        }
+    }
+    
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::Quit => self.should_quit = true,
+            Action::Back => {
+                if self.mode == AppMode::Tags {
+                    self.close_tag_editor();
+                } else if self.mode == AppMode::Rename {
+                    // Back to whichever view the files were picked in.
+                    self.rename_plan = None;
+                    self.mode = if self.tag_editor.is_some() { AppMode::Tags } else { AppMode::Playlist };
+                } else if matches!(self.mode, AppMode::Playlist | AppMode::Library) && self.search.is_active() {
+                    self.search.clear();
+                } else if self.mode == AppMode::Playlist && self.mark.is_some() {
+                    self.mark = None;
+                } else {
+                    self.mode = AppMode::Player;
+                }
+            }
+            Action::TogglePause => self.player.toggle_pause(),
+            Action::Next => self.next_track(),
+            Action::Prev => self.prev_track(),
+            Action::VolumeUp => self.player.volume_up(),
+            Action::VolumeDown => self.player.volume_down(),
+            Action::SeekForward => {
+                let _ = self.execute(Command::Seek(Seek::Forward(Duration::from_secs(10))));
+            }
+            Action::SeekBack => {
+                let _ = self.execute(Command::Seek(Seek::Back(Duration::from_secs(10))));
+            }
+            Action::Playlist => {
+                self.mode = if self.mode == AppMode::Playlist {
+                    AppMode::Player
+                } else {
+                    AppMode::Playlist
+                };
+            }
+            Action::Library => self.mode = AppMode::Library,
+            Action::History => self.open_history(),
+            Action::Help => self.mode = AppMode::Help,
+            Action::Search => self.start_search(),
+            Action::SearchNext => self.search_next(),
+            Action::SearchPrev => self.search_prev(),
+            Action::CommandLine => self.command.open(),
+            Action::Up => match self.mode {
+                AppMode::Library => self.browser.move_up(&self.library.read().unwrap()),
+                AppMode::History => self.scroll_history(true),
+                AppMode::Playlists => wrap_select(&mut self.playlists_state, self.playlists.len(), true),
+                AppMode::Dupes => wrap_select(&mut self.dupes_state, dupe_count(&self.dupes), true),
+                AppMode::Tags => wrap_select(&mut self.tags_state, Field::ALL.len(), true),
+                AppMode::Rename => wrap_select(&mut self.rename_state, rename_lines(&self.rename_plan), true),
+                _ => self.scroll_up(),
+            },
+            Action::Down => match self.mode {
+                AppMode::Library => self.browser.move_down(&self.library.read().unwrap()),
+                AppMode::History => self.scroll_history(false),
+                AppMode::Playlists => wrap_select(&mut self.playlists_state, self.playlists.len(), false),
+                AppMode::Dupes => wrap_select(&mut self.dupes_state, dupe_count(&self.dupes), false),
+                AppMode::Tags => wrap_select(&mut self.tags_state, Field::ALL.len(), false),
+                AppMode::Rename => wrap_select(&mut self.rename_state, rename_lines(&self.rename_plan), false),
+                _ => self.scroll_down(),
+            },
+            // Only the library has columns to move between.
+            Action::Left if self.mode == AppMode::Library => self.browser.move_left(),
+            Action::Right if self.mode == AppMode::Library => self.browser.move_right(&self.library.read().unwrap()),
+            Action::Left | Action::Right => {}
+            Action::PlaySelected if self.mode == AppMode::Playlists => {
+                // Stays in the manager when the playlist can't be loaded.
+                if let Some(name) = self.selected_playlist() {
+                    match self.execute(Command::Playlist(Some(name))) {
+                        Ok(message) => {
+                            self.status = message;
+                            self.mode = AppMode::Player;
+                        }
+                        Err(err) => self.status = Some(format!("Error: {:#}", err)),
+                    }
+                }
+            }
+            Action::PlaySelected if self.mode == AppMode::Dupes => {
+                // Stays in the view so copies can be compared.
+                if let Some(path) = self.selected_duplicate().map(|file| file.path.clone()) {
+                    if let Err(err) = self.play_path(path) {
+                        self.status = Some(format!("Error: {:#}", err));
+                    }
+                }
+            }
+            Action::PlaySelected => {
+                match self.mode {
+                    AppMode::Library => self.play_library_selection(),
+                    AppMode::History => self.replay_selected(),
+                    _ => self.play_selected(),
+                }
+                self.mode = AppMode::Player;
+            }
+            Action::Enqueue => match self.mode {
+                AppMode::History => self.enqueue_selected_play(),
+                _ => self.enqueue_library_selection(),
+            },
+            Action::PlayTrack(index) => {
+                let mut playlist = self.playlist.lock().unwrap();
+                if let Some(song) = playlist.play_index(index) {
+                    let _ = self.play_song(song);
+                    self.list_state.select(Some(index));
+                }
+            }
+            Action::Rate(stars) => {
+                let result = self.rate(stars);
+                self.show_result(result);
+            }
+            Action::Favorite => {
+                let result = self.toggle_favorite();
+                self.show_result(result);
+            }
+            Action::Playlists => self.open_playlists(),
+            Action::NewPlaylist => self.prompt_playlist("new", false),
+            Action::RenamePlaylist => self.prompt_playlist("rename", true),
+            Action::CopyPlaylist => self.prompt_playlist("copy", true),
+            Action::DeletePlaylist => self.prompt_playlist("delete", true),
+            Action::AddToPlaylist => self.command.open_with("playlist add "),
+            Action::Mark => {
+                self.mark = match self.mark {
+                    Some(_) => None,
+                    None => self.list_state.selected(),
+                };
+            }
+            Action::Remove => {
+                let result = self.remove_tracks(false);
+                self.show_result(result);
+            }
+            Action::Cut => {
+                let result = self.remove_tracks(true);
+                self.show_result(result);
+            }
+            Action::Paste => {
+                let result = self.paste_tracks();
+                self.show_result(result);
+            }
+            Action::MoveUp | Action::MoveDown => {
+                if let Err(err) = self.move_tracks(action == Action::MoveUp) {
+                    self.status = Some(format!("Error: {:#}", err));
+                }
+            }
+            Action::CycleSort => {
+                let key = self.playlist.lock().unwrap().sorted_by().map_or(SortKey::Path, |key| key.next());
+                let result = self.execute(Command::Sort(key)).map(|_| format!("Sorted by {}", key.name()));
+                self.show_result(result);
+            }
+            Action::GroupAlbums => self.group_albums = !self.group_albums,
+            Action::Dupes => {
+                if self.dupes.is_empty() && self.dupes_job.is_none() {
+                    self.find_dupes(false);
+                } else {
+                    self.mode = AppMode::Dupes;
+                }
+            }
+            Action::ToggleKeep => self.toggle_keep(),
+            Action::DropDuplicates => {
+                let result = self.drop_duplicates();
+                self.show_result(result);
+            }
+            Action::EditTags => {
+                let result = self.open_tag_editor().map(|count| format!("Editing tags of {} tracks", count));
+                self.show_result(result);
+            }
+            Action::EditField => {
+                let field = self.selected_field();
+                let value = self.tag_editor.as_ref()
+                    .and_then(|editor| editor.value(field).0.flatten())
+                    .unwrap_or_default();
+                self.command.open_with(&format!("tag {} {}", field.name(), value));
+            }
+            Action::ClearField => {
+                let field = self.selected_field();
+                self.run_command(Command::Tag(Edit::Clear(field)));
+            }
+            Action::NumberTracks => self.run_command(Command::Tag(Edit::Number)),
+            Action::WriteTags => self.run_command(Command::TagWrite),
+            Action::TagsFromPath => self.command.open_with(&format!("tag frompath {}", DEFAULT_PATTERN)),
+            Action::Identify => self.run_command(Command::Identify),
+            Action::TrackInfo => {
+                if let Err(err) = self.open_info() {
+                    self.status = Some(format!("Error: {:#}", err));
+                }
+            }
+            Action::Analyze => self.run_command(Command::Analyze(false)),
+            Action::RenameFiles => self.command.open_with(&format!("rename {}", DEFAULT_PATTERN)),
+            Action::ApplyRename => self.run_command(Command::RenameApply),
+            Action::Undo if self.mode == AppMode::Tags => {
+                let undone = self.tag_editor.as_mut().is_some_and(TagEditor::undo);
+                self.status = Some(if undone { "Undid tag edit" } else { "Nothing to undo" }.to_string());
+            }
+            Action::Undo | Action::Redo => match self.undo(action == Action::Undo) {
+                Ok(message) => self.status = message,
+                Err(err) => self.status = Some(format!("Error: {:#}", err)),
+            },
+            Action::Run(line) => self.run_command_line(&line),
+        }
+    }
+    
+    fn show_result(&mut self, result: Result<String>) {
+        self.status = Some(match result {
+            Ok(message) => message,
+            Err(err) => format!("Error: {:#}", err),
+        });
+    }
+    
+    fn handle_command_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Esc => self.command.close(),
+            KeyCode::Enter => {
+                if let Some(line) = self.command.submit() {
+                    self.run_command_line(&line);
+                }
+            }
+            KeyCode::Tab => self.command.complete(),
+            KeyCode::Up => self.command.history_prev(),
+            KeyCode::Down => self.command.history_next(),
+            KeyCode::Backspace => self.command.backspace(),
+            KeyCode::Char(c) => self.command.push(c),
+            _ => {}
+        }
+    }
+    
+    fn run_command_line(&mut self, line: &str) {
+        let result = Command::parse(line).and_then(|command| self.execute(command));
+        self.status = match result {
+            Ok(message) => message,
+            Err(err) => Some(format!("Error: {}", err)),
+        };
+    }
+    
+    fn run_command(&mut self, command: Command) {
+        self.status = match self.execute(command) {
+            Ok(message) => message,
+            Err(err) => Some(format!("Error: {:#}", err)),
+        };
+    }
+    
+    // Runs a parsed command, returning an optional message for the status line.
+    fn execute(&mut self, command: Command) -> Result<Option<String>> {
+        match command {
+            Command::Play(None) => self.player.resume(),
+            Command::Play(Some(index)) => {
+                let mut playlist = self.playlist.lock().unwrap();
+                match playlist.play_index(index) {
+                    Some(song) => self.play_song(song)?,
+                    None => anyhow::bail!("No track {}", index + 1),
+                }
+                self.list_state.select(Some(index));
+            }
+            Command::Pause => self.player.pause(),
+            Command::Toggle => self.player.toggle_pause(),
+            Command::Stop => self.player.stop(),
+            Command::Next => self.next_track(),
+            Command::Prev => self.prev_track(),
+            Command::Seek(seek) => {
+                match seek {
+                    Seek::To(position) => self.player.seek(position)?,
+                    Seek::Forward(offset) => self.player.seek(self.player.position().saturating_add(offset))?,
+                    Seek::Back(offset) => self.player.seek(self.player.position().saturating_sub(offset))?,
+                }
+                self.events.emit(PlayerEvent::Seeked(self.player.position()));
+            }
+            Command::Volume(change) => {
+                let current = (self.player.get_volume() * 100.0).round() as i32;
+                let level = match change {
+                    command::Volume::Set(v) => v as i32,
+                    command::Volume::Up(v) => current + v as i32,
+                    command::Volume::Down(v) => current - v as i32,
+                };
+                self.player.set_volume(level as f32 / 100.0);
+            }
+            Command::Shuffle(on) => {
+                let mut playlist = self.playlist.lock().unwrap();
+                let on = on.unwrap_or(!playlist.is_shuffled());
+                playlist.set_shuffle(on);
+                self.list_state.select(Some(playlist.current_index()));
+                self.events.emit(PlayerEvent::PlaylistChanged);
+                return Ok(Some(format!("Shuffle {}", if on { "on" } else { "off" })));
+            }
+            Command::Save(path) => {
+                self.playlist.lock().unwrap().save_m3u(&path)?;
+                return Ok(Some(format!("Saved {}", path.display())));
+            }
+            Command::Add(path) => {
+                let added = self.playlist.lock().unwrap().add_path(&path)?;
+                self.library.write().unwrap().add_paths(&added.songs);
+                self.events.emit(PlayerEvent::PlaylistChanged);
+                let message = format!("Added {} tracks", added.songs.len());
+                return Ok(Some(match added.summary() {
+                    Some(summary) => format!("{}. {}", message, summary),
+                    None => message,
+                }));
+            }
+            Command::Sort(key) => {
+                let mut playlist = self.playlist.lock().unwrap();
+                playlist.sort(key, &self.library.read().unwrap());
                 self.list_state.select(Some(playlist.current_index()));
+                self.events.emit(PlayerEvent::PlaylistChanged);
+            }
+            Command::Filter(Some((field, value))) => {
+                let mut playlist = self.playlist.lock().unwrap();
+                let count = playlist.filter(&field, &value, &self.library.read().unwrap())?;
+                self.list_state.select(Some(playlist.current_index()));
+                self.events.emit(PlayerEvent::PlaylistChanged);
+                return Ok(Some(format!("{} tracks match {}={}", count, field, value)));
+            }
+            Command::Filter(None) => {
+                let mut playlist = self.playlist.lock().unwrap();
+                playlist.clear_filter();
+                self.list_state.select(Some(playlist.current_index()));
+                self.events.emit(PlayerEvent::PlaylistChanged);
+            }
+            Command::Query(text) => {
+                return self.load_query(Query::parse(&text)?).map(Some);
+            }
+            Command::Smart(Some(name)) => {
+                let query = smart::saved_query(&name)?;
+                return self.load_query(query).map(|loaded| Some(format!("{}: {}", name, loaded)));
+            }
+            Command::Smart(None) => {
+                let names = smart::saved_names();
+                if names.is_empty() {
+                    return Ok(Some("No saved smart playlists".to_string()));
+                }
+                return Ok(Some(format!("Smart playlists: {}", names.join(", "))));
+            }
+            Command::SmartSave(name, text) => {
+                smart::save(&name, &Query::parse(&text)?)?;
+                return Ok(Some(format!("Saved smart playlist {}", name)));
+            }
+            Command::SmartDelete(name) => {
+                smart::delete(&name)?;
+                return Ok(Some(format!("Deleted smart playlist {}", name)));
+            }
+            Command::Playlist(Some(name)) => {
+                let mut playlist = self.playlist.lock().unwrap();
+                let count = playlist.load_named(&name)?;
+                self.library.write().unwrap().add_paths(playlist.songs());
+                if let Some(song) = playlist.current() {
+                    self.play_song(song)?;
+                }
+                self.list_state.select(Some(0));
+                self.mark = None;
+                self.events.emit(PlayerEvent::PlaylistChanged);
+                return Ok(Some(format!("{}: {} tracks", name, count)));
+            }
+            Command::Playlist(None) => {
+                let names = playlist::named_playlists();
+                if names.is_empty() {
+                    return Ok(Some("No playlists yet".to_string()));
+                }
+                return Ok(Some(format!("Playlists: {}", names.join(", "))));
+            }
+            Command::PlaylistNew(name) => {
+                playlist::create_named(&name, &[])?;
+                self.refresh_playlists();
+                return Ok(Some(format!("Created playlist {}", name)));
+            }
+            Command::PlaylistRename(old, new) => {
+                playlist::rename_named(&old, &new)?;
+                let mut playlist = self.playlist.lock().unwrap();
+                if playlist.name() == Some(old.as_str()) {
+                    playlist.set_name(Some(new.clone()));
+                }
+                drop(playlist);
+                self.refresh_playlists();
+                return Ok(Some(format!("Renamed {} to {}", old, new)));
+            }
+            Command::PlaylistCopy(name, copy) => {
+                playlist::create_named(&copy, &playlist::read_named(&name)?)?;
+                self.refresh_playlists();
+                return Ok(Some(format!("Copied {} to {}", name, copy)));
+            }
+            Command::PlaylistDelete(name) => {
+                playlist::delete_named(&name)?;
+                let mut playlist = self.playlist.lock().unwrap();
+                // The tracks stay queued, just no longer as that playlist.
+                if playlist.name() == Some(name.as_str()) {
+                    playlist.set_name(None);
+                }
+                drop(playlist);
+                self.refresh_playlists();
+                return Ok(Some(format!("Deleted playlist {}", name)));
+            }
+            Command::TagEdit => {
+                let count = self.open_tag_editor()?;
+                return Ok(Some(format!("Editing tags of {} tracks", count)));
+            }
+            Command::Tag(edit) => {
+                if self.tag_editor.is_none() {
+                    self.open_tag_editor()?;
+                }
+                if let Some(editor) = &mut self.tag_editor {
+                    editor.apply(edit)?;
+                }
+                self.mode = AppMode::Tags;
+            }
+            Command::TagWrite => {
+                let editor = self.tag_editor.as_mut().context("The tag editor isn't open")?;
+                let written = editor.save()?;
+                self.library.write().unwrap().reload(&written);
+                self.events.emit(PlayerEvent::PlaylistChanged);
+                return Ok(Some(format!("Wrote tags to {} files", written.len())));
+            }
+            Command::Identify => return self.identify().map(Some),
+            Command::Analyze(replaygain) => return self.analyze(replaygain).map(Some),
+            Command::Rename(pattern) => return self.plan_rename(pattern).map(Some),
+            Command::RenameApply => return self.apply_rename().map(Some),
+            Command::Dupes(audio) => {
+                self.find_dupes(audio);
+                return Ok(Some("Looking for duplicates…".to_string()));
+            }
+            Command::PlaylistAdd(name) => {
+                let songs = self.selected_tracks();
+                if songs.is_empty() {
+                    anyhow::bail!("Nothing selected to add");
+                }
+                let count = songs.len();
+                if self.playlist.lock().unwrap().name() == Some(name.as_str()) {
+                    self.edit_playlist(|playlist| playlist.insert(playlist.len(), songs))?;
+                } else {
+                    let mut saved = playlist::read_named(&name)?;
+                    saved.extend(songs);
+                    playlist::write_named(&name, &saved)?;
+                }
+                self.refresh_playlists();
+                return Ok(Some(format!("Added {} tracks to {}", count, name)));
+            }
+            Command::Clear => {
+                self.search.clear();
+                self.status = None;
+            }
+            Command::Empty => {
+                self.player.stop();
+                self.playlist.lock().unwrap().clear();
+                self.search.clear();
+                self.list_state.select(None);
+                self.playlist_offset = 0;
+                self.events.emit(PlayerEvent::PlaylistChanged);
+            }
+            Command::Quit => self.should_quit = true,
+        }
+        Ok(None)
+    }
+    
+    // Replaces the playlist with a smart playlist's tracks; whatever is
+    // playing carries on.
+    fn load_query(&mut self, query: Query) -> Result<String> {
+        let mut playlist = self.playlist.lock().unwrap();
+        let count = playlist.load_query(query, &self.library.read().unwrap())?;
+        self.list_state.select(Some(playlist.current_index()));
+        self.events.emit(PlayerEvent::PlaylistChanged);
+        Ok(format!("{} tracks", count))
+    }
+    
+    fn scroll_up(&mut self) {
+        let playlist = self.playlist.lock().unwrap();
+        let len = playlist.len();
+        if len > 0 {
+            let selected = self.list_state.selected().unwrap_or(0);
+            let new_selected = if selected == 0 { len - 1 } else { selected - 1 };
+            self.list_state.select(Some(new_selected));
+        }
+    }
+    
+    fn scroll_down(&mut self) {
+        let playlist = self.playlist.lock().unwrap();
+        let len = playlist.len();
+        if len > 0 {
+            let selected = self.list_state.selected().unwrap_or(0);
+            let new_selected = if selected >= len - 1 { 0 } else { selected + 1 };
+            self.list_state.select(Some(new_selected));
+        }
+    }
+}
+
+fn dupe_count(groups: &[dupes::Group]) -> usize {
+    groups.iter().map(|group| group.files.len()).sum()
+}
+
+// Moves a list selection one step, wrapping around at either end.
+fn wrap_select(state: &mut ListState, len: usize, up: bool) {
+    if len > 0 {
+        let selected = state.selected().unwrap_or(0);
+        let new_selected = match up {
+            true if selected == 0 => len - 1,
+            true => selected - 1,
+            false if selected >= len - 1 => 0,
+            false => selected + 1,
+        };
+        state.select(Some(new_selected));
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let (mut config, problems) = Config::load(args.config.as_deref())?;
+    
+    match &args.command {
+        Some(CliCommand::Config { action: ConfigAction::Check }) => {
+            return config_check(&args, &config, problems);
+        }
+        #[cfg(unix)]
+        Some(CliCommand::Ctl { json, words }) => {
+            // Words may start with '-' (`seek -10`), so clap hands a trailing --json to us.
+            let raw = *json || words.iter().any(|w| w == "--json");
+            let words: Vec<String> = words.iter().filter(|w| *w != "--json").cloned().collect();
+            return ipc::ctl(&words, raw);
+        }
+        #[cfg(not(unix))]
+        Some(CliCommand::Ctl { .. }) => anyhow::bail!("ctl needs Unix domain sockets"),
+        // Needs the scan settings from the flags below.
+        Some(CliCommand::Dupes { .. } | CliCommand::Fingerprint | CliCommand::Analyze { .. }) => {}
+        Some(CliCommand::Identify { files }) => {
+            let path = config.fingerprint_db.clone().or_else(identify::default_path).context("No data directory")?;
+            return identify_report(&path, files);
+        }
+        Some(CliCommand::Stats { by, top, periods }) => {
+            let path = history::default_path().context("No data directory")?;
+            let period = Period::parse(by).unwrap_or(Period::Day);
+            for line in History::load(&path)?.stats(period).report(*top, *periods) {
+                println!("{}", line);
             }
+            return Ok(());
+        }
+        None => {}
+    }
+    for problem in &problems {
+        eprintln!("config: {}", problem);
+    }
+    
+    if !args.dir.is_empty() || !args.paths.is_empty() {
+        config.music_dirs = args.dir.iter().chain(&args.paths).cloned().collect();
+    }
+    config.add_exclude(&args.exclude).context("--exclude")?;
+    if let Some(depth) = args.max_depth {
+        config.set_max_depth(depth).context("--max-depth")?;
+    }
+    config.hidden |= args.hidden;
+    config.follow_symlinks &= !args.no_follow_symlinks;
+    if let Some(volume) = args.volume {
+        config.volume = volume.clamp(0.0, 1.0);
+    }
+    let scan = ScanOptions {
+        extensions: config.extensions.clone(),
+        exclude: config.exclude_set(),
+        max_depth: config.max_depth,
+        hidden: config.hidden,
+        follow_symlinks: config.follow_symlinks,
+    };
+    match args.command {
+        Some(CliCommand::Dupes { audio }) => return dupes_report(&config.music_dirs, scan, audio),
+        Some(CliCommand::Fingerprint) => return fingerprint_report(&config.music_dirs, scan),
+        Some(CliCommand::Analyze { files, replaygain, force }) => {
+            let files = if files.is_empty() {
+                scan_tracks(&config.music_dirs, scan)?.into_iter().map(|track| track.path).collect()
+            } else {
+                files
+            };
+            return analyze_report(&files, replaygain, force);
+        }
+        _ => {}
+    }
+    
+    // Claimed before anything else starts so a second daemon bails out early.
+    #[cfg(unix)]
+    let _pidfile = if args.daemon {
+        let path = args.pidfile.clone().unwrap_or_else(daemon::default_pidfile);
+        Some(daemon::Pidfile::create(&path)?)
+    } else {
+        None
+    };
+    // Caught from here on, so a SIGTERM while the library loads still ends
+    // in a clean shutdown rather than killing us.
+    #[cfg(unix)]
+    let signals = if args.daemon { Some(daemon::Signals::register()?) } else { None };
+    #[cfg(not(unix))]
+    let signals: Option<()> = None;
+    
+    // Fills in as the tags are read; a music dir with nothing to play
+    // stops us here, before the audio device or the terminal.
+    let (playlist, scanner) = Playlist::new_from_dirs(&config.music_dirs, scan)?;
+    let mut library = Library::default();
+    // Opened before any query so ratings and play counts can be matched.
+    if let Some(path) = library::user_data_path() {
+        if let Err(err) = library.open_user_data(path) {
+            eprintln!("Ratings and play counts disabled: {:#}", err);
+        }
+    }
+    // Checked now so a bad name fails before the terminal is taken over;
+    // it runs once the scan is done.
+    let query = args.smart.as_deref().map(smart::saved_query).transpose()?;
+    let player = AudioPlayer::new(config.output.as_deref())?;
+    player.set_volume(config.volume);
+    
+    // Pick up where we left off, or play the first song
+    let resume = match config.resume {
+        Resume::Off => None,
+        Resume::Track => ResumeState::load().map(|state| ResumeState { position: Duration::ZERO, ..state }),
+        Resume::Position => ResumeState::load(),
+    };
+    
+    let keymap = load_keymap(&args, &config)?;
+    let theme = Theme::resolve(&config.theme)?;
+    
+    let mut app = App::new(playlist, library, player, keymap, theme);
+    app.rating_tags = config.rating_tags;
+    app.group_albums = config.group_albums;
+    app.fingerprint_db = config.fingerprint_db.clone();
+    app.scanner = Some(scanner);
+    app.pending_query = query;
+    app.autoplay = true;
+    app.resume = resume;
+    
+    #[cfg(target_os = "linux")]
+    if config.mpris {
+        if let Err(err) = mpris::spawn(app.remote.clone()) {
+            eprintln!("MPRIS disabled: {:#}", err);
+        }
+    }
+    if let Some(address) = &config.mpd {
+        let roots = config.music_dirs.iter().filter(|root| root.is_dir()).cloned().collect();
+        if let Err(err) = mpd::spawn(app.remote.clone(), address, roots) {
+            eprintln!("MPD server disabled: {:#}", err);
+        }
+    }
+    if let Some(address) = &config.http {
+        if let Err(err) = http::spawn(app.remote.clone(), address, config.http_token.clone()) {
+            eprintln!("HTTP server disabled: {:#}", err);
+        }
+    }
+    hooks::spawn(app.remote.clone(), config.hooks.clone());
+    scrobble::spawn(app.remote.clone(), &config.scrobble);
+    if config.history {
+        app.recorder = history::spawn(app.remote.clone());
+    }
+    #[cfg(unix)]
+    let _ipc = if config.ipc {
+        ipc::spawn(app.remote.clone())
+            .map_err(|err| eprintln!("Control socket disabled: {:#}", err))
+            .ok()
+    } else {
+        None
+    };
+    
+    let rc_path = args.rc.clone().or_else(command::default_rc_path);
+    if let Some(rc_path) = rc_path {
+        // A missing default rc file is fine; an explicit --rc must exist.
+        if args.rc.is_some() || rc_path.exists() {
+            for (line_number, line) in command::read_rc(&rc_path)? {
+                let result = Command::parse(&line).and_then(|command| app.execute(command));
+                if let Err(err) = result {
+                    eprintln!("{}:{}: {}", rc_path.display(), line_number, err);
+                }
+            }
+        }
+    }
+    
+    let res = if args.daemon {
+        run_daemon(&mut app, config.tick_rate, signals)
+    } else {
+        // Setup terminal
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+        let backend = CrosstermBackend::new(stdout);
+        let mut terminal = Terminal::new(backend)?;
+        
+        let res = run_app(&mut terminal, &mut app, config.tick_rate);
+        
+        // Restore terminal
+        disable_raw_mode()?;
+        execute!(
+            terminal.backend_mut(),
+            LeaveAlternateScreen,
+            DisableMouseCapture
+        )?;
+        terminal.show_cursor()?;
+        res
+    };
+    
+    if let Err(err) = res {
+        println!("{err:?}");
+    }
+    for (path, reason) in &app.unreadable {
+        eprintln!("scan: {}: {}", path.display(), reason);
+    }
+    app.shutdown();
+    
+    Ok(())
+}
+
+// Reads tags with the same scanner the player starts with, with progress
+// on stderr.
+fn scan_tracks(roots: &[PathBuf], scan: ScanOptions) -> Result<Vec<library::TrackInfo>> {
+    let (_, mut scanner) = Playlist::new_from_dirs(roots, scan)?;
+    let mut tracks = Vec::new();
+    while !scanner.is_done() {
+        std::thread::sleep(Duration::from_millis(100));
+        tracks.extend(scanner.poll().tracks);
+        eprint!("\rReading tags: {}/{}", scanner.progress.read, scanner.progress.found);
+    }
+    eprintln!();
+    for (path, reason) in &scanner.unreadable {
+        eprintln!("scan: {}: {}", path.display(), reason);
+    }
+    if tracks.is_empty() {
+        anyhow::bail!(scanner.summary().unwrap_or_default());
+    }
+    Ok(tracks)
+}
+
+// Prints each group of duplicates with the copy worth keeping first.
+fn dupes_report(roots: &[PathBuf], scan: ScanOptions, audio: bool) -> Result<()> {
+    let tracks = scan_tracks(roots, scan)?;
+    let groups = dupes::find(&tracks, audio);
+    for group in &groups {
+        println!("{}", group.title());
+        for file in &group.files {
+            println!("  {}", file.label());
         }
+        println!();
     }
-    
-    fn next_track(&mut self) {
-        let mut playlist = self.playlist.lock().unwrap();
-        if let Some(next_song) = playlist.next() {
-            let _ = self.player.play_song(next_song);
-            self.list_state.select(Some(playlist.current_index()));
+    println!("{} tracks with duplicates", groups.len());
+    Ok(())
+}
+
+// Database lines on stdout, ready to append to the fingerprint file.
+fn fingerprint_report(roots: &[PathBuf], scan: ScanOptions) -> Result<()> {
+    let tracks = scan_tracks(roots, scan)?;
+    let mut written = 0;
+    for (i, track) in tracks.iter().enumerate() {
+        eprint!("\rFingerprinting: {}/{}", i + 1, tracks.len());
+        match Fingerprint::compute(&track.path) {
+            Ok(print) => {
+                if let Some(line) = identify::line(&print, track) {
+                    println!("{}", line);
+                    written += 1;
+                }
+            }
+            Err(err) => eprintln!("\nfingerprint: {:#}", err),
         }
     }
-    
-    fn prev_track(&mut self) {
-        let mut playlist = self.playlist.lock().unwrap();
-        if let Some(prev_song) = playlist.prev() {
-            let _ = self.player.play_song(prev_song);
-            self.list_state.select(Some(playlist.current_index()));
+    eprintln!("\n{} of {} tracks were tagged well enough to add", written, tracks.len());
+    Ok(())
+}
+
+// Results go into the library, so the player's info panel has them too.
+fn analyze_report(files: &[PathBuf], replaygain: bool, force: bool) -> Result<()> {
+    let mut library = Library::default();
+    library.open_user_data(library::user_data_path().context("No data directory")?)?;
+    let mut failed = 0;
+    for (i, file) in files.iter().enumerate() {
+        eprint!("\r[{}/{}] ", i + 1, files.len());
+        let cached = library.user_data(file).analysis.filter(|analysis| !force && analysis.is_current(file));
+        let analysis = match cached {
+            Some(analysis) => Ok(analysis),
+            None => Analysis::compute(file).inspect(|analysis| library.set_analysis(file, analysis.clone())),
+        };
+        let written = analysis.and_then(|mut analysis| {
+            if replaygain {
+                write_replaygain(file, &mut analysis)?;
+                library.set_analysis(file, analysis.clone());
+            }
+            Ok(analysis)
+        });
+        match written {
+            Ok(analysis) => println!("{}  {}", file.display(), analysis.summary()),
+            Err(err) => {
+                eprintln!("{:#}", err);
+                failed += 1;
+            }
         }
+        library.save_if_due()?;
     }
-    
-    fn play_selected(&mut self) {
-        if let Some(selected) = self.list_state.selected() {
-            let mut playlist = self.playlist.lock().unwrap();
-            if let Some(song) = playlist.play_index(selected) {
-                let _ = self.player.play_song(song);
+    eprintln!();
+    library.save_user_data()?;
+    if failed > 0 {
+        anyhow::bail!("{} of {} files failed", failed, files.len());
+    }
+    Ok(())
+}
+
+// Writing the tags changes the file but not the audio, so a measurement
+// that was current stays current.
+fn write_replaygain(path: &Path, analysis: &mut Analysis) -> Result<()> {
+    let current = analysis.is_current(path);
+    let (gain, peak) = analysis.replaygain();
+    tags::write_replaygain(path, gain, peak)?;
+    if current {
+        analysis.stamp = analysis::Stamp::of(path);
+    }
+    Ok(())
+}
+
+fn identify_report(database: &Path, files: &[PathBuf]) -> Result<()> {
+    let database = Database::load(database)?;
+    eprintln!("{} known fingerprints", database.len());
+    for file in files {
+        let print = match Fingerprint::compute(file) {
+            Ok(print) => print,
+            Err(err) => {
+                eprintln!("{:#}", err);
+                continue;
+            }
+        };
+        match database.identify(&print) {
+            Some(suggestion) => {
+                let values: Vec<String> = suggestion.values.iter()
+                    .map(|(field, value)| format!("{}: {}", field.name(), value))
+                    .collect();
+                println!("{}  ({:.0}%)  {}", file.display(), suggestion.score * 100.0, values.join(", "));
             }
+            None => println!("{}  no match", file.display()),
         }
     }
-    
-    fn scroll_up(&mut self) {
-        let playlist = self.playlist.lock().unwrap();
-        let len = playlist.len();
-        if len > 0 {
-            let selected = self.list_state.selected().unwrap_or(0);
-            let new_selected = if selected == 0 { len - 1 } else { selected - 1 };
-            self.list_state.select(Some(new_selected));
+    Ok(())
+}
+
+// Keys file from --keys, then `keymap` in the config, then the default
+// location; `[keys]` tables from the config are layered on top.
+fn load_keymap(args: &Args, config: &Config) -> Result<Keymap> {
+    let explicit = args.keys.clone().or_else(|| config.keymap.clone());
+    let mut keymap = match explicit.clone().or_else(Keymap::default_path) {
+        // A missing default keys file is fine; an explicit one must exist.
+        Some(path) if explicit.is_some() || path.exists() => Keymap::load(&path)?,
+        _ => Keymap::default(),
+    };
+    keymap.apply(&config.keys)?;
+    Ok(keymap)
+}
+
+fn config_check(args: &Args, config: &Config, mut problems: Vec<String>) -> Result<()> {
+    if let Err(err) = load_keymap(args, config) {
+        problems.push(format!("keys: {:#}", err));
+    }
+    if let Err(err) = Theme::resolve(&config.theme) {
+        problems.push(format!("theme: {:#}", err));
+    }
+    if let Some(output) = &config.output {
+        if !player::output_device_names().contains(output) {
+            problems.push(format!("output: no such device '{}'", output));
         }
     }
-    
-    fn scroll_down(&mut self) {
-        let playlist = self.playlist.lock().unwrap();
-        let len = playlist.len();
-        if len > 0 {
-            let selected = self.list_state.selected().unwrap_or(0);
-            let new_selected = if selected >= len - 1 { 0 } else { selected + 1 };
-            self.list_state.select(Some(new_selected));
+    for dir in &config.music_dirs {
+        if !dir.is_dir() {
+            problems.push(format!("music_dirs: {} is not a directory", dir.display()));
         }
     }
-}
-
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let initial_volume = args.volume.clamp(0.0, 1.0);
     
-    let playlist = Playlist::new_from_dir(&args.dir)?;
-    let player = AudioPlayer::new()?;
-    player.set_volume(initial_volume);
-    
-    // Play first song
-    if let Some(first_song) = playlist.current() {
-        let _ = player.play_song(first_song);
+    let path = args.config.clone().or_else(config::default_path);
+    if let Some(path) = path {
+        println!("Config file: {}", path.display());
     }
-    
-    let app = App::new(playlist, player);
-    
-    // Setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-    
-    let tick_rate = Duration::from_millis(50);
-    let res = run_app(&mut terminal, app, tick_rate);
-    
-    // Restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-    
-    if let Err(err) = res {
-        println!("{err:?}");
+    if problems.is_empty() {
+        println!("OK");
+        Ok(())
+    } else {
+        for problem in &problems {
+            println!("  {}", problem);
+        }
+        anyhow::bail!("{} problem(s) found", problems.len());
     }
-    
-    Ok(())
 }
 
 fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
-    mut app: App,
+    app: &mut App,
     tick_rate: Duration,
 ) -> Result<()> {
     let mut last_tick = Instant::now();
     
     loop {
-        terminal.draw(|f| ui(f, &mut app))?;
+        terminal.draw(|f| ui(f, app))?;
         
         let timeout = tick_rate.saturating_sub(last_tick.elapsed());
         
         if crossterm::event::poll(timeout)? {
             if let Event::Key(key) = event::read()? {
                 if key.kind == KeyEventKind::Press {
+                    if !app.command.active {
+                        app.status = None;
+                    }
                     match app.mode {
-                        AppMode::Player => match key.code {
-                            KeyCode::Char('q') | KeyCode::Esc => {
-                                return Ok(());
-                            }
-                            KeyCode::Char(' ') | KeyCode::Char('p') => {
-                                app.player.toggle_pause();
-                            }
-                            KeyCode::Char('n') | KeyCode::Right => {
-                                app.next_track();
-                            }
-                            KeyCode::Char('b') | KeyCode::Left => {
-                                app.prev_track();
-                            }
-                            KeyCode::Char('+') | KeyCode::Char('=') => {
-                                app.player.volume_up();
-                            }
-                            KeyCode::Char('-') => {
-                                app.player.volume_down();
-                            }
-                            KeyCode::Char('l') | KeyCode::Tab => {
-                                app.mode = AppMode::Playlist;
+                        _ if app.command.active => app.handle_command_key(key.code),
+                        AppMode::Playlist | AppMode::Library if app.search.editing => match key.code {
+                            KeyCode::Esc => {
+                                app.search.clear();
                             }
-                            KeyCode::Char('h') | KeyCode::F(1) => {
-                                app.mode = AppMode::Help;
-                            }
-                            KeyCode::Char(c) if c.is_ascii_digit() => {
-                                let digit = c.to_digit(10).unwrap() as usize;
-                                if digit > 0 {
-                                    let mut playlist = app.playlist.lock().unwrap();
-                                    if let Some(song) = playlist.play_index(digit - 1) {
-                                        let _ = app.player.play_song(song);
-                                        app.list_state.select(Some(digit - 1));
+                            KeyCode::Enter => {
+                                app.search.editing = false;
+                                if app.search.match_count() > 0 {
+                                    match app.search.scope {
+                                        SearchScope::Playlist => app.play_selected(),
+                                        SearchScope::Library => app.play_library_selection(),
                                     }
+                                    app.mode = AppMode::Player;
                                 }
                             }
-                            _ => {}
-                        },
-                        AppMode::Playlist => match key.code {
-                            KeyCode::Char('q') | KeyCode::Esc => {
-                                app.mode = AppMode::Player;
-                            }
-                            KeyCode::Up | KeyCode::Char('k') => {
-                                app.scroll_up();
-                            }
-                            KeyCode::Down | KeyCode::Char('j') => {
-                                app.scroll_down();
-                            }
-                            KeyCode::Enter => {
-                                app.play_selected();
-                                app.mode = AppMode::Player;
-                            }
-                            KeyCode::Tab => {
-                                app.mode = AppMode::Player;
-                            }
-                            _ => {}
+                            code => app.update_search(code),
                         },
-                        AppMode::Help => match key.code {
-                            KeyCode::Char('q') | KeyCode::Esc | KeyCode::Char('h') => {
-                                app.mode = AppMode::Player;
+                        _ => {
+                            let mode = app.mode.clone();
+                            for action in app.keymap.feed(&mode, &key) {
+                                app.perform(action);
                             }
-                            _ => {}
-                        },
+                        }
                     }
                 }
             }
         }
         
+        if app.should_quit {
+            return Ok(());
+        }
+        
+        if let Some(action) = app.keymap.expire() {
+            app.perform(action);
+        }
+        
         if last_tick.elapsed() >= tick_rate {
             app.on_tick();
             last_tick = Instant::now();
@@ -255,77 +2089,157 @@ fn run_app<B: Backend>(
     }
 }
 
+// Headless main loop for --daemon: no terminal, control comes from the
+// control socket, MPRIS or MPD, plus SIGUSR1 to skip a track.
+#[cfg(unix)]
+fn run_daemon(app: &mut App, tick_rate: Duration, signals: Option<daemon::Signals>) -> Result<()> {
+    let signals = match signals {
+        Some(signals) => signals,
+        None => daemon::Signals::register()?,
+    };
+    
+    while !app.should_quit && !signals.quit_requested() {
+        if signals.take_skip() {
+            app.next_track();
+        }
+        app.on_tick();
+        std::thread::sleep(tick_rate);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run_daemon(_app: &mut App, _tick_rate: Duration, _signals: Option<()>) -> Result<()> {
+    anyhow::bail!("--daemon needs Unix signals")
+}
+
 fn ui(f: &mut Frame, app: &mut App) {
+    let scanning = if app.scanner.is_some() { 3 } else { 0 };
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
-            Constraint::Length(3),  // Header
-            Constraint::Min(10),    // Main content
-            Constraint::Length(3),  // Footer
+            Constraint::Length(3),          // Header
+            Constraint::Min(10),            // Main content
+            Constraint::Length(scanning),   // Scan progress
+            Constraint::Length(3),          // Footer
         ])
         .split(f.size());
+    let theme = app.theme.clone();
     
     // Header
     let header = Paragraph::new("🎵 CLI Music Player")
-        .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))
+        .style(theme.header)
         .alignment(Alignment::Center)
-        .block(Block::default().borders(Borders::ALL));
+        .block(Block::default().borders(Borders::ALL).border_style(theme.border));
     f.render_widget(header, chunks[0]);
     
     match app.mode {
         AppMode::Player => render_player_view(f, chunks[1], app),
         AppMode::Playlist => render_playlist_view(f, chunks[1], app),
-        AppMode::Help => render_help_view(f, chunks[1]),
+        AppMode::Playlists => render_playlists_view(f, chunks[1], app),
+        AppMode::Library => render_library_view(f, chunks[1], app),
+        AppMode::History => render_history_view(f, chunks[1], app),
+        AppMode::Dupes => render_dupes_view(f, chunks[1], app),
+        AppMode::Tags => render_tags_view(f, chunks[1], app),
+        AppMode::Rename => render_rename_view(f, chunks[1], app),
+        AppMode::Info => render_info_view(f, chunks[1], app),
+        AppMode::Help => render_help_view(f, chunks[1], app),
+    }
+    
+    if let Some(scanner) = &app.scanner {
+        let progress = &scanner.progress;
+        let label = if progress.walked {
+            let eta = progress.eta().map(library::format_duration).unwrap_or_else(|| "-".to_string());
+            format!("Reading tags: {}/{} ({:.0}%) · {:.0} files/s · ETA {}",
+                progress.read, progress.found, progress.ratio() * 100.0, progress.rate(), eta)
+        } else {
+            format!("Scanning: {} files found, {} read · {:.0} files/s", progress.found, progress.read, progress.rate())
+        };
+        let gauge = Gauge::default()
+            .block(Block::default().borders(Borders::ALL).border_style(theme.border))
+            .gauge_style(theme.gauge)
+            .ratio(progress.ratio())
+            .label(label);
+        f.render_widget(gauge, chunks[2]);
     }
     
     // Footer
-    let mode_text = match app.mode {
-        AppMode::Player => "Player Mode | Tab: Playlist | H: Help | Q: Quit",
-        AppMode::Playlist => "Playlist Mode | ↑↓: Navigate | Enter: Play | Tab: Back | Q: Exit",
-        AppMode::Help => "Help | Q/H/Esc: Back",
-    };
+    let mode_text = footer_hints(app);
     
-    let footer = Paragraph::new(mode_text)
-        .style(Style::default().fg(Color::Yellow))
-        .alignment(Alignment::Center)
-        .block(Block::default().borders(Borders::ALL));
-    f.render_widget(footer, chunks[2]);
+    let footer = if app.command.active {
+        Paragraph::new(format!(":{}_", app.command.input))
+            .style(theme.command_line)
+            .alignment(Alignment::Left)
+    } else if let Some(status) = &app.status {
+        Paragraph::new(status.as_str())
+            .style(theme.message)
+            .alignment(Alignment::Center)
+    } else {
+        Paragraph::new(mode_text.as_str())
+            .style(theme.footer)
+            .alignment(Alignment::Center)
+    };
+    let footer = footer.block(Block::default().borders(Borders::ALL).border_style(theme.border));
+    f.render_widget(footer, chunks[3]);
 }
 
 fn render_player_view(f: &mut Frame, area: Rect, app: &App) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
-            Constraint::Length(7),  // Now playing
+            Constraint::Length(8),  // Now playing
             Constraint::Length(3),  // Controls
             Constraint::Min(5),     // Track list preview
         ])
         .split(area);
     
     // Now Playing
+    let theme = &app.theme;
     let playlist = app.playlist.lock().unwrap();
     let current_song = playlist.current_song_name();
+    let library = app.library.read().unwrap();
+    let current_info = playlist.current().and_then(|path| library.get(path));
+    let badge = playlist.current().map(|path| library.user_data(path).badge()).unwrap_or_default();
+    let position = library::format_duration(app.player.position());
+    let elapsed = match current_info.and_then(|info| info.duration) {
+        Some(duration) => format!("{} / {}", position, library::format_duration(duration)),
+        None => position,
+    };
     let current_index = playlist.current_index() + 1;
     let total_songs = playlist.len();
     let volume = (app.player.get_volume() * 100.0) as u8;
     
-    let (status_text, status_color) = match app.player.get_state() {
-        PlaybackState::Playing => ("▶ Playing", Color::Green),
-        PlaybackState::Paused => ("⏸ Paused", Color::Yellow),
-        PlaybackState::Stopped => ("⏹ Stopped", Color::Red),
+    let (status_text, status_style) = match app.player.get_state() {
+        PlaybackState::Playing => ("▶ Playing", theme.status_playing),
+        PlaybackState::Paused => ("⏸ Paused", theme.status_paused),
+        PlaybackState::Stopped => ("⏹ Stopped", theme.status_stopped),
     };
     
     let now_playing_text = vec![
         Line::from(vec![
-            Span::styled(status_text, Style::default().fg(status_color).add_modifier(Modifier::BOLD)),
+            Span::styled(status_text, status_style),
         ]),
         Line::from(""),
         Line::from(vec![
             Span::raw("Track: "),
-            Span::styled(&current_song, Style::default().fg(Color::White).add_modifier(Modifier::BOLD)),
+            Span::styled(&current_song, theme.track_name),
+            Span::raw(if badge.is_empty() { "" } else { "  " }),
+            Span::styled(badge, theme.time),
         ]),
+        Line::from(match current_info {
+            Some(info) => {
+                let album = match info.year {
+                    Some(year) => format!("{} ({})", info.album, year),
+                    None => info.album.clone(),
+                };
+                format!("{} - {}", info.artist, album)
+            }
+            None => String::new(),
+        }),
         Line::from(vec![
             Span::raw(format!("{}/{} tracks", current_index, total_songs)),
+            Span::raw("  "),
+            Span::styled(elapsed, theme.time),
         ]),
     ];
     
@@ -334,16 +2248,18 @@ fn render_player_view(f: &mut Frame, area: Rect, app: &App) {
         .wrap(Wrap { trim: true })
         .block(Block::default()
             .borders(Borders::ALL)
+            .border_style(theme.border)
             .title("Now Playing")
-            .title_style(Style::default().fg(Color::Cyan)));
+            .title_style(theme.title));
     f.render_widget(now_playing, chunks[0]);
     
     // Volume control
     let volume_gauge = Gauge::default()
         .block(Block::default()
             .borders(Borders::ALL)
+            .border_style(theme.border)
             .title(format!("Volume: {}%", volume)))
-        .gauge_style(Style::default().fg(Color::Cyan))
+        .gauge_style(theme.gauge)
         .ratio(volume as f64 / 100.0);
     f.render_widget(volume_gauge, chunks[1]);
     
@@ -359,9 +2275,9 @@ fn render_player_view(f: &mut Frame, area: Rect, app: &App) {
             );
             
             let style = if *idx == playlist.current_index() {
-                Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+                theme.current_track
             } else {
-                Style::default().fg(Color::White)
+                theme.text
             };
             
             ListItem::new(content).style(style)
@@ -371,80 +2287,592 @@ fn render_player_view(f: &mut Frame, area: Rect, app: &App) {
     let tracks_list = List::new(tracks)
         .block(Block::default()
             .borders(Borders::ALL)
+            .border_style(theme.border)
             .title("Tracks (Tab for full playlist)"))
-        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_style(theme.selection)
         .highlight_symbol("♪ ");
     
     f.render_widget(tracks_list, chunks[2]);
 }
 
 fn render_playlist_view(f: &mut Frame, area: Rect, app: &mut App) {
+    let theme = &app.theme;
     let playlist = app.playlist.lock().unwrap();
     let current_index = playlist.current_index();
+    let songs = playlist.songs();
     
-    let tracks: Vec<ListItem> = playlist.list()
+    // Only build list items for the rows on screen so huge playlists stay fast.
+    let height = area.height.saturating_sub(2) as usize;
+    let selected = app.list_state.selected().unwrap_or(0);
+    if selected < app.playlist_offset {
+        app.playlist_offset = selected;
+    } else if height > 0 && selected >= app.playlist_offset + height {
+        app.playlist_offset = selected + 1 - height;
+    }
+    let library = app.library.read().unwrap();
+    let mut offset = app.playlist_offset.min(songs.len());
+    let mut rows = playlist_rows(songs, &library, offset, height, app.group_albums);
+    // Headers take up rows too, so scroll on until the selection fits.
+    while offset < selected && rows.iter().rev().find_map(PlaylistRow::track).is_some_and(|last| last < selected) {
+        offset += 1;
+        rows = playlist_rows(songs, &library, offset, height, app.group_albums);
+    }
+    app.playlist_offset = offset;
+    
+    let marked = app.mark.and(app.selected_range(songs.len()));
+    let tracks: Vec<ListItem> = rows
         .iter()
-        .map(|(idx, song)| {
-            let content = format!("{}. {}", 
-                idx + 1,
-                song.file_stem().unwrap_or_default().to_string_lossy()
-            );
+        .map(|row| {
+            let (idx, song) = match row {
+                PlaylistRow::Header(header) => return ListItem::new(header.as_str()).style(theme.section),
+                PlaylistRow::Track(idx) => (*idx, &songs[*idx]),
+            };
+            let name = song.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let badge = library.user_data(song).badge();
+            let badge = if badge.is_empty() { badge } else { format!("  {}", badge) };
             
-            let style = if *idx == current_index {
-                Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+            let style = if idx == current_index {
+                theme.current_track
+            } else if marked.as_ref().is_some_and(|range| range.contains(&idx)) {
+                theme.selection_inactive
             } else {
-                Style::default().fg(Color::White)
+                theme.text
             };
             
-            ListItem::new(content).style(style)
+            let searching = app.search.scope == SearchScope::Playlist && !app.search.query.is_empty();
+            if searching && app.search.is_match(idx) {
+                let mut spans = vec![Span::styled(format!("{}. ", idx + 1), style)];
+                spans.extend(highlight_matches(&app.search.query, &name, style, theme.search_match));
+                spans.push(Span::styled(badge, style));
+                ListItem::new(Line::from(spans))
+            } else {
+                ListItem::new(format!("{}. {}{}", idx + 1, name, badge)).style(style)
+            }
         })
         .collect();
     
+    let title = if app.search.is_active() && app.search.scope == SearchScope::Playlist {
+        let cursor = if app.search.editing { "_" } else { "" };
+        format!("Playlist | /{}{} ({} matches)", app.search.query, cursor, app.search.match_count())
+    } else if let Some(query) = playlist.query() {
+        format!("Playlist | {}", query.text())
+    } else if let Some(name) = playlist.name() {
+        format!("Playlist | {}", name)
+    } else {
+        "Playlist".to_string()
+    };
+    let title = match playlist.sorted_by() {
+        Some(key) if !(app.search.is_active() && app.search.scope == SearchScope::Playlist) => format!("{} | sorted by {}", title, key.name()),
+        _ => title,
+    };
+    
     let tracks_list = List::new(tracks)
         .block(Block::default()
             .borders(Borders::ALL)
-            .title("Playlist"))
-        .highlight_style(Style::default().bg(Color::DarkGray))
+            .border_style(theme.border)
+            .title(title))
+        .highlight_style(theme.selection)
+        .highlight_symbol("♪ ");
+    
+    let mut state = ListState::default();
+    state.select(rows.iter().position(|row| row.track() == Some(selected)));
+    f.render_stateful_widget(tracks_list, area, &mut state);
+}
+
+// `text` with the characters a fuzzy search for `query` matched in
+// `highlight`.
+fn highlight_matches(query: &str, text: &str, style: Style, highlight: Style) -> Vec<Span<'static>> {
+    let positions = search::fuzzy_positions(query, text);
+    text.chars()
+        .enumerate()
+        .map(|(ci, c)| Span::styled(c.to_string(), if positions.contains(&ci) { highlight } else { style }))
+        .collect()
+}
+
+enum PlaylistRow {
+    Header(String),
+    Track(usize),
+}
+
+impl PlaylistRow {
+    fn track(&self) -> Option<usize> {
+        match self {
+            PlaylistRow::Track(index) => Some(*index),
+            PlaylistRow::Header(_) => None,
+        }
+    }
+}
+
+// Up to `height` rows from track `offset` on. With `grouped`, a header goes
+// before each run of tracks from the same album, and at the top so the
+// first album on screen is always labelled.
+fn playlist_rows(songs: &[PathBuf], library: &Library, offset: usize, height: usize, grouped: bool) -> Vec<PlaylistRow> {
+    let mut rows = Vec::new();
+    let mut last_album = None;
+    for (index, song) in songs.iter().enumerate().skip(offset) {
+        if grouped {
+            let album = match library.get(song) {
+                Some(info) => format!("{} - {}", info.artist, info.album),
+                None => song.parent().and_then(Path::file_name).unwrap_or_default().to_string_lossy().to_string(),
+            };
+            if last_album.as_ref() != Some(&album) {
+                if rows.len() + 1 >= height {
+                    break;
+                }
+                rows.push(PlaylistRow::Header(format!("── {} ──", album)));
+                last_album = Some(album);
+            }
+        }
+        if rows.len() >= height {
+            break;
+        }
+        rows.push(PlaylistRow::Track(index));
+    }
+    rows
+}
+
+fn render_library_view(f: &mut Frame, area: Rect, app: &App) {
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(20),  // Genre
+            Constraint::Percentage(25),  // Artist
+            Constraint::Percentage(25),  // Album
+            Constraint::Percentage(30),  // Track
+        ])
+        .split(area);
+    
+    let theme = &app.theme;
+    let library = app.library.read().unwrap();
+    let searching = app.search.scope == SearchScope::Library && !app.search.query.is_empty();
+    for (column, chunk) in BrowserColumn::ALL.iter().zip(columns.iter()) {
+        let focused = *column == app.browser.column;
+        let items = app.browser.items(&library, *column);
+        let items: Vec<ListItem> = if *column == BrowserColumn::Track && searching {
+            // Same as the playlist view: matched tracks get the matched
+            // characters picked out.
+            let tracks = app.browser.shown_tracks(&library);
+            items.into_iter()
+                .zip(tracks)
+                .map(|(item, track)| {
+                    if library.index_of(&track.path).is_some_and(|idx| app.search.is_match(idx)) {
+                        ListItem::new(Line::from(highlight_matches(&app.search.query, &item, theme.text, theme.search_match)))
+                    } else {
+                        ListItem::new(item).style(theme.text)
+                    }
+                })
+                .collect()
+        } else {
+            items.into_iter()
+                .map(|item| ListItem::new(item).style(theme.text))
+                .collect()
+        };
+        
+        let border_style = if focused {
+            theme.border_focused
+        } else {
+            theme.border
+        };
+        let highlight_style = if focused {
+            theme.selection
+        } else {
+            theme.selection_inactive
+        };
+        
+        let title = if *column == BrowserColumn::Track && app.search.is_active() && app.search.scope == SearchScope::Library {
+            let cursor = if app.search.editing { "_" } else { "" };
+            format!("Track | /{}{} ({} matches)", app.search.query, cursor, app.search.match_count())
+        } else {
+            column.title().to_string()
+        };
+        let list = List::new(items)
+            .block(Block::default()
+                .borders(Borders::ALL)
+                .border_style(border_style)
+                .title(title))
+            .highlight_style(highlight_style);
+        
+        let mut state = ListState::default();
+        state.select(Some(app.browser.selected(*column)));
+        f.render_stateful_widget(list, *chunk, &mut state);
+    }
+}
+
+fn render_playlists_view(f: &mut Frame, area: Rect, app: &App) {
+    let theme = &app.theme;
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(theme.border_focused)
+        .title("Playlists");
+    if app.playlists.is_empty() {
+        let keys = app.keymap.keys_for(&AppMode::Playlists, &Action::NewPlaylist).unwrap_or_default();
+        let hint = Paragraph::new(format!("No playlists yet. Press {} to make one.", keys))
+            .style(theme.text)
+            .block(block);
+        f.render_widget(hint, area);
+        return;
+    }
+    
+    let active = app.playlist.lock().unwrap().name().map(str::to_string);
+    let items: Vec<ListItem> = app.playlists
+        .iter()
+        .map(|(name, count)| {
+            let style = if Some(name) == active.as_ref() { theme.current_track } else { theme.text };
+            ListItem::new(format!("{}  ({} tracks)", name, count)).style(style)
+        })
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(theme.selection)
+        .highlight_symbol("♪ ");
+    let mut state = app.playlists_state.clone();
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+fn render_history_view(f: &mut Frame, area: Rect, app: &App) {
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(55),  // Plays
+            Constraint::Percentage(45),  // Stats
+        ])
+        .split(area);
+    
+    let theme = &app.theme;
+    let plays: Vec<ListItem> = app.history.plays()
+        .iter()
+        .rev()
+        .take(HISTORY_VIEW_LIMIT)
+        .map(|play| ListItem::new(play.label()).style(theme.text))
+        .collect();
+    let list = List::new(plays)
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .border_style(theme.border_focused)
+            .title("History"))
+        .highlight_style(theme.selection)
+        .highlight_symbol("♪ ");
+    let mut state = app.history_state.clone();
+    f.render_stateful_widget(list, columns[0], &mut state);
+    
+    let stats: Vec<Line> = app.history_report.iter().map(|line| Line::from(line.as_str())).collect();
+    let stats = Paragraph::new(stats)
+        .style(theme.text)
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .border_style(theme.border)
+            .title("Stats")
+            .title_style(theme.title));
+    f.render_widget(stats, columns[1]);
+}
+
+fn render_dupes_view(f: &mut Frame, area: Rect, app: &App) {
+    let theme = &app.theme;
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(theme.border_focused)
+        .title("Duplicates");
+    let message = if app.dupes_job.is_some() {
+        Some("Looking for duplicates…")
+    } else if app.dupes.is_empty() {
+        Some("No duplicates found.")
+    } else {
+        None
+    };
+    if let Some(message) = message {
+        f.render_widget(Paragraph::new(message).style(theme.text).block(block), area);
+        return;
+    }
+    
+    // A header per group, so the selected copy sits one row further down
+    // for every group before it.
+    let mut items = Vec::new();
+    let mut selected_row = None;
+    let mut copies = 0;
+    for group in &app.dupes {
+        items.push(ListItem::new(group.title()).style(theme.section));
+        for file in &group.files {
+            if app.dupes_state.selected() == Some(copies) {
+                selected_row = Some(items.len());
+            }
+            copies += 1;
+            let (mark, style) = if app.dupes_dropped.contains(&file.path) {
+                ("drop", theme.selection_inactive)
+            } else {
+                ("keep", theme.text)
+            };
+            items.push(ListItem::new(format!("  {}  {}", mark, file.label())).style(style));
+        }
+    }
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(theme.selection)
+        .highlight_symbol("♪ ");
+    let mut state = ListState::default();
+    state.select(selected_row);
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+fn render_tags_view(f: &mut Frame, area: Rect, app: &App) {
+    let editor = match &app.tag_editor {
+        Some(editor) => editor,
+        None => return,
+    };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(Field::ALL.len() as u16 + 2),  // Fields
+            Constraint::Min(3),                               // Preview
+        ])
+        .split(area);
+    
+    let theme = &app.theme;
+    let fields: Vec<ListItem> = Field::ALL
+        .iter()
+        .map(|&field| {
+            let (value, changed) = editor.value(field);
+            let value = match value {
+                Some(value) => value.unwrap_or_default(),
+                None => "(various)".to_string(),
+            };
+            let style = if changed { theme.current_track } else { theme.text };
+            ListItem::new(format!("{:<8}{}", field.name(), value)).style(style)
+        })
+        .collect();
+    let list = List::new(fields)
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .border_style(theme.border_focused)
+            .title(format!("Edit tags: {} tracks", editor.len())))
+        .highlight_style(theme.selection)
         .highlight_symbol("♪ ");
+    let mut state = app.tags_state.clone();
+    f.render_stateful_widget(list, chunks[0], &mut state);
     
-    f.render_stateful_widget(tracks_list, area, &mut app.list_state);
+    let changes = editor.preview();
+    let title = if changes.is_empty() {
+        "No changes".to_string()
+    } else {
+        format!("Changes: {}", changes.len())
+    };
+    let lines: Vec<Line> = changes.iter().map(|line| Line::from(line.as_str())).collect();
+    let preview = Paragraph::new(lines)
+        .style(theme.text)
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .border_style(theme.border)
+            .title(title)
+            .title_style(theme.title));
+    f.render_widget(preview, chunks[1]);
 }
 
-fn render_help_view(f: &mut Frame, area: Rect) {
-    let help_text = vec![
+fn render_info_view(f: &mut Frame, area: Rect, app: &App) {
+    let path = match &app.info_path {
+        Some(path) => path,
+        None => return,
+    };
+    let theme = &app.theme;
+    let library = app.library.read().unwrap();
+    let track = match library.get(path).or(app.info_track.as_ref()) {
+        Some(track) => track.clone(),
+        None => return,
+    };
+    let data = library.user_data(path);
+    
+    let row = |label: &str, value: String| Line::from(vec![
+        Span::styled(format!("{:<14}", label), theme.section),
+        Span::styled(value, theme.text),
+    ]);
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let mut lines = vec![
+        row("Title", track.title.clone()),
+        row("Artist", track.artist.clone()),
+        row("Album", track.album.clone()),
+        row("Track", or_dash(track.track.map(|n| n.to_string()))),
+        row("Year", or_dash(track.year.map(|n| n.to_string()))),
+        row("Genre", track.genre.clone()),
+        row("Length", or_dash(track.duration.map(library::format_duration))),
+        row("File", path.display().to_string()),
         Line::from(""),
-        Line::from(vec![
-            Span::styled("Player Controls:", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
-        ]),
-        Line::from("  Space/P     - Play/Pause"),
-        Line::from("  N/→         - Next track"),
-        Line::from("  B/←         - Previous track"),
-        Line::from("  +/=         - Volume up"),
-        Line::from("  -           - Volume down"),
-        Line::from("  1-9         - Play track number"),
+        row("Rating", if data.badge().is_empty() { "-".to_string() } else { data.badge() }),
+        row("Plays", format!("{} ({} skipped)", data.plays, data.skips)),
         Line::from(""),
-        Line::from(vec![
-            Span::styled("Navigation:", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+    ];
+    match &data.analysis {
+        Some(analysis) => {
+            let (gain, _) = analysis.replaygain();
+            lines.extend([
+                row("Loudness", format!("{:.1} LUFS (ReplayGain {:+.2} dB)", analysis.loudness, gain)),
+                row("True peak", format!("{:.1} dBTP", analysis.true_peak)),
+                row("Range", format!("{:.1} LU", analysis.range)),
+                row("Dynamics", format!("DR{:.0}", analysis.dynamic_range)),
+                row("Tempo", or_dash(analysis.bpm.map(|bpm| format!("{:.0} BPM", bpm)))),
+                row("Key", or_dash(analysis.key.clone())),
+            ]);
+        }
+        None => {
+            let hint = match app.keymap.keys_for(&AppMode::Info, &Action::Analyze) {
+                Some(keys) => format!("Not analyzed yet ({} to measure loudness, tempo and key)", keys),
+                None => "Not analyzed yet".to_string(),
+            };
+            lines.push(Line::from(Span::styled(hint, theme.time)));
+        }
+    }
+    
+    let info = Paragraph::new(lines)
+        .wrap(Wrap { trim: false })
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .border_style(theme.border_focused)
+            .title("Track Info")
+            .title_style(theme.title));
+    f.render_widget(info, area);
+}
+
+fn rename_lines(plan: &Option<RenamePlan>) -> usize {
+    plan.as_ref().map_or(0, |plan| plan.problems.len() + plan.moves.len())
+}
+
+fn render_rename_view(f: &mut Frame, area: Rect, app: &App) {
+    let plan = match &app.rename_plan {
+        Some(plan) => plan,
+        None => return,
+    };
+    let theme = &app.theme;
+    let items: Vec<ListItem> = plan.preview()
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let style = if i < plan.problems.len() { theme.message } else { theme.text };
+            ListItem::new(line).style(style)
+        })
+        .collect();
+    let mut title = format!("Rename {}: {} files", plan.pattern.text(), plan.moves.len());
+    if !plan.problems.is_empty() {
+        title.push_str(&format!(", {} problems", plan.problems.len()));
+    }
+    if plan.unchanged > 0 {
+        title.push_str(&format!(", {} already in place", plan.unchanged));
+    }
+    let list = List::new(items)
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .border_style(theme.border_focused)
+            .title(title))
+        .highlight_style(theme.selection)
+        .highlight_symbol("♪ ");
+    let mut state = app.rename_state.clone();
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+fn footer_hints(app: &App) -> String {
+    let (title, hints): (&str, Vec<(Action, &str)>) = match app.mode {
+        AppMode::Player => ("Player Mode", vec![
+            (Action::Playlist, "Playlist"),
+            (Action::Help, "Help"),
+            (Action::Quit, "Quit"),
         ]),
-        Line::from("  Tab/L       - Toggle playlist view"),
-        Line::from("  H/F1        - Show this help"),
-        Line::from("  Q/Esc       - Quit/Back"),
-        Line::from(""),
-        Line::from(vec![
-            Span::styled("Playlist View:", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+        AppMode::Playlist | AppMode::Library if app.search.editing => {
+            return "Search | Type to filter | Enter: Play | Esc: Cancel".to_string();
+        }
+        AppMode::Playlist => ("Playlist Mode", vec![
+            (Action::PlaySelected, "Play"),
+            (Action::Search, "Search"),
+            (Action::SearchNext, "Next match"),
+            (Action::Playlist, "Back"),
+            (Action::Back, "Exit"),
         ]),
-        Line::from("  ↑↓/J/K      - Navigate tracks"),
-        Line::from("  Enter       - Play selected track"),
-        Line::from(""),
+        AppMode::Playlists => ("Playlists", vec![
+            (Action::PlaySelected, "Play"),
+            (Action::NewPlaylist, "New"),
+            (Action::RenamePlaylist, "Rename"),
+            (Action::DeletePlaylist, "Delete"),
+            (Action::Back, "Back"),
+        ]),
+        AppMode::Library => ("Library Mode", vec![
+            (Action::PlaySelected, "Play"),
+            (Action::Enqueue, "Enqueue"),
+            (Action::Search, "Search"),
+            (Action::Back, "Back"),
+        ]),
+        AppMode::History => ("History Mode", vec![
+            (Action::PlaySelected, "Play"),
+            (Action::Enqueue, "Enqueue"),
+            (Action::Back, "Back"),
+        ]),
+        AppMode::Dupes => ("Duplicates", vec![
+            (Action::PlaySelected, "Play"),
+            (Action::ToggleKeep, "Keep/drop"),
+            (Action::DropDuplicates, "Remove dropped"),
+            (Action::Back, "Back"),
+        ]),
+        AppMode::Tags => ("Tag Editor", vec![
+            (Action::EditField, "Edit"),
+            (Action::NumberTracks, "Number"),
+            (Action::TagsFromPath, "From path"),
+            (Action::Identify, "Identify"),
+            (Action::RenameFiles, "Rename"),
+            (Action::Undo, "Undo"),
+            (Action::WriteTags, "Write"),
+            (Action::Back, "Cancel"),
+        ]),
+        AppMode::Rename => ("Rename", vec![
+            (Action::ApplyRename, "Rename"),
+            (Action::Back, "Cancel"),
+        ]),
+        AppMode::Info => ("Track Info", vec![
+            (Action::Analyze, "Analyze"),
+            (Action::Back, "Back"),
+        ]),
+        AppMode::Help => ("Help", vec![(Action::Back, "Back")]),
+    };
+    
+    let mut parts = vec![title.to_string()];
+    let pending = app.keymap.pending();
+    if !pending.is_empty() {
+        parts.push(format!("{} …", pending));
+    }
+    for (action, label) in hints {
+        if let Some(keys) = app.keymap.keys_for(&app.mode, &action) {
+            parts.push(format!("{}: {}", keys, label));
+        }
+    }
+    parts.join(" | ")
+}
+
+fn render_help_view(f: &mut Frame, area: Rect, app: &App) {
+    let sections = [
+        ("Player Controls:", AppMode::Player),
+        ("Playlist View:", AppMode::Playlist),
+        ("Playlists:", AppMode::Playlists),
+        ("Library View:", AppMode::Library),
+        ("History View:", AppMode::History),
+        ("Duplicates:", AppMode::Dupes),
+        ("Tag Editor:", AppMode::Tags),
+        ("Rename Preview:", AppMode::Rename),
+        ("Track Info:", AppMode::Info),
     ];
     
+    let mut help_text = vec![Line::from("")];
+    for (title, mode) in sections {
+        help_text.push(Line::from(vec![
+            Span::styled(title, app.theme.section),
+        ]));
+        for (keys, description) in app.keymap.help_rows(&mode) {
+            help_text.push(Line::from(format!("  {:<12}- {}", keys, description)));
+        }
+        help_text.push(Line::from(""));
+    }
+    help_text.push(Line::from("  On the command line (:) Tab completes and ↑↓ walk the history."));
+    
     let help_paragraph = Paragraph::new(help_text)
         .alignment(Alignment::Left)
         .wrap(Wrap { trim: true })
         .block(Block::default()
             .borders(Borders::ALL)
+            .border_style(app.theme.border)
             .title("Help")
-            .title_style(Style::default().fg(Color::Cyan)));
+            .title_style(app.theme.title));
     
     f.render_widget(help_paragraph, area);
 }
\ No newline at end of file
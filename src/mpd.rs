@@ -0,0 +1,608 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+
+use crate::command::{Command, Seek, Volume};
+use crate::events::PlayerEvent;
+use crate::library::TrackInfo;
+use crate::player::PlaybackState;
+use crate::remote::Remote;
+
+// Enough of the Music Player Daemon protocol for mpc, ncmpcpp and the
+// usual phone remotes: playback, volume, the queue and the file list.
+// https://mpd.readthedocs.io/en/latest/protocol.html
+const PROTOCOL_VERSION: &str = "0.23.0";
+
+const SUPPORTED: &[&str] = &[
+    "add", "clear", "clearerror", "close", "command_list_begin", "command_list_end",
+    "command_list_ok_begin", "commands", "currentsong", "decoders", "getvol",
+    "idle", "listall", "next", "noidle", "notcommands", "outputs", "pause",
+    "ping", "play", "playid", "playlistid", "playlistinfo", "plchanges",
+    "previous", "random", "seek", "seekcur", "seekid", "setvol", "status",
+    "stop", "tagtypes", "urlhandlers",
+];
+
+const TAG_TYPES: [&str; 6] = ["Artist", "Album", "Title", "Track", "Genre", "Date"];
+
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_PERMISSION: u32 = 4;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_SYSTEM: u32 = 52;
+
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Ack { code, message: message.into() }
+    }
+}
+
+type Reply = std::result::Result<String, Ack>;
+
+// MPD has no encryption and this doesn't do its `password` command, so
+// only loopback addresses are allowed.
+pub fn spawn(remote: Remote, address: &str, roots: Vec<PathBuf>) -> Result<()> {
+    let loopback = address.to_socket_addrs()
+        .with_context(|| format!("Bad address {}", address))?
+        .all(|addr| addr.ip().is_loopback());
+    if !loopback {
+        anyhow::bail!("The MPD server only listens on localhost, not {}", address);
+    }
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("Failed to listen on {}", address))?;
+    accept(listener, remote, roots);
+    Ok(())
+}
+
+fn accept(listener: TcpListener, remote: Remote, roots: Vec<PathBuf>) {
+    // MPD clients poll `status` for a playlist version to know when to
+    // re-fetch the queue.
+    let version = Arc::new(AtomicU32::new(1));
+    let events = remote.events.subscribe();
+    let counter = version.clone();
+    std::thread::spawn(move || {
+        for event in events {
+            if let PlayerEvent::PlaylistChanged = event {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut session = Session {
+                events: remote.events.subscribe(),
+                remote: remote.clone(),
+                roots: roots.clone(),
+                version: version.clone(),
+                pending: BTreeSet::new(),
+            };
+            std::thread::spawn(move || {
+                let _ = session.serve(stream);
+            });
+        }
+    });
+}
+
+struct Session {
+    remote: Remote,
+    roots: Vec<PathBuf>,
+    version: Arc<AtomicU32>,
+    events: Receiver<PlayerEvent>,
+    // Subsystems that changed since the client last asked via `idle`.
+    pending: BTreeSet<&'static str>,
+}
+
+impl Session {
+    fn serve(&mut self, stream: TcpStream) -> Result<()> {
+        // A short read timeout lets us notice events while a client idles.
+        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        writeln!(writer, "OK MPD {}", PROTOCOL_VERSION)?;
+
+        let mut line = String::new();
+        let mut idle: Option<Vec<String>> = None;
+        let mut list: Option<(bool, Vec<String>)> = None;
+        loop {
+            match reader.read_line(&mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) if line.ends_with('\n') => {
+                    let request = std::mem::take(&mut line);
+                    let request = request.trim_end_matches(['\r', '\n']);
+                    if request == "close" {
+                        return Ok(());
+                    }
+                    if idle.is_some() {
+                        // Only `noidle` is allowed while idling.
+                        if request != "noidle" {
+                            return Ok(());
+                        }
+                        idle = None;
+                        writer.write_all(b"OK\n")?;
+                    } else if let Some((ok_mode, commands)) = &mut list {
+                        if request == "command_list_end" {
+                            let response = self.run_list(commands, *ok_mode);
+                            writer.write_all(response.as_bytes())?;
+                            list = None;
+                        } else {
+                            commands.push(request.to_string());
+                        }
+                    } else {
+                        match request {
+                            "command_list_begin" => list = Some((false, Vec::new())),
+                            "command_list_ok_begin" => list = Some((true, Vec::new())),
+                            _ if request.starts_with("idle") => {
+                                idle = Some(tokenize(request).into_iter().skip(1).collect());
+                            }
+                            _ => {
+                                let response = match self.run(request) {
+                                    Ok(body) => body + "OK\n",
+                                    Err(ack) => ack_line(&ack, 0, request),
+                                };
+                                writer.write_all(response.as_bytes())?;
+                            }
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            self.collect_events();
+            if let Some(filter) = &idle {
+                let changed: Vec<&'static str> = self.pending.iter()
+                    .copied()
+                    .filter(|s| filter.is_empty() || filter.iter().any(|f| f == s))
+                    .collect();
+                if !changed.is_empty() {
+                    let mut response = String::new();
+                    for subsystem in changed {
+                        self.pending.remove(subsystem);
+                        let _ = writeln!(response, "changed: {}", subsystem);
+                    }
+                    response.push_str("OK\n");
+                    writer.write_all(response.as_bytes())?;
+                    idle = None;
+                }
+            }
+        }
+    }
+
+    fn collect_events(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            let subsystem = match event {
+                PlayerEvent::VolumeChanged(_) => "mixer",
+                PlayerEvent::PlaylistChanged => "playlist",
+                _ => "player",
+            };
+            self.pending.insert(subsystem);
+        }
+    }
+
+    fn run_list(&mut self, commands: &[String], ok_mode: bool) -> String {
+        let mut response = String::new();
+        for (index, request) in commands.iter().enumerate() {
+            match self.run(request) {
+                Ok(body) => {
+                    response.push_str(&body);
+                    if ok_mode {
+                        response.push_str("list_OK\n");
+                    }
+                }
+                Err(ack) => return response + &ack_line(&ack, index, request),
+            }
+        }
+        response + "OK\n"
+    }
+
+    fn run(&mut self, request: &str) -> Reply {
+        let words = tokenize(request);
+        let Some((name, args)) = words.split_first() else {
+            return Err(Ack::new(ACK_ERROR_UNKNOWN, "No command given"));
+        };
+        let arg = |i: usize| args.get(i).map(String::as_str);
+
+        match name.as_str() {
+            "ping" | "clearerror" => Ok(String::new()),
+            "status" => Ok(self.status()),
+            "currentsong" => {
+                let status = self.remote.status();
+                Ok(match status.track {
+                    Some(track) => self.song(&track, status.index),
+                    None => String::new(),
+                })
+            }
+            "playlistinfo" | "playlistid" | "plchanges" => self.playlist_info(name, arg(0)),
+            "play" | "playid" => match arg(0) {
+                Some(pos) => self.call(Command::Play(Some(parse_number(pos)?))),
+                None => self.call(Command::Play(self.start_index())),
+            },
+            "pause" => match arg(0) {
+                Some("1") => self.call(Command::Pause),
+                Some("0") => self.call(Command::Play(self.start_index())),
+                Some(other) => Err(Ack::new(ACK_ERROR_ARG, format!("Boolean (0/1) expected: {}", other))),
+                None => self.call(Command::Toggle),
+            },
+            "stop" => self.call(Command::Stop),
+            "next" => self.call(Command::Next),
+            "previous" => self.call(Command::Prev),
+            "seek" | "seekid" => {
+                let pos = parse_number(arg(0).unwrap_or(""))?;
+                let time = parse_seconds(arg(1).unwrap_or(""))?;
+                if pos != self.remote.status().index {
+                    self.call(Command::Play(Some(pos)))?;
+                }
+                self.call(Command::Seek(Seek::To(time)))
+            }
+            "seekcur" => {
+                let time = arg(0).unwrap_or("");
+                let seek = if let Some(rest) = time.strip_prefix('+') {
+                    Seek::Forward(parse_seconds(rest)?)
+                } else if let Some(rest) = time.strip_prefix('-') {
+                    Seek::Back(parse_seconds(rest)?)
+                } else {
+                    Seek::To(parse_seconds(time)?)
+                };
+                self.call(Command::Seek(seek))
+            }
+            "setvol" => {
+                let volume = parse_number(arg(0).unwrap_or(""))?;
+                self.call(Command::Volume(Volume::Set(volume.min(100) as u8)))
+            }
+            "getvol" => Ok(format!("volume: {}\n", self.volume())),
+            "random" => match arg(0) {
+                Some("0") => self.call(Command::Shuffle(Some(false))),
+                Some("1") => self.call(Command::Shuffle(Some(true))),
+                _ => Err(Ack::new(ACK_ERROR_ARG, "Boolean (0/1) expected")),
+            },
+            "add" => {
+                let path = self.resolve(arg(0).unwrap_or(""))?;
+                self.call(Command::Add(path))
+            }
+            "clear" => self.call(Command::Empty),
+            "listall" => Ok(self.list_all(arg(0).unwrap_or(""))),
+            "commands" => Ok(SUPPORTED.iter().map(|c| format!("command: {}\n", c)).collect()),
+            "notcommands" | "decoders" | "urlhandlers" => Ok(String::new()),
+            "tagtypes" => Ok(TAG_TYPES.iter().map(|t| format!("tagtype: {}\n", t)).collect()),
+            "outputs" => Ok("outputid: 0\noutputname: default\nplugin: rodio\noutputenabled: 1\n".to_string()),
+            other => Err(Ack::new(ACK_ERROR_UNKNOWN, format!("unknown command \"{}\"", other))),
+        }
+    }
+
+    fn call(&self, command: Command) -> Reply {
+        self.remote
+            .call(command)
+            .map(|_| String::new())
+            .map_err(|err| Ack::new(ACK_ERROR_SYSTEM, err.to_string()))
+    }
+
+    // A stopped player has nothing to resume, so `play` restarts the current track.
+    fn start_index(&self) -> Option<usize> {
+        let status = self.remote.status();
+        (status.state == PlaybackState::Stopped).then_some(status.index)
+    }
+
+    fn volume(&self) -> u32 {
+        (self.remote.player.get_volume() * 100.0).round() as u32
+    }
+
+    fn status(&self) -> String {
+        let status = self.remote.status();
+        let mut out = String::new();
+        let _ = writeln!(out, "volume: {}", self.volume());
+        // The playlist always wraps around at the end.
+        let _ = writeln!(out, "repeat: 1\nrandom: {}\nsingle: 0\nconsume: 0", status.shuffle as u8);
+        let _ = writeln!(out, "playlist: {}", self.version.load(Ordering::Relaxed));
+        let _ = writeln!(out, "playlistlength: {}", status.length);
+        let state = match status.state {
+            PlaybackState::Playing => "play",
+            PlaybackState::Paused => "pause",
+            PlaybackState::Stopped => "stop",
+        };
+        let _ = writeln!(out, "state: {}", state);
+        if status.length > 0 {
+            let _ = writeln!(out, "song: {}\nsongid: {}", status.index, status.index);
+        }
+        if status.state != PlaybackState::Stopped {
+            let duration = status.track.as_ref().and_then(|t| t.duration).unwrap_or_default();
+            let _ = writeln!(out, "time: {}:{}", status.position.as_secs(), duration.as_secs());
+            let _ = writeln!(out, "elapsed: {:.3}", status.position.as_secs_f64());
+            let _ = writeln!(out, "duration: {:.3}", duration.as_secs_f64());
+        }
+        out
+    }
+
+    fn playlist_info(&self, name: &str, arg: Option<&str>) -> Reply {
+        let songs = self.remote.playlist.lock().unwrap().songs().to_vec();
+        // We don't keep a change log, so `plchanges` resends the whole queue.
+        let range = match (name, arg) {
+            ("plchanges", _) | (_, None) => 0..songs.len(),
+            (_, Some(arg)) => parse_range(arg, songs.len())?,
+        };
+        if range.end > songs.len() || range.start > range.end {
+            return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+        }
+        Ok(range
+            .map(|index| self.song(&self.remote.track_info(&songs[index]), index))
+            .collect())
+    }
+
+    fn song(&self, track: &TrackInfo, index: usize) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "file: {}", self.uri(&track.path));
+        let _ = writeln!(out, "Title: {}", track.title);
+        let _ = writeln!(out, "Artist: {}", track.artist);
+        let _ = writeln!(out, "Album: {}", track.album);
+        let _ = writeln!(out, "Genre: {}", track.genre);
+        if let Some(year) = track.year {
+            let _ = writeln!(out, "Date: {}", year);
+        }
+        if let Some(number) = track.track {
+            let _ = writeln!(out, "Track: {}", number);
+        }
+        if let Some(disc) = track.disc {
+            let _ = writeln!(out, "Disc: {}", disc);
+        }
+        if let Some(duration) = track.duration {
+            let _ = writeln!(out, "Time: {}", duration.as_secs());
+            let _ = writeln!(out, "duration: {:.3}", duration.as_secs_f64());
+        }
+        let _ = writeln!(out, "Pos: {}\nId: {}", index, index);
+        out
+    }
+
+    fn list_all(&self, prefix: &str) -> String {
+        let prefix = prefix.trim_matches('/');
+        let library = self.remote.library.read().unwrap();
+        let mut files: Vec<String> = library.all().iter()
+            .map(|track| self.uri(&track.path))
+            .filter(|uri| prefix.is_empty() || uri == prefix || uri.starts_with(&format!("{}/", prefix)))
+            .collect();
+        files.sort();
+
+        // Directories come right before the first file inside them.
+        let mut out = String::new();
+        let mut seen = BTreeSet::new();
+        for file in files {
+            let mut dir = String::new();
+            for part in Path::new(&file).parent().into_iter().flat_map(Path::iter) {
+                if !dir.is_empty() {
+                    dir.push('/');
+                }
+                dir.push_str(&part.to_string_lossy());
+                if seen.insert(dir.clone()) {
+                    let _ = writeln!(out, "directory: {}", dir);
+                }
+            }
+            let _ = writeln!(out, "file: {}", file);
+        }
+        out
+    }
+
+    // Songs are named relative to the music directory they were found in,
+    // like MPD does; anything else keeps its absolute path.
+    fn uri(&self, path: &Path) -> String {
+        self.roots.iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // Only what's inside the music directories can be added, however the
+    // URI is spelled: `..` and symlinks are resolved before checking. The
+    // path handed on is the one under the configured root, as the library
+    // scan would have found it, so the same file isn't added twice.
+    fn resolve(&self, uri: &str) -> std::result::Result<PathBuf, Ack> {
+        let path = Path::new(uri.trim_start_matches("file://"));
+        let candidates: Vec<PathBuf> = if path.is_absolute() {
+            vec![path.to_path_buf()]
+        } else {
+            self.roots.iter().map(|root| root.join(path)).collect()
+        };
+        let found: Vec<PathBuf> = candidates.iter()
+            .filter_map(|candidate| candidate.canonicalize().ok())
+            .collect();
+        if found.is_empty() {
+            return Err(Ack::new(ACK_ERROR_NO_EXIST, "No such directory"));
+        }
+        let roots: Vec<(&PathBuf, PathBuf)> = self.roots.iter()
+            .filter_map(|root| Some((root, root.canonicalize().ok()?)))
+            .collect();
+        found.iter()
+            .find_map(|path| roots.iter().find_map(|(root, canonical)| {
+                Some(root.join(path.strip_prefix(canonical).ok()?))
+            }))
+            .ok_or_else(|| Ack::new(ACK_ERROR_PERMISSION, "Access denied"))
+    }
+}
+
+fn ack_line(ack: &Ack, index: usize, request: &str) -> String {
+    let name = request.split_whitespace().next().unwrap_or("");
+    format!("ACK [{}@{}] {{{}}} {}\n", ack.code, index, name, ack.message)
+}
+
+// Splits a request into words; arguments may be double-quoted with
+// backslash escapes, e.g. add "Some Artist/01 \"Song\".mp3".
+fn tokenize(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => word.extend(chars.next()),
+                    '"' => break,
+                    _ => word.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+        }
+        words.push(word);
+    }
+    words
+}
+
+fn parse_number(text: &str) -> std::result::Result<usize, Ack> {
+    text.parse().map_err(|_| Ack::new(ACK_ERROR_ARG, format!("Integer expected: {}", text)))
+}
+
+fn parse_seconds(text: &str) -> std::result::Result<Duration, Ack> {
+    // Negative, NaN and times too long to represent are all refused.
+    text.parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| Ack::new(ACK_ERROR_ARG, format!("Number expected: {}", text)))
+}
+
+// "5" or "2:7" (end exclusive, open-ended "2:").
+fn parse_range(text: &str, len: usize) -> std::result::Result<std::ops::Range<usize>, Ack> {
+    match text.split_once(':') {
+        Some((start, "")) => Ok(parse_number(start)?..len),
+        Some((start, end)) => Ok(parse_number(start)?..parse_number(end)?),
+        None => {
+            let index = parse_number(text)?;
+            Ok(index..index + 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::remote::Request;
+
+    // Talks to the server over a real socket, like mpc would.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: std::net::SocketAddr) -> Client {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut client = Client { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream };
+            assert_eq!(client.line(), format!("OK MPD {}", PROTOCOL_VERSION));
+            client
+        }
+
+        fn line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line.trim_end().to_string()
+        }
+
+        fn write(&mut self, request: &str) {
+            writeln!(self.writer, "{}", request).unwrap();
+        }
+
+        // The response lines up to and including the final OK or ACK.
+        fn send(&mut self, request: &str) -> Vec<String> {
+            self.write(request);
+            let mut lines = Vec::new();
+            loop {
+                let line = self.line();
+                let done = line == "OK" || line.starts_with("ACK ") || line.is_empty();
+                lines.push(line);
+                if done {
+                    return lines;
+                }
+            }
+        }
+    }
+
+    // Stands in for the main loop: records each command and says it worked.
+    fn answer(requests: mpsc::Receiver<Request>) -> mpsc::Receiver<Command> {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for request in requests {
+                if let Some(reply) = request.reply {
+                    let _ = reply.send(Ok(None));
+                }
+                let _ = tx.send(request.command);
+            }
+        });
+        rx
+    }
+
+    #[test]
+    fn answers_a_client_over_tcp() {
+        let dir = std::env::temp_dir().join(format!("rcmp-mpd-test-{}", std::process::id()));
+        let music = dir.join("music");
+        std::fs::create_dir_all(music.join("Artist")).unwrap();
+        std::fs::write(music.join("Artist/song.mp3"), b"").unwrap();
+        std::fs::write(dir.join("outside.mp3"), b"").unwrap();
+
+        let (remote, requests) = Remote::detached();
+        remote.library.write().unwrap().add_paths(&[music.join("Artist/song.mp3")]);
+        let commands = answer(requests);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        accept(listener, remote, vec![music.clone()]);
+        let mut client = Client::connect(address);
+        let next = || commands.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let status = client.send("status");
+        assert!(status.contains(&"state: stop".to_string()), "{:?}", status);
+        assert!(status.contains(&"playlistlength: 0".to_string()), "{:?}", status);
+        assert_eq!(status.last().unwrap(), "OK");
+
+        assert_eq!(client.send("currentsong"), ["OK"]);
+        assert_eq!(client.send("listall"), ["directory: Artist", "file: Artist/song.mp3", "OK"]);
+
+        assert_eq!(client.send("play 0"), ["OK"]);
+        assert_eq!(next(), Command::Play(Some(0)));
+        assert_eq!(client.send("setvol 140"), ["OK"]);
+        assert_eq!(next(), Command::Volume(Volume::Set(100)));
+        assert_eq!(client.send("seekcur +1.5"), ["OK"]);
+        assert_eq!(next(), Command::Seek(Seek::Forward(Duration::from_millis(1500))));
+        assert_eq!(client.send("seekcur 1e300"), ["ACK [2@0] {seekcur} Number expected: 1e300"]);
+        assert_eq!(client.send("seekcur -5"), ["OK"]);
+        assert_eq!(next(), Command::Seek(Seek::Back(Duration::from_secs(5))));
+
+        assert_eq!(client.send("add \"Artist/song.mp3\""), ["OK"]);
+        assert_eq!(next(), Command::Add(music.join("Artist/song.mp3")));
+        assert_eq!(client.send("add ../outside.mp3"), ["ACK [4@0] {add} Access denied"]);
+        let outside = format!("add {}", dir.join("outside.mp3").display());
+        assert_eq!(client.send(&outside), ["ACK [4@0] {add} Access denied"]);
+        assert_eq!(client.send("add nothing.mp3"), ["ACK [50@0] {add} No such directory"]);
+
+        client.write("command_list_ok_begin");
+        client.write("next");
+        let list = client.send("command_list_end");
+        assert_eq!(list, ["list_OK", "OK"]);
+        assert_eq!(next(), Command::Next);
+        assert_eq!(client.send("frobnicate"), ["ACK [5@0] {frobnicate} unknown command \"frobnicate\""]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use zbus::blocking::connection;
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+
+use crate::command::{Command, Seek, Volume};
+use crate::events::PlayerEvent;
+use crate::player::PlaybackState;
+use crate::remote::{file_uri, uri_path, Remote};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.rust_cli_music_player";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+// org.mpris.MediaPlayer2
+struct Root {
+    remote: Remote,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {
+        self.remote.send(Command::Quit);
+    }
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        "Rust CLI Music Player".to_string()
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".to_string()]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        vec!["audio/mpeg".to_string(), "audio/x-wav".to_string()]
+    }
+}
+
+// org.mpris.MediaPlayer2.Player
+struct Player {
+    remote: Remote,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) {
+        self.remote.send(Command::Next);
+    }
+
+    fn previous(&self) {
+        self.remote.send(Command::Prev);
+    }
+
+    fn pause(&self) {
+        self.remote.send(Command::Pause);
+    }
+
+    fn play_pause(&self) {
+        self.remote.send(Command::Toggle);
+    }
+
+    fn stop(&self) {
+        self.remote.send(Command::Stop);
+    }
+
+    fn play(&self) {
+        // A stopped player has nothing to resume, so start the current track.
+        let status = self.remote.status();
+        match status.state {
+            PlaybackState::Stopped => self.remote.send(Command::Play(Some(status.index))),
+            _ => self.remote.send(Command::Play(None)),
+        }
+    }
+
+    fn seek(&self, offset: i64) {
+        let amount = Duration::from_micros(offset.unsigned_abs());
+        let seek = if offset < 0 { Seek::Back(amount) } else { Seek::Forward(amount) };
+        self.remote.send(Command::Seek(seek));
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        // Per the spec, requests for a track that is no longer current are ignored.
+        let status = self.remote.status();
+        if position < 0 || track_id.as_str() != track_path(status.index).as_str() {
+            return;
+        }
+        self.remote.send(Command::Seek(Seek::To(Duration::from_micros(position as u64))));
+    }
+
+    // Adds the file or folder to the end of the queue and plays the first
+    // track added, as the spec asks.
+    async fn open_uri(&self, uri: String) -> zbus::fdo::Result<()> {
+        let start = self.remote.status().length;
+        let played = async {
+            self.call(Command::Add(uri_path(&uri))).await?;
+            self.call(Command::Play(Some(start))).await
+        };
+        played.await
+            .map(|_| ())
+            .map_err(|err| zbus::fdo::Error::Failed(err.to_string()))
+    }
+
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        match self.remote.player.get_state() {
+            PlaybackState::Playing => "Playing",
+            PlaybackState::Paused => "Paused",
+            PlaybackState::Stopped => "Stopped",
+        }
+        .to_string()
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let status = self.remote.status();
+        let mut metadata = HashMap::new();
+        let mut insert = |key: &str, value: Value<'_>| {
+            if let Ok(value) = value.try_to_owned() {
+                metadata.insert(key.to_string(), value);
+            }
+        };
+        insert("mpris:trackid", Value::from(track_path(status.index)));
+        let Some(track) = status.track else {
+            return metadata;
+        };
+        if let Some(duration) = track.duration {
+            insert("mpris:length", Value::from(duration.as_micros() as i64));
+        }
+        insert("xesam:title", Value::from(track.title));
+        insert("xesam:artist", Value::from(vec![track.artist]));
+        insert("xesam:album", Value::from(track.album));
+        insert("xesam:genre", Value::from(vec![track.genre]));
+        if let Some(number) = track.track {
+            insert("xesam:trackNumber", Value::from(number as i32));
+        }
+        if let Ok(path) = track.path.canonicalize() {
+            insert("xesam:url", Value::from(file_uri(&path)));
+        }
+        metadata
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.remote.player.get_volume() as f64
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&self, volume: f64) {
+        let percent = (volume.clamp(0.0, 1.0) * 100.0).round() as u8;
+        // Wait for the change so the PropertiesChanged signal zbus sends
+        // after a set carries the new value.
+        let _ = self.call(Command::Volume(Volume::Set(percent))).await;
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        self.remote.player.position().as_micros() as i64
+    }
+
+    #[zbus(property)]
+    fn shuffle(&self) -> bool {
+        self.remote.playlist.lock().unwrap().is_shuffled()
+    }
+
+    #[zbus(property)]
+    async fn set_shuffle(&self, shuffle: bool) {
+        let _ = self.call(Command::Shuffle(Some(shuffle))).await;
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn set_rate(&self, _rate: f64) {}
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+impl Player {
+    // Waits for the main loop on a thread of its own, so a busy player
+    // doesn't hold up the bus for every other caller meanwhile.
+    async fn call(&self, command: Command) -> Result<Option<String>> {
+        let remote = self.remote.clone();
+        blocking::unblock(move || remote.call(command)).await
+    }
+}
+
+fn track_path(index: usize) -> ObjectPath<'static> {
+    ObjectPath::from_string_unchecked(format!("/org/mpris/MediaPlayer2/track/{}", index))
+}
+
+// Claims the MPRIS bus name on the session bus and keeps the exported
+// properties in sync with the player from a background thread.
+pub fn spawn(remote: Remote) -> Result<()> {
+    let events = remote.events.subscribe();
+    let connection = connection::Builder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, Root { remote: remote.clone() })?
+        .serve_at(OBJECT_PATH, Player { remote })?
+        .build()
+        .context("Could not connect to the D-Bus session bus")?;
+    let player: InterfaceRef<Player> = connection.object_server().interface(OBJECT_PATH)?;
+
+    std::thread::spawn(move || {
+        // The connection has to stay alive for the name to stay ours.
+        let _connection = connection;
+        for event in events {
+            let _ = zbus::block_on(notify(&player, event));
+        }
+    });
+    Ok(())
+}
+
+async fn notify(player: &InterfaceRef<Player>, event: PlayerEvent) -> zbus::Result<()> {
+    let emitter = player.signal_emitter();
+    let iface = player.get();
+    match event {
+        PlayerEvent::TrackStarted { .. } => {
+            iface.metadata_changed(emitter).await?;
+            iface.playback_status_changed(emitter).await
+        }
+        PlayerEvent::Paused | PlayerEvent::Resumed | PlayerEvent::Stopped => {
+            iface.playback_status_changed(emitter).await
+        }
+        PlayerEvent::VolumeChanged(_) => iface.volume_changed(emitter).await,
+        PlayerEvent::Seeked(position) => Player::seeked(emitter, position.as_micros() as i64).await,
+        PlayerEvent::PlaylistChanged => {
+            iface.shuffle_changed(emitter).await?;
+            iface.metadata_changed(emitter).await
+        }
+        PlayerEvent::TrackEnded { .. } | PlayerEvent::Error { .. } => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::path::PathBuf;
+    use std::process::{Child, Stdio};
+    use std::sync::mpsc;
+    use zbus::blocking::{fdo::PropertiesProxy, Connection, Proxy};
+    use zbus::names::InterfaceName;
+
+    use super::*;
+    use crate::remote::Request;
+
+    // A bus of our own, so the test can't poke at the desktop's players.
+    struct PrivateBus(Child);
+
+    impl PrivateBus {
+        fn start() -> Option<(PrivateBus, String)> {
+            let mut child = std::process::Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(child.stdout.take()?).read_line(&mut address).ok()?;
+            Some((PrivateBus(child), address.trim().to_string()))
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn next_command(requests: &mpsc::Receiver<Request>) -> Command {
+        requests.recv_timeout(Duration::from_secs(5)).expect("a command").command
+    }
+
+    #[test]
+    fn controls_the_player_over_a_private_bus() {
+        let Some((_bus, address)) = PrivateBus::start() else {
+            eprintln!("dbus-daemon not found, skipping");
+            return;
+        };
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", &address);
+
+        let (remote, requests) = Remote::detached();
+        spawn(remote.clone()).unwrap();
+        let connection = Connection::session().unwrap();
+        let player = Proxy::new(&connection, BUS_NAME, OBJECT_PATH, "org.mpris.MediaPlayer2.Player").unwrap();
+
+        player.call_method("Next", &()).unwrap();
+        assert_eq!(next_command(&requests), Command::Next);
+        player.call_method("PlayPause", &()).unwrap();
+        assert_eq!(next_command(&requests), Command::Toggle);
+        // Offsets are in microseconds and keep their fraction of a second.
+        player.call_method("Seek", &(-1_500_000i64)).unwrap();
+        assert_eq!(next_command(&requests), Command::Seek(Seek::Back(Duration::from_millis(1500))));
+
+        let status: String = player.get_property("PlaybackStatus").unwrap();
+        assert_eq!(status, "Stopped");
+        let identity: String = Proxy::new(&connection, BUS_NAME, OBJECT_PATH, "org.mpris.MediaPlayer2").unwrap()
+            .get_property("Identity").unwrap();
+        assert_eq!(identity, "Rust CLI Music Player");
+
+        // Property writes wait for the main loop, so answer like it would.
+        let main_loop = std::thread::spawn(move || {
+            let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+            if let Some(reply) = request.reply {
+                let _ = reply.send(Ok(None));
+            }
+            (request.command, requests)
+        });
+        player.set_property("Volume", 0.3f64).unwrap();
+        let (command, requests) = main_loop.join().unwrap();
+        assert_eq!(command, Command::Volume(Volume::Set(30)));
+
+        // OpenUri decodes the URI, queues the file and plays it.
+        let main_loop = std::thread::spawn(move || {
+            (0..2).map(|_| {
+                let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+                if let Some(reply) = request.reply {
+                    let _ = reply.send(Ok(None));
+                }
+                request.command
+            }).collect::<Vec<_>>()
+        });
+        player.call_method("OpenUri", &("file:///music/A%20B/caf%C3%A9%231.flac")).unwrap();
+        let path = PathBuf::from("/music/A B/café#1.flac");
+        assert_eq!(main_loop.join().unwrap(), vec![Command::Add(path.clone()), Command::Play(Some(0))]);
+        assert_eq!(file_uri(&path), "file:///music/A%20B/caf%C3%A9%231.flac");
+
+        // Player events come out as PropertiesChanged.
+        let properties = PropertiesProxy::builder(&connection)
+            .destination(BUS_NAME).unwrap()
+            .path(OBJECT_PATH).unwrap()
+            .build()
+            .unwrap();
+        let mut changes = properties.receive_properties_changed().unwrap();
+        remote.events.emit(PlayerEvent::Paused);
+        let change = changes.next().expect("a PropertiesChanged signal");
+        let args = change.args().unwrap();
+        assert_eq!(args.interface_name, InterfaceName::from_static_str_unchecked("org.mpris.MediaPlayer2.Player"));
+        assert!(args.changed_properties.contains_key("PlaybackStatus"));
+    }
+}
@@ -1,38 +1,161 @@
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use rodio::{Decoder, OutputStream, Sink};
-use anyhow::Result;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
+use anyhow::{Context, Result};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PlaybackState {
     Playing,
     Paused,
     Stopped,
 }
 
+// How every file gets decoded, for playback and for analysis alike, so
+// they agree on what's in it.
+pub fn open(path: &Path) -> Result<Decoder<BufReader<File>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Decoder::new(BufReader::new(file)).with_context(|| format!("Failed to decode {}", path.display()))
+}
+
 pub struct AudioPlayer {
-    _stream: OutputStream,
+    _stream_handle: Option<OutputStreamHandle>,
     sink: Arc<Mutex<Sink>>,
     state: Arc<Mutex<PlaybackState>>,
     volume: Arc<Mutex<f32>>,
+    current: Arc<Mutex<Option<PathBuf>>>,
+    clock: Arc<Mutex<PlaybackClock>>,
+    // Bumped on every `play_song` (not on seeks) so listeners can tell a
+    // replay of the same file from the track simply continuing.
+    generation: Arc<Mutex<u64>>,
+    // Length reported by the decoder, when the format knows it up front.
+    duration: Arc<Mutex<Option<Duration>>>,
+}
+
+// rodio 0.17 can't report the sink position, so we keep our own clock:
+// time accumulated before the last pause plus time since the last resume.
+#[derive(Debug, Default)]
+struct PlaybackClock {
+    base: Duration,
+    resumed_at: Option<Instant>,
+}
+
+impl PlaybackClock {
+    fn start_at(&mut self, position: Duration) {
+        self.base = position;
+        self.resumed_at = Some(Instant::now());
+    }
+    
+    fn pause(&mut self) {
+        if let Some(resumed_at) = self.resumed_at.take() {
+            self.base += resumed_at.elapsed();
+        }
+    }
+    
+    fn resume(&mut self) {
+        if self.resumed_at.is_none() {
+            self.resumed_at = Some(Instant::now());
+        }
+    }
+    
+    fn position(&self) -> Duration {
+        self.base + self.resumed_at.map(|r| r.elapsed()).unwrap_or_default()
+    }
+}
+
+// How much of a track has actually been heard, for listeners that sample
+// the position now and then. Only the position moving at (roughly) the
+// speed of the clock counts, so pauses and seeks in either direction don't.
+#[derive(Debug)]
+pub struct ListenTimer {
+    pub heard: Duration,
+    last_position: Duration,
+    last_update: Instant,
+}
+
+impl ListenTimer {
+    pub fn new(position: Duration) -> Self {
+        ListenTimer {
+            heard: Duration::ZERO,
+            last_position: position,
+            last_update: Instant::now(),
+        }
+    }
+
+    // `slack` is how far the position may run ahead of the wall clock
+    // between samples and still count as listening.
+    pub fn update(&mut self, position: Duration, slack: Duration) {
+        let elapsed = self.last_update.elapsed();
+        if position >= self.last_position && position - self.last_position <= elapsed + slack {
+            self.heard += position - self.last_position;
+        }
+        self.last_position = position;
+        self.last_update = Instant::now();
+    }
 }
 
 impl AudioPlayer {
-    pub fn new() -> Result<Self> {
-        let (_stream, stream_handle) = OutputStream::try_default()?;
+    // `output` picks a device by name; `None` uses the system default.
+    pub fn new(output: Option<&str>) -> Result<Self> {
+        let stream_handle = spawn_output_stream(output.map(str::to_string))?;
         let sink = Sink::try_new(&stream_handle)?;
         
-        Ok(AudioPlayer {
-            _stream,
+        Ok(AudioPlayer::with_sink(Some(stream_handle), sink))
+    }
+    
+    // A player with no output device, for tests.
+    #[cfg(test)]
+    pub fn silent() -> Self {
+        AudioPlayer::with_sink(None, Sink::new_idle().0)
+    }
+    
+    fn with_sink(stream_handle: Option<OutputStreamHandle>, sink: Sink) -> Self {
+        AudioPlayer {
+            _stream_handle: stream_handle,
             sink: Arc::new(Mutex::new(sink)),
             state: Arc::new(Mutex::new(PlaybackState::Stopped)),
             volume: Arc::new(Mutex::new(0.5)),
-        })
+            current: Arc::new(Mutex::new(None)),
+            clock: Arc::new(Mutex::new(PlaybackClock::default())),
+            generation: Arc::new(Mutex::new(0)),
+            duration: Arc::new(Mutex::new(None)),
+        }
     }
     
     pub fn play_song(&self, path: &Path) -> Result<()> {
-        let file = std::fs::File::open(path)?;
-        let source = Decoder::new(file)?;
+        self.play_from(path, Duration::ZERO)?;
+        *self.generation.lock().unwrap() += 1;
+        Ok(())
+    }
+    
+    pub fn current_path(&self) -> Option<PathBuf> {
+        self.current.lock().unwrap().clone()
+    }
+    
+    // The playing file was moved; seeking reopens it from the new place.
+    pub fn moved(&self, from: &Path, to: &Path) {
+        let mut current = self.current.lock().unwrap();
+        if current.as_deref() == Some(from) {
+            *current = Some(to.to_path_buf());
+        }
+    }
+    
+    pub fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+    
+    pub fn duration(&self) -> Option<Duration> {
+        *self.duration.lock().unwrap()
+    }
+    
+    fn play_from(&self, path: &Path, position: Duration) -> Result<()> {
+        let decoder = open(path)?;
+        let duration = decoder.total_duration();
+        let source = decoder.skip_duration(position);
         
         {
             let sink = self.sink.lock().unwrap();
@@ -44,24 +167,56 @@ impl AudioPlayer {
         }
         
         *self.state.lock().unwrap() = PlaybackState::Playing;
+        *self.current.lock().unwrap() = Some(path.to_path_buf());
+        *self.duration.lock().unwrap() = duration;
+        self.clock.lock().unwrap().start_at(position);
+        
+        Ok(())
+    }
+    
+    // Seeking re-opens the file and skips ahead, so it works for every format
+    // the decoder handles. Keeps the paused state if we were paused.
+    pub fn seek(&self, position: Duration) -> Result<()> {
+        let path = match self.current.lock().unwrap().clone() {
+            Some(path) => path,
+            None => anyhow::bail!("Nothing is playing"),
+        };
+        let was_paused = matches!(self.get_state(), PlaybackState::Paused);
         
+        self.play_from(&path, position)?;
+        if was_paused {
+            self.pause();
+        }
         Ok(())
     }
     
+    pub fn position(&self) -> Duration {
+        self.clock.lock().unwrap().position()
+    }
+    
     pub fn pause(&self) {
         let sink = self.sink.lock().unwrap();
         sink.pause();
         *self.state.lock().unwrap() = PlaybackState::Paused;
+        self.clock.lock().unwrap().pause();
     }
     
     pub fn resume(&self) {
         let sink = self.sink.lock().unwrap();
         sink.play();
         *self.state.lock().unwrap() = PlaybackState::Playing;
+        self.clock.lock().unwrap().resume();
+    }
+    
+    pub fn stop(&self) {
+        let sink = self.sink.lock().unwrap();
+        sink.stop();
+        *self.state.lock().unwrap() = PlaybackState::Stopped;
+        *self.clock.lock().unwrap() = PlaybackClock::default();
     }
     
     pub fn toggle_pause(&self) {
-        let current_state = self.state.lock().unwrap().clone();
+        let current_state = *self.state.lock().unwrap();
         match current_state {
             PlaybackState::Playing => self.pause(),
             PlaybackState::Paused => self.resume(),
@@ -94,7 +249,7 @@ impl AudioPlayer {
     }
     
     pub fn get_state(&self) -> PlaybackState {
-        self.state.lock().unwrap().clone()
+        *self.state.lock().unwrap()
     }
     
     pub fn is_finished(&self) -> bool {
@@ -103,3 +258,44 @@ impl AudioPlayer {
     }
     
 }
+
+// The cpal stream behind `OutputStream` is not `Send`, so it lives on its own
+// parked thread for the life of the process and only the handle is shared.
+fn spawn_output_stream(output: Option<String>) -> Result<OutputStreamHandle> {
+    let (tx, rx) = mpsc::channel();
+    
+    thread::spawn(move || match open_output(output.as_deref()) {
+        Ok((_stream, handle)) => {
+            let _ = tx.send(Ok(handle));
+            loop {
+                thread::park();
+            }
+        }
+        Err(err) => {
+            let _ = tx.send(Err(err));
+        }
+    });
+    
+    rx.recv()?
+}
+
+fn open_output(name: Option<&str>) -> Result<(OutputStream, OutputStreamHandle)> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(OutputStream::try_default()?),
+    };
+    let device = rodio::cpal::default_host()
+        .output_devices()?
+        .find(|d| d.name().map(|n| n == name).unwrap_or(false));
+    match device {
+        Some(device) => Ok(OutputStream::try_from_device(&device)?),
+        None => anyhow::bail!("Output device not found: {}", name),
+    }
+}
+
+pub fn output_device_names() -> Vec<String> {
+    rodio::cpal::default_host()
+        .output_devices()
+        .map(|devices| devices.filter_map(|d| d.name().ok()).collect())
+        .unwrap_or_default()
+}
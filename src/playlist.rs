@@ -1,45 +1,824 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use globset::GlobSet;
 use walkdir::WalkDir;
-use anyhow::Result;
+use anyhow::{Context, Result};
+
+use crate::config::data_dir;
+use crate::library::{Library, TrackInfo, UserData};
+use crate::scanner::Scanner;
+use crate::smart::Query;
+
+// Edits remembered for undo; older ones are dropped.
+const UNDO_LIMIT: usize = 100;
+// A walk hands over what it found every this many files or this often,
+// whichever comes first.
+const BATCH_SIZE: usize = 500;
+const BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Path,
+    Title,
+    Artist,
+    Album,
+    // Disc then track number.
+    Track,
+    Added,
+    Duration,
+    Genre,
+    Year,
+    Rating,
+    Favorite,
+    Plays,
+    Skips,
+}
+
+impl SortKey {
+    // Also the order the sort key cycles through.
+    pub const NAMES: [&'static str; 13] = [
+        "path", "title", "artist", "album", "track", "added", "duration",
+        "genre", "year", "rating", "favorite", "plays", "skips",
+    ];
+    
+    pub fn parse(name: &str) -> Option<SortKey> {
+        match name {
+            "path" => Some(SortKey::Path),
+            "title" => Some(SortKey::Title),
+            "artist" => Some(SortKey::Artist),
+            "album" => Some(SortKey::Album),
+            "track" => Some(SortKey::Track),
+            "added" => Some(SortKey::Added),
+            "duration" => Some(SortKey::Duration),
+            "genre" => Some(SortKey::Genre),
+            "year" => Some(SortKey::Year),
+            "rating" => Some(SortKey::Rating),
+            "favorite" => Some(SortKey::Favorite),
+            "plays" => Some(SortKey::Plays),
+            "skips" => Some(SortKey::Skips),
+            _ => None,
+        }
+    }
+    
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortKey::Path => "path",
+            SortKey::Title => "title",
+            SortKey::Artist => "artist",
+            SortKey::Album => "album",
+            SortKey::Track => "track",
+            SortKey::Added => "added",
+            SortKey::Duration => "duration",
+            SortKey::Genre => "genre",
+            SortKey::Year => "year",
+            SortKey::Rating => "rating",
+            SortKey::Favorite => "favorite",
+            SortKey::Plays => "plays",
+            SortKey::Skips => "skips",
+        }
+    }
+    
+    // The key after this one, wrapping around.
+    pub fn next(&self) -> SortKey {
+        let index = SortKey::NAMES.iter().position(|name| *name == self.name()).unwrap_or(0);
+        SortKey::parse(SortKey::NAMES[(index + 1) % SortKey::NAMES.len()]).unwrap_or(SortKey::Path)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    pub extensions: Vec<String>,
+    pub exclude: GlobSet,
+    // Directory levels to descend, 1 being only the files directly inside.
+    pub max_depth: Option<usize>,
+    // Include dotfiles and dot directories.
+    pub hidden: bool,
+    // Descend into symlinked directories. Loops are caught and reported
+    // either way; symlinked files are always played.
+    pub follow_symlinks: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            extensions: vec!["mp3".to_string(), "wav".to_string()],
+            exclude: GlobSet::empty(),
+            max_depth: None,
+            hidden: false,
+            follow_symlinks: true,
+        }
+    }
+}
+
+// What a scan found, and the paths it had to skip with the reason why.
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub songs: Vec<PathBuf>,
+    pub unreadable: Vec<(PathBuf, String)>,
+}
+
+impl Scan {
+    pub fn summary(&self) -> Option<String> {
+        unreadable_summary(&self.unreadable)
+    }
+}
+
+// One line for the status bar, e.g. "Skipped 3 unreadable paths
+// (/music/x: Permission denied, ...)".
+pub fn unreadable_summary(unreadable: &[(PathBuf, String)]) -> Option<String> {
+    let (path, reason) = unreadable.first()?;
+    let more = if unreadable.len() > 1 { ", ..." } else { "" };
+    Some(format!("Skipped {} unreadable paths ({}: {}{})", unreadable.len(), path.display(), reason, more))
+}
+
+impl ScanOptions {
+    fn is_supported(&self, path: &Path) -> bool {
+        match path.extension() {
+            Some(extension) => {
+                let ext = extension.to_string_lossy().to_lowercase();
+                self.extensions.contains(&ext)
+            }
+            None => false,
+        }
+    }
+    
+    pub fn describe(&self) -> String {
+        self.extensions.iter()
+            .map(|e| format!(".{}", e))
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+    
+    pub fn scan(&self, roots: &[PathBuf]) -> Scan {
+        let mut scan = Scan::default();
+        self.walk(roots, |found| {
+            scan.songs.extend(found.songs);
+            scan.unreadable.extend(found.unreadable);
+        });
+        scan
+    }
+    
+    // Hands what it finds to `batch` a bit at a time, in the same order
+    // `scan` returns it. Files are taken as they are (no hidden or exclude
+    // check, since they were asked for by name); directories are walked.
+    pub fn walk(&self, roots: &[PathBuf], mut batch: impl FnMut(Scan)) {
+        let mut found = Scan::default();
+        let mut flushed = Instant::now();
+        let mut flush = |found: &mut Scan, force: bool| {
+            if force || found.songs.len() >= BATCH_SIZE || flushed.elapsed() >= BATCH_INTERVAL {
+                if !found.songs.is_empty() || !found.unreadable.is_empty() {
+                    batch(std::mem::take(found));
+                }
+                flushed = Instant::now();
+            }
+        };
+        for root in roots {
+            if root.is_file() {
+                if self.is_supported(root) {
+                    found.songs.push(root.clone());
+                } else {
+                    found.unreadable.push((root.clone(), format!("not a {} file", self.describe())));
+                }
+            } else {
+                self.walk_dir(root, &mut found, &mut flush);
+            }
+        }
+        flush(&mut found, true);
+    }
+    
+    fn walk_dir(&self, dir: &Path, found: &mut Scan, flush: &mut impl FnMut(&mut Scan, bool)) {
+        // Sorting each directory as it's read keeps the walk in natural
+        // order without waiting for the whole tree.
+        let mut walk = WalkDir::new(dir)
+            .follow_links(self.follow_symlinks)
+            .sort_by_key(|entry| natural_key(&entry.file_name().to_string_lossy()));
+        if let Some(depth) = self.max_depth {
+            walk = walk.max_depth(depth);
+        }
+        
+        let entries = walk.into_iter().filter_entry(|e| {
+            // The root was named explicitly, so it's in even when hidden.
+            let hidden = e.depth() > 0 && e.file_name().to_string_lossy().starts_with('.');
+            (self.hidden || !hidden) && !self.exclude.is_match(e.path())
+        });
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let path = entry.path();
+                    if path.is_file() && self.is_supported(path) {
+                        found.songs.push(path.to_path_buf());
+                    }
+                }
+                Err(err) => {
+                    let path = err.path().unwrap_or(dir).to_path_buf();
+                    let reason = match (err.loop_ancestor(), err.io_error()) {
+                        (Some(ancestor), _) => format!("symlink loop back to {}", ancestor.display()),
+                        (None, Some(io)) => io.to_string(),
+                        (None, None) => err.to_string(),
+                    };
+                    found.unreadable.push((path, reason));
+                }
+            }
+            flush(found, false);
+        }
+    }
+}
+
+// One stretch of the list replaced: `removed` came out at `at` and
+// `inserted` went in its place.
+#[derive(Debug, Clone)]
+struct Splice {
+    at: usize,
+    removed: Vec<PathBuf>,
+    inserted: Vec<PathBuf>,
+}
+
+impl Splice {
+    fn apply(&self, songs: &mut Vec<PathBuf>) {
+        songs.splice(self.at..self.at + self.removed.len(), self.inserted.iter().cloned());
+    }
+}
+
+// Splices that take back `splices`, made one after another.
+fn invert(splices: Vec<Splice>) -> Vec<Splice> {
+    splices.into_iter()
+        .rev()
+        .map(|splice| Splice { at: splice.at, removed: splice.inserted, inserted: splice.removed })
+        .collect()
+}
+
+// Makes `splice`, an edit to the shuffled list `shown`, to the unshuffled
+// `order` too and returns the splices that took. Tracks that went are taken
+// out wherever they are, new ones go in after the track they follow in the
+// shuffled list, and tracks that only moved stay where they were.
+fn mirror(order: &mut Vec<PathBuf>, shown: &[PathBuf], splice: &Splice) -> Vec<Splice> {
+    let mut removed = splice.removed.clone();
+    let mut inserted = Vec::new();
+    for song in &splice.inserted {
+        match removed.iter().position(|s| s == song) {
+            Some(i) => {
+                removed.swap_remove(i);
+            }
+            None => inserted.push(song.clone()),
+        }
+    }
+    let mut splices = Vec::new();
+    for song in removed {
+        if let Some(at) = order.iter().position(|s| *s == song) {
+            order.remove(at);
+            splices.push(Splice { at, removed: vec![song], inserted: Vec::new() });
+        }
+    }
+    if !inserted.is_empty() {
+        let at = splice.at.checked_sub(1)
+            .and_then(|i| shown.get(i))
+            .and_then(|before| order.iter().position(|s| s == before))
+            .map_or(0, |i| i + 1);
+        order.splice(at..at, inserted.iter().cloned());
+        splices.push(Splice { at, removed: Vec::new(), inserted });
+    }
+    splices
+}
+
+// A step undo or redo can take.
+#[derive(Debug, Clone)]
+enum Change {
+    // Splices to the list as shown and to the unshuffled order, each in
+    // the order they're made. Only the tracks involved are kept.
+    Edit { shown: Vec<Splice>, unshuffled: Vec<Splice> },
+    // A sort or shuffle moves everything, so the orders from before it are
+    // kept whole.
+    Reorder { songs: Vec<PathBuf>, unshuffled: Option<Vec<PathBuf>> },
+}
+
+impl Change {
+    fn lists_mut(&mut self) -> Vec<&mut Vec<PathBuf>> {
+        match self {
+            Change::Edit { shown, unshuffled } => shown.iter_mut()
+                .chain(unshuffled.iter_mut())
+                .flat_map(|splice| [&mut splice.removed, &mut splice.inserted])
+                .collect(),
+            Change::Reorder { songs, unshuffled } => std::iter::once(songs).chain(unshuffled.iter_mut()).collect(),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Playlist {
     songs: Vec<PathBuf>,
     current_index: usize,
+    unshuffled: Option<Vec<PathBuf>>,
+    unfiltered: Option<Vec<PathBuf>>,
+    scan: ScanOptions,
+    // The smart playlist query the songs came from and the library revision
+    // they were picked at. Editing the list by hand drops the query.
+    query: Option<Query>,
+    query_revision: u64,
+    // The named playlist this is, if any. Edits are saved back to it.
+    name: Option<String>,
+    // The key the list was last sorted by, until it's reordered some other way.
+    sorted: Option<SortKey>,
+    // Changes to take back and undone ones to make again, newest last.
+    undo: Vec<Change>,
+    redo: Vec<Change>,
+    // Still the list a startup scan is filling in.
+    from_scan: bool,
+    // Bumped whenever the songs change, so a running search knows to
+    // look again.
+    revision: u64,
 }
 
 impl Playlist {
-    pub fn new_from_dir(dir: &Path) -> Result<Self> {
-        let mut songs = Vec::new();
+    pub fn with_songs(songs: Vec<PathBuf>, scan: ScanOptions) -> Self {
+        Playlist {
+            songs,
+            current_index: 0,
+            unshuffled: None,
+            unfiltered: None,
+            scan,
+            query: None,
+            query_revision: 0,
+            name: None,
+            sorted: None,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            from_scan: false,
+            revision: 0,
+        }
+    }
+    
+    // `roots` are music directories or single files. They're scanned in
+    // the background and the list fills in through `extend_scan` as the
+    // scanner is polled. Only waits for the first file, so a missing or
+    // empty music dir is still an error up front.
+    pub fn new_from_dirs(roots: &[PathBuf], scan: ScanOptions) -> Result<(Self, Scanner)> {
+        let mut scanner = Scanner::spawn(roots.to_vec(), scan.clone());
+        scanner.wait_for_first();
+        if scanner.progress.found == 0 {
+            anyhow::bail!(scanner.summary().unwrap_or_default());
+        }
+        Ok((Playlist { from_scan: true, ..Playlist::with_songs(Vec::new(), scan) }, scanner))
+    }
+    
+    // A playlist of whatever `query` picks from the library, kept up to
+    // date by `refresh`.
+    pub fn from_query(query: Query, library: &Library, scan: ScanOptions) -> Result<Self> {
+        let mut playlist = Playlist::with_songs(Vec::new(), scan);
+        playlist.load_query(query, library)?;
+        Ok(playlist)
+    }
+    
+    pub fn scan_options(&self) -> &ScanOptions {
+        &self.scan
+    }
+    
+    // Appends songs the scan found, unless the list has been swapped for
+    // another one meanwhile. Returns whether anything was added.
+    pub fn extend_scan(&mut self, songs: Vec<PathBuf>) -> bool {
+        if !self.from_scan || songs.is_empty() {
+            return false;
+        }
+        // While filtered they wait in the full list.
+        if let Some(unfiltered) = &mut self.unfiltered {
+            unfiltered.extend(songs.iter().cloned());
+            self.extend_kept(&songs);
+            return true;
+        }
+        self.append(songs);
+        true
+    }
+    
+    // New tracks go at the end of the list and, while shuffled, of the
+    // unshuffled order too.
+    fn append(&mut self, songs: Vec<PathBuf>) {
+        self.extend_kept(&songs);
+        if let Some(unshuffled) = &mut self.unshuffled {
+            unshuffled.extend(songs.iter().cloned());
+        }
+        self.songs.extend(songs);
+        self.sorted = None;
+        self.revision += 1;
+    }
+    
+    // Orders kept whole for undo get the new tracks as well, so taking back
+    // a sort or shuffle doesn't drop them.
+    fn extend_kept(&mut self, songs: &[PathBuf]) {
+        for change in self.undo.iter_mut().chain(self.redo.iter_mut()) {
+            if let Change::Reorder { songs: kept, unshuffled } = change {
+                kept.extend(songs.iter().cloned());
+                if let Some(unshuffled) = unshuffled {
+                    unshuffled.extend(songs.iter().cloned());
+                }
+            }
+        }
+    }
+    
+    // Swaps in whatever `query` picks from the library, kept up to date
+    // by `refresh`.
+    pub fn load_query(&mut self, query: Query, library: &Library) -> Result<usize> {
+        let songs = query.evaluate(library);
+        if songs.is_empty() {
+            anyhow::bail!("No tracks match {}", query.text());
+        }
+        let count = songs.len();
+        self.unshuffled = None;
+        self.unfiltered = None;
+        self.set_songs_keep_current(songs);
+        self.forget_list();
+        self.query = Some(query);
+        self.query_revision = library.revision();
+        Ok(count)
+    }
+    
+    pub fn query(&self) -> Option<&Query> {
+        self.query.as_ref()
+    }
+    
+    // Re-evaluates a smart playlist once the library has changed (new
+    // files, ratings, play counts). Waits while a filter is on, since the
+    // filtered list isn't the query's. Returns whether it re-evaluated.
+    pub fn refresh(&mut self, library: &Library) -> bool {
+        let query = match &self.query {
+            Some(query) if self.query_revision != library.revision() && self.unfiltered.is_none() => query,
+            _ => return false,
+        };
+        let songs = query.refresh(&self.songs, library);
+        if let Some(unshuffled) = &self.unshuffled {
+            self.unshuffled = Some(query.refresh(unshuffled, library));
+        }
+        self.query_revision = library.revision();
+        self.set_songs_keep_current(songs);
+        self.sorted = None;
+        // Orders kept for undo are from before the new results.
+        self.undo.clear();
+        self.redo.clear();
+        true
+    }
+    
+    // Adds a single file or every supported file under a directory.
+    pub fn add_path(&mut self, path: &Path) -> Result<Scan> {
+        if !path.exists() {
+            anyhow::bail!("Not a music file or directory: {}", path.display());
+        }
+        let added = self.scan.scan(&[path.to_path_buf()]);
+        if added.songs.is_empty() {
+            let reason = added.summary().map(|summary| format!(". {}", summary)).unwrap_or_default();
+            anyhow::bail!("No {} files found in: {}{}", self.scan.describe(), path.display(), reason);
+        }
         
-        for entry in WalkDir::new(dir)
-            .follow_links(true)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            let path = entry.path();
-            if path.is_file() {
-                if let Some(extension) = path.extension() {
-                    let ext = extension.to_string_lossy().to_lowercase();
-                    if ext == "mp3" || ext == "wav" {
-                        songs.push(path.to_path_buf());
+        self.append(added.songs.clone());
+        self.query = None;
+        Ok(added)
+    }
+    
+    pub fn save_m3u(&self, path: &Path) -> Result<()> {
+        write_m3u(path, &self.songs)
+    }
+    
+    // Swaps in a named playlist, starting from its first track.
+    pub fn load_named(&mut self, name: &str) -> Result<usize> {
+        let songs = read_named(name)?;
+        if songs.is_empty() {
+            anyhow::bail!("Playlist {} is empty", name);
+        }
+        let count = songs.len();
+        self.replace(songs);
+        self.name = Some(name.to_string());
+        Ok(count)
+    }
+    
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    
+    pub fn set_name(&mut self, name: Option<String>) {
+        self.name = name;
+    }
+    
+    // Follows files that were moved on disk, wherever they appear,
+    // including the orders and versions kept for later.
+    pub fn rename_paths(&mut self, moves: &HashMap<PathBuf, PathBuf>) {
+        let lists = std::iter::once(&mut self.songs)
+            .chain(self.unshuffled.iter_mut())
+            .chain(self.unfiltered.iter_mut())
+            .chain(self.undo.iter_mut().chain(self.redo.iter_mut()).flat_map(Change::lists_mut));
+        for list in lists {
+            rename_in(list, moves);
+        }
+        self.revision += 1;
+    }
+    
+    // Writes the list back to its named playlist, if it has one.
+    pub fn save_named(&self) -> Result<()> {
+        match &self.name {
+            Some(name) => write_named(name, &self.songs),
+            None => Ok(()),
+        }
+    }
+    
+    // A different list altogether: it's no longer a named playlist or a
+    // query's results, and undo won't go back to the old one.
+    fn forget_list(&mut self) {
+        self.from_scan = false;
+        self.query = None;
+        self.name = None;
+        self.sorted = None;
+        self.undo.clear();
+        self.redo.clear();
+    }
+    
+    // Hand edits work on the list as shown, so they wait while a filter
+    // hides part of it. While shuffled, each splice is made to the
+    // unshuffled order as well. Each edit can be undone.
+    fn edit(&mut self, splices: Vec<Splice>) -> Result<()> {
+        if self.unfiltered.is_some() {
+            anyhow::bail!("Clear the filter before editing the playlist");
+        }
+        let current = self.current().cloned();
+        let index = self.current_index;
+        let mut unshuffled = Vec::new();
+        for splice in &splices {
+            if let Some(order) = &mut self.unshuffled {
+                unshuffled.extend(mirror(order, &self.songs, splice));
+            }
+            splice.apply(&mut self.songs);
+        }
+        self.remember(Change::Edit { shown: invert(splices), unshuffled: invert(unshuffled) });
+        self.keep_place(current, index);
+        self.query = None;
+        Ok(())
+    }
+    
+    // A new change to undo; anything undone before it can't be redone.
+    fn remember(&mut self, change: Change) {
+        self.undo.push(change);
+        if self.undo.len() > UNDO_LIMIT {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+    
+    // Makes a change from the undo or redo stack and returns its opposite.
+    fn apply(&mut self, change: Change) -> Change {
+        match change {
+            Change::Edit { shown, unshuffled } => {
+                for splice in &shown {
+                    splice.apply(&mut self.songs);
+                }
+                if let Some(order) = &mut self.unshuffled {
+                    for splice in &unshuffled {
+                        splice.apply(order);
                     }
                 }
+                Change::Edit { shown: invert(shown), unshuffled: invert(unshuffled) }
+            }
+            Change::Reorder { songs, unshuffled } => Change::Reorder {
+                songs: std::mem::replace(&mut self.songs, songs),
+                unshuffled: std::mem::replace(&mut self.unshuffled, unshuffled),
+            },
+        }
+    }
+    
+    // Keeps the playing track current when it's still on the list, or
+    // stays at the same spot when it was removed so playback carries on
+    // from there.
+    fn keep_place(&mut self, current: Option<PathBuf>, index: usize) {
+        self.current_index = current
+            .and_then(|c| self.songs.iter().position(|s| *s == c))
+            .unwrap_or_else(|| index.min(self.songs.len().saturating_sub(1)));
+        self.sorted = None;
+        self.revision += 1;
+    }
+    
+    pub fn insert(&mut self, index: usize, songs: Vec<PathBuf>) -> Result<()> {
+        let at = index.min(self.songs.len());
+        self.edit(vec![Splice { at, removed: Vec::new(), inserted: songs }])
+    }
+    
+    // Returns the removed tracks, for cut and paste.
+    pub fn remove(&mut self, range: RangeInclusive<usize>) -> Result<Vec<PathBuf>> {
+        if *range.end() >= self.songs.len() {
+            return Ok(Vec::new());
+        }
+        let removed = self.songs[range.clone()].to_vec();
+        self.edit(vec![Splice { at: *range.start(), removed: removed.clone(), inserted: Vec::new() }])?;
+        Ok(removed)
+    }
+    
+    // Drops every entry for any of `paths`. Returns how many went.
+    pub fn remove_paths(&mut self, paths: &HashSet<PathBuf>) -> Result<usize> {
+        // One splice per run of matching tracks, last run first so the
+        // earlier positions still hold as each one is made.
+        let mut splices: Vec<Splice> = Vec::new();
+        for (i, song) in self.songs.iter().enumerate().rev() {
+            if !paths.contains(song) {
+                continue;
+            }
+            match splices.last_mut() {
+                Some(run) if run.at == i + 1 => {
+                    run.at = i;
+                    run.removed.insert(0, song.clone());
+                }
+                _ => splices.push(Splice { at: i, removed: vec![song.clone()], inserted: Vec::new() }),
+            }
+        }
+        let removed = splices.iter().map(|splice| splice.removed.len()).sum();
+        if removed > 0 {
+            self.edit(splices)?;
+        }
+        Ok(removed)
+    }
+    
+    // Moves a block of tracks one place up or down. Returns false at the
+    // top or bottom of the list.
+    pub fn move_range(&mut self, range: RangeInclusive<usize>, up: bool) -> Result<bool> {
+        let (start, end) = (*range.start(), *range.end());
+        if (up && start == 0) || (!up && end + 1 >= self.songs.len()) {
+            return Ok(false);
+        }
+        let span = if up { start - 1..=end } else { start..=end + 1 };
+        let removed = self.songs[span.clone()].to_vec();
+        let mut inserted = removed.clone();
+        if up {
+            inserted.rotate_left(1);
+        } else {
+            inserted.rotate_right(1);
+        }
+        self.edit(vec![Splice { at: *span.start(), removed, inserted }])?;
+        Ok(true)
+    }
+    
+    pub fn undo(&mut self) -> Result<bool> {
+        self.step(true)
+    }
+    
+    pub fn redo(&mut self) -> Result<bool> {
+        self.step(false)
+    }
+    
+    fn step(&mut self, back: bool) -> Result<bool> {
+        if self.unfiltered.is_some() {
+            anyhow::bail!("Clear the filter before editing the playlist");
+        }
+        let change = match if back { self.undo.pop() } else { self.redo.pop() } {
+            Some(change) => change,
+            None => return Ok(false),
+        };
+        let current = self.current().cloned();
+        let index = self.current_index;
+        let opposite = self.apply(change);
+        if back {
+            self.redo.push(opposite);
+        } else {
+            self.undo.push(opposite);
+        }
+        self.keep_place(current, index);
+        self.query = None;
+        Ok(true)
+    }
+    
+    pub fn is_shuffled(&self) -> bool {
+        self.unshuffled.is_some()
+    }
+    
+    pub fn set_shuffle(&mut self, on: bool) {
+        if on == self.is_shuffled() {
+            return;
+        }
+        let before = Change::Reorder { songs: self.songs.clone(), unshuffled: self.unshuffled.clone() };
+        self.remember(before);
+        self.sorted = None;
+        
+        if on {
+            self.revision += 1;
+            self.unshuffled = Some(self.songs.clone());
+            let current = self.current().cloned();
+            shuffle(&mut self.songs);
+            // The playing track moves to the front so the rest of the
+            // shuffled order is still ahead of us.
+            if let Some(current) = current {
+                if let Some(pos) = self.songs.iter().position(|s| *s == current) {
+                    self.songs.swap(0, pos);
+                }
+                self.current_index = 0;
+            }
+        } else if let Some(original) = self.unshuffled.take() {
+            // Edits while shuffled were made to both, so it's the same tracks.
+            self.set_songs_keep_current(original);
+        }
+    }
+    
+    pub fn sort(&mut self, key: SortKey, library: &Library) {
+        let mut songs = self.songs.clone();
+        songs.sort_by_cached_key(|song| sort_key(key, library.get(song), &library.user_data(song), song));
+        let before = Change::Reorder { songs: self.songs.clone(), unshuffled: self.unshuffled.take() };
+        self.remember(before);
+        self.set_songs_keep_current(songs);
+        self.sorted = Some(key);
+    }
+    
+    pub fn sorted_by(&self) -> Option<SortKey> {
+        self.sorted
+    }
+    
+    // Keeps only tracks whose tag `field` contains `value` (case-insensitive).
+    // The counters take a number, or `4+` for "at least 4", and `favorite`
+    // takes yes/no. The full list is remembered so `clear_filter` can bring
+    // it back.
+    pub fn filter(&mut self, field_name: &str, value: &str, library: &Library) -> Result<usize> {
+        let value = value.to_lowercase();
+        let source = self.unfiltered.clone().unwrap_or_else(|| self.songs.clone());
+        
+        let mut songs = Vec::new();
+        for song in &source {
+            let info = library.get(song);
+            let data = library.user_data(song);
+            let text = |value: Option<String>| value.unwrap_or_default().to_lowercase();
+            let matches = match field_name {
+                "path" => text(Some(song.to_string_lossy().to_string())).contains(&value),
+                "title" => text(field(info, |t| t.title.clone())).contains(&value),
+                "artist" => text(field(info, |t| t.artist.clone())).contains(&value),
+                "album" => text(field(info, |t| t.album.clone())).contains(&value),
+                "genre" => text(field(info, |t| t.genre.clone())).contains(&value),
+                "year" => text(field(info, |t| t.year).flatten().map(|y| y.to_string())).contains(&value),
+                "rating" => count_matches(data.rating.unwrap_or(0) as u32, &value)?,
+                "plays" => count_matches(data.plays, &value)?,
+                "skips" => count_matches(data.skips, &value)?,
+                "favorite" => match value.as_str() {
+                    "yes" | "true" => data.favorite,
+                    "no" | "false" => !data.favorite,
+                    _ => anyhow::bail!("favorite takes yes or no"),
+                },
+                _ => anyhow::bail!("Unknown filter field: {}", field_name),
+            };
+            if matches {
+                songs.push(song.clone());
             }
         }
         
         if songs.is_empty() {
-            anyhow::bail!("No .mp3 or .wav files found in directory: {}", dir.display());
+            anyhow::bail!("No tracks match {}={}", field_name, value);
         }
         
-        songs.sort();
-        
-        Ok(Playlist {
-            songs,
-            current_index: 0,
-        })
+        let count = songs.len();
+        self.unfiltered = Some(source);
+        self.unshuffled = None;
+        self.set_songs_keep_current(songs);
+        Ok(count)
     }
     
+    pub fn clear_filter(&mut self) {
+        if let Some(songs) = self.unfiltered.take() {
+            self.unshuffled = None;
+            self.set_songs_keep_current(songs);
+        }
+    }
+    
+    fn set_songs_keep_current(&mut self, songs: Vec<PathBuf>) {
+        let current = self.current().cloned();
+        self.songs = songs;
+        self.current_index = current
+            .and_then(|c| self.songs.iter().position(|s| *s == c))
+            .unwrap_or(0);
+        self.revision += 1;
+    }
+    
+    pub fn replace(&mut self, songs: Vec<PathBuf>) {
+        if !songs.is_empty() {
+            self.songs = songs;
+            self.current_index = 0;
+            self.unshuffled = None;
+            self.unfiltered = None;
+            self.forget_list();
+            self.revision += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.songs.clear();
+        self.current_index = 0;
+        self.unshuffled = None;
+        self.unfiltered = None;
+        self.forget_list();
+        self.revision += 1;
+    }
+
+    pub fn enqueue(&mut self, songs: Vec<PathBuf>) {
+        self.append(songs);
+        self.query = None;
+    }
+
+    pub fn songs(&self) -> &[PathBuf] {
+        &self.songs
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
     pub fn current(&self) -> Option<&PathBuf> {
         self.songs.get(self.current_index)
     }
@@ -100,3 +879,299 @@ impl Playlist {
         }
     }
 }
+
+// (number, text, disc, track, path) so every key sorts with the same tuple
+// type. The number is the year or duration, or negated so the highest or
+// newest come first; tracks that don't have one go last either way. Text
+// and paths compare naturally.
+type SortTuple = (Option<i64>, NaturalKey, Option<u32>, Option<u32>, NaturalKey);
+
+fn sort_key(key: SortKey, info: Option<&TrackInfo>, data: &UserData, path: &Path) -> SortTuple {
+    let path = natural_key(&path.to_string_lossy());
+    let text = |text: &str| natural_key(&text.to_lowercase());
+    let number = |value: Option<i64>| (Some(value.unwrap_or(i64::MAX)), Vec::new(), None, None, path.clone());
+    let highest_first = |value: i64| number(Some(-value));
+    match (key, info) {
+        (SortKey::Rating, _) => highest_first(data.rating.unwrap_or(0) as i64),
+        (SortKey::Favorite, _) => highest_first(data.favorite as i64),
+        (SortKey::Plays, _) => highest_first(data.plays as i64),
+        (SortKey::Skips, _) => highest_first(data.skips as i64),
+        (SortKey::Added, _) => number(data.added.map(|added| -added)),
+        (SortKey::Duration, _) => number(info.and_then(|info| info.duration).map(|duration| duration.as_secs() as i64)),
+        (SortKey::Year, _) => number(info.and_then(|info| info.year).map(i64::from)),
+        (SortKey::Path, _) | (_, None) => (None, Vec::new(), None, None, path),
+        (SortKey::Title, Some(info)) => (None, text(&info.title), None, None, path),
+        (SortKey::Artist, Some(info)) => (
+            None,
+            text(&format!("{}\0{}", info.artist, info.album)),
+            info.disc,
+            info.track,
+            path,
+        ),
+        (SortKey::Album, Some(info)) => (None, text(&info.album), info.disc, info.track, path),
+        (SortKey::Track, Some(info)) => (None, Vec::new(), info.disc, info.track, path),
+        (SortKey::Genre, Some(info)) => (None, text(&info.genre), None, None, path),
+    }
+}
+
+// Runs of digits compare by value, so "track2" sorts before "track10".
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum NaturalChunk {
+    // Digit count (without leading zeros), then the digits: no overflow on
+    // long runs.
+    Number(usize, String),
+    Text(String),
+}
+
+type NaturalKey = Vec<NaturalChunk>;
+
+fn natural_key(text: &str) -> NaturalKey {
+    let mut chunks = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let digit = c.is_ascii_digit();
+        let mut run = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_digit() == digit) {
+            run.push(c);
+        }
+        chunks.push(if digit {
+            let digits = run.trim_start_matches('0').to_string();
+            NaturalChunk::Number(digits.len(), digits)
+        } else {
+            NaturalChunk::Text(run)
+        });
+    }
+    chunks
+}
+
+fn field<T>(info: Option<&TrackInfo>, get: impl Fn(&TrackInfo) -> T) -> Option<T> {
+    info.map(get)
+}
+
+// "3" matches exactly 3, "3+" matches 3 or more.
+fn count_matches(count: u32, value: &str) -> Result<bool> {
+    let (number, at_least) = match value.strip_suffix('+') {
+        Some(number) => (number, true),
+        None => (value, false),
+    };
+    let number: u32 = number.trim().parse()
+        .map_err(|_| anyhow::anyhow!("Expected a number like 3 or 3+, got '{}'", value))?;
+    Ok(if at_least { count >= number } else { count == number })
+}
+
+fn write_m3u(path: &Path, songs: &[PathBuf]) -> Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    writeln!(file, "#EXTM3U")?;
+    for song in songs {
+        writeln!(file, "{}", song.display())?;
+    }
+    Ok(())
+}
+
+// Named playlists are M3U files in `playlists/` in the data directory, one
+// per name. Names are single words so commands can take them.
+fn named_dir() -> Result<PathBuf> {
+    data_dir().map(|dir| dir.join("playlists")).context("No data directory")
+}
+
+fn named_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains(|c: char| c.is_whitespace() || c == '/' || c == '\\') {
+        anyhow::bail!("Invalid playlist name: '{}'", name);
+    }
+    Ok(named_dir()?.join(format!("{}.m3u", name)))
+}
+
+pub fn named_playlists() -> Vec<String> {
+    let entries = match named_dir().and_then(|dir| Ok(std::fs::read_dir(dir)?)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "m3u"))
+        .filter_map(|path| Some(path.file_stem()?.to_string_lossy().to_string()))
+        .filter(|name| named_path(name).is_ok())
+        .collect();
+    names.sort_by_key(|name| name.to_lowercase());
+    names
+}
+
+// Comment and `#EXT` lines are skipped; relative paths are taken from the
+// playlist's own directory, as other players write them.
+pub fn read_named(name: &str) -> Result<Vec<PathBuf>> {
+    let path = named_path(name)?;
+    let content = std::fs::read_to_string(&path).with_context(|| format!("No playlist named '{}'", name))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| dir.join(line))
+        .collect())
+}
+
+pub fn write_named(name: &str, songs: &[PathBuf]) -> Result<()> {
+    let path = named_path(name)?;
+    std::fs::create_dir_all(named_dir()?)?;
+    write_m3u(&path, songs).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// Points saved playlists at files that were moved. Returns how many
+// playlists changed.
+pub fn rename_in_named(moves: &HashMap<PathBuf, PathBuf>) -> Result<usize> {
+    let mut changed = 0;
+    for name in named_playlists() {
+        let mut songs = read_named(&name)?;
+        if rename_in(&mut songs, moves) {
+            write_named(&name, &songs)?;
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+fn rename_in(songs: &mut [PathBuf], moves: &HashMap<PathBuf, PathBuf>) -> bool {
+    let mut renamed = false;
+    for song in songs {
+        if let Some(to) = moves.get(song) {
+            *song = to.clone();
+            renamed = true;
+        }
+    }
+    renamed
+}
+
+pub fn create_named(name: &str, songs: &[PathBuf]) -> Result<()> {
+    if named_path(name)?.exists() {
+        anyhow::bail!("A playlist named '{}' already exists", name);
+    }
+    write_named(name, songs)
+}
+
+pub fn rename_named(old: &str, new: &str) -> Result<()> {
+    let (from, to) = (named_path(old)?, named_path(new)?);
+    if to.exists() {
+        anyhow::bail!("A playlist named '{}' already exists", new);
+    }
+    std::fs::rename(&from, &to).with_context(|| format!("No playlist named '{}'", old))
+}
+
+pub fn delete_named(name: &str) -> Result<()> {
+    std::fs::remove_file(named_path(name)?).with_context(|| format!("No playlist named '{}'", name))
+}
+
+// Fisher-Yates with a small xorshift generator; good enough for a play order
+// and saves pulling in `rand`.
+pub fn shuffle<T>(songs: &mut [T]) {
+    let mut seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0x9E37_79B9_7F4A_7C15)
+        | 1;
+    for i in (1..songs.len()).rev() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let j = (seed % (i as u64 + 1)) as usize;
+        songs.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    fn shuffled(names: &[&str]) -> Playlist {
+        let mut playlist = Playlist::with_songs(paths(names), ScanOptions::default());
+        playlist.set_shuffle(true);
+        playlist
+    }
+
+    fn unshuffled(playlist: &Playlist) -> Vec<PathBuf> {
+        playlist.unshuffled.clone().expect("shuffled")
+    }
+
+    #[test]
+    fn edits_while_shuffled_reach_the_unshuffled_order() {
+        let original = paths(&["a", "b", "c", "d", "e", "f"]);
+        let mut playlist = shuffled(&["a", "b", "c", "d", "e", "f"]);
+        let order = playlist.songs().to_vec();
+        assert_eq!(unshuffled(&playlist), original);
+
+        // Removed tracks come out of the unshuffled order wherever they are.
+        let removed = playlist.remove(1..=2).unwrap();
+        let mut expected: Vec<PathBuf> = original.iter().filter(|song| !removed.contains(song)).cloned().collect();
+        assert_eq!(unshuffled(&playlist), expected);
+
+        // New ones go in after the track they follow in the shown list.
+        let before = playlist.songs()[0].clone();
+        playlist.insert(1, paths(&["new"])).unwrap();
+        let at = expected.iter().position(|song| *song == before).unwrap() + 1;
+        expected.insert(at, PathBuf::from("new"));
+        assert_eq!(unshuffled(&playlist), expected);
+        let edited = playlist.songs().to_vec();
+
+        // Undo and redo take both orders back and forth together.
+        assert!(playlist.undo().unwrap());
+        assert!(playlist.undo().unwrap());
+        assert_eq!(playlist.songs(), order);
+        assert_eq!(unshuffled(&playlist), original);
+        assert!(playlist.redo().unwrap());
+        assert!(playlist.redo().unwrap());
+        assert!(!playlist.redo().unwrap());
+        assert_eq!(playlist.songs(), edited);
+        assert_eq!(unshuffled(&playlist), expected);
+
+        // Turning shuffle off keeps the edits, and undoing that brings
+        // the shuffled order back.
+        playlist.set_shuffle(false);
+        assert_eq!(playlist.songs(), expected);
+        assert!(playlist.undo().unwrap());
+        assert_eq!(playlist.songs(), edited);
+        assert_eq!(unshuffled(&playlist), expected);
+    }
+
+    #[test]
+    fn cut_and_paste_while_shuffled_moves_the_unshuffled_track_too() {
+        let original = paths(&["a", "b", "c", "d", "e", "f"]);
+        let mut playlist = shuffled(&["a", "b", "c", "d", "e", "f"]);
+        let order = playlist.songs().to_vec();
+
+        // Tracks that only moved stay put in the unshuffled order.
+        assert!(playlist.move_range(1..=2, false).unwrap());
+        assert_eq!(unshuffled(&playlist), original);
+        let moved = playlist.songs().to_vec();
+
+        // A cut takes the track out; pasting it at the top puts it first
+        // in the unshuffled order as well, since nothing comes before it.
+        let cut = playlist.remove(5..=5).unwrap();
+        let without: Vec<PathBuf> = original.iter().filter(|song| **song != cut[0]).cloned().collect();
+        assert_eq!(unshuffled(&playlist), without);
+        playlist.insert(0, cut.clone()).unwrap();
+        assert_eq!(playlist.songs()[0], cut[0]);
+        let pasted: Vec<PathBuf> = cut.iter().chain(&without).cloned().collect();
+        assert_eq!(unshuffled(&playlist), pasted);
+        let edited = playlist.songs().to_vec();
+
+        // Undoing the paste and the cut puts the track back where it was
+        // in both lists.
+        assert!(playlist.undo().unwrap());
+        assert_eq!(unshuffled(&playlist), without);
+        assert!(!playlist.songs().contains(&cut[0]));
+        assert!(playlist.undo().unwrap());
+        assert_eq!(playlist.songs(), moved);
+        assert_eq!(unshuffled(&playlist), original);
+        assert!(playlist.undo().unwrap());
+        assert_eq!(playlist.songs(), order);
+
+        for _ in 0..3 {
+            assert!(playlist.redo().unwrap());
+        }
+        assert_eq!(playlist.songs(), edited);
+        assert_eq!(unshuffled(&playlist), pasted);
+    }
+}
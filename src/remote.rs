@@ -0,0 +1,196 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::command::Command;
+use crate::events::EventBus;
+use crate::library::{Library, TrackInfo};
+use crate::player::{AudioPlayer, PlaybackState};
+use crate::playlist::Playlist;
+
+// How long a remote caller waits for the UI/daemon loop to pick up a command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Request {
+    pub command: Command,
+    pub reply: Option<Sender<Result<Option<String>, String>>>,
+}
+
+// What control front-ends (MPRIS, sockets, ...) get to drive the player.
+// Reads go straight to the shared state; anything that changes it is sent
+// as a `Command` to the main loop so it runs through `App::execute` like a
+// typed `:command` would.
+#[derive(Clone)]
+pub struct Remote {
+    pub playlist: Arc<Mutex<Playlist>>,
+    pub player: Arc<AudioPlayer>,
+    pub library: Arc<RwLock<Library>>,
+    pub events: EventBus,
+    requests: Sender<Request>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub state: PlaybackState,
+    pub volume: f32,
+    pub position: Duration,
+    pub index: usize,
+    pub length: usize,
+    pub shuffle: bool,
+    pub track: Option<TrackInfo>,
+}
+
+impl Remote {
+    pub fn new(
+        playlist: Arc<Mutex<Playlist>>,
+        player: Arc<AudioPlayer>,
+        library: Arc<RwLock<Library>>,
+        events: EventBus,
+        requests: Sender<Request>,
+    ) -> Self {
+        Remote { playlist, player, library, events, requests }
+    }
+
+    // A remote over an empty playlist and a silent player, with the
+    // receiving end of its requests standing in for the main loop.
+    #[cfg(test)]
+    pub fn detached() -> (Remote, mpsc::Receiver<Request>) {
+        use crate::playlist::ScanOptions;
+        let (tx, rx) = mpsc::channel();
+        let remote = Remote::new(
+            Arc::new(Mutex::new(Playlist::with_songs(Vec::new(), ScanOptions::default()))),
+            Arc::new(AudioPlayer::silent()),
+            Arc::new(RwLock::new(Library::default())),
+            EventBus::default(),
+            tx,
+        );
+        (remote, rx)
+    }
+
+    pub fn send(&self, command: Command) {
+        let _ = self.requests.send(Request { command, reply: None });
+    }
+
+    // Runs a command on the main loop and waits for its outcome.
+    pub fn call(&self, command: Command) -> Result<Option<String>> {
+        let (tx, rx) = mpsc::channel();
+        self.requests
+            .send(Request { command, reply: Some(tx) })
+            .map_err(|_| anyhow::anyhow!("Player is shutting down"))?;
+        match rx.recv_timeout(REPLY_TIMEOUT) {
+            Ok(result) => result.map_err(anyhow::Error::msg),
+            Err(_) => anyhow::bail!("Timed out waiting for the player"),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let playlist = self.playlist.lock().unwrap();
+        let track = playlist.current().map(|path| self.track_info(path));
+        Status {
+            state: self.player.get_state(),
+            volume: self.player.get_volume(),
+            position: self.player.position(),
+            index: playlist.current_index(),
+            length: playlist.len(),
+            shuffle: playlist.is_shuffled(),
+            track,
+        }
+    }
+
+    pub fn track_info(&self, path: &Path) -> TrackInfo {
+        match self.library.read().unwrap().get(path) {
+            Some(info) => info.clone(),
+            None => TrackInfo::read(path),
+        }
+    }
+
+    pub fn playlist_json(&self) -> Value {
+        let songs = self.playlist.lock().unwrap().songs().to_vec();
+        songs.iter().map(|path| track_json(&self.track_info(path))).collect()
+    }
+}
+
+// JSON shapes shared by the control socket and the HTTP API.
+impl Status {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "state": state_name(self.state),
+            "volume": (self.volume * 100.0).round() as u8,
+            "position": self.position.as_secs_f64(),
+            "index": self.index,
+            "length": self.length,
+            "shuffle": self.shuffle,
+            "track": self.track.as_ref().map(track_json),
+        })
+    }
+}
+
+fn state_name(state: PlaybackState) -> &'static str {
+    match state {
+        PlaybackState::Playing => "playing",
+        PlaybackState::Paused => "paused",
+        PlaybackState::Stopped => "stopped",
+    }
+}
+
+pub fn track_json(track: &TrackInfo) -> Value {
+    json!({
+        "path": track.path.to_string_lossy(),
+        "title": track.title,
+        "artist": track.artist,
+        "album": track.album,
+        "genre": track.genre,
+        "track": track.track,
+        "disc": track.disc,
+        "year": track.year,
+        "duration": track.duration.map(|d| d.as_secs_f64()),
+    })
+}
+
+// `file://` URIs as MPRIS and desktop tools pass them around: the path with
+// anything but unreserved characters and slashes percent-encoded.
+pub fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+// The path in a `file://` URI, or the text itself when it's a plain path.
+pub fn uri_path(uri: &str) -> PathBuf {
+    match uri.strip_prefix("file://") {
+        // A host part, as in file://localhost/home/..., is skipped.
+        Some(rest) => PathBuf::from(percent_decode(&rest[rest.find('/').unwrap_or(0)..])),
+        None => PathBuf::from(uri),
+    }
+}
+
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
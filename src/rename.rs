@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+
+use crate::tags::{self, Field, Values};
+
+// What the editor offers when asked for a pattern.
+pub const DEFAULT_PATTERN: &str = "%artist%/%album%/%track% - %title%";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+// A path layout such as `%artist%/%album%/%track% - %title%`. It covers
+// the last few components of a path, one per `/` plus the file name,
+// without the extension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    text: String,
+    parts: Vec<Part>,
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Result<Pattern> {
+        let pieces: Vec<&str> = text.split('%').collect();
+        if pieces.len().is_multiple_of(2) {
+            anyhow::bail!("Unmatched % in {}", text);
+        }
+        let mut parts = Vec::new();
+        for (i, piece) in pieces.iter().enumerate() {
+            if i % 2 == 0 {
+                if !piece.is_empty() {
+                    parts.push(Part::Text(piece.to_string()));
+                }
+                continue;
+            }
+            let field = Field::parse(piece)
+                .with_context(|| format!("Unknown field %{}% (try {})", piece, Field::NAMES.join(", ")))?;
+            // Without a separator there's no telling where one ends.
+            if let Some(Part::Field(previous)) = parts.last() {
+                anyhow::bail!("Put something between %{}% and %{}%", previous.name(), field.name());
+            }
+            parts.push(Part::Field(field));
+        }
+        if !parts.iter().any(|part| matches!(part, Part::Field(_))) {
+            anyhow::bail!("The pattern needs at least one %field%");
+        }
+        if text.starts_with('/') || text.ends_with('/') || text.contains("//") {
+            anyhow::bail!("Empty path component in {}", text);
+        }
+        // Files stay under the folder the pattern starts from.
+        if text.split('/').any(|component| matches!(component.trim(), "." | "..")) {
+            anyhow::bail!("No . or .. folders in {}", text);
+        }
+        Ok(Pattern { text: text.to_string(), parts })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // How many path components the pattern spans.
+    fn depth(&self) -> usize {
+        let slashes: usize = self.parts.iter()
+            .map(|part| match part {
+                Part::Text(text) => text.matches('/').count(),
+                Part::Field(_) => 0,
+            })
+            .sum();
+        slashes + 1
+    }
+
+    // Tag values from the end of `path`, or `None` when it doesn't fit.
+    // Fields take as little as they can, never cross a `/`, and track and
+    // year only take digits.
+    pub fn read(&self, path: &Path) -> Option<Vec<(Field, String)>> {
+        let stem = path.with_extension("");
+        let components: Vec<String> = stem.components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let tail = components.get(components.len().checked_sub(self.depth())?..)?.join("/");
+        let mut found = Vec::new();
+        match_parts(&self.parts, &tail, &mut found).then_some(found)
+    }
+
+    // The path (relative, without extension) for a file with `values`.
+    fn render(&self, values: &Values) -> Result<String> {
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => path.push_str(text),
+                Part::Field(field) => {
+                    let value = values[*field as usize].as_deref()
+                        .filter(|value| !value.trim().is_empty())
+                        .with_context(|| format!("no {} tag", field.name()))?;
+                    match field {
+                        Field::Track => path.push_str(&format!("{:0>2}", value)),
+                        _ => path.push_str(&safe_name(value)),
+                    }
+                }
+            }
+        }
+        Ok(path)
+    }
+}
+
+fn match_parts(parts: &[Part], text: &str, found: &mut Vec<(Field, String)>) -> bool {
+    let (part, rest) = match parts.split_first() {
+        Some(split) => split,
+        None => return text.is_empty(),
+    };
+    match part {
+        Part::Text(literal) => match text.strip_prefix(literal.as_str()) {
+            Some(text) => match_parts(rest, text, found),
+            None => false,
+        },
+        Part::Field(field) => {
+            let numeric = matches!(field, Field::Track | Field::Year);
+            // Every end after the first character, shortest first.
+            let ends = text.char_indices().map(|(i, _)| i).skip(1).chain([text.len()]);
+            for end in ends.filter(|&end| end > 0) {
+                let value = text[..end].trim();
+                if text[..end].contains('/') || (numeric && !value.chars().all(|c| c.is_ascii_digit())) {
+                    break;
+                }
+                if value.is_empty() {
+                    continue;
+                }
+                // "03" and "3" are the same track number.
+                let value = match numeric {
+                    true => value.trim_start_matches('0').parse::<u32>().unwrap_or(0).to_string(),
+                    false => value.to_string(),
+                };
+                found.push((*field, value));
+                if match_parts(rest, &text[end..], found) {
+                    return true;
+                }
+                found.pop();
+            }
+            false
+        }
+    }
+}
+
+// Tag values can hold characters file names can't.
+fn safe_name(value: &str) -> String {
+    let name: String = value.trim()
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control() { '_' } else { c })
+        .collect();
+    // A leading dot would hide the file from the next scan, and dropping
+    // them keeps ".." from climbing out of the folder. A name left empty
+    // becomes "_" rather than dropping a folder level.
+    match name.trim_start_matches('.').trim() {
+        "" => "_".to_string(),
+        name => name.to_string(),
+    }
+}
+
+// Where each file goes when renamed from its tags, and anything that
+// stands in the way. Nothing moves while there are problems.
+#[derive(Debug, Clone)]
+pub struct RenamePlan {
+    pub pattern: Pattern,
+    pub moves: Vec<(PathBuf, PathBuf)>,
+    pub problems: Vec<String>,
+    // Files already where the pattern puts them.
+    pub unchanged: usize,
+}
+
+impl RenamePlan {
+    pub fn new(paths: &[PathBuf], pattern: Pattern) -> RenamePlan {
+        let mut plan = RenamePlan { pattern, moves: Vec::new(), problems: Vec::new(), unchanged: 0 };
+        let depth = plan.pattern.depth();
+        for path in paths {
+            let target = tags::read_values(path).and_then(|values| {
+                let base = path.ancestors().nth(depth).context("path is too short for the pattern")?;
+                let mut name = plan.pattern.render(&values)?;
+                if let Some(extension) = path.extension() {
+                    name = format!("{}.{}", name, extension.to_string_lossy());
+                }
+                Ok(base.join(name))
+            });
+            match target {
+                Ok(target) if target == *path => plan.unchanged += 1,
+                Ok(target) => plan.moves.push((path.clone(), target)),
+                Err(err) => plan.problems.push(format!("{}: {:#}", path.display(), err)),
+            }
+        }
+
+        let mut targets: HashMap<&PathBuf, &PathBuf> = HashMap::new();
+        for (from, to) in &plan.moves {
+            if let Some(other) = targets.insert(to, from) {
+                plan.problems.push(format!("{} and {} would both become {}", other.display(), from.display(), to.display()));
+            } else if to.exists() {
+                plan.problems.push(format!("{} already exists", to.display()));
+            }
+        }
+        plan
+    }
+
+    // "old → new" for every move, problems first.
+    pub fn preview(&self) -> Vec<String> {
+        self.problems.iter()
+            .map(|problem| format!("! {}", problem))
+            .chain(self.moves.iter().map(|(from, to)| format!("{} → {}", from.display(), to.display())))
+            .collect()
+    }
+
+    // Moves the files, creating folders as needed and removing the ones
+    // left empty. Returns the moves that were made, which is all of them
+    // unless one failed partway.
+    pub fn apply(&self) -> (Vec<(PathBuf, PathBuf)>, Result<()>) {
+        if !self.problems.is_empty() {
+            return (Vec::new(), Err(anyhow::anyhow!("{} problems to sort out first", self.problems.len())));
+        }
+        let mut done = Vec::new();
+        let mut left: HashSet<PathBuf> = HashSet::new();
+        for (from, to) in &self.moves {
+            let moved = to.parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::rename(from, to))
+                .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()));
+            if let Err(err) = moved {
+                return (done, Err(err));
+            }
+            left.extend(from.ancestors().skip(1).take(self.pattern.depth() - 1).map(Path::to_path_buf));
+            done.push((from.clone(), to.clone()));
+        }
+        // Deepest first, so a folder whose only content was an emptied
+        // folder goes too. Folders with anything else in them stay.
+        let mut left: Vec<PathBuf> = left.into_iter().collect();
+        left.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for dir in left {
+            let _ = std::fs::remove_dir(dir);
+        }
+        (done, Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::{Tag, TagLike, Version};
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("rcmp-rename-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        // An MP3 with nothing but a tag, which is all renaming looks at.
+        fn song(&self, name: &str, artist: &str, title: &str, track: u32) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, b"").unwrap();
+            let mut tag = Tag::new();
+            tag.set_artist(artist);
+            tag.set_album("Album");
+            tag.set_title(title);
+            tag.set_track(track);
+            tag.write_to_path(&path, Version::Id3v24).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn pattern(text: &str) -> Pattern {
+        Pattern::parse(text).unwrap()
+    }
+
+    #[test]
+    fn patterns_stay_inside_their_folder() {
+        assert!(Pattern::parse("../%artist%/%title%").is_err());
+        assert!(Pattern::parse("%artist%/./%title%").is_err());
+        assert!(Pattern::parse("%artist%/../%title%").is_err());
+        assert!(Pattern::parse("/%artist%/%title%").is_err());
+        assert!(Pattern::parse("%artist%%title%").is_err());
+        assert!(Pattern::parse("%artist% - %title").is_err());
+        assert!(Pattern::parse("..%artist%/%title%").is_ok());
+    }
+
+    #[test]
+    fn reads_fields_from_the_end_of_a_path() {
+        let pattern = pattern(DEFAULT_PATTERN);
+        assert_eq!(pattern.read(Path::new("/music/Miles Davis/Kind of Blue/02 - Freddie Freeloader.mp3")), Some(vec![
+            (Field::Artist, "Miles Davis".to_string()),
+            (Field::Album, "Kind of Blue".to_string()),
+            (Field::Track, "2".to_string()),
+            (Field::Title, "Freddie Freeloader".to_string()),
+        ]));
+        // Too shallow, or no " - " to split on.
+        assert_eq!(pattern.read(Path::new("02 - Freddie Freeloader.mp3")), None);
+        assert_eq!(pattern.read(Path::new("/music/Miles Davis/Kind of Blue/Freddie Freeloader.mp3")), None);
+
+        // Track and year only take digits, so the title keeps its dash.
+        let pattern = self::pattern("%year% - %track% - %title%");
+        assert_eq!(pattern.read(Path::new("1959 - 03 - So - What.flac")), Some(vec![
+            (Field::Year, "1959".to_string()),
+            (Field::Track, "3".to_string()),
+            (Field::Title, "So - What".to_string()),
+        ]));
+        assert_eq!(pattern.read(Path::new("1959 - three - So What.flac")), None);
+    }
+
+    #[test]
+    fn plans_moves_and_reports_what_stands_in_the_way() {
+        let dir = TempDir::new("plan");
+        let moved = dir.song("incoming/a.mp3", "Artist", "Song", 3);
+        let unchanged = dir.song("Artist/01 - First.mp3", "Artist", "First", 1);
+        let plan = RenamePlan::new(&[moved.clone(), unchanged], pattern("%artist%/%track% - %title%"));
+        assert_eq!(plan.moves, vec![(moved, dir.0.join("Artist/03 - Song.mp3"))]);
+        assert_eq!(plan.unchanged, 1);
+        assert!(plan.problems.is_empty(), "{:?}", plan.problems);
+
+        // Two files with the same tags, and one whose name is taken.
+        let first = dir.song("x/one.mp3", "Artist", "Twice", 4);
+        let second = dir.song("x/two.mp3", "Artist", "Twice", 4);
+        let taken = dir.song("x/three.mp3", "Artist", "First", 1);
+        let plan = RenamePlan::new(&[first.clone(), second.clone(), taken], pattern("%artist%/%track% - %title%"));
+        assert_eq!(plan.problems.len(), 2, "{:?}", plan.problems);
+        assert!(plan.problems[0].contains("would both become"));
+        assert!(plan.problems[1].ends_with("already exists"));
+        assert!(plan.apply().1.is_err());
+        assert!(first.exists() && second.exists());
+    }
+}
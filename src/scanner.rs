@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::library::TrackInfo;
+use crate::playlist::{unreadable_summary, ScanOptions};
+
+// Reading tags is mostly waiting on the disk, but past a handful of
+// threads a spinning disk only gets slower.
+const MAX_WORKERS: usize = 8;
+
+enum Message {
+    Found(Vec<PathBuf>, Vec<(PathBuf, String)>),
+    // A track's tags, with its position in the walk.
+    Read(usize, TrackInfo),
+    Walked,
+}
+
+// What came in since the last poll.
+#[derive(Debug, Default)]
+pub struct ScanBatch {
+    pub songs: Vec<PathBuf>,
+    pub tracks: Vec<TrackInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub found: usize,
+    pub read: usize,
+    // The walk is over, so `found` is the final count.
+    pub walked: bool,
+    started: Instant,
+}
+
+impl Progress {
+    // Files read per second so far.
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.read as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    // Only known once the walk is over and something has been read.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        if !self.walked || rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64((self.found - self.read) as f64 / rate))
+    }
+
+    pub fn ratio(&self) -> f64 {
+        if self.found == 0 {
+            0.0
+        } else {
+            (self.read as f64 / self.found as f64).min(1.0)
+        }
+    }
+}
+
+// A scan running in the background: one thread walks the roots and a
+// pool of workers reads the tags of whatever it finds.
+pub struct Scanner {
+    roots: Vec<PathBuf>,
+    description: String,
+    messages: Receiver<Message>,
+    pub progress: Progress,
+    pub unreadable: Vec<(PathBuf, String)>,
+    // Tags read ahead of an earlier track, held back so tracks reach the
+    // library in walk order.
+    pending: BTreeMap<usize, TrackInfo>,
+    next: usize,
+    // Received but not yet handed out by `poll`.
+    ready: ScanBatch,
+    done: bool,
+}
+
+impl Scanner {
+    pub fn spawn(roots: Vec<PathBuf>, options: ScanOptions) -> Scanner {
+        let (messages_tx, messages) = mpsc::channel();
+        let (jobs_tx, jobs) = mpsc::channel::<(usize, PathBuf)>();
+        let jobs = Arc::new(Mutex::new(jobs));
+
+        let workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(MAX_WORKERS);
+        for _ in 0..workers {
+            let jobs = jobs.clone();
+            let messages_tx = messages_tx.clone();
+            thread::spawn(move || loop {
+                // The lock is only held while waiting for a job, not while reading it.
+                let job = jobs.lock().unwrap().recv();
+                match job {
+                    Ok((index, path)) => {
+                        if messages_tx.send(Message::Read(index, TrackInfo::read(&path))).is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => return,
+                }
+            });
+        }
+
+        let description = options.describe();
+        let walked = roots.clone();
+        thread::spawn(move || walk(&walked, &options, messages_tx, jobs_tx));
+
+        Scanner {
+            roots,
+            description,
+            messages,
+            progress: Progress { found: 0, read: 0, walked: false, started: Instant::now() },
+            unreadable: Vec::new(),
+            pending: BTreeMap::new(),
+            next: 0,
+            ready: ScanBatch::default(),
+            done: false,
+        }
+    }
+
+    // Collects what arrived without waiting for more.
+    pub fn poll(&mut self) -> ScanBatch {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => self.receive(message),
+                Err(TryRecvError::Empty) => break,
+                // Every thread has finished.
+                Err(TryRecvError::Disconnected) => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+        std::mem::take(&mut self.ready)
+    }
+
+    // Blocks until the walk turns up its first file, or ends without one.
+    pub fn wait_for_first(&mut self) {
+        while self.progress.found == 0 && !self.progress.walked {
+            match self.messages.recv() {
+                Ok(message) => self.receive(message),
+                Err(_) => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, message: Message) {
+        match message {
+            Message::Found(songs, unreadable) => {
+                self.progress.found += songs.len();
+                self.unreadable.extend(unreadable);
+                self.ready.songs.extend(songs);
+            }
+            Message::Read(index, track) => {
+                self.progress.read += 1;
+                self.pending.insert(index, track);
+                while let Some(track) = self.pending.remove(&self.next) {
+                    self.ready.tracks.push(track);
+                    self.next += 1;
+                }
+            }
+            Message::Walked => self.progress.walked = true,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // For the status line: why nothing was found, or what was skipped.
+    pub fn summary(&self) -> Option<String> {
+        if self.progress.found > 0 {
+            return unreadable_summary(&self.unreadable);
+        }
+        let roots: Vec<String> = self.roots.iter().map(|d| d.display().to_string()).collect();
+        let reason = unreadable_summary(&self.unreadable).map(|summary| format!(". {}", summary)).unwrap_or_default();
+        Some(format!("No {} files found in: {}{}", self.description, roots.join(", "), reason))
+    }
+}
+
+// Each batch is announced before its files are queued, so `found` never
+// lags behind `read`. Dropping `jobs` when done lets the workers finish.
+fn walk(roots: &[PathBuf], options: &ScanOptions, messages: Sender<Message>, jobs: Sender<(usize, PathBuf)>) {
+    let mut index = 0;
+    options.walk(roots, |found| {
+        let _ = messages.send(Message::Found(found.songs.clone(), found.unreadable));
+        for song in found.songs {
+            let _ = jobs.send((index, song));
+            index += 1;
+        }
+    });
+    let _ = messages.send(Message::Walked);
+}
@@ -0,0 +1,394 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use ureq::Agent;
+
+use crate::config::{data_dir, ScrobbleConfig, ScrobbleService, APP_NAME};
+use crate::events::PlayerEvent;
+use crate::library::{UNKNOWN_ALBUM, UNKNOWN_ARTIST};
+use crate::player::ListenTimer;
+use crate::remote::Remote;
+
+const LISTENBRAINZ_URL: &str = "https://api.listenbrainz.org";
+const LASTFM_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+// The usual rules: a listen counts after half the track or four minutes,
+// whichever comes first, and tracks under 30 seconds never count.
+const MAX_THRESHOLD: Duration = Duration::from_secs(4 * 60);
+const MIN_LENGTH: Duration = Duration::from_secs(30);
+const POLL: Duration = Duration::from_secs(1);
+const RETRY: Duration = Duration::from_secs(60);
+// Last.fm takes at most 50 scrobbles per request.
+const BATCH: usize = 50;
+
+// One listen, as submitted and as stored in the offline queue.
+#[derive(Debug, Clone)]
+struct Listen {
+    artist: String,
+    title: String,
+    album: Option<String>,
+    duration: Option<Duration>,
+    track_number: Option<u32>,
+    listened_at: u64,
+}
+
+impl Listen {
+    fn to_json(&self) -> Value {
+        json!({
+            "artist": self.artist,
+            "title": self.title,
+            "album": self.album,
+            "duration": self.duration.map(|d| d.as_secs()),
+            "track_number": self.track_number,
+            "listened_at": self.listened_at,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Listen> {
+        Some(Listen {
+            artist: value["artist"].as_str()?.to_string(),
+            title: value["title"].as_str()?.to_string(),
+            album: value["album"].as_str().map(str::to_string),
+            duration: value["duration"].as_u64().map(Duration::from_secs),
+            track_number: value["track_number"].as_u64().map(|n| n as u32),
+            listened_at: value["listened_at"].as_u64()?,
+        })
+    }
+}
+
+enum Failure {
+    // The server can't be reached or is having trouble; try again later.
+    Retry,
+    // The server looked at the listens and refused them; retrying won't help.
+    Rejected,
+    // The credentials were refused; nothing will get through until they're
+    // fixed, so keep the queue and stop trying.
+    Auth(u16),
+}
+
+impl From<ureq::Error> for Failure {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::StatusCode(400) => Failure::Rejected,
+            ureq::Error::StatusCode(status @ (401 | 403)) => Failure::Auth(status),
+            _ => Failure::Retry,
+        }
+    }
+}
+
+enum Service {
+    ListenBrainz { url: String, token: String },
+    LastFm(LastFm),
+}
+
+struct LastFm {
+    url: String,
+    api_key: String,
+    api_secret: String,
+    session_key: String,
+}
+
+impl Service {
+    // `None` until the chosen service has its credentials.
+    fn from_config(config: &ScrobbleConfig) -> Option<Service> {
+        match config.service {
+            ScrobbleService::ListenBrainz => Some(Service::ListenBrainz {
+                url: config.url.clone().unwrap_or_else(|| LISTENBRAINZ_URL.to_string()),
+                token: config.token.clone()?,
+            }),
+            ScrobbleService::LastFm => Some(Service::LastFm(LastFm {
+                url: config.url.clone().unwrap_or_else(|| LASTFM_URL.to_string()),
+                api_key: config.api_key.clone()?,
+                api_secret: config.api_secret.clone()?,
+                session_key: config.session_key.clone()?,
+            })),
+        }
+    }
+
+    fn now_playing(&self, agent: &Agent, listen: &Listen) -> Result<(), Failure> {
+        match self {
+            Service::ListenBrainz { url, token } => {
+                let mut payload = listenbrainz_listen(listen);
+                if let Some(payload) = payload.as_object_mut() {
+                    payload.remove("listened_at");
+                }
+                listenbrainz_submit(agent, url, token, "playing_now", vec![payload])
+            }
+            Service::LastFm(lastfm) => {
+                let mut params = vec![("method".to_string(), "track.updateNowPlaying".to_string())];
+                params.extend(lastfm_params(listen, None));
+                lastfm.call(agent, params)
+            }
+        }
+    }
+
+    fn submit(&self, agent: &Agent, listens: &[Listen]) -> Result<(), Failure> {
+        match self {
+            Service::ListenBrainz { url, token } => {
+                let listen_type = if listens.len() == 1 { "single" } else { "import" };
+                let payload = listens.iter().map(listenbrainz_listen).collect();
+                listenbrainz_submit(agent, url, token, listen_type, payload)
+            }
+            Service::LastFm(lastfm) => {
+                let mut params = vec![("method".to_string(), "track.scrobble".to_string())];
+                for (index, listen) in listens.iter().enumerate() {
+                    params.extend(lastfm_params(listen, Some(index)));
+                }
+                lastfm.call(agent, params)
+            }
+        }
+    }
+}
+
+impl LastFm {
+    // Audioscrobbler 2.0: a form POST signed with the API secret.
+    fn call(&self, agent: &Agent, mut params: Vec<(String, String)>) -> Result<(), Failure> {
+        params.push(("api_key".to_string(), self.api_key.clone()));
+        params.push(("sk".to_string(), self.session_key.clone()));
+        params.sort();
+        let mut signature: String = params.iter().map(|(key, value)| format!("{}{}", key, value)).collect();
+        signature.push_str(&self.api_secret);
+        params.push(("api_sig".to_string(), format!("{:x}", md5::compute(signature))));
+        params.push(("format".to_string(), "json".to_string()));
+
+        agent.post(&self.url).send_form(params.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        Ok(())
+    }
+}
+
+fn listenbrainz_listen(listen: &Listen) -> Value {
+    json!({
+        "listened_at": listen.listened_at,
+        "track_metadata": {
+            "artist_name": listen.artist,
+            "track_name": listen.title,
+            "release_name": listen.album,
+            "additional_info": {
+                "duration_ms": listen.duration.map(|d| d.as_millis() as u64),
+                "tracknumber": listen.track_number,
+                "media_player": APP_NAME,
+                "submission_client": APP_NAME,
+                "submission_client_version": env!("CARGO_PKG_VERSION"),
+            },
+        },
+    })
+}
+
+fn listenbrainz_submit(agent: &Agent, url: &str, token: &str, listen_type: &str, payload: Vec<Value>) -> Result<(), Failure> {
+    let body = json!({ "listen_type": listen_type, "payload": payload });
+    agent.post(&format!("{}/1/submit-listens", url.trim_end_matches('/')))
+        .header("Authorization", &format!("Token {}", token))
+        .content_type("application/json")
+        .send(body.to_string())?;
+    Ok(())
+}
+
+// Scrobbles number their fields (`artist[0]`, ...), now-playing doesn't.
+fn lastfm_params(listen: &Listen, index: Option<usize>) -> Vec<(String, String)> {
+    let name = |field: &str| match index {
+        Some(index) => format!("{}[{}]", field, index),
+        None => field.to_string(),
+    };
+    let mut params = vec![
+        (name("artist"), listen.artist.clone()),
+        (name("track"), listen.title.clone()),
+    ];
+    if index.is_some() {
+        params.push((name("timestamp"), listen.listened_at.to_string()));
+    }
+    if let Some(album) = &listen.album {
+        params.push((name("album"), album.clone()));
+    }
+    if let Some(duration) = listen.duration {
+        params.push((name("duration"), duration.as_secs().to_string()));
+    }
+    if let Some(number) = listen.track_number {
+        params.push((name("trackNumber"), number.to_string()));
+    }
+    params
+}
+
+// The track being listened to and how much of it has actually been heard.
+struct Playing {
+    listen: Listen,
+    generation: u64,
+    timer: ListenTimer,
+    scrobbled: bool,
+}
+
+impl Playing {
+    fn threshold(&self) -> Option<Duration> {
+        match self.listen.duration {
+            Some(duration) if duration < MIN_LENGTH => None,
+            Some(duration) => Some((duration / 2).min(MAX_THRESHOLD)),
+            None => Some(MAX_THRESHOLD),
+        }
+    }
+}
+
+struct Scrobbler {
+    service: Service,
+    agent: Agent,
+    // Listens waiting to be submitted, oldest first; mirrored on disk.
+    queue: Vec<Listen>,
+    queue_path: Option<PathBuf>,
+    last_attempt: Instant,
+    // Set once the service refuses our credentials.
+    refused: bool,
+}
+
+impl Scrobbler {
+    fn start(&mut self, remote: &Remote, path: &Path) -> Option<Playing> {
+        let track = remote.track_info(path);
+        // Without an artist tag there's nothing useful to submit.
+        if track.artist == UNKNOWN_ARTIST {
+            return None;
+        }
+        let listen = Listen {
+            artist: track.artist,
+            title: track.title,
+            album: Some(track.album).filter(|album| album != UNKNOWN_ALBUM),
+            // The decoder knows the real length; TLEN is often missing or wrong.
+            duration: remote.player.duration().or(track.duration),
+            track_number: track.track,
+            listened_at: unix_time(),
+        };
+        // Now-playing is best effort and never queued.
+        if !self.refused {
+            let _ = self.service.now_playing(&self.agent, &listen);
+        }
+        Some(Playing {
+            listen,
+            generation: remote.player.generation(),
+            timer: ListenTimer::new(remote.player.position()),
+            scrobbled: false,
+        })
+    }
+
+    fn scrobble(&mut self, remote: &Remote, listen: Listen) {
+        self.queue.push(listen);
+        self.flush(remote);
+    }
+
+    fn flush(&mut self, remote: &Remote) {
+        self.last_attempt = Instant::now();
+        // How many listens are left to send one at a time after a batch
+        // was refused: one bad listen fails the lot, and only it should
+        // be dropped.
+        let mut singly = 0;
+        while !self.queue.is_empty() && !self.refused {
+            let count = if singly > 0 { 1 } else { self.queue.len().min(BATCH) };
+            match self.service.submit(&self.agent, &self.queue[..count]) {
+                Err(Failure::Rejected) if count > 1 => singly = count,
+                Ok(()) | Err(Failure::Rejected) => {
+                    self.queue.drain(..count);
+                    singly = singly.saturating_sub(1);
+                }
+                Err(Failure::Retry) => break,
+                Err(Failure::Auth(status)) => {
+                    self.refused = true;
+                    remote.events.emit(PlayerEvent::Error {
+                        path: None,
+                        message: format!("Scrobbling stopped: the service refused the credentials (HTTP {})", status),
+                    });
+                }
+            }
+        }
+        self.save_queue();
+    }
+
+    fn load_queue(&mut self) {
+        let content = match self.queue_path.as_ref().and_then(|path| std::fs::read_to_string(path).ok()) {
+            Some(content) => content,
+            None => return,
+        };
+        self.queue = content.lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .filter_map(|value| Listen::from_json(&value))
+            .collect();
+    }
+
+    fn save_queue(&self) {
+        let path = match &self.queue_path {
+            Some(path) => path,
+            None => return,
+        };
+        if self.queue.is_empty() {
+            let _ = std::fs::remove_file(path);
+            return;
+        }
+        let content: String = self.queue.iter().map(|listen| format!("{}\n", listen.to_json())).collect();
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        // Written next to the old queue and renamed over it, so a crash
+        // halfway leaves the old one.
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        if std::fs::write(&temp, content).and_then(|_| std::fs::rename(&temp, path)).is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Follows the player from a thread of its own, so slow or unreachable
+// servers never hold up playback. Listens that can't be submitted wait in
+// `scrobbles.jsonl` in the data directory and are retried every minute.
+pub fn spawn(remote: Remote, config: &ScrobbleConfig) {
+    let service = match Service::from_config(config) {
+        Some(service) => service,
+        None => return,
+    };
+    let agent: Agent = Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(10)))
+        .user_agent(format!("{}/{}", APP_NAME, env!("CARGO_PKG_VERSION")))
+        .build()
+        .into();
+    let mut scrobbler = Scrobbler {
+        service,
+        agent,
+        queue: Vec::new(),
+        queue_path: data_dir().map(|dir| dir.join("scrobbles.jsonl")),
+        last_attempt: Instant::now(),
+        refused: false,
+    };
+    scrobbler.load_queue();
+
+    let events = remote.events.subscribe();
+    std::thread::spawn(move || {
+        if !scrobbler.queue.is_empty() {
+            scrobbler.flush(&remote);
+        }
+        let mut playing: Option<Playing> = None;
+        loop {
+            match events.recv_timeout(POLL) {
+                Ok(PlayerEvent::TrackStarted { path }) => playing = scrobbler.start(&remote, &path),
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            if let Some(current) = playing.as_mut() {
+                // A different track is already on; its event is on the way.
+                if current.generation == remote.player.generation() && !current.scrobbled {
+                    if current.listen.duration.is_none() {
+                        current.listen.duration = remote.player.duration();
+                    }
+                    current.timer.update(remote.player.position(), POLL / 2);
+                    if current.threshold().is_some_and(|threshold| current.timer.heard >= threshold) {
+                        current.scrobbled = true;
+                        let listen = current.listen.clone();
+                        scrobbler.scrobble(&remote, listen);
+                    }
+                }
+            }
+            if !scrobbler.queue.is_empty() && !scrobbler.refused && scrobbler.last_attempt.elapsed() >= RETRY {
+                scrobbler.flush(&remote);
+            }
+        }
+    });
+}
@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+use crate::library::Library;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SearchScope {
+    #[default]
+    Playlist,
+    // Every track in the library, whether it's on the playlist or not.
+    Library,
+}
+
+// Incremental fuzzy search over the playlist or the library. Each entry is
+// flattened once into a lowercase "title artist album path" haystack when
+// the search starts; typing then narrows the previous matches instead of
+// rescanning everything. The haystacks are rebuilt when the list they came
+// from changes.
+#[derive(Debug, Default)]
+pub struct Search {
+    pub query: String,
+    pub editing: bool,
+    pub scope: SearchScope,
+    haystacks: Vec<String>,
+    matches: Vec<usize>,
+    best: Option<usize>,
+    scanned_query: String,
+    // The playlist and library revisions the haystacks were built at.
+    built_at: (u64, u64),
+}
+
+impl Search {
+    pub fn start(&mut self, scope: SearchScope, songs: &[PathBuf], library: &Library, playlist_revision: u64) {
+        self.scope = scope;
+        self.build(songs, library, playlist_revision);
+        self.query.clear();
+        self.scanned_query.clear();
+        self.matches.clear();
+        self.best = None;
+        self.editing = true;
+    }
+
+    fn build(&mut self, songs: &[PathBuf], library: &Library, playlist_revision: u64) {
+        let haystack = |path: &Path| {
+            let mut haystack = match library.get(path) {
+                Some(info) => format!("{} {} {} ", info.title, info.artist, info.album),
+                None => String::new(),
+            };
+            haystack.push_str(&path.to_string_lossy());
+            haystack.to_lowercase()
+        };
+        self.haystacks = match self.scope {
+            SearchScope::Playlist => songs.iter().map(|path| haystack(path)).collect(),
+            SearchScope::Library => library.all().iter().map(|track| haystack(&track.path)).collect(),
+        };
+        self.built_at = (playlist_revision, library.revision());
+    }
+
+    // Rebuilds the haystacks and redoes the query when the list being
+    // searched has changed since. Returns whether it did.
+    pub fn refresh(&mut self, songs: &[PathBuf], library: &Library, playlist_revision: u64) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+        let current = match self.scope {
+            SearchScope::Playlist => (playlist_revision, library.revision()),
+            SearchScope::Library => (self.built_at.0, library.revision()),
+        };
+        if current == self.built_at {
+            return false;
+        }
+        self.build(songs, library, playlist_revision);
+        self.scanned_query.clear();
+        self.update();
+        true
+    }
+
+    pub fn clear(&mut self) {
+        *self = Search::default();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.editing || !self.query.is_empty()
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.query.push(c);
+        self.update();
+    }
+
+    pub fn pop(&mut self) {
+        self.query.pop();
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let needle: Vec<char> = self.query.to_lowercase().chars().collect();
+        if needle.is_empty() {
+            self.matches.clear();
+            self.best = None;
+            self.scanned_query.clear();
+            return;
+        }
+
+        // A longer query can only match a subset of what the shorter one did.
+        let narrowing = !self.scanned_query.is_empty() && self.query.starts_with(&self.scanned_query);
+        let candidates: Vec<usize> = if narrowing {
+            std::mem::take(&mut self.matches)
+        } else {
+            (0..self.haystacks.len()).collect()
+        };
+
+        let mut best: Option<(i64, usize)> = None;
+        self.matches = candidates.into_iter()
+            .filter(|&idx| match fuzzy_score(&needle, &self.haystacks[idx]) {
+                Some(score) => {
+                    if best.is_none_or(|(s, _)| score > s) {
+                        best = Some((score, idx));
+                    }
+                    true
+                }
+                None => false,
+            })
+            .collect();
+        self.best = best.map(|(_, idx)| idx);
+        self.scanned_query = self.query.clone();
+    }
+
+    pub fn best(&self) -> Option<usize> {
+        self.best
+    }
+
+    pub fn match_count(&self) -> usize {
+        self.matches.len()
+    }
+
+    pub fn is_match(&self, idx: usize) -> bool {
+        self.matches.binary_search(&idx).is_ok()
+    }
+
+    pub fn next_after(&self, idx: usize) -> Option<usize> {
+        let pos = self.matches.partition_point(|&m| m <= idx);
+        self.matches.get(pos).or_else(|| self.matches.first()).copied()
+    }
+
+    pub fn prev_before(&self, idx: usize) -> Option<usize> {
+        let pos = self.matches.partition_point(|&m| m < idx);
+        if pos == 0 {
+            self.matches.last().copied()
+        } else {
+            self.matches.get(pos - 1).copied()
+        }
+    }
+}
+
+fn is_word_start(prev: Option<char>) -> bool {
+    match prev {
+        None => true,
+        Some(c) => c == ' ' || c == '/' || c == '\\' || c == '-' || c == '_' || c == '.',
+    }
+}
+
+// Greedy subsequence match. Consecutive runs and matches at word starts
+// score higher so "dsotm" prefers "Dark Side of the Moon".
+fn fuzzy_walk(needle: &[char], haystack: &str, mut on_match: impl FnMut(usize)) -> Option<i64> {
+    let mut score = 0i64;
+    let mut ni = 0;
+    let mut prev: Option<char> = None;
+    let mut last_match: Option<usize> = None;
+
+    for (ci, c) in haystack.chars().enumerate() {
+        if ni == needle.len() {
+            break;
+        }
+        if c == needle[ni] {
+            score += 1;
+            if last_match.is_some_and(|l| l + 1 == ci) {
+                score += 5;
+            }
+            if is_word_start(prev) {
+                score += 3;
+            }
+            on_match(ci);
+            last_match = Some(ci);
+            ni += 1;
+        }
+        prev = Some(c);
+    }
+
+    if ni == needle.len() {
+        Some(score)
+    } else {
+        None
+    }
+}
+
+pub fn fuzzy_score(needle: &[char], haystack: &str) -> Option<i64> {
+    fuzzy_walk(needle, haystack, |_| {})
+}
+
+// Character positions in `text` matched by `query`, for highlighting.
+pub fn fuzzy_positions(query: &str, text: &str) -> Vec<usize> {
+    let needle: Vec<char> = query.to_lowercase().chars().collect();
+    let mut positions = Vec::new();
+    if needle.is_empty() {
+        return positions;
+    }
+    // Lowercasing can turn one char into several (İ is i + a combining
+    // dot), so remember which char of `text` each lowercase one came from.
+    let mut lowercase = String::new();
+    let mut origin = Vec::new();
+    for (ci, c) in text.chars().enumerate() {
+        for lower in c.to_lowercase() {
+            lowercase.push(lower);
+            origin.push(ci);
+        }
+    }
+    match fuzzy_walk(&needle, &lowercase, |li| positions.push(origin[li])) {
+        Some(_) => {
+            positions.dedup();
+            positions
+        }
+        None => Vec::new(),
+    }
+}
@@ -0,0 +1,690 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use toml::{Table, Value};
+
+use crate::command::parse_time;
+use crate::config::data_dir;
+use crate::library::{Library, TrackInfo, UserData};
+use crate::playlist::shuffle;
+
+// Fields a query can test and sort on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Path,
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Year,
+    Track,
+    Disc,
+    Duration,
+    Rating,
+    Favorite,
+    Plays,
+    Skips,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Number,
+    Flag,
+}
+
+impl Field {
+    const NAMES: [&'static str; 13] = [
+        "path", "title", "artist", "album", "genre", "year", "track", "disc", "duration",
+        "rating", "favorite", "plays", "skips",
+    ];
+
+    fn parse(name: &str) -> Option<Field> {
+        let field = match name.to_lowercase().as_str() {
+            "path" => Field::Path,
+            "title" => Field::Title,
+            "artist" => Field::Artist,
+            "album" => Field::Album,
+            "genre" => Field::Genre,
+            "year" => Field::Year,
+            "track" => Field::Track,
+            "disc" => Field::Disc,
+            "duration" => Field::Duration,
+            "rating" => Field::Rating,
+            "favorite" => Field::Favorite,
+            "plays" => Field::Plays,
+            "skips" => Field::Skips,
+            _ => return None,
+        };
+        Some(field)
+    }
+
+    fn kind(&self) -> Kind {
+        match self {
+            Field::Path | Field::Title | Field::Artist | Field::Album | Field::Genre => Kind::Text,
+            Field::Favorite => Kind::Flag,
+            _ => Kind::Number,
+        }
+    }
+
+    fn text(&self, track: &TrackInfo) -> String {
+        let text = match self {
+            Field::Path => track.path.to_string_lossy().to_string(),
+            Field::Title => track.title.clone(),
+            Field::Artist => track.artist.clone(),
+            Field::Album => track.album.clone(),
+            Field::Genre => track.genre.clone(),
+            _ => String::new(),
+        };
+        text.to_lowercase()
+    }
+
+    // Unrated tracks count as rated 0; missing tags have no number at all.
+    fn number(&self, track: &TrackInfo, data: &UserData) -> Option<i64> {
+        match self {
+            Field::Year => track.year.map(i64::from),
+            Field::Track => track.track.map(i64::from),
+            Field::Disc => track.disc.map(i64::from),
+            Field::Duration => track.duration.map(|d| d.as_secs() as i64),
+            Field::Rating => Some(data.rating.unwrap_or(0) as i64),
+            Field::Plays => Some(data.plays as i64),
+            Field::Skips => Some(data.skips as i64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    // `:` - contains for text, equals otherwise.
+    Has,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    // Lowercased, as text fields are compared case-insensitively.
+    Text(String),
+    Number(i64),
+    Flag(bool),
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    field: Field,
+    op: Op,
+    value: Operand,
+}
+
+impl Condition {
+    fn matches(&self, track: &TrackInfo, data: &UserData) -> bool {
+        match &self.value {
+            Operand::Text(value) => {
+                let text = self.field.text(track);
+                match self.op {
+                    Op::Has => text.contains(value.as_str()),
+                    Op::Eq => text == *value,
+                    Op::Ne => text != *value,
+                    // Rejected by the parser.
+                    _ => false,
+                }
+            }
+            Operand::Number(value) => match self.field.number(track, data) {
+                Some(number) => match self.op {
+                    Op::Has | Op::Eq => number == *value,
+                    Op::Ne => number != *value,
+                    Op::Lt => number < *value,
+                    Op::Le => number <= *value,
+                    Op::Gt => number > *value,
+                    Op::Ge => number >= *value,
+                },
+                None => false,
+            },
+            Operand::Flag(value) => match self.op {
+                Op::Ne => data.favorite != *value,
+                _ => data.favorite == *value,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Condition(Condition),
+}
+
+impl Expr {
+    fn matches(&self, track: &TrackInfo, data: &UserData) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(track, data) && b.matches(track, data),
+            Expr::Or(a, b) => a.matches(track, data) || b.matches(track, data),
+            Expr::Not(a) => !a.matches(track, data),
+            Expr::Condition(condition) => condition.matches(track, data),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum OrderKey {
+    Random,
+    Field(Field, bool),
+}
+
+// A parsed smart playlist query, e.g.
+//
+//     genre:jazz AND year>=1960 AND rating>=4 ORDER BY random LIMIT 50
+//
+// Conditions are `field:value` (contains, for text) or a comparison with
+// = != < <= > >=. They combine with AND (or just a space), OR, NOT and
+// parentheses; quote values with spaces. ORDER BY takes fields with an
+// optional ASC/DESC, or `random`. Every part is optional, so an empty
+// query is the whole library in scan order.
+#[derive(Debug, Clone)]
+pub struct Query {
+    text: String,
+    filter: Option<Expr>,
+    order: Vec<OrderKey>,
+    limit: Option<usize>,
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Query> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let filter = if parser.at_end() || parser.keyword("ORDER") || parser.keyword("LIMIT") {
+            None
+        } else {
+            Some(parser.or()?)
+        };
+
+        let mut order = Vec::new();
+        if parser.eat_keyword("ORDER") {
+            if !parser.eat_keyword("BY") {
+                anyhow::bail!("Expected BY after ORDER");
+            }
+            loop {
+                order.push(parser.order_key()?);
+                if parser.peek() != Some(&Token::Comma) {
+                    break;
+                }
+                parser.pos += 1;
+            }
+        }
+
+        let mut limit = None;
+        if parser.eat_keyword("LIMIT") {
+            limit = match parser.next() {
+                Some(Token::Word(word)) => Some(word.parse().with_context(|| format!("Invalid LIMIT: {}", word))?),
+                _ => anyhow::bail!("Expected a number after LIMIT"),
+            };
+        }
+
+        if let Some(token) = parser.peek() {
+            anyhow::bail!("Unexpected {}", token.describe());
+        }
+        Ok(Query { text: text.trim().to_string(), filter, order, limit })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn is_random(&self) -> bool {
+        self.order.iter().any(|key| matches!(key, OrderKey::Random))
+    }
+
+    // Matching tracks in query order, cut to LIMIT when `limited`.
+    fn select<'a>(&self, library: &'a Library, limited: bool) -> Vec<&'a TrackInfo> {
+        let mut tracks: Vec<(&TrackInfo, UserData)> = library.all()
+            .iter()
+            .map(|track| (track, library.user_data(&track.path)))
+            .filter(|(track, data)| self.filter.as_ref().is_none_or(|filter| filter.matches(track, data)))
+            .collect();
+
+        // Shuffling first makes `random` the tie-breaker for any other keys.
+        if self.is_random() {
+            shuffle(&mut tracks);
+        }
+        tracks.sort_by(|a, b| {
+            self.order.iter()
+                .map(|key| compare(key, a, b))
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        });
+
+        if let (true, Some(limit)) = (limited, self.limit) {
+            tracks.truncate(limit);
+        }
+        tracks.into_iter().map(|(track, _)| track).collect()
+    }
+
+    pub fn evaluate(&self, library: &Library) -> Vec<PathBuf> {
+        self.select(library, true).into_iter().map(|track| track.path.clone()).collect()
+    }
+
+    // Re-evaluates for a list built from this query earlier. Tracks that
+    // still match keep their place and new matches go at the end, so a
+    // refresh never reorders what's queued. With `ORDER BY random` the
+    // tracks already picked stay while they match rather than a fresh
+    // random set being drawn.
+    pub fn refresh(&self, current: &[PathBuf], library: &Library) -> Vec<PathBuf> {
+        let matches: Vec<PathBuf> = self.select(library, !self.is_random())
+            .into_iter()
+            .map(|track| track.path.clone())
+            .collect();
+        let matching: HashSet<&PathBuf> = matches.iter().collect();
+        let mut songs: Vec<PathBuf> = current.iter().filter(|song| matching.contains(song)).cloned().collect();
+        let kept: HashSet<PathBuf> = songs.iter().cloned().collect();
+        songs.extend(matches.iter().filter(|song| !kept.contains(*song)).cloned());
+        if let Some(limit) = self.limit {
+            songs.truncate(limit);
+        }
+        songs
+    }
+}
+
+fn compare(key: &OrderKey, a: &(&TrackInfo, UserData), b: &(&TrackInfo, UserData)) -> Ordering {
+    let (field, descending) = match key {
+        OrderKey::Random => return Ordering::Equal,
+        OrderKey::Field(field, descending) => (*field, *descending),
+    };
+    let ordering = match field.kind() {
+        Kind::Text => field.text(a.0).cmp(&field.text(b.0)),
+        Kind::Flag => a.1.favorite.cmp(&b.1.favorite),
+        // Tracks without the tag go last either way.
+        Kind::Number => match (field.number(a.0, &a.1), field.number(b.0, &b.1)) {
+            (Some(x), Some(y)) => x.cmp(&y),
+            (Some(_), None) => return Ordering::Less,
+            (None, Some(_)) => return Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        },
+    };
+    if descending { ordering.reverse() } else { ordering }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    Open,
+    Close,
+    Comma,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(word) => format!("'{}'", word),
+            Token::Quoted(text) => format!("\"{}\"", text),
+            Token::Op(_) => "operator".to_string(),
+            Token::Open => "'('".to_string(),
+            Token::Close => "')'".to_string(),
+            Token::Comma => "','".to_string(),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            ':' => Token::Op(Op::Has),
+            '=' => Token::Op(Op::Eq),
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Ne),
+            '<' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '"' | '\'' => {
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(other) => quoted.push(other),
+                        None => anyhow::bail!("Missing closing {}", c),
+                    }
+                }
+                Token::Quoted(quoted)
+            }
+            '!' => anyhow::bail!("Expected != (use NOT to negate)"),
+            c => {
+                // A value right after an operator can hold colons, as in
+                // duration>5:00.
+                let value = matches!(tokens.last(), Some(Token::Op(_)));
+                let mut word = c.to_string();
+                while let Some(next) = chars.next_if(|n| !n.is_whitespace() && !"(),=!<>\"'".contains(*n) && (value || *n != ':')) {
+                    word.push(next);
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat_keyword("OR") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    // AND may be left out: `genre:jazz year>=1960` means both.
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+        loop {
+            if !self.eat_keyword("AND") {
+                let ends = ["OR", "ORDER", "LIMIT"].iter().any(|k| self.keyword(k));
+                if ends || matches!(self.peek(), None | Some(Token::Close)) {
+                    return Ok(expr);
+                }
+            }
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat_keyword("NOT") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let expr = self.or()?;
+            if self.next() != Some(Token::Close) {
+                anyhow::bail!("Missing closing ')'");
+            }
+            return Ok(expr);
+        }
+        self.condition()
+    }
+
+    fn condition(&mut self) -> Result<Expr> {
+        let name = match self.next() {
+            Some(Token::Word(word)) => word,
+            Some(token) => anyhow::bail!("Expected a field, found {}", token.describe()),
+            None => anyhow::bail!("Expected a field at the end of the query"),
+        };
+        let field = Field::parse(&name)
+            .with_context(|| format!("Unknown field '{}' (try {})", name, Field::NAMES.join(", ")))?;
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => anyhow::bail!("Expected : = != < <= > or >= after {}", name),
+        };
+        let value = match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => value,
+            _ => anyhow::bail!("Expected a value after {}", name),
+        };
+
+        let value = match field.kind() {
+            Kind::Text => {
+                if !matches!(op, Op::Has | Op::Eq | Op::Ne) {
+                    anyhow::bail!("{} can only be compared with : = or !=", name);
+                }
+                Operand::Text(value.to_lowercase())
+            }
+            Kind::Number if field == Field::Duration => Operand::Number(parse_time(&value)?.as_secs() as i64),
+            Kind::Number => Operand::Number(value.parse().with_context(|| format!("{} needs a number, got '{}'", name, value))?),
+            Kind::Flag => {
+                if !matches!(op, Op::Has | Op::Eq | Op::Ne) {
+                    anyhow::bail!("{} can only be compared with : = or !=", name);
+                }
+                match value.to_lowercase().as_str() {
+                    "yes" | "true" => Operand::Flag(true),
+                    "no" | "false" => Operand::Flag(false),
+                    _ => anyhow::bail!("{} takes yes or no", name),
+                }
+            }
+        };
+        Ok(Expr::Condition(Condition { field, op, value }))
+    }
+
+    fn order_key(&mut self) -> Result<OrderKey> {
+        let name = match self.next() {
+            Some(Token::Word(word)) => word,
+            _ => anyhow::bail!("Expected a field or random after ORDER BY"),
+        };
+        if name.eq_ignore_ascii_case("random") {
+            return Ok(OrderKey::Random);
+        }
+        let field = Field::parse(&name)
+            .with_context(|| format!("Can't order by '{}' (try random, {})", name, Field::NAMES.join(", ")))?;
+        let descending = if self.eat_keyword("DESC") {
+            true
+        } else {
+            self.eat_keyword("ASC");
+            false
+        };
+        Ok(OrderKey::Field(field, descending))
+    }
+}
+
+// Saved queries live in `smart.toml` in the data directory as
+// `name = "query"`.
+fn saved_path() -> Result<PathBuf> {
+    data_dir().map(|dir| dir.join("smart.toml")).context("No data directory")
+}
+
+fn load_table() -> Result<Table> {
+    let path = saved_path()?;
+    match std::fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content).with_context(|| format!("Failed to parse {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Table::new()),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+fn save_table(table: &Table) -> Result<()> {
+    let path = saved_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, toml::to_string(table)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+pub fn saved_names() -> Vec<String> {
+    load_table().map(|table| table.keys().cloned().collect()).unwrap_or_default()
+}
+
+pub fn saved_query(name: &str) -> Result<Query> {
+    let table = load_table()?;
+    let text = table.get(name)
+        .and_then(Value::as_str)
+        .with_context(|| format!("No smart playlist named '{}'", name))?;
+    Query::parse(text).with_context(|| format!("In smart playlist '{}'", name))
+}
+
+pub fn save(name: &str, query: &Query) -> Result<()> {
+    let mut table = load_table()?;
+    table.insert(name.to_string(), Value::String(query.text().to_string()));
+    save_table(&table)
+}
+
+pub fn delete(name: &str) -> Result<()> {
+    let mut table = load_table()?;
+    if table.remove(name).is_none() {
+        anyhow::bail!("No smart playlist named '{}'", name);
+    }
+    save_table(&table)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn track(name: &str, artist: &str, genre: &str, year: Option<i32>, seconds: u64) -> TrackInfo {
+        TrackInfo {
+            path: PathBuf::from(format!("/music/{}.mp3", name)),
+            title: name.to_string(),
+            artist: artist.to_string(),
+            album: String::new(),
+            genre: genre.to_string(),
+            track: None,
+            disc: None,
+            year,
+            duration: Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    // a to d in scan order; a is rated 5 and b 3.
+    fn library() -> Library {
+        let mut library = Library::default();
+        library.add_tracks(vec![
+            track("a", "Miles Davis", "Jazz", Some(1959), 562),
+            track("b", "John Coltrane", "Jazz", Some(1965), 420),
+            track("c", "Miles Kane", "Rock", Some(2011), 355),
+            track("d", "Bill Evans", "Jazz", None, 180),
+        ]);
+        library.set_rating(Path::new("/music/a.mp3"), Some(5));
+        library.set_rating(Path::new("/music/b.mp3"), Some(3));
+        library
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        paths.iter().map(|path| path.file_stem().unwrap().to_string_lossy().to_string()).collect()
+    }
+
+    fn run(query: &str, library: &Library) -> Vec<String> {
+        names(&Query::parse(query).unwrap().evaluate(library))
+    }
+
+    fn error(query: &str) -> String {
+        Query::parse(query).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn conditions_combine_with_and_or_not_and_parentheses() {
+        let library = library();
+        assert_eq!(run("", &library), ["a", "b", "c", "d"]);
+        assert_eq!(run("artist:miles", &library), ["a", "c"]);
+        assert_eq!(run("genre=jazz year>=1960", &library), ["b"]);
+        assert_eq!(run("genre=jazz AND year>=1960", &library), ["b"]);
+        // AND binds tighter than OR, and NOT tighter than both.
+        assert_eq!(run("artist:miles OR genre:jazz AND year>1960", &library), ["a", "b", "c"]);
+        assert_eq!(run("(artist:miles OR genre:jazz) AND year>1960", &library), ["b", "c"]);
+        assert_eq!(run("NOT genre:jazz OR year=1959", &library), ["a", "c"]);
+        assert_eq!(run("NOT (genre:jazz OR year=1959)", &library), ["c"]);
+        assert_eq!(run("artist!=\"miles davis\" genre:jazz", &library), ["b", "d"]);
+        // Unrated counts as 0; a missing year matches no comparison.
+        assert_eq!(run("rating<3", &library), ["c", "d"]);
+        assert_eq!(run("year<2000", &library), ["a", "b"]);
+    }
+
+    #[test]
+    fn durations_take_minutes_and_seconds() {
+        let library = library();
+        assert_eq!(run("duration>5:00", &library), ["a", "b", "c"]);
+        assert_eq!(run("duration<=7:00 genre:jazz", &library), ["b", "d"]);
+        assert_eq!(run("duration=180", &library), ["d"]);
+        assert_eq!(run("duration>1:00:00", &library), Vec::<String>::new());
+    }
+
+    #[test]
+    fn order_by_and_limit() {
+        let library = library();
+        // Tracks without a year go last either way.
+        assert_eq!(run("genre:jazz ORDER BY year DESC", &library), ["b", "a", "d"]);
+        assert_eq!(run("genre:jazz ORDER BY year", &library), ["a", "b", "d"]);
+        assert_eq!(run("ORDER BY rating DESC, title LIMIT 3", &library), ["a", "b", "c"]);
+        assert_eq!(run("order by artist asc limit 2", &library), ["d", "b"]);
+        assert_eq!(run("LIMIT 1", &library), ["a"]);
+        let mut random = run("ORDER BY random", &library);
+        random.sort();
+        assert_eq!(random, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn mistakes_are_explained() {
+        assert_eq!(error("genre"), "Expected : = != < <= > or >= after genre");
+        assert_eq!(error("genre:"), "Expected a value after genre");
+        assert_eq!(error("genre<jazz"), "genre can only be compared with : = or !=");
+        assert_eq!(error("year>recent"), "year needs a number, got 'recent'");
+        assert_eq!(error("duration>long"), "Invalid time: long");
+        assert_eq!(error("favorite:maybe"), "favorite takes yes or no");
+        assert!(error("mood:happy").starts_with("Unknown field 'mood' (try path, title"));
+        assert_eq!(error("(genre:jazz OR genre:rock"), "Missing closing ')'");
+        assert_eq!(error("genre:jazz)"), "Unexpected ')'");
+        assert_eq!(error("title:\"so what"), "Missing closing \"");
+        assert_eq!(error("genre!jazz"), "Expected != (use NOT to negate)");
+        assert_eq!(error("genre:jazz AND"), "Expected a field at the end of the query");
+        assert_eq!(error("ORDER year"), "Expected BY after ORDER");
+        assert!(error("ORDER BY mood").starts_with("Can't order by 'mood'"));
+        assert_eq!(error("LIMIT"), "Expected a number after LIMIT");
+        assert_eq!(error("LIMIT ten"), "Invalid LIMIT: ten");
+    }
+
+    #[test]
+    fn refresh_keeps_the_order_and_the_limit() {
+        let mut library = library();
+        let paths = |names: &[&str]| -> Vec<PathBuf> {
+            names.iter().map(|name| PathBuf::from(format!("/music/{}.mp3", name))).collect()
+        };
+        let query = Query::parse("rating>=3").unwrap();
+        // Reordered by hand since it was loaded.
+        let current = paths(&["b", "a"]);
+
+        library.set_rating(Path::new("/music/d.mp3"), Some(4));
+        library.set_rating(Path::new("/music/a.mp3"), Some(1));
+        // a no longer matches and goes; d is new and goes at the end.
+        assert_eq!(names(&query.refresh(&current, &library)), ["b", "d"]);
+
+        let limited = Query::parse("rating>=3 LIMIT 2").unwrap();
+        library.set_rating(Path::new("/music/a.mp3"), Some(5));
+        library.set_rating(Path::new("/music/c.mp3"), Some(4));
+        // Full already: nothing new gets in until something leaves.
+        assert_eq!(names(&limited.refresh(&current, &library)), ["b", "a"]);
+        library.set_rating(Path::new("/music/b.mp3"), None);
+        assert_eq!(names(&limited.refresh(&current, &library)), ["a", "c"]);
+    }
+}
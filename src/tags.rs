@@ -0,0 +1,316 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use id3::frame::{ExtendedText, Timestamp};
+use id3::{Tag, TagLike, Version};
+
+use crate::rename::Pattern;
+use crate::vorbis::{self, Comments};
+
+// Formats whose tags we can write, the same tags the library reads: ID3v2,
+// either on its own in an MP3 or as the `id3 ` chunk of a WAV, and Vorbis
+// comments in FLAC and Ogg.
+const WRITABLE: [&str; 5] = ["mp3", "wav", "flac", "ogg", "oga"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    Track,
+    Year,
+    Genre,
+}
+
+impl Field {
+    // Also the order the editor lists them in.
+    pub const ALL: [Field; 6] = [Field::Title, Field::Artist, Field::Album, Field::Track, Field::Year, Field::Genre];
+    pub const NAMES: [&'static str; 6] = ["title", "artist", "album", "track", "year", "genre"];
+
+    pub fn parse(name: &str) -> Option<Field> {
+        Field::NAMES.iter().position(|n| *n == name).map(|i| Field::ALL[i])
+    }
+
+    pub fn name(&self) -> &'static str {
+        Field::NAMES[*self as usize]
+    }
+
+    // The Vorbis comment holding the field.
+    fn comment(&self) -> &'static str {
+        ["TITLE", "ARTIST", "ALBUM", "TRACKNUMBER", "DATE", "GENRE"][*self as usize]
+    }
+}
+
+// A file's values by field, `None` where the tag has no such frame.
+pub type Values = [Option<String>; 6];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    Set(Field, String),
+    Clear(Field),
+    // Track numbers 1, 2, 3... in list order.
+    Number,
+    // Whatever the pattern picks out of each file's path; files it
+    // doesn't fit are left alone.
+    FromPath(Pattern),
+    // Values for particular files, e.g. matched by fingerprint.
+    Suggested(Vec<(PathBuf, Vec<(Field, String)>)>),
+}
+
+// Pending changes to the tags of a set of files. Nothing touches the
+// files until `save`, so the preview can show exactly what will change.
+#[derive(Debug, Clone)]
+pub struct TagEditor {
+    files: Vec<(PathBuf, Values)>,
+    edits: Vec<Edit>,
+}
+
+impl TagEditor {
+    pub fn open(paths: &[PathBuf]) -> Result<TagEditor> {
+        if paths.is_empty() {
+            anyhow::bail!("Nothing selected to edit");
+        }
+        let files = paths.iter()
+            .map(|path| Ok((path.clone(), read_values(path)?)))
+            .collect::<Result<_>>()?;
+        Ok(TagEditor { files, edits: Vec::new() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.iter().map(|(path, _)| path.clone()).collect()
+    }
+
+    pub fn apply(&mut self, edit: Edit) -> Result<()> {
+        match &edit {
+            Edit::Set(field, value) => check_value(*field, value)?,
+            Edit::Suggested(suggestions) => {
+                for (path, values) in suggestions {
+                    for (field, value) in values {
+                        check_value(*field, value).with_context(|| path.display().to_string())?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.edits.push(edit);
+        Ok(())
+    }
+
+    // Drops the last pending edit. Returns false when there was none.
+    pub fn undo(&mut self) -> bool {
+        self.edits.pop().is_some()
+    }
+
+    pub fn has_changes(&self) -> bool {
+        self.files.iter().zip(self.planned()).any(|((_, old), new)| *old != new)
+    }
+
+    fn planned(&self) -> Vec<Values> {
+        let mut planned: Vec<Values> = self.files.iter().map(|(_, values)| values.clone()).collect();
+        for edit in &self.edits {
+            for (i, ((path, _), values)) in self.files.iter().zip(planned.iter_mut()).enumerate() {
+                match edit {
+                    Edit::Set(field, value) => values[*field as usize] = Some(value.clone()),
+                    Edit::Clear(field) => values[*field as usize] = None,
+                    Edit::Number => values[Field::Track as usize] = Some((i + 1).to_string()),
+                    Edit::FromPath(pattern) => {
+                        for (field, value) in pattern.read(path).unwrap_or_default() {
+                            values[field as usize] = Some(value);
+                        }
+                    }
+                    Edit::Suggested(suggestions) => {
+                        let found = suggestions.iter().filter(|(p, _)| p == path).flat_map(|(_, found)| found);
+                        for (field, value) in found {
+                            values[*field as usize] = Some(value.clone());
+                        }
+                    }
+                }
+            }
+        }
+        planned
+    }
+
+    // What the field will be for every file, `None` when they differ, and
+    // whether that's a change.
+    pub fn value(&self, field: Field) -> (Option<Option<String>>, bool) {
+        let planned = self.planned();
+        let first = planned[0][field as usize].clone();
+        let shared = planned.iter().all(|values| values[field as usize] == first).then_some(first);
+        let changed = self.files.iter().zip(&planned).any(|((_, old), new)| old[field as usize] != new[field as usize]);
+        (shared, changed)
+    }
+
+    // One line per changed value: "a.mp3  album: Old → New".
+    pub fn preview(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for ((path, old), new) in self.files.iter().zip(self.planned()) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            for field in Field::ALL {
+                let (old, new) = (&old[field as usize], &new[field as usize]);
+                if old != new {
+                    lines.push(format!("{}  {}: {} → {}", name, field.name(), show(old), show(new)));
+                }
+            }
+        }
+        lines
+    }
+
+    // Writes every file with a change and starts over from the new values.
+    // Returns the files written. Stops at the first failure; files written
+    // before it keep their new tags.
+    pub fn save(&mut self) -> Result<Vec<PathBuf>> {
+        let planned = self.planned();
+        let mut written = Vec::new();
+        for ((path, old), new) in self.files.iter_mut().zip(planned) {
+            if *old != new {
+                write_values(path, old, &new)?;
+                *old = new;
+                written.push(path.clone());
+            }
+        }
+        self.edits.clear();
+        Ok(written)
+    }
+}
+
+// ReplayGain track gain (dB) and peak (fraction of full scale), as the
+// TXXX frames or Vorbis comments foobar2000 and most players read.
+pub fn write_replaygain(path: &Path, gain: f64, peak: f64) -> Result<()> {
+    check_writable(path)?;
+    let values = [
+        ("REPLAYGAIN_TRACK_GAIN", format!("{:.2} dB", gain)),
+        ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", peak)),
+    ];
+    if vorbis::is_vorbis(path) {
+        let mut comments = Comments::read(path)?;
+        for (name, value) in &values {
+            comments.set(name, Some(value));
+        }
+        return comments.write(path);
+    }
+    let mut tag = read_tag(path)?;
+    for (description, value) in values {
+        tag.add_frame(ExtendedText { description: description.to_string(), value });
+    }
+    tag.write_to_path(path, Version::Id3v24)
+        .with_context(|| format!("Failed to write tags to {}", path.display()))
+}
+
+// Track and year are plain numbers, whatever the format: "3", not "3/12",
+// and "1959", not "1959-08-17".
+pub fn check_value(field: Field, value: &str) -> Result<()> {
+    if matches!(field, Field::Track | Field::Year) {
+        number(field, value)?;
+    }
+    Ok(())
+}
+
+fn number(field: Field, value: &str) -> Result<u32> {
+    value.parse().with_context(|| format!("{} takes a number, not '{}'", field.name(), value))
+}
+
+fn show(value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value),
+        None => "(none)".to_string(),
+    }
+}
+
+fn check_writable(path: &Path) -> Result<()> {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    if !WRITABLE.contains(&extension.as_str()) {
+        anyhow::bail!("Can't edit tags in {}: only MP3, WAV, FLAC and Ogg Vorbis are supported", path.display());
+    }
+    Ok(())
+}
+
+fn read_tag(path: &Path) -> Result<Tag> {
+    match Tag::read_from_path(path) {
+        Ok(tag) => Ok(tag),
+        Err(err) if matches!(err.kind, id3::ErrorKind::NoTag) => Ok(Tag::new()),
+        Err(err) => Err(err).with_context(|| format!("Failed to read tags from {}", path.display())),
+    }
+}
+
+pub fn read_values(path: &Path) -> Result<Values> {
+    check_writable(path)?;
+    if vorbis::is_vorbis(path) {
+        let comments = Comments::read(path)?;
+        return Ok(Field::ALL.map(|field| {
+            let value = comments.get(field.comment())?;
+            match field {
+                // "3/12" and "1959-08-17" read as their numbers, like ID3.
+                Field::Track | Field::Year => vorbis::number(value).map(|n| n.to_string()),
+                _ => Some(value.to_string()),
+            }
+        }));
+    }
+    let tag = read_tag(path)?;
+    Ok([
+        tag.title().map(str::to_string),
+        tag.artist().map(str::to_string),
+        tag.album().map(str::to_string),
+        tag.track().map(|n| n.to_string()),
+        // ID3v2.4 keeps the year in the recording date.
+        tag.year().or_else(|| tag.date_recorded().map(|date| date.year)).map(|n| n.to_string()),
+        tag.genre_parsed().map(|g| g.to_string()),
+    ])
+}
+
+// Only the fields that changed are touched, so a full date or a "3/12"
+// track number stays unless it was edited, and other frames (ratings,
+// pictures) stay as they were.
+fn write_values(path: &Path, old: &Values, new: &Values) -> Result<()> {
+    check_writable(path)?;
+    let changed: Vec<Field> = Field::ALL.into_iter().filter(|field| old[*field as usize] != new[*field as usize]).collect();
+    for &field in &changed {
+        if let Some(value) = &new[field as usize] {
+            check_value(field, value).with_context(|| format!("Not writing tags to {}", path.display()))?;
+        }
+    }
+    if vorbis::is_vorbis(path) {
+        let mut comments = Comments::read(path)?;
+        for field in changed {
+            comments.set(field.comment(), new[field as usize].as_deref());
+        }
+        return comments.write(path);
+    }
+    let mut tag = read_tag(path)?;
+    for field in changed {
+        let value = new[field as usize].as_deref();
+        match (field, value) {
+            (Field::Title, Some(value)) => tag.set_title(value),
+            (Field::Title, None) => tag.remove_title(),
+            (Field::Artist, Some(value)) => tag.set_artist(value),
+            (Field::Artist, None) => tag.remove_artist(),
+            (Field::Album, Some(value)) => tag.set_album(value),
+            (Field::Album, None) => tag.remove_album(),
+            (Field::Genre, Some(value)) => tag.set_genre(value),
+            (Field::Genre, None) => tag.remove_genre(),
+            (Field::Track, Some(value)) => tag.set_track(number(field, value)?),
+            (Field::Track, None) => tag.remove_track(),
+            // TYER is gone from ID3v2.4, which we write; the year goes in
+            // the recording date (TDRC) instead.
+            (Field::Year, Some(value)) => {
+                tag.remove_year();
+                tag.set_date_recorded(Timestamp {
+                    year: number(field, value)? as i32,
+                    month: None,
+                    day: None,
+                    hour: None,
+                    minute: None,
+                    second: None,
+                });
+            }
+            (Field::Year, None) => {
+                tag.remove_year();
+                tag.remove_date_recorded();
+            }
+        }
+    }
+    tag.write_to_path(path, Version::Id3v24)
+        .with_context(|| format!("Failed to write tags to {}", path.display()))
+}
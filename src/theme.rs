@@ -0,0 +1,282 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use anyhow::{Context, Result};
+use ratatui::style::{Color, Modifier, Style};
+use toml::{Table, Value};
+
+use crate::command::expand_path;
+
+pub const BUILTIN_THEMES: [&str; 4] = ["default", "high-contrast", "no-color", "ocean"];
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub header: Style,
+    pub title: Style,
+    pub border: Style,
+    pub border_focused: Style,
+    pub text: Style,
+    pub track_name: Style,
+    pub current_track: Style,
+    pub selection: Style,
+    pub selection_inactive: Style,
+    pub status_playing: Style,
+    pub status_paused: Style,
+    pub status_stopped: Style,
+    pub time: Style,
+    pub gauge: Style,
+    pub search_match: Style,
+    pub section: Style,
+    pub footer: Style,
+    pub command_line: Style,
+    pub message: Style,
+}
+
+fn fg(color: Color) -> Style {
+    Style::default().fg(color)
+}
+
+fn bold(style: Style) -> Style {
+    style.add_modifier(Modifier::BOLD)
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            header: bold(fg(Color::Cyan)),
+            title: fg(Color::Cyan),
+            border: Style::default(),
+            border_focused: fg(Color::Cyan),
+            text: fg(Color::White),
+            track_name: bold(fg(Color::White)),
+            current_track: bold(fg(Color::Yellow)),
+            selection: Style::default().bg(Color::DarkGray),
+            selection_inactive: fg(Color::Yellow),
+            status_playing: bold(fg(Color::Green)),
+            status_paused: bold(fg(Color::Yellow)),
+            status_stopped: bold(fg(Color::Red)),
+            time: fg(Color::Cyan),
+            gauge: fg(Color::Cyan),
+            search_match: bold(fg(Color::Green)).add_modifier(Modifier::UNDERLINED),
+            section: bold(fg(Color::Yellow)),
+            footer: fg(Color::Yellow),
+            command_line: fg(Color::White),
+            message: fg(Color::Green),
+        }
+    }
+}
+
+impl Theme {
+    fn high_contrast() -> Self {
+        Theme {
+            header: bold(Style::default().fg(Color::Black).bg(Color::White)),
+            title: bold(fg(Color::White)),
+            border: fg(Color::White),
+            border_focused: bold(fg(Color::LightYellow)),
+            text: fg(Color::White),
+            track_name: bold(fg(Color::White)),
+            current_track: bold(fg(Color::LightYellow)),
+            selection: bold(Style::default().fg(Color::Black).bg(Color::LightYellow)),
+            selection_inactive: bold(fg(Color::LightYellow)),
+            status_playing: bold(fg(Color::LightGreen)),
+            status_paused: bold(fg(Color::LightYellow)),
+            status_stopped: bold(fg(Color::LightRed)),
+            time: bold(fg(Color::White)),
+            gauge: Style::default().fg(Color::White).bg(Color::Black),
+            search_match: bold(fg(Color::LightCyan)).add_modifier(Modifier::UNDERLINED),
+            section: bold(fg(Color::LightYellow)).add_modifier(Modifier::UNDERLINED),
+            footer: bold(fg(Color::White)),
+            command_line: bold(fg(Color::White)),
+            message: bold(fg(Color::LightGreen)),
+        }
+    }
+
+    // Only modifiers, never colors, per https://no-color.org.
+    fn no_color() -> Self {
+        let plain = Style::default();
+        Theme {
+            header: bold(plain),
+            title: plain,
+            border: plain,
+            border_focused: bold(plain),
+            text: plain,
+            track_name: bold(plain),
+            current_track: bold(plain),
+            selection: plain.add_modifier(Modifier::REVERSED),
+            selection_inactive: plain.add_modifier(Modifier::UNDERLINED),
+            status_playing: bold(plain),
+            status_paused: plain,
+            status_stopped: plain.add_modifier(Modifier::DIM),
+            time: plain,
+            gauge: plain,
+            search_match: bold(plain).add_modifier(Modifier::UNDERLINED),
+            section: bold(plain),
+            footer: plain,
+            command_line: plain,
+            message: bold(plain),
+        }
+    }
+
+    fn ocean() -> Self {
+        Theme {
+            header: bold(fg(Color::LightBlue)),
+            title: fg(Color::LightBlue),
+            border: fg(Color::Blue),
+            border_focused: fg(Color::LightCyan),
+            text: fg(Color::Gray),
+            track_name: bold(fg(Color::White)),
+            current_track: bold(fg(Color::LightCyan)),
+            selection: Style::default().bg(Color::Blue),
+            selection_inactive: fg(Color::LightCyan),
+            status_playing: bold(fg(Color::LightGreen)),
+            status_paused: bold(fg(Color::LightBlue)),
+            status_stopped: bold(fg(Color::Magenta)),
+            time: fg(Color::LightBlue),
+            gauge: fg(Color::LightBlue),
+            search_match: bold(fg(Color::LightGreen)).add_modifier(Modifier::UNDERLINED),
+            section: bold(fg(Color::LightCyan)),
+            footer: fg(Color::LightBlue),
+            command_line: fg(Color::White),
+            message: fg(Color::LightGreen),
+        }
+    }
+
+    pub fn builtin(name: &str) -> Option<Theme> {
+        match name {
+            "default" => Some(Theme::default()),
+            "high-contrast" => Some(Theme::high_contrast()),
+            "no-color" => Some(Theme::no_color()),
+            "ocean" => Some(Theme::ocean()),
+            _ => None,
+        }
+    }
+
+    // `NO_COLOR` wins over everything. Otherwise `name` is a built-in theme,
+    // a theme file in ~/.config/rust-cli-music-player/themes/, or a path.
+    pub fn resolve(name: &str) -> Result<Theme> {
+        if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+            return Ok(Theme::no_color());
+        }
+        Self::resolve_named(name, 0)
+    }
+
+    fn resolve_named(name: &str, depth: usize) -> Result<Theme> {
+        if let Some(theme) = Theme::builtin(name) {
+            return Ok(theme);
+        }
+        if depth > 8 {
+            anyhow::bail!("Theme inheritance is too deep at '{}'", name);
+        }
+        let path = theme_path(name)
+            .with_context(|| format!("Unknown theme '{}' (built-in: {})", name, BUILTIN_THEMES.join(", ")))?;
+        Theme::load(&path, depth)
+    }
+
+    // A theme file overrides styles of the theme it `inherits` (default:
+    // "default"). Each style is a table of `fg`, `bg` and `modifiers`:
+    //
+    //     inherits = "high-contrast"
+    //     [current_track]
+    //     fg = "#ff8800"
+    //     modifiers = ["bold", "italic"]
+    fn load(path: &Path, depth: usize) -> Result<Theme> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let table: Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        let base = match table.get("inherits") {
+            Some(Value::String(parent)) => Self::resolve_named(parent, depth + 1)?,
+            Some(_) => anyhow::bail!("{}: inherits should be a theme name", path.display()),
+            None => Theme::default(),
+        };
+
+        let mut theme = base;
+        for (key, value) in &table {
+            if key == "inherits" {
+                continue;
+            }
+            let slot = theme.style_mut(key)
+                .with_context(|| format!("{}: unknown style '{}'", path.display(), key))?;
+            *slot = parse_style(*slot, value)
+                .with_context(|| format!("{}: in [{}]", path.display(), key))?;
+        }
+        Ok(theme)
+    }
+
+    fn style_mut(&mut self, name: &str) -> Option<&mut Style> {
+        let style = match name {
+            "header" => &mut self.header,
+            "title" => &mut self.title,
+            "border" => &mut self.border,
+            "border_focused" => &mut self.border_focused,
+            "text" => &mut self.text,
+            "track_name" => &mut self.track_name,
+            "current_track" => &mut self.current_track,
+            "selection" => &mut self.selection,
+            "selection_inactive" => &mut self.selection_inactive,
+            "status_playing" => &mut self.status_playing,
+            "status_paused" => &mut self.status_paused,
+            "status_stopped" => &mut self.status_stopped,
+            "time" => &mut self.time,
+            "gauge" => &mut self.gauge,
+            "search_match" => &mut self.search_match,
+            "section" => &mut self.section,
+            "footer" => &mut self.footer,
+            "command_line" => &mut self.command_line,
+            "message" => &mut self.message,
+            _ => return None,
+        };
+        Some(style)
+    }
+}
+
+fn theme_path(name: &str) -> Option<PathBuf> {
+    if name.contains('/') || name.ends_with(".toml") {
+        return Some(expand_path(name));
+    }
+    let path = crate::config::config_dir()?.join("themes").join(format!("{}.toml", name));
+    path.exists().then_some(path)
+}
+
+// Fields replace the inherited style's; a style table with only `fg` keeps
+// the parent's background and modifiers.
+fn parse_style(base: Style, value: &Value) -> Result<Style> {
+    let table = value.as_table().context("expected a table with fg/bg/modifiers")?;
+    let mut style = base;
+    for (key, value) in table {
+        match key.as_str() {
+            "fg" => style.fg = Some(parse_color(value)?),
+            "bg" => style.bg = Some(parse_color(value)?),
+            "modifiers" => {
+                let mut modifiers = Modifier::empty();
+                for name in value.as_array().context("modifiers should be a list")? {
+                    let name = name.as_str().context("modifiers should be strings")?;
+                    modifiers |= parse_modifier(name)?;
+                }
+                style.add_modifier = modifiers;
+                style.sub_modifier = Modifier::empty();
+            }
+            _ => anyhow::bail!("unknown field '{}'", key),
+        }
+    }
+    Ok(style)
+}
+
+fn parse_color(value: &Value) -> Result<Color> {
+    let text = value.as_str().context("colors should be strings")?;
+    Color::from_str(text).map_err(|_| anyhow::anyhow!("unknown color '{}'", text))
+}
+
+fn parse_modifier(name: &str) -> Result<Modifier> {
+    let modifier = match name {
+        "bold" => Modifier::BOLD,
+        "dim" => Modifier::DIM,
+        "italic" => Modifier::ITALIC,
+        "underlined" => Modifier::UNDERLINED,
+        "reversed" => Modifier::REVERSED,
+        "crossed_out" => Modifier::CROSSED_OUT,
+        "slow_blink" => Modifier::SLOW_BLINK,
+        _ => anyhow::bail!("unknown modifier '{}'", name),
+    };
+    Ok(modifier)
+}
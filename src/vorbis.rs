@@ -0,0 +1,435 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+
+// FLAC metadata block types.
+const STREAMINFO: u8 = 0;
+const PADDING: u8 = 1;
+const VORBIS_COMMENT: u8 = 4;
+// Room left for later edits when a FLAC file has to be rewritten anyway,
+// so the next save can be done in place.
+const NEW_PADDING: usize = 4096;
+// Block lengths are 24 bits.
+const MAX_BLOCK: usize = (1 << 24) - 1;
+const OGG_IDENTIFICATION: &[u8] = b"\x01vorbis";
+const OGG_COMMENT: &[u8] = b"\x03vorbis";
+
+// The NAME=value tags FLAC and Ogg Vorbis files carry instead of ID3.
+// Names are case-insensitive and may repeat; `get` takes the first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Comments {
+    vendor: String,
+    fields: Vec<(String, String)>,
+}
+
+pub fn is_vorbis(path: &Path) -> bool {
+    format(path).is_some()
+}
+
+// The number a field starts with: 3 for TRACKNUMBER=3/12, 1959 for
+// DATE=1959-08-17.
+pub fn number(value: &str) -> Option<u32> {
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    value[..digits].parse().ok()
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Flac,
+    Ogg,
+}
+
+fn format(path: &Path) -> Option<Format> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "flac" => Some(Format::Flac),
+        "ogg" | "oga" => Some(Format::Ogg),
+        _ => None,
+    }
+}
+
+impl Comments {
+    // A file without a comment block reads as empty.
+    pub fn read(path: &Path) -> Result<Comments> {
+        let read = match format(path) {
+            Some(Format::Flac) => read_flac(path),
+            Some(Format::Ogg) => read_ogg(path),
+            None => anyhow::bail!("{} isn't a FLAC or Ogg file", path.display()),
+        };
+        read.with_context(|| format!("Failed to read tags from {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let written = match format(path) {
+            Some(Format::Flac) => write_flac(path, self),
+            Some(Format::Ogg) => write_ogg(path, self),
+            None => anyhow::bail!("{} isn't a FLAC or Ogg file", path.display()),
+        };
+        written.with_context(|| format!("Failed to write tags to {}", path.display()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Replaces every value of `name`; `None` just removes them.
+    pub fn set(&mut self, name: &str, value: Option<&str>) {
+        self.fields.retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+        if let Some(value) = value {
+            self.fields.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    fn parse(mut data: &[u8]) -> Option<Comments> {
+        let vendor = take_string(&mut data)?;
+        let count = take_u32(&mut data)?;
+        let mut fields = Vec::new();
+        for _ in 0..count {
+            let field = take_string(&mut data)?;
+            if let Some((name, value)) = field.split_once('=') {
+                fields.push((name.to_string(), value.to_string()));
+            }
+        }
+        Some(Comments { vendor, fields })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_string(&mut bytes, &self.vendor);
+        bytes.extend((self.fields.len() as u32).to_le_bytes());
+        for (name, value) in &self.fields {
+            put_string(&mut bytes, &format!("{}={}", name, value));
+        }
+        bytes
+    }
+}
+
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    let (number, rest) = data.split_first_chunk::<4>()?;
+    *data = rest;
+    Some(u32::from_le_bytes(*number))
+}
+
+fn take_string(data: &mut &[u8]) -> Option<String> {
+    let len = take_u32(data)? as usize;
+    if len > data.len() {
+        return None;
+    }
+    let (text, rest) = data.split_at(len);
+    *data = rest;
+    Some(String::from_utf8_lossy(text).into_owned())
+}
+
+fn put_string(bytes: &mut Vec<u8>, text: &str) {
+    bytes.extend((text.len() as u32).to_le_bytes());
+    bytes.extend(text.as_bytes());
+}
+
+// A FLAC metadata block: its type and contents.
+type Block = (u8, Vec<u8>);
+
+// The metadata blocks of a FLAC file, and where the audio starts.
+fn flac_blocks(file: &mut impl Read) -> Result<(Vec<Block>, u64)> {
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        anyhow::bail!("not a FLAC file");
+    }
+    let mut blocks = Vec::new();
+    let mut offset = 4;
+    loop {
+        let mut header = [0; 4];
+        file.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut data = vec![0; len];
+        file.read_exact(&mut data)?;
+        blocks.push((header[0] & 0x7f, data));
+        offset += 4 + len as u64;
+        if header[0] & 0x80 != 0 {
+            return Ok((blocks, offset));
+        }
+    }
+}
+
+fn read_flac(path: &Path) -> Result<Comments> {
+    let (blocks, _) = flac_blocks(&mut BufReader::new(File::open(path)?))?;
+    match blocks.iter().find(|(kind, _)| *kind == VORBIS_COMMENT) {
+        Some((_, data)) => Comments::parse(data).context("damaged comment block"),
+        None => Ok(Comments::default()),
+    }
+}
+
+// Done in place when the new blocks fit where the old ones were, with
+// padding taking up any slack. Otherwise the audio has to move, so the
+// whole file is written anew next to the old one and renamed over it.
+fn write_flac(path: &Path, comments: &Comments) -> Result<()> {
+    let mut file = BufReader::new(File::open(path)?);
+    let (mut blocks, audio) = flac_blocks(&mut file)?;
+    blocks.retain(|(kind, _)| *kind != PADDING && *kind != VORBIS_COMMENT);
+    // STREAMINFO has to stay first.
+    let at = blocks.iter().position(|(kind, _)| *kind == STREAMINFO).map_or(0, |i| i + 1);
+    blocks.insert(at, (VORBIS_COMMENT, comments.to_bytes()));
+
+    let room = audio as usize - 4;
+    let needed: usize = blocks.iter().map(|(_, data)| 4 + data.len()).sum();
+    if needed == room || needed + 4 <= room {
+        if needed < room {
+            blocks.push((PADDING, vec![0; room - needed - 4]));
+        }
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&flac_metadata(&blocks)?)?;
+        return Ok(());
+    }
+
+    blocks.push((PADDING, vec![0; NEW_PADDING]));
+    let temp = temp_path(path);
+    let copied = (|| {
+        let mut out = BufWriter::new(File::create(&temp)?);
+        out.write_all(b"fLaC")?;
+        out.write_all(&flac_metadata(&blocks)?)?;
+        file.seek(SeekFrom::Start(audio))?;
+        std::io::copy(&mut file, &mut out)?;
+        out.flush()?;
+        Ok(())
+    })();
+    replace(&temp, path, copied)
+}
+
+fn flac_metadata(blocks: &[Block]) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for (i, (kind, data)) in blocks.iter().enumerate() {
+        if data.len() > MAX_BLOCK {
+            anyhow::bail!("tags too large for a FLAC block");
+        }
+        let last = if i + 1 == blocks.len() { 0x80 } else { 0 };
+        bytes.push(kind | last);
+        bytes.extend(&(data.len() as u32).to_be_bytes()[1..]);
+        bytes.extend(data);
+    }
+    Ok(bytes)
+}
+
+// The comment header is the second packet of a Vorbis stream.
+fn read_ogg(path: &Path) -> Result<Comments> {
+    let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+    let identification = reader.read_packet_expected()?;
+    if !identification.data.starts_with(OGG_IDENTIFICATION) {
+        anyhow::bail!("not an Ogg Vorbis file");
+    }
+    let comment = reader.read_packet_expected()?;
+    comment.data.strip_prefix(OGG_COMMENT)
+        .and_then(Comments::parse)
+        .context("damaged comment header")
+}
+
+// Ogg pages carry checksums and sequence numbers, so the file is written
+// anew packet by packet, keeping the page breaks and granule positions,
+// with the comment header swapped.
+fn write_ogg(path: &Path, comments: &Comments) -> Result<()> {
+    let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+    let temp = temp_path(path);
+    let copied = (|| {
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(&temp)?));
+        let mut serial = None;
+        let mut index = 0;
+        while let Some(packet) = reader.read_packet()? {
+            let stream = *serial.get_or_insert(packet.stream_serial());
+            let mut data = packet.data.clone();
+            if packet.stream_serial() == stream {
+                if index == 0 && !data.starts_with(OGG_IDENTIFICATION) {
+                    anyhow::bail!("not an Ogg Vorbis file");
+                }
+                if index == 1 {
+                    data = OGG_COMMENT.to_vec();
+                    data.extend(comments.to_bytes());
+                    // The framing bit.
+                    data.push(1);
+                }
+                index += 1;
+            }
+            let end = if packet.last_in_stream() {
+                PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            writer.write_packet(data.into_boxed_slice(), packet.stream_serial(), end, packet.absgp_page())?;
+        }
+        writer.inner_mut().flush()?;
+        Ok(())
+    })();
+    replace(&temp, path, copied)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+// Puts the rewritten file in place of the old one, with the old one's
+// permissions, or cleans up after a failed rewrite.
+fn replace(temp: &Path, path: &Path, written: Result<()>) -> Result<()> {
+    let renamed = written.and_then(|_| {
+        std::fs::set_permissions(temp, std::fs::metadata(path)?.permissions())?;
+        Ok(std::fs::rename(temp, path)?)
+    });
+    match renamed {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = std::fs::remove_file(temp);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("rcmp-vorbis-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn comments(title: &str) -> Comments {
+        let mut comments = Comments { vendor: "test".to_string(), fields: Vec::new() };
+        comments.set("TITLE", Some(title));
+        comments.set("ARTIST", Some("Someone"));
+        comments
+    }
+
+    // Only the metadata is ever parsed, so the "audio" can be any bytes.
+    const AUDIO: &[u8] = b"not really audio, but it has to come through untouched";
+
+    fn flac(padding: usize) -> Vec<u8> {
+        let blocks = vec![
+            (STREAMINFO, vec![7; 34]),
+            (VORBIS_COMMENT, comments("Before").to_bytes()),
+            (PADDING, vec![0; padding]),
+        ];
+        let mut bytes = b"fLaC".to_vec();
+        bytes.extend(flac_metadata(&blocks).unwrap());
+        bytes.extend(AUDIO);
+        bytes
+    }
+
+    #[test]
+    fn flac_tags_are_rewritten_in_place_within_the_padding() {
+        let dir = TempDir::new("flac-in-place");
+        let path = dir.0.join("song.flac");
+        std::fs::write(&path, flac(1000)).unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        comments("After").write(&path).unwrap();
+        let after = std::fs::read(&path).unwrap();
+        assert_eq!(after.len(), before.len());
+        assert!(after.ends_with(AUDIO));
+        assert_eq!(Comments::read(&path).unwrap(), comments("After"));
+
+        let (blocks, _) = flac_blocks(&mut after.as_slice()).unwrap();
+        let kinds: Vec<u8> = blocks.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [STREAMINFO, VORBIS_COMMENT, PADDING]);
+        assert_eq!(blocks[0].1, vec![7; 34]);
+    }
+
+    #[test]
+    fn flac_files_grow_when_the_tags_outgrow_the_padding() {
+        let dir = TempDir::new("flac-grow");
+        let path = dir.0.join("song.flac");
+        std::fs::write(&path, flac(0)).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        }
+
+        let long = "x".repeat(500);
+        comments(&long).write(&path).unwrap();
+        let after = std::fs::read(&path).unwrap();
+        assert!(after.ends_with(AUDIO));
+        assert_eq!(Comments::read(&path).unwrap(), comments(&long));
+        let (blocks, _) = flac_blocks(&mut after.as_slice()).unwrap();
+        assert_eq!(blocks.last().unwrap(), &(PADDING, vec![0; NEW_PADDING]));
+        assert!(!temp_path(&path).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        }
+
+        // The padding it was given makes room for the next edit.
+        let size = after.len();
+        comments("Short again").write(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), size);
+        assert_eq!(Comments::read(&path).unwrap(), comments("Short again"));
+    }
+
+    // (data, last in page, last in stream, granule position)
+    type Packet = (Vec<u8>, bool, bool, u64);
+
+    fn packets(path: &Path) -> Vec<Packet> {
+        let mut reader = PacketReader::new(File::open(path).unwrap());
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push((packet.data.clone(), packet.last_in_page(), packet.last_in_stream(), packet.absgp_page()));
+        }
+        packets
+    }
+
+    #[test]
+    fn ogg_pages_and_granule_positions_survive_a_rewrite() {
+        let dir = TempDir::new("ogg");
+        let path = dir.0.join("song.ogg");
+        let mut comment = OGG_COMMENT.to_vec();
+        comment.extend(comments("Before").to_bytes());
+        comment.push(1);
+        {
+            let mut writer = PacketWriter::new(File::create(&path).unwrap());
+            let mut write = |data: Vec<u8>, end, granule| {
+                writer.write_packet(data.into_boxed_slice(), 42, end, granule).unwrap();
+            };
+            let mut identification = OGG_IDENTIFICATION.to_vec();
+            identification.extend([0; 23]);
+            write(identification, PacketWriteEndInfo::EndPage, 0);
+            write(comment, PacketWriteEndInfo::NormalPacket, 0);
+            write(b"\x05vorbis setup".to_vec(), PacketWriteEndInfo::EndPage, 0);
+            write(vec![1; 300], PacketWriteEndInfo::NormalPacket, 1024);
+            write(vec![2; 300], PacketWriteEndInfo::EndPage, 1024);
+            write(vec![3; 300], PacketWriteEndInfo::EndPage, 2048);
+            write(vec![4; 100], PacketWriteEndInfo::EndStream, 2500);
+        }
+        let before = packets(&path);
+
+        comments("After").write(&path).unwrap();
+        assert_eq!(Comments::read(&path).unwrap(), comments("After"));
+        let after = packets(&path);
+        assert_eq!(after.len(), before.len());
+        for (i, (old, new)) in before.iter().zip(&after).enumerate() {
+            if i == 1 {
+                assert!(new.0.starts_with(OGG_COMMENT));
+                assert_eq!(new.0.last(), Some(&1));
+                assert_eq!((old.1, old.2, old.3), (new.1, new.2, new.3));
+            } else {
+                assert_eq!(old, new, "packet {}", i);
+            }
+        }
+    }
+}